@@ -1,6 +1,7 @@
 use core::fmt;
+use std::path::PathBuf;
 
-use clap::{Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 #[derive(Parser, Debug)]
 #[command(name="Fuck Vanguard")]
@@ -9,6 +10,144 @@ use clap::{Parser, ValueEnum};
 pub struct Cli {
     #[arg(short, long, default_value_t=Verbosity::Debug, value_enum)]
     pub verbosity: Verbosity,
+
+    /// VM configuration file (TOML)
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Restore the VM from a snapshot instead of booting the firmware
+    #[arg(short, long)]
+    pub restore: Option<PathBuf>,
+
+    /// ISO images attached as CD-ROMs, after the disks of the configuration file
+    #[arg(long)]
+    pub cdrom: Vec<PathBuf>,
+
+    /// Print the device kinds a configuration file can use, and exit
+    #[arg(long)]
+    pub list_devices: bool,
+
+    /// Unix socket to listen on for monitor commands
+    #[arg(short, long)]
+    pub monitor: Option<PathBuf>,
+
+    /// MSRs whose guest reads and writes are trapped and logged, comma separated,
+    /// `default` for the syscall and feature control ones
+    #[arg(long, value_delimiter = ',')]
+    pub trap_msrs: Vec<String>,
+
+    /// What trapped MSR accesses do, comma separated `index=pass|ignore|gp|value`,
+    /// the MSRs are trapped too
+    #[arg(long, value_delimiter = ',')]
+    pub msr_rules: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Tools run instead of the VM
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Inspect or edit an OVMF variable store image (OVMF_VARS.fd)
+    Vars {
+        image: PathBuf,
+
+        #[command(subcommand)]
+        action: VarsAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum VarsAction {
+    /// Print the variables of the store, the well-known ones decoded
+    List,
+    /// Print a variable and its data
+    Show {
+        name: String,
+
+        /// Vendor GUID, the UEFI global variable one by default
+        #[arg(long)]
+        guid: Option<String>,
+    },
+    /// Add or replace a variable
+    Set {
+        name: String,
+
+        /// Vendor GUID, the UEFI global variable one by default
+        #[arg(long)]
+        guid: Option<String>,
+
+        /// Comma separated NV, BS, RT, HR, AW, AT and AP, or a number, those of
+        /// the variable it replaces or NV,BS,RT by default
+        #[arg(long)]
+        attributes: Option<String>,
+
+        #[command(flatten)]
+        data: VarData,
+
+        /// Image written, instead of editing the store in place
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Remove a variable
+    Delete {
+        name: String,
+
+        /// Vendor GUID, the UEFI global variable one by default
+        #[arg(long)]
+        guid: Option<String>,
+
+        /// Image written, instead of editing the store in place
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Enroll Secure Boot keys from X.509 certificates (DER or PEM) or `.esl` signature lists
+    Enroll {
+        /// Platform key
+        #[arg(long)]
+        pk: Option<PathBuf>,
+
+        /// Key exchange keys
+        #[arg(long)]
+        kek: Vec<PathBuf>,
+
+        /// Allowed signatures database
+        #[arg(long)]
+        db: Vec<PathBuf>,
+
+        /// Forbidden signatures database
+        #[arg(long)]
+        dbx: Vec<PathBuf>,
+
+        /// GUID owning the enrolled certificates
+        #[arg(long, default_value = "00000000-0000-0000-0000-000000000000")]
+        owner: String,
+
+        /// Image written, instead of editing the store in place
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Data of a variable set, one of them
+#[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
+pub struct VarData {
+    /// Bytes in hex, spaces allowed
+    #[arg(long)]
+    pub hex: Option<String>,
+
+    /// ASCII string, NUL terminated as Lang and PlatformLang
+    #[arg(long)]
+    pub string: Option<String>,
+
+    /// Comma separated boot option numbers in hex, for BootOrder
+    #[arg(long)]
+    pub options: Option<String>,
+
+    /// File holding the data
+    #[arg(long)]
+    pub file: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
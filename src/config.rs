@@ -0,0 +1,149 @@
+//! VM configuration file, TOML.
+//!
+//! ```toml
+//! irqchip = "split"
+//! virtio_transport = "mmio"
+//! kvm_devices = ["vfio"]
+//!
+//! [[disk]]
+//! path = "win11.qcow2"
+//! format = "qcow2"
+//! interface = "ahci"
+//!
+//! [[disk]]
+//! path = "data.raw"
+//! interface = "nvme"
+//!
+//! [[disk]]
+//! path = "Win11_24H2.iso"
+//! cdrom = true
+//!
+//! [[device]]
+//! kind = "nvme"
+//! path = "scratch.raw"
+//! readonly = false
+//!
+//! [[device]]
+//! kind = "tpm-crb"
+//! socket = "/run/swtpm/ctrl.sock"
+//!
+//! [rtc]
+//! clock = "localtime"
+//!
+//! [reset]
+//! triple_fault = "pause"
+//!
+//! [smbios]
+//! manufacturer = "Dell Inc."
+//! product = "OptiPlex 7080"
+//! serial = "7XK2Q93"
+//! uuid = "4c4c4544-0058-4b10-8032-b7c04f513933"
+//!
+//! [cpu]
+//! model = "x86-64-v3"
+//!
+//! [[cpu.cpuid]]
+//! leaf = 0x7
+//! ebx = { clear = 0x20 }
+//! ```
+
+use std::path::{ Path, PathBuf };
+
+use serde::Deserialize;
+
+use crate::vmm::{
+    cpuid::CpuConfig,
+    disk::{ DiskFormat, DiskInterface },
+    irq::IrqChipMode,
+    registry::DeviceOptions,
+    reset::ResetConfig,
+    rtc::RtcConfig,
+    smbios::SmbiosConfig,
+    virtio::VirtioTransport,
+};
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ConfigError {
+    /// Can't read config file: {0}
+    Io(#[from] std::io::Error),
+    /// Bad config file: {0}
+    Parse(#[from] toml::de::Error),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VmConfig {
+    pub irqchip: IrqChipMode,
+    pub cpu: CpuConfig,
+    /// `mmio` takes 4 virtio devices at most, `pci` by default
+    pub virtio_transport: VirtioTransport,
+    pub rtc: RtcConfig,
+    /// Reboot, exit or pause on each reset source
+    pub reset: ResetConfig,
+    pub smbios: SmbiosConfig,
+    #[serde(rename = "disk")]
+    pub disks: Vec<DiskConfig>,
+    /// Any device of the registry, after the disks
+    #[serde(rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+    /// In-kernel KVM devices by kind name
+    pub kvm_devices: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiskConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub readonly: bool,
+    /// Needed for writable qcow2 images: without it a writable image is raw,
+    /// and refused if it has a qcow2 header
+    #[serde(default)]
+    pub format: Option<DiskFormat>,
+    /// qcow2 backing files may be out of the image directory
+    #[serde(default)]
+    pub backing_anywhere: bool,
+    #[serde(default)]
+    pub interface: DiskInterface,
+    /// ATAPI CD-ROM on the AHCI controller, always read only
+    #[serde(default)]
+    pub cdrom: bool,
+}
+
+/// Device kind name and its options, checked by the registry
+#[derive(Debug, Deserialize)]
+pub struct DeviceConfig {
+    pub kind: String,
+    #[serde(flatten)]
+    pub options: DeviceOptions,
+}
+
+impl DiskConfig {
+    /// The registry kind and options this disk stands for
+    pub fn device(&self) -> DeviceConfig {
+        let kind = match (self.cdrom, self.interface) {
+            (true, _) => "cdrom",
+            (false, DiskInterface::Virtio) => "virtio-blk",
+            (false, DiskInterface::Ahci) => "ahci-disk",
+            (false, DiskInterface::Nvme) => "nvme",
+        };
+        let mut options = DeviceOptions::default();
+        options.set_path("path", self.path.clone());
+        if !self.cdrom {
+            options.set("readonly", self.readonly);
+            if let Some(format) = self.format {
+                options.set("format", format.to_string());
+            }
+            if self.backing_anywhere {
+                options.set("backing_anywhere", true);
+            }
+        }
+        DeviceConfig { kind: kind.to_string(), options }
+    }
+}
+
+impl VmConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
@@ -7,10 +7,18 @@ use kvm_ioctls::Kvm;
 
 mod args;
 mod asm_code;
+mod config;
 mod mem_inspection;
+mod monitor;
+mod util;
+mod vars;
 mod vmm;
 
-use crate::args::{ Cli, Verbosity };
+use crate::args::{ Cli, Command, Verbosity };
+use crate::config::{ DiskConfig, VmConfig };
+use crate::monitor::Monitor;
+use crate::vmm::msr::{ parse_msr_index, MsrFilter, RuleMsrHandler, INTERESTING_MSRS };
+use crate::vmm::registry;
 use crate::vmm::vm_builder::*;
 
 #[allow(unused)]
@@ -50,19 +58,72 @@ fn setup_logging(
 
 fn main() {
     let cli = Cli::parse();
+    if cli.list_devices {
+        for kind in registry::DEVICE_KINDS {
+            print!("{kind}");
+        }
+        for kind in registry::KVM_DEVICE_KINDS {
+            print!("{kind}");
+        }
+        return;
+    }
+    if let Some(Command::Vars { image, action }) = cli.command {
+        if let Err(e) = vars::run(&image, action) {
+            eprintln!("{}: {e}", image.display());
+            std::process::exit(1);
+        }
+        return;
+    }
     setup_logging(cli.verbosity, Some("/tmp/vmm.log")).unwrap();
     debug!("logger init done");
     info!("--- Fuck Vanguard Starting ---");
 
+    let config = match &cli.config {
+        Some(path) => VmConfig::load(path).expect("Bad VM config"),
+        None => VmConfig::default(),
+    };
     let kvm: Kvm = Kvm::new().expect("KVM Failed to start");
-    let mut vm = kvm
-        .setup_vm()
-        .expect("KVM Create VM failed")
-        .ram(0x100000000) // 4GB (see OVMF doc)
-        .load("/home/paco/repo/edk2/Build/OvmfX64/DEBUG_GCC5/FV/OVMF.fd")
-        .unwrap()
-        .build()
-        .expect("VM Creation failed");
+    let builder = kvm.setup_vm(config.irqchip).expect("KVM Create VM failed");
+    let builder = match cli.restore {
+        Some(snapshot_path) => builder.restore(snapshot_path).expect("Snapshot restore failed"),
+        None =>
+            builder
+                .ram(0x100000000) // 4GB (see OVMF doc)
+                .load("/home/paco/repo/edk2/Build/OvmfX64/DEBUG_GCC5/FV/OVMF.fd")
+                .unwrap(),
+    };
+    let mut trapped_msrs = vec![];
+    for msr in &cli.trap_msrs {
+        match msr.as_str() {
+            "default" => trapped_msrs.extend_from_slice(INTERESTING_MSRS),
+            msr => trapped_msrs.push(parse_msr_index(msr).expect("Bad MSR trap")),
+        }
+    }
+    let msr_rules = RuleMsrHandler::parse(&cli.msr_rules).expect("Bad MSR rules");
+    trapped_msrs.extend(msr_rules.msrs());
+    let mut builder = builder
+        .cpu(config.cpu)
+        .rtc(config.rtc)
+        .reset(config.reset)
+        .smbios(config.smbios)
+        .msr_filter(MsrFilter::both(&trapped_msrs))
+        .virtio_transport(config.virtio_transport);
+    for device in config.disks.iter().map(DiskConfig::device).chain(config.devices) {
+        builder = builder.device(&device.kind, &device.options).expect("Bad device");
+    }
+    for kind in &config.kvm_devices {
+        builder = builder.kvm_device(kind).expect("Bad KVM device");
+    }
+    for path in &cli.cdrom {
+        builder = builder.cdrom(path).expect("Can't open CD-ROM image");
+    }
+    let mut vm = builder.build().expect("VM Creation failed");
+    vm.set_msr_handler(Box::new(msr_rules));
+    // SIGUSR1 asks the guest to shut down, like pressing the power button
+    if let Some(button) = vm.power_button() {
+        button.press_on_signal(libc::SIGUSR1).expect("Power button signal setup failed");
+    }
+    let mut monitor = cli.monitor.map(|path| Monitor::listen(path).expect("Monitor setup failed"));
     info!("Starting VM");
 
     // Todo bring errors up here, only error!() in calling function. panic!() here ?
@@ -70,6 +131,9 @@ fn main() {
         if !keep_running {
             break;
         }
+        if let Some(monitor) = monitor.as_mut() {
+            monitor.serve(&mut vm);
+        }
     }
     info!("Nicely shutdown, well played ;)")
 }
@@ -0,0 +1,251 @@
+//! Monitor: line based control socket for a running VM.
+//!
+//! A listener thread reads commands from a Unix socket and queues them for the
+//! vCPU thread, kicking it out of KVM_RUN with a signal so they are served
+//! between two guest exits. Every command gets a text reply.
+
+use std::{
+    io::{ BufRead, BufReader, Write },
+    os::unix::net::{ UnixListener, UnixStream },
+    path::Path,
+    sync::mpsc::{ channel, Receiver, RecvTimeoutError, Sender },
+    thread,
+    time::Duration,
+};
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vmm_sys_util::signal::{ register_signal_handler, SIGRTMIN };
+
+use crate::{ util::parse_u64, vmm::{ i8042::InputEvent, write_diff::DiffMark, Vm } };
+
+/// How often a client re-kicks the vCPU while its command is pending
+const KICK_INTERVAL: Duration = Duration::from_millis(100);
+
+const HELP: &str = "\
+help                      this help
+mark [addr:len ...]       start tracking guest writes, with byte diffs for the given physical ranges
+diff [virtual]            pages written since mark, virtual adds guest virtual addresses and modules
+watch addr:len            trap and log guest writes to a physical range
+unwatch addr:len          stop trapping writes to a physical range
+watches                   list the watched physical ranges
+msrs [count]              last trapped MSR accesses, 32 by default
+key code [code ...]       press PS/2 keys in order and release them in reverse, set 2 hex codes, e0xx extended
+mouse dx dy [buttons]     move the PS/2 mouse, y going up, then set the left/right/middle button bits
+powerdown                 press the ACPI power button, the guest shuts down if it listens to it
+reset                     reset the machine and run the firmware again, also resumes a VM paused by a reset
+tpm                       PCR extends of the guest since the TPM was powered on
+snapshot path             save the whole VM, it becomes the base of incremental snapshots and resets
+snapshot-incr path        save the RAM pages written since the base snapshot, with the vCPU and devices
+reset-base                rewind the VM to the base snapshot, the last full one taken or restored
+";
+
+type Command = (String, Sender<String>);
+
+extern "C" fn kick_handler(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {}
+
+pub struct Monitor {
+    commands: Receiver<Command>,
+    diff_mark: Option<DiffMark>,
+}
+
+impl Monitor {
+    /// Listen on `path`, must be called from the thread running the vCPU
+    pub fn listen<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        register_signal_handler(SIGRTMIN(), kick_handler).map_err(std::io::Error::from)?;
+        let vcpu_thread = unsafe { libc::pthread_self() };
+        let (tx, commands) = channel();
+        info!("Monitor listening on {}", path.to_string_lossy());
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let tx = tx.clone();
+                        thread::spawn(move || serve_client(stream, tx, vcpu_thread));
+                    }
+                    Err(e) => warn!("Monitor accept failed: {e}"),
+                }
+            }
+        });
+        Ok(Self { commands, diff_mark: None })
+    }
+
+    /// Run the queued commands, to be called between two `Vm::run`
+    pub fn serve(&mut self, vm: &mut Vm) {
+        while let Ok((line, reply)) = self.commands.try_recv() {
+            debug!("Monitor command: {line}");
+            let _ = reply.send(self.execute(vm, line.trim()));
+        }
+    }
+
+    fn execute(&mut self, vm: &mut Vm, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return String::new();
+        };
+        let args: Vec<&str> = words.collect();
+        match command {
+            "help" => HELP.to_string(),
+            "mark" => {
+                let ranges: Option<Vec<(u64, usize)>> = args.iter().map(|a| parse_range(a)).collect();
+                let Some(ranges) = ranges else {
+                    return "usage: mark [addr:len ...]\n".to_string();
+                };
+                match vm.diff_mark(&ranges) {
+                    Ok(mark) => {
+                        self.diff_mark = Some(mark);
+                        "marked\n".to_string()
+                    }
+                    Err(e) => format!("mark failed: {e}\n"),
+                }
+            }
+            "diff" => {
+                let Some(mark) = &self.diff_mark else {
+                    return "no mark, run mark first\n".to_string();
+                };
+                match vm.diff_report(mark, args.first() == Some(&"virtual")) {
+                    Ok(diff) => diff.to_string(),
+                    Err(e) => format!("diff failed: {e}\n"),
+                }
+            }
+            "watch" | "unwatch" => {
+                let Some((addr, len)) = args.first().and_then(|a| parse_range(a)) else {
+                    return format!("usage: {command} addr:len\n");
+                };
+                let res = if command == "watch" {
+                    vm.watch_writes(addr, len)
+                } else {
+                    vm.unwatch_writes(addr, len)
+                };
+                match res {
+                    Ok(()) => "ok\n".to_string(),
+                    Err(e) => format!("{command} failed: {e}\n"),
+                }
+            }
+            "watches" =>
+                vm.ram.watched()
+                    .iter()
+                    .map(|(start, end)| format!("0x{start:x}..0x{end:x}\n"))
+                    .collect(),
+            "msrs" => {
+                let count = args.first().and_then(|a| parse_u64(a)).unwrap_or(32) as usize;
+                let log: Vec<_> = vm.msr_log().collect();
+                log[log.len().saturating_sub(count)..]
+                    .iter()
+                    .map(|entry| format!("{entry}\n"))
+                    .collect()
+            }
+            "key" => {
+                let codes: Option<Vec<u16>> = args.iter().map(|a| u16::from_str_radix(a.trim_start_matches("0x"), 16).ok()).collect();
+                let (Some(codes), Some(input)) = (codes.filter(|codes| !codes.is_empty()), vm.input()) else {
+                    return "usage: key code [code ...]\n".to_string();
+                };
+                let presses = codes.iter().map(|&code| InputEvent::Key { code, pressed: true });
+                let releases = codes.iter().rev().map(|&code| InputEvent::Key { code, pressed: false });
+                match presses.chain(releases).try_for_each(|event| input.send(event)) {
+                    Ok(()) => "ok\n".to_string(),
+                    Err(e) => format!("key failed: {e}\n"),
+                }
+            }
+            "mouse" => {
+                let values: Option<Vec<i32>> = args.iter().map(|a| a.parse().ok()).collect();
+                let (Some(&[dx, dy, ref buttons @ ..]), Some(input)) = (values.as_deref(), vm.input()) else {
+                    return "usage: mouse dx dy [buttons]\n".to_string();
+                };
+                let mut events = vec![InputEvent::MouseMove { dx, dy, dz: 0 }];
+                events.extend(buttons.first().map(|&buttons| InputEvent::MouseButtons(buttons as u8)));
+                match events.into_iter().try_for_each(|event| input.send(event)) {
+                    Ok(()) => "ok\n".to_string(),
+                    Err(e) => format!("mouse failed: {e}\n"),
+                }
+            }
+            "powerdown" => {
+                let Some(button) = vm.power_button() else {
+                    return "no power button\n".to_string();
+                };
+                match button.press() {
+                    Ok(()) => "ok\n".to_string(),
+                    Err(e) => format!("powerdown failed: {e}\n"),
+                }
+            }
+            "tpm" => {
+                let Some(measurements) = vm.tpm_measurements() else {
+                    return "no TPM\n".to_string();
+                };
+                measurements.iter().map(|extend| format!("{extend}\n")).collect()
+            }
+            "reset" => match vm.reset() {
+                Ok(()) => "ok\n".to_string(),
+                Err(e) => format!("reset failed: {e}\n"),
+            },
+            // The vCPU is out of KVM_RUN while commands are served, the VM is stopped as it is saved
+            "snapshot" => {
+                let Some(path) = args.first() else {
+                    return "usage: snapshot path\n".to_string();
+                };
+                match vm.snapshot(path) {
+                    Ok(()) => "ok\n".to_string(),
+                    Err(e) => format!("snapshot failed: {e}\n"),
+                }
+            }
+            "snapshot-incr" => {
+                let Some(path) = args.first() else {
+                    return "usage: snapshot-incr path\n".to_string();
+                };
+                match vm.snapshot_incremental(path) {
+                    Ok(()) => "ok\n".to_string(),
+                    Err(e) => format!("snapshot-incr failed: {e}\n"),
+                }
+            }
+            "reset-base" =>
+                match vm.reset_to_base() {
+                    Ok(()) => "ok\n".to_string(),
+                    Err(e) => format!("reset-base failed: {e}\n"),
+                }
+            _ => format!("unknown command {command}, try help\n"),
+        }
+    }
+}
+
+fn serve_client(stream: UnixStream, tx: Sender<Command>, vcpu_thread: libc::pthread_t) {
+    let mut out = match stream.try_clone() {
+        Ok(out) => out,
+        Err(e) => {
+            warn!("Monitor client dropped: {e}");
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        let (reply_tx, reply_rx) = channel();
+        if tx.send((line, reply_tx)).is_err() {
+            return;
+        }
+        // A kick landing right before KVM_RUN is lost, keep kicking until served
+        let reply = loop {
+            unsafe {
+                libc::pthread_kill(vcpu_thread, SIGRTMIN());
+            }
+            match reply_rx.recv_timeout(KICK_INTERVAL) {
+                Ok(reply) => break reply,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        };
+        if out.write_all(reply.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+/// `addr:len`, both decimal or 0x prefixed hex
+fn parse_range(arg: &str) -> Option<(u64, usize)> {
+    let (addr, len) = arg.split_once(':')?;
+    Some((parse_u64(addr)?, parse_u64(len)? as usize))
+}
@@ -0,0 +1,22 @@
+//! Helpers shared by the VMM and its front ends.
+
+/// `0x` prefixed hexadecimal or decimal
+pub fn parse_u64(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_or_decimal() {
+        assert_eq!(parse_u64("0x1b"), Some(0x1b));
+        assert_eq!(parse_u64("27"), Some(27));
+        assert_eq!(parse_u64("1b"), None);
+        assert_eq!(parse_u64("0x"), None);
+    }
+}
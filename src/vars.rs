@@ -0,0 +1,117 @@
+//! The `vars` subcommand, variable store images edited offline
+
+use std::{ fs, path::Path };
+
+use crate::args::{ VarData, VarsAction };
+use crate::vmm::secure_boot::SecureBootKeys;
+use crate::vmm::uefi_vars::{
+    format_attributes, parse_attributes, Guid, Result, VarStore, VarStoreError, Variable, DEFAULT_ATTRIBUTES,
+    EFI_GLOBAL_VARIABLE,
+};
+
+/// Data shown by `list` before it is cut
+const LIST_DATA_BYTES: usize = 16;
+
+fn hex(data: &[u8]) -> String {
+    let bytes: Vec<String> = data.iter().map(|b| format!("{b:02x}")).collect();
+    bytes.join(" ")
+}
+
+/// 16 bytes a line, with their offset and ASCII
+fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let ascii: String =
+            line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        out.push_str(&format!("{:08x}  {:<47}  {ascii}\n", i * 16, hex(line)));
+    }
+    out
+}
+
+fn parse_guid(guid: Option<String>) -> Result<Guid> {
+    guid.map_or(Ok(EFI_GLOBAL_VARIABLE), |guid| guid.parse())
+}
+
+fn parse_data(data: VarData) -> Result<Vec<u8>> {
+    let bad = |e: &str| VarStoreError::Data(e.to_string());
+    if let Some(text) = data.hex {
+        let digits: Vec<u8> = text.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+        if !digits.len().is_multiple_of(2) {
+            return Err(bad("odd number of hex digits"));
+        }
+        return digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap_or("?"), 16).map_err(|_| bad("not hex")))
+            .collect();
+    }
+    if let Some(text) = data.string {
+        return Ok(text.bytes().chain([0]).collect());
+    }
+    if let Some(options) = data.options {
+        let mut out = vec![];
+        for number in options.split(',') {
+            let number = number.trim().trim_start_matches("Boot");
+            out.extend(u16::from_str_radix(number, 16).map_err(|_| bad("bad boot option number"))?.to_le_bytes());
+        }
+        return Ok(out);
+    }
+    match data.file {
+        Some(path) => Ok(fs::read(path)?),
+        None => Err(bad("no data")),
+    }
+}
+
+fn summary(variable: &Variable) -> String {
+    let value = variable.describe().unwrap_or_else(|| {
+        let cut = variable.data.len().min(LIST_DATA_BYTES);
+        let more = if cut < variable.data.len() { " ..." } else { "" };
+        format!("{}{more}", hex(&variable.data[..cut]))
+    });
+    format!(
+        "{} {} [{}] {} bytes: {value}",
+        variable.guid,
+        variable.name,
+        format_attributes(variable.attributes),
+        variable.data.len()
+    )
+}
+
+pub fn run(image: &Path, action: VarsAction) -> Result<()> {
+    let mut store = VarStore::open(image)?;
+    match action {
+        VarsAction::List => {
+            for variable in store.variables() {
+                println!("{}", summary(variable));
+            }
+        }
+        VarsAction::Show { name, guid } => {
+            let guid = parse_guid(guid)?;
+            let variable = store.get(guid, &name).ok_or(VarStoreError::NotFound(name))?;
+            println!("{}", summary(variable));
+            print!("{}", hexdump(&variable.data));
+        }
+        VarsAction::Set { name, guid, attributes, data, output } => {
+            let guid = parse_guid(guid)?;
+            let attributes = match attributes {
+                Some(attributes) => parse_attributes(&attributes)?,
+                None => store.get(guid, &name).map_or(DEFAULT_ATTRIBUTES, |old| old.attributes),
+            };
+            store.set(Variable::new(guid, &name, attributes, parse_data(data)?));
+            store.save(output.as_deref().unwrap_or(image))?;
+        }
+        VarsAction::Delete { name, guid, output } => {
+            if !store.delete(parse_guid(guid)?, &name) {
+                return Err(VarStoreError::NotFound(name));
+            }
+            store.save(output.as_deref().unwrap_or(image))?;
+        }
+        VarsAction::Enroll { pk, kek, db, dbx, owner, output } => {
+            store.enroll(&SecureBootKeys { pk, kek, db, dbx }, owner.parse()?)?;
+            store.save(output.as_deref().unwrap_or(image))?;
+            if store.get(EFI_GLOBAL_VARIABLE, "PK").is_none() {
+                println!("No PK enrolled, the firmware stays in setup mode");
+            }
+        }
+    }
+    Ok(())
+}
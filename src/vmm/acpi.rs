@@ -0,0 +1,484 @@
+//! ACPI tables describing our machine, handed to the firmware through fw_cfg.
+//!
+//! All tables but the RSDP go in one "etc/acpi/tables" blob. The table-loader
+//! script makes the firmware allocate it, patch the pointers between tables
+//! with the address it got and fix the checksums up; OVMF then installs every
+//! table a pointer leads to. The PM block ports are the ICH9 ones OVMF
+//! programs on Q35.
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+
+use super::{
+    aml::{ self, AddressSpace },
+    fw_cfg::{ AllocZone, TableLoader },
+    hpet::{ HPET_BASE, HPET_BLOCK_ID },
+    ioapic::IOAPIC_BASE,
+    irq::IrqChipMode,
+    q35::{ Q35_ECAM_BASE, Q35_ECAM_BUSES },
+    ram::{ PCI_HOLE_END, PCI_HOLE_START },
+    rtc::REG_CENTURY,
+    tpm::{ CRB_CTRL_REQ, TPM_CRB_BASE, TPM_CRB_REGION },
+    Vm,
+};
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+pub const ACPI_TABLES_FILE: &str = "etc/acpi/tables";
+pub const ACPI_RSDP_FILE: &str = "etc/acpi/rsdp";
+pub const TABLE_LOADER_FILE: &str = "etc/table-loader";
+
+pub const PM_BASE: u16 = 0x600;
+pub const PM1_EVT_BLK: u16 = PM_BASE;
+pub const PM1_EVT_LEN: u8 = 4;
+pub const PM1_CNT_BLK: u16 = PM_BASE + 0x04;
+pub const PM1_CNT_LEN: u8 = 2;
+pub const PM_TMR_BLK: u16 = PM_BASE + 0x08;
+pub const PM_TMR_LEN: u8 = 4;
+pub const GPE0_BLK: u16 = PM_BASE + 0x20;
+pub const GPE0_BLK_LEN: u8 = 16;
+pub const SCI_IRQ: u8 = 9;
+/// SLP_TYP of S5 in the DSDT _S5 package
+pub const SLP_TYP_S5: u8 = 0;
+/// GPE0 bit whose _Exx method notifies the power button
+pub const GPE_POWER_BUTTON: u8 = 0;
+/// Reset register of the FADT, a spare byte of the PM block so that its resets
+/// can be told from those of the reset control register
+pub const ACPI_RESET_REG: u16 = PM_BASE + 0x40;
+pub const RESET_VALUE: u8 = 0x06;
+
+const LAPIC_BASE: u32 = 0xfee0_0000;
+
+const OEM_ID: &[u8; 6] = b"FVVMM ";
+const OEM_TABLE_ID: &[u8; 8] = b"FVVMMACP";
+const CREATOR_ID: &[u8; 4] = b"FVVM";
+const HEADER_SIZE: usize = 36;
+const CHECKSUM_OFFSET: usize = 9;
+
+const FADT_REVISION: u8 = 6;
+const FADT_SIZE: usize = 276;
+const FADT_FIRMWARE_CTRL: usize = 36;
+const FADT_X_DSDT: usize = 140;
+const FADT_WBINVD: u32 = 1 << 0;
+const FADT_PROC_C1: u32 = 1 << 2;
+/// Power button is a control method device
+const FADT_PWR_BUTTON: u32 = 1 << 4;
+/// No fixed feature sleep button
+const FADT_SLP_BUTTON: u32 = 1 << 5;
+/// 32 bit PM timer
+const FADT_TMR_VAL_EXT: u32 = 1 << 8;
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+const BOOT_ARCH_8042: u16 = 1 << 1;
+const GAS_SYSTEM_IO: u8 = 1;
+const GAS_BYTE_ACCESS: u8 = 1;
+
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+/// Active high, level triggered
+const OVERRIDE_LEVEL_HIGH: u16 = 0x000d;
+const ALL_PROCESSORS: u8 = 0xff;
+
+const TPM2_SIZE: usize = 64;
+const TPM2_START_METHOD_CRB: u32 = 7;
+
+const FACS_SIZE: usize = 64;
+const FACS_ALIGN: u32 = 64;
+
+/// What the tables describe
+#[derive(Debug, Clone)]
+pub struct AcpiPlatform {
+    pub cpus: u8,
+    pub irqchip: IrqChipMode,
+    /// RAM above 4G, the 64 bit PCI window starts after it
+    pub high_ram: u64,
+    pub hpet: Option<u64>,
+    pub tpm: bool,
+}
+
+/// Blobs of the fw_cfg files
+#[derive(Debug)]
+pub struct AcpiTables {
+    pub rsdp: Vec<u8>,
+    pub tables: Vec<u8>,
+    pub loader: TableLoader,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg()
+}
+
+/// Table with its header, the length and checksum are set by [`AcpiBuilder`]
+fn sdt(signature: &[u8; 4], revision: u8, size: usize) -> Vec<u8> {
+    let mut table = vec![0; size.max(HEADER_SIZE)];
+    table[0..4].copy_from_slice(signature);
+    table[8] = revision;
+    table[10..16].copy_from_slice(OEM_ID);
+    table[16..24].copy_from_slice(OEM_TABLE_ID);
+    table[24..28].copy_from_slice(&1u32.to_le_bytes());
+    table[28..32].copy_from_slice(CREATOR_ID);
+    table[32..36].copy_from_slice(&1u32.to_le_bytes());
+    table
+}
+
+/// Generic address structure of an I/O port block
+fn gas_io(port: u16, len: u8) -> [u8; 12] {
+    let mut gas = [0; 12];
+    if len > 0 {
+        gas[0] = GAS_SYSTEM_IO;
+        gas[1] = len * 8;
+        gas[3] = GAS_BYTE_ACCESS;
+        gas[4..12].copy_from_slice(&(port as u64).to_le_bytes());
+    }
+    gas
+}
+
+fn put_u16(table: &mut [u8], offset: usize, value: u16) {
+    table[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(table: &mut [u8], offset: usize, value: u32) {
+    table[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(table: &mut [u8], offset: usize, value: u64) {
+    table[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Lays the tables out in the blob, recording the loader commands
+#[derive(Debug, Default)]
+struct AcpiBuilder {
+    tables: Vec<u8>,
+    /// Offset and length of each table with a checksum
+    checksummed: Vec<(usize, usize)>,
+    loader: TableLoader,
+}
+
+impl AcpiBuilder {
+    /// Append `table`, returns its offset in the blob
+    fn add_table(&mut self, mut table: Vec<u8>) -> u32 {
+        let offset = self.tables.len();
+        let len = table.len();
+        table[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        self.tables.extend(table);
+        self.checksummed.push((offset, len));
+        offset as u32
+    }
+
+    /// Pointer of `size` bytes at `field` in the table at `table`, to `target` in the blob
+    fn link(&mut self, table: u32, field: usize, size: u8, target: u32) {
+        let at = table as usize + field;
+        self.tables[at..at + size as usize].copy_from_slice(&(target as u64).to_le_bytes()[..size as usize]);
+        self.loader.add_pointer(ACPI_TABLES_FILE, at as u32, size, ACPI_TABLES_FILE);
+    }
+
+    /// Checksum the tables, the firmware does it again once the pointers are patched
+    fn finish(mut self) -> (Vec<u8>, TableLoader) {
+        for &(start, len) in &self.checksummed {
+            self.tables[start + CHECKSUM_OFFSET] = 0;
+            self.tables[start + CHECKSUM_OFFSET] = checksum(&self.tables[start..start + len]);
+            self.loader.add_checksum(ACPI_TABLES_FILE, (start + CHECKSUM_OFFSET) as u32, start as u32, len as u32);
+        }
+        (self.tables, self.loader)
+    }
+}
+
+fn facs() -> Vec<u8> {
+    let mut facs = vec![0; FACS_SIZE];
+    facs[0..4].copy_from_slice(b"FACS");
+    put_u32(&mut facs, 4, FACS_SIZE as u32);
+    facs[32] = 2;
+    facs
+}
+
+fn dsdt(platform: &AcpiPlatform) -> Vec<u8> {
+    let ecam_end = Q35_ECAM_BASE + ((Q35_ECAM_BUSES as u64) << 20);
+    let high_start = PCI_HOLE_END + platform.high_ram;
+    // What the 64 bit BARs may get, up to a 40 bit physical address space
+    let high_end = (high_start.next_power_of_two() << 1).max(1 << 39) - 1;
+    // No _PRT: every function on bus 0 interrupts through MSI or MSI-X and
+    // leaves its Interrupt Pin at 0, the LPC PIRQ routes stay disabled, so
+    // there is no INTx for the OS to route
+    let pci0 = aml::device("PCI0", vec![
+        aml::name("_HID", aml::eisa_id("PNP0A08")),
+        aml::name("_CID", aml::eisa_id("PNP0A03")),
+        aml::name("_ADR", aml::integer(0)),
+        aml::name("_SEG", aml::integer(0)),
+        aml::name("_UID", aml::integer(0)),
+        aml::name("_BBN", aml::integer(0)),
+        aml::name("_CRS", aml::resource_template(vec![
+            aml::word_space(AddressSpace::BusNumber, 0, Q35_ECAM_BUSES - 1),
+            aml::io(0xcf8, 8),
+            aml::word_space(AddressSpace::Io, 0x0000, 0x0cf7),
+            aml::word_space(AddressSpace::Io, 0x0d00, 0xffff),
+            // VGA window
+            aml::dword_space(AddressSpace::Memory, 0x000a_0000, 0x000b_ffff),
+            aml::dword_space(AddressSpace::Memory, ecam_end.max(PCI_HOLE_START) as u32, (IOAPIC_BASE - 1) as u32),
+            aml::qword_space(AddressSpace::Memory, high_start, high_end),
+        ])),
+    ]);
+    // No COM port: the serial port only takes output, it is no 16550 a driver could bind to
+    let mut sb = vec![pci0];
+    sb.push(aml::device("PS2K", vec![
+        aml::name("_HID", aml::eisa_id("PNP0303")),
+        aml::name("_CRS", aml::resource_template(vec![aml::io(0x60, 1), aml::io(0x64, 1), aml::irq(1)])),
+    ]));
+    sb.push(aml::device("PS2M", vec![
+        aml::name("_HID", aml::eisa_id("PNP0F13")),
+        aml::name("_CRS", aml::resource_template(vec![aml::irq(12)])),
+    ]));
+    sb.push(aml::device("RTC", vec![
+        aml::name("_HID", aml::eisa_id("PNP0B00")),
+        aml::name("_CRS", aml::resource_template(vec![aml::io(0x70, 2), aml::irq(8)])),
+    ]));
+    if let Some(base) = platform.hpet {
+        sb.push(aml::device("HPET", vec![
+            aml::name("_HID", aml::eisa_id("PNP0103")),
+            aml::name("_CRS", aml::resource_template(vec![aml::dword_space(
+                AddressSpace::Memory,
+                base as u32,
+                base as u32 + 0x3ff,
+            )])),
+        ]));
+    }
+    if platform.tpm {
+        sb.push(aml::device("TPM", vec![
+            aml::name("_HID", aml::string("MSFT0101")),
+            aml::name("_STA", aml::integer(0xf)),
+            aml::name("_CRS", aml::resource_template(vec![aml::memory32_fixed(
+                TPM_CRB_BASE as u32,
+                TPM_CRB_REGION as u32,
+            )])),
+        ]));
+    }
+    sb.push(aml::device("PWRB", vec![
+        aml::name("_HID", aml::eisa_id("PNP0C0C")),
+        aml::name("_UID", aml::integer(0)),
+    ]));
+    let mut dsdt = sdt(b"DSDT", 2, HEADER_SIZE);
+    dsdt.extend(aml::scope("\\_SB", sb));
+    dsdt.extend(aml::scope("\\_GPE", vec![aml::method(&format!("_E{GPE_POWER_BUTTON:02X}"), 0, vec![aml::notify(
+        "\\_SB.PWRB",
+        0x80,
+    )])]));
+    let s5 = SLP_TYP_S5 as u64;
+    dsdt.extend(aml::name("\\_S5", aml::package(vec![aml::integer(s5), aml::integer(s5), aml::integer(0), aml::integer(0)])));
+    dsdt
+}
+
+fn fadt() -> Vec<u8> {
+    let mut fadt = sdt(b"FACP", FADT_REVISION, FADT_SIZE);
+    put_u16(&mut fadt, 46, SCI_IRQ as u16);
+    put_u32(&mut fadt, 56, PM1_EVT_BLK as u32);
+    put_u32(&mut fadt, 64, PM1_CNT_BLK as u32);
+    put_u32(&mut fadt, 76, PM_TMR_BLK as u32);
+    put_u32(&mut fadt, 80, GPE0_BLK as u32);
+    fadt[88] = PM1_EVT_LEN;
+    fadt[89] = PM1_CNT_LEN;
+    fadt[91] = PM_TMR_LEN;
+    fadt[92] = GPE0_BLK_LEN;
+    // No C2 nor C3
+    put_u16(&mut fadt, 96, 0x0fff);
+    put_u16(&mut fadt, 98, 0x0fff);
+    fadt[108] = REG_CENTURY as u8;
+    put_u16(&mut fadt, 109, BOOT_ARCH_LEGACY_DEVICES | BOOT_ARCH_8042);
+    put_u32(&mut fadt, 112, FADT_WBINVD | FADT_PROC_C1 | FADT_PWR_BUTTON | FADT_SLP_BUTTON | FADT_TMR_VAL_EXT | FADT_RESET_REG_SUP);
+    fadt[116..128].copy_from_slice(&gas_io(ACPI_RESET_REG, 1));
+    fadt[128] = RESET_VALUE;
+    fadt[148..160].copy_from_slice(&gas_io(PM1_EVT_BLK, PM1_EVT_LEN));
+    fadt[172..184].copy_from_slice(&gas_io(PM1_CNT_BLK, PM1_CNT_LEN));
+    fadt[208..220].copy_from_slice(&gas_io(PM_TMR_BLK, PM_TMR_LEN));
+    fadt[220..232].copy_from_slice(&gas_io(GPE0_BLK, GPE0_BLK_LEN));
+    fadt
+}
+
+fn madt(platform: &AcpiPlatform) -> Vec<u8> {
+    let mut madt = sdt(b"APIC", 5, HEADER_SIZE + 8);
+    put_u32(&mut madt, 36, LAPIC_BASE);
+    // The 8259s are only there with the in-kernel irqchip
+    put_u32(&mut madt, 40, if platform.irqchip == IrqChipMode::Kernel { MADT_PCAT_COMPAT } else { 0 });
+    for id in 0..platform.cpus {
+        madt.extend([MADT_LOCAL_APIC, 8, id, id]);
+        madt.extend(LOCAL_APIC_ENABLED.to_le_bytes());
+    }
+    madt.extend([MADT_IO_APIC, 12, 0, 0]);
+    madt.extend((IOAPIC_BASE as u32).to_le_bytes());
+    madt.extend(0u32.to_le_bytes());
+    // ISA IRQs are identity mapped on the IOAPIC pins, only the SCI is level triggered
+    madt.extend([MADT_INTERRUPT_OVERRIDE, 10, 0, SCI_IRQ]);
+    madt.extend((SCI_IRQ as u32).to_le_bytes());
+    madt.extend(OVERRIDE_LEVEL_HIGH.to_le_bytes());
+    madt.extend([MADT_LOCAL_APIC_NMI, 6, ALL_PROCESSORS, 0, 0, 1]);
+    madt
+}
+
+fn mcfg() -> Vec<u8> {
+    let mut mcfg = sdt(b"MCFG", 1, HEADER_SIZE + 8);
+    mcfg.extend(Q35_ECAM_BASE.to_le_bytes());
+    mcfg.extend(0u16.to_le_bytes());
+    mcfg.extend([0, (Q35_ECAM_BUSES - 1) as u8]);
+    mcfg.extend(0u32.to_le_bytes());
+    mcfg
+}
+
+fn hpet(base: u64) -> Vec<u8> {
+    let mut hpet = sdt(b"HPET", 1, HEADER_SIZE + 20);
+    put_u32(&mut hpet, 36, HPET_BLOCK_ID);
+    hpet[40..52].copy_from_slice(&{
+        let mut gas = [0; 12];
+        gas[1] = 64;
+        put_u64(&mut gas, 4, base);
+        gas
+    });
+    // Minimum tick in periodic mode
+    put_u16(&mut hpet, 53, 0x80);
+    hpet
+}
+
+/// Client platform, started through the CRB without an ACPI method
+fn tpm2() -> Vec<u8> {
+    let mut tpm2 = sdt(b"TPM2", 4, TPM2_SIZE);
+    put_u64(&mut tpm2, 40, TPM_CRB_BASE + CRB_CTRL_REQ);
+    put_u32(&mut tpm2, 48, TPM2_START_METHOD_CRB);
+    tpm2
+}
+
+fn rsdp() -> Vec<u8> {
+    let mut rsdp = vec![0; 36];
+    rsdp[0..8].copy_from_slice(b"RSD PTR ");
+    rsdp[9..15].copy_from_slice(OEM_ID);
+    rsdp[15] = 2;
+    put_u32(&mut rsdp, 20, 36);
+    rsdp
+}
+
+pub fn build_tables(platform: &AcpiPlatform) -> AcpiTables {
+    let mut builder = AcpiBuilder::default();
+    builder.loader.allocate(ACPI_TABLES_FILE, FACS_ALIGN, AllocZone::High);
+    // At the start of the blob for its 64 byte alignment, it has no checksum
+    let facs_offset = 0;
+    builder.tables.extend(facs());
+    let dsdt = builder.add_table(dsdt(platform));
+    let fadt = builder.add_table(fadt());
+    builder.link(fadt, FADT_FIRMWARE_CTRL, 4, facs_offset);
+    builder.link(fadt, FADT_X_DSDT, 8, dsdt);
+    let mut entries = vec![fadt, builder.add_table(madt(platform)), builder.add_table(mcfg())];
+    if let Some(base) = platform.hpet {
+        entries.push(builder.add_table(hpet(base)));
+    }
+    if platform.tpm {
+        entries.push(builder.add_table(tpm2()));
+    }
+    let xsdt = builder.add_table(sdt(b"XSDT", 1, HEADER_SIZE + 8 * entries.len()));
+    for (i, &entry) in entries.iter().enumerate() {
+        builder.link(xsdt, HEADER_SIZE + 8 * i, 8, entry);
+    }
+    let (tables, mut loader) = builder.finish();
+
+    let mut rsdp = rsdp();
+    put_u64(&mut rsdp, 24, xsdt as u64);
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(&rsdp);
+    loader.allocate(ACPI_RSDP_FILE, 16, AllocZone::FSeg);
+    loader.add_pointer(ACPI_RSDP_FILE, 24, 8, ACPI_TABLES_FILE);
+    loader.add_checksum(ACPI_RSDP_FILE, 8, 0, 20);
+    loader.add_checksum(ACPI_RSDP_FILE, 32, 0, 36);
+    AcpiTables { rsdp, tables, loader }
+}
+
+impl Vm {
+    /// Describe the machine as built so far to the firmware
+    pub(super) fn add_acpi_tables(&mut self) -> Result<()> {
+        let platform = AcpiPlatform {
+            cpus: self.vcpu_count() as u8,
+            irqchip: self.irq.mode(),
+            high_ram: self.ram.split_4g().1,
+            hpet: self.hpet.as_ref().map(|_| HPET_BASE),
+            tpm: self.tpm.is_some(),
+        };
+        let tables = build_tables(&platform);
+        info!("ACPI tables: {} bytes for {} vCPUs", tables.tables.len(), platform.cpus);
+        self.add_fw_cfg_file(ACPI_RSDP_FILE, tables.rsdp)?;
+        self.add_fw_cfg_file(ACPI_TABLES_FILE, tables.tables)?;
+        self.add_fw_cfg_file(TABLE_LOADER_FILE, tables.loader.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmm::fw_cfg::LOADER_COMMAND_SIZE;
+
+    fn platform() -> AcpiPlatform {
+        AcpiPlatform {
+            cpus: 2,
+            irqchip: IrqChipMode::Kernel,
+            high_ram: 1 << 30,
+            hpet: Some(HPET_BASE),
+            tpm: true,
+        }
+    }
+
+    /// Offset of every table in the blob, following the XSDT entries from the RSDP
+    fn xsdt_entries(acpi: &AcpiTables) -> Vec<(String, usize)> {
+        let xsdt = u64::from_le_bytes(acpi.rsdp[24..32].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(acpi.tables[xsdt + 4..xsdt + 8].try_into().unwrap()) as usize;
+        (xsdt + HEADER_SIZE..xsdt + len)
+            .step_by(8)
+            .map(|at| {
+                let table = u64::from_le_bytes(acpi.tables[at..at + 8].try_into().unwrap()) as usize;
+                (String::from_utf8_lossy(&acpi.tables[table..table + 4]).into_owned(), table)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tables_linked_and_checksummed() {
+        let acpi = build_tables(&platform());
+        assert_eq!(checksum(&acpi.rsdp[..20]), 0);
+        assert_eq!(checksum(&acpi.rsdp), 0);
+        let entries = xsdt_entries(&acpi);
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["FACP", "APIC", "MCFG", "HPET", "TPM2"]);
+        for (name, table) in &entries {
+            let len = u32::from_le_bytes(acpi.tables[table + 4..table + 8].try_into().unwrap()) as usize;
+            assert_eq!(checksum(&acpi.tables[*table..table + len]), 0, "{name} checksum");
+        }
+        let fadt = entries[0].1;
+        let dsdt = u64::from_le_bytes(acpi.tables[fadt + FADT_X_DSDT..fadt + FADT_X_DSDT + 8].try_into().unwrap()) as usize;
+        assert_eq!(&acpi.tables[dsdt..dsdt + 4], b"DSDT");
+        assert_eq!(&acpi.tables[0..4], b"FACS");
+
+        // Two allocations, a pointer per link and a checksum per table, the DSDT and XSDT included
+        let loader = acpi.loader.into_bytes();
+        assert_eq!(loader.len() % LOADER_COMMAND_SIZE, 0);
+        let commands: Vec<u32> = loader
+            .chunks(LOADER_COMMAND_SIZE)
+            .map(|command| u32::from_le_bytes(command[..4].try_into().unwrap()))
+            .collect();
+        assert_eq!(commands.iter().filter(|&&command| command == 1).count(), 2);
+        assert_eq!(commands.iter().filter(|&&command| command == 2).count(), 2 + entries.len() + 1);
+        assert_eq!(commands.iter().filter(|&&command| command == 3).count(), 2 + entries.len() + 2);
+    }
+
+    #[test]
+    fn madt_lists_every_cpu() {
+        let madt = madt(&AcpiPlatform { cpus: 4, irqchip: IrqChipMode::Split, ..platform() });
+        let mut apic_ids = vec![];
+        let mut at = HEADER_SIZE + 8;
+        while at < madt.len() {
+            if madt[at] == MADT_LOCAL_APIC {
+                apic_ids.push(madt[at + 3]);
+            }
+            at += madt[at + 1] as usize;
+        }
+        assert_eq!(at, madt.len());
+        assert_eq!(apic_ids, [0, 1, 2, 3]);
+        // No 8259s without the in-kernel irqchip
+        assert_eq!(u32::from_le_bytes(madt[40..44].try_into().unwrap()), 0);
+    }
+}
@@ -0,0 +1,1287 @@
+//! ICH9 AHCI controller: SATA disks and ATAPI CD-ROMs on any disk backend.
+//!
+//! Commands run on the vCPU thread as soon as the guest sets their PxCI bit,
+//! so a command never stays in flight and nothing needs cancelling on reset.
+//! No NCQ, no port multiplier, interrupts through MSI only.
+
+use std::io;
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vm_memory::{ Bytes, GuestAddress, GuestMemoryError };
+
+use super::{
+    disk::{ DiskBackend, SECTOR_SIZE },
+    irq::{ MsiMessage, MsiSender },
+    pci::{ BarKind, PciBar, PciConfig, PciDevice },
+    snapshot::{ self, StateBuf, StateReader },
+    virtio::GuestMem,
+    Vm,
+};
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+const ICH9_AHCI_VENDOR_ID: u16 = 0x8086;
+const ICH9_AHCI_DEVICE_ID: u16 = 0x2922;
+/// Mass storage, SATA, AHCI 1.0
+const AHCI_CLASS: u32 = 0x01_06_01;
+
+const PCI_CAP_ID_MSI: u8 = 0x05;
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+
+/// ABAR, BAR 5 like on the real chipset
+const ABAR: usize = 5;
+const ABAR_SIZE: u64 = 0x1000;
+const PORT_BASE: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+pub const AHCI_MAX_PORTS: usize = 6;
+
+/// Generic host control registers
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_IS: u64 = 0x08;
+const HBA_PI: u64 = 0x0c;
+const HBA_VS: u64 = 0x10;
+
+const CAP_S64A: u32 = 1 << 31;
+const CAP_SAM: u32 = 1 << 18;
+/// Interface speed support, 3 Gbps
+const CAP_ISS_GEN2: u32 = 2 << 20;
+const CAP_NCS_SHIFT: u32 = 8;
+const COMMAND_SLOTS: u32 = 32;
+const AHCI_VERSION: u32 = 0x0001_0200;
+
+const GHC_HR: u32 = 1 << 0;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+/// Port registers, relative to the port base
+const PX_CLB: u64 = 0x00;
+const PX_CLBU: u64 = 0x04;
+const PX_FB: u64 = 0x08;
+const PX_FBU: u64 = 0x0c;
+const PX_IS: u64 = 0x10;
+const PX_IE: u64 = 0x14;
+const PX_CMD: u64 = 0x18;
+const PX_TFD: u64 = 0x20;
+const PX_SIG: u64 = 0x24;
+const PX_SSTS: u64 = 0x28;
+const PX_SCTL: u64 = 0x2c;
+const PX_SERR: u64 = 0x30;
+const PX_SACT: u64 = 0x34;
+const PX_CI: u64 = 0x38;
+
+const PORT_IS_DHRS: u32 = 1 << 0;
+const PORT_IS_PSS: u32 = 1 << 1;
+const PORT_IS_TFES: u32 = 1 << 30;
+
+const PORT_CMD_ST: u32 = 1 << 0;
+const PORT_CMD_CLO: u32 = 1 << 3;
+const PORT_CMD_FRE: u32 = 1 << 4;
+const PORT_CMD_CCS_SHIFT: u32 = 8;
+const PORT_CMD_FR: u32 = 1 << 14;
+const PORT_CMD_CR: u32 = 1 << 15;
+/// ST, SUD, POD, CLO, FRE, ATAPI, DLAE, ALPE, ASP, ICC
+const PORT_CMD_WRITABLE: u32 = 0xf700_001f;
+
+/// Device detected, Gen2 speed, active power state
+const SSTS_LINK_UP: u32 = 0x123;
+const SCTL_DET_MASK: u32 = 0xf;
+const SCTL_DET_COMRESET: u32 = 1;
+
+const SIG_ATA: u32 = 0x0000_0101;
+const SIG_ATAPI: u32 = 0xeb14_0101;
+
+/// Received FIS area offsets
+const RFIS_PIO_SETUP: u64 = 0x20;
+const RFIS_D2H: u64 = 0x40;
+const FIS_TYPE_H2D: u8 = 0x27;
+const FIS_TYPE_D2H: u8 = 0x34;
+const FIS_TYPE_PIO_SETUP: u8 = 0x5f;
+const FIS_H2D_COMMAND: u8 = 1 << 7;
+const FIS_INTERRUPT: u8 = 1 << 6;
+const FIS_PIO_TO_HOST: u8 = 1 << 5;
+const FIS_SIZE: usize = 20;
+const CONTROL_SRST: u8 = 1 << 2;
+
+const COMMAND_HEADER_SIZE: u64 = 32;
+const HEADER_ATAPI: u32 = 1 << 5;
+const COMMAND_TABLE_ACMD: u64 = 0x40;
+const COMMAND_TABLE_PRDT: u64 = 0x80;
+const PRD_SIZE: u64 = 16;
+const PRD_BYTE_COUNT_MASK: u32 = 0x3f_ffff;
+
+const ATA_STATUS_ERR: u8 = 1 << 0;
+const ATA_STATUS_DSC: u8 = 1 << 4;
+const ATA_STATUS_DRDY: u8 = 1 << 6;
+const ATA_STATUS_BSY: u8 = 1 << 7;
+const ATA_STATUS_DRQ: u8 = 1 << 3;
+const ATA_STATUS_READY: u8 = ATA_STATUS_DRDY | ATA_STATUS_DSC;
+const ATA_ERROR_ABRT: u8 = 1 << 2;
+const ATA_ERROR_UNC: u8 = 1 << 6;
+
+const ATA_DATA_SET_MANAGEMENT: u8 = 0x06;
+const ATA_READ_SECTORS: u8 = 0x20;
+const ATA_READ_SECTORS_EXT: u8 = 0x24;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_READ_MULTIPLE_EXT: u8 = 0x29;
+const ATA_WRITE_SECTORS: u8 = 0x30;
+const ATA_WRITE_SECTORS_EXT: u8 = 0x34;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_WRITE_MULTIPLE_EXT: u8 = 0x39;
+const ATA_READ_VERIFY: u8 = 0x40;
+const ATA_READ_VERIFY_EXT: u8 = 0x42;
+const ATA_INITIALIZE_DEVICE_PARAMETERS: u8 = 0x91;
+const ATA_PACKET: u8 = 0xa0;
+const ATA_IDENTIFY_PACKET_DEVICE: u8 = 0xa1;
+const ATA_READ_MULTIPLE: u8 = 0xc4;
+const ATA_WRITE_MULTIPLE: u8 = 0xc5;
+const ATA_SET_MULTIPLE_MODE: u8 = 0xc6;
+const ATA_READ_DMA: u8 = 0xc8;
+const ATA_WRITE_DMA: u8 = 0xca;
+const ATA_STANDBY_IMMEDIATE: u8 = 0xe0;
+const ATA_IDLE_IMMEDIATE: u8 = 0xe1;
+const ATA_STANDBY: u8 = 0xe2;
+const ATA_IDLE: u8 = 0xe3;
+const ATA_CHECK_POWER_MODE: u8 = 0xe5;
+const ATA_FLUSH_CACHE: u8 = 0xe7;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_IDENTIFY_DEVICE: u8 = 0xec;
+const ATA_SET_FEATURES: u8 = 0xef;
+
+const DSM_TRIM: u8 = 1 << 0;
+/// Sectors per READ/WRITE MULTIPLE block
+const MULTIPLE_SECTORS: u16 = 16;
+const IDENTIFY_SIZE: usize = 512;
+
+const CD_SECTOR_SIZE: u64 = 2048;
+/// Bounce buffer size of disk reads to guest memory
+const DMA_CHUNK: usize = 64 << 10;
+/// Frames before LBA 0 in MSF addresses
+const MSF_OFFSET: u64 = 150;
+/// Bigger media is reported as a DVD
+const CD_MAX_SECTORS: u64 = 404_850;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_START_STOP_UNIT: u8 = 0x1b;
+const SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_READ_TOC: u8 = 0x43;
+const SCSI_GET_CONFIGURATION: u8 = 0x46;
+const SCSI_GET_EVENT_STATUS_NOTIFICATION: u8 = 0x4a;
+const SCSI_READ_DISC_INFORMATION: u8 = 0x51;
+const SCSI_MODE_SENSE_10: u8 = 0x5a;
+const SCSI_READ_12: u8 = 0xa8;
+const SCSI_SET_CD_SPEED: u8 = 0xbb;
+const SCSI_MECHANISM_STATUS: u8 = 0xbd;
+
+const MODE_PAGE_ERROR_RECOVERY: u8 = 0x01;
+const MODE_PAGE_CAPABILITIES: u8 = 0x2a;
+const MODE_PAGE_ALL: u8 = 0x3f;
+
+const PROFILE_CD_ROM: u16 = 0x0008;
+const PROFILE_DVD_ROM: u16 = 0x0010;
+
+/// SCSI sense key, additional sense code and qualifier
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Sense(u8, u8, u8);
+
+const SENSE_NONE: Sense = Sense(0, 0, 0);
+const SENSE_MEDIUM_ERROR: Sense = Sense(0x03, 0x11, 0x00);
+const SENSE_INVALID_OPCODE: Sense = Sense(0x05, 0x20, 0x00);
+const SENSE_LBA_OUT_OF_RANGE: Sense = Sense(0x05, 0x21, 0x00);
+const SENSE_INVALID_FIELD: Sense = Sense(0x05, 0x24, 0x00);
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+enum CommandError {
+    /// Unsupported command 0x{0:02x}
+    Unsupported(u8),
+    /// Sectors past the end of the disk
+    OutOfRange,
+    /// Scatter gather list shorter than the transfer
+    ShortPrdt,
+    /// Guest memory access failed: {0}
+    Memory(#[from] GuestMemoryError),
+    /// Disk I/O failed: {0}
+    Io(#[from] io::Error),
+}
+
+/// Registers of the D2H (or PIO setup) FIS that ends a command
+#[derive(Debug, Clone, Copy, Default)]
+struct TaskFile {
+    status: u8,
+    error: u8,
+    lba: u64,
+    count: u16,
+}
+
+impl TaskFile {
+    fn ok() -> Self {
+        Self { status: ATA_STATUS_READY, ..Default::default() }
+    }
+
+    fn abort(error: u8) -> Self {
+        Self { status: ATA_STATUS_READY | ATA_STATUS_ERR, error, ..Default::default() }
+    }
+
+    /// Device signature, reported after a reset or by IDENTIFY DEVICE on ATAPI
+    fn signature(signature: u32) -> Self {
+        Self { status: ATA_STATUS_READY, error: 1, lba: (signature >> 8) as u64, count: signature as u16 & 0xff }
+    }
+
+    fn fis(&self, kind: u8, flags: u8) -> [u8; FIS_SIZE] {
+        let mut fis = [0u8; FIS_SIZE];
+        let lba = self.lba.to_le_bytes();
+        fis[0] = kind;
+        fis[1] = flags;
+        fis[2] = self.status;
+        fis[3] = self.error;
+        fis[4..7].copy_from_slice(&lba[..3]);
+        fis[7] = 1 << 6;
+        fis[8..11].copy_from_slice(&lba[3..6]);
+        fis[12..14].copy_from_slice(&self.count.to_le_bytes());
+        fis
+    }
+}
+
+/// A disk or CD-ROM behind a SATA port
+pub struct AhciDrive {
+    disk: Box<dyn DiskBackend>,
+    cdrom: bool,
+    /// Serial number in IDENTIFY data
+    serial: String,
+    /// Sense data of the last failed ATAPI command
+    sense: Sense,
+}
+
+impl std::fmt::Debug for AhciDrive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AhciDrive")
+            .field("serial", &self.serial)
+            .field("cdrom", &self.cdrom)
+            .field("size", &self.disk.size())
+            .finish()
+    }
+}
+
+/// ATA strings are space padded with the bytes of every word swapped
+fn ata_string(words: &mut [u16], s: &str) {
+    let mut bytes = s.bytes().chain(std::iter::repeat(b' '));
+    for word in words {
+        *word = (bytes.next().unwrap() as u16) << 8 | bytes.next().unwrap() as u16;
+    }
+}
+
+fn identify_bytes(words: &mut [u16; IDENTIFY_SIZE / 2]) -> Vec<u8> {
+    // Integrity word: signature 0xa5 and a checksum making all bytes sum to zero
+    words[255] = 0xa5;
+    let sum = words[..255].iter().fold(0xa5u8, |sum, word| sum.wrapping_add(*word as u8).wrapping_add((word >> 8) as u8));
+    words[255] |= (sum.wrapping_neg() as u16) << 8;
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+fn msf(lba: u64) -> [u8; 4] {
+    let frames = lba + MSF_OFFSET;
+    [0, (frames / (75 * 60)) as u8, ((frames / 75) % 60) as u8, (frames % 75) as u8]
+}
+
+fn be16(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+fn be32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+/// Scatter gather list of a command, (guest address, length)
+type Prdt = Vec<(u64, usize)>;
+
+fn prdt_len(prdt: &Prdt) -> usize {
+    prdt.iter().map(|(_, len)| len).sum()
+}
+
+/// Copy `data` to the guest buffers, returns the bytes copied
+fn dma_to_guest(mem: &GuestMem, prdt: &Prdt, data: &[u8]) -> std::result::Result<usize, CommandError> {
+    let mut done = 0;
+    for &(addr, len) in prdt {
+        let chunk = len.min(data.len() - done);
+        mem.write_slice(&data[done..done + chunk], GuestAddress(addr))?;
+        done += chunk;
+        if done == data.len() {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// Read `len` bytes of `disk` at `offset` straight into the guest buffers, a
+/// chunk at a time: the guest sizes the transfer, not a host buffer. Stops
+/// where the PRDT does, returns the bytes copied.
+fn disk_to_guest(
+    disk: &mut dyn DiskBackend,
+    mem: &GuestMem,
+    prdt: &Prdt,
+    offset: u64,
+    len: usize,
+) -> std::result::Result<usize, CommandError> {
+    let len = len.min(prdt_len(prdt));
+    let mut buf = vec![0u8; len.min(DMA_CHUNK)];
+    let mut done = 0;
+    for &(addr, region) in prdt {
+        let mut in_region = 0;
+        while in_region < region && done < len {
+            let chunk = (region - in_region).min(len - done).min(DMA_CHUNK);
+            disk.read_at(&mut buf[..chunk], offset + done as u64)?;
+            mem.write_slice(&buf[..chunk], GuestAddress(addr + in_region as u64))?;
+            in_region += chunk;
+            done += chunk;
+        }
+        if done == len {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+fn dma_from_guest(mem: &GuestMem, prdt: &Prdt, len: usize) -> std::result::Result<Vec<u8>, CommandError> {
+    if prdt_len(prdt) < len {
+        return Err(CommandError::ShortPrdt);
+    }
+    let mut data = vec![0u8; len];
+    let mut done = 0;
+    for &(addr, chunk) in prdt {
+        let chunk = chunk.min(len - done);
+        mem.read_slice(&mut data[done..done + chunk], GuestAddress(addr))?;
+        done += chunk;
+        if done == len {
+            break;
+        }
+    }
+    Ok(data)
+}
+
+impl AhciDrive {
+    pub fn disk(disk: Box<dyn DiskBackend>, id: &str) -> Self {
+        Self { disk, cdrom: false, serial: id.chars().take(20).collect(), sense: SENSE_NONE }
+    }
+
+    /// Read only whatever the backend says
+    pub fn cdrom(disk: Box<dyn DiskBackend>, id: &str) -> Self {
+        Self { disk, cdrom: true, serial: id.chars().take(20).collect(), sense: SENSE_NONE }
+    }
+
+    fn signature(&self) -> u32 {
+        if self.cdrom { SIG_ATAPI } else { SIG_ATA }
+    }
+
+    fn sectors(&self) -> u64 {
+        self.disk.size() / SECTOR_SIZE
+    }
+
+    fn identify_device(&self) -> Vec<u8> {
+        let mut id = [0u16; IDENTIFY_SIZE / 2];
+        let sectors = self.sectors();
+        // Not removable, legacy CHS geometry capped like real disks
+        id[0] = 0x0040;
+        id[1] = (sectors / (16 * 63)).min(16383) as u16;
+        id[3] = 16;
+        id[6] = 63;
+        ata_string(&mut id[10..20], &self.serial);
+        ata_string(&mut id[23..27], "1.0");
+        ata_string(&mut id[27..47], "VMM HARDDISK");
+        id[47] = 0x8000 | MULTIPLE_SECTORS;
+        // LBA and DMA
+        id[49] = 0x0300;
+        id[53] = 0x0006;
+        let geometry = [id[1], id[3], id[6]];
+        id[54..57].copy_from_slice(&geometry);
+        let chs = id[1] as u32 * id[3] as u32 * id[6] as u32;
+        id[57..59].copy_from_slice(&[chs as u16, (chs >> 16) as u16]);
+        id[59] = 0x0100 | MULTIPLE_SECTORS;
+        let lba28 = sectors.min(0x0fff_ffff) as u32;
+        id[60..62].copy_from_slice(&[lba28 as u16, (lba28 >> 16) as u16]);
+        id[63] = 0x0007;
+        id[64] = 0x0003;
+        id[65..69].copy_from_slice(&[120; 4]);
+        // SATA Gen1 and Gen2
+        id[76] = 0x0006;
+        // ATA8-ACS and earlier
+        id[80] = 0x01f0;
+        // NOP and write cache; 48 bit LBA, FLUSH CACHE (EXT)
+        id[82] = 0x4020;
+        id[83] = 0x7400;
+        id[84] = 0x4000;
+        id[85] = 0x4020;
+        id[86] = 0x3400;
+        id[87] = 0x4000;
+        // UDMA 0-6, mode 6 selected
+        id[88] = 0x407f;
+        for (i, word) in id[100..104].iter_mut().enumerate() {
+            *word = (sectors >> (16 * i)) as u16;
+        }
+        if !self.disk.read_only() {
+            // DATA SET MANAGEMENT TRIM, one block of ranges at a time
+            id[105] = 1;
+            id[169] = 0x0001;
+        }
+        // Solid state, Windows then skips defragmenting it
+        id[217] = 0x0001;
+        identify_bytes(&mut id)
+    }
+
+    fn identify_packet_device(&self) -> Vec<u8> {
+        let mut id = [0u16; IDENTIFY_SIZE / 2];
+        // ATAPI, CD-ROM, removable, DRQ within 50 us
+        id[0] = 0x85c0;
+        ata_string(&mut id[10..20], &self.serial);
+        ata_string(&mut id[23..27], "1.0");
+        ata_string(&mut id[27..47], "VMM DVD-ROM");
+        id[49] = 0x0300;
+        id[53] = 0x0006;
+        id[63] = 0x0007;
+        id[64] = 0x0003;
+        id[65..69].copy_from_slice(&[120; 4]);
+        id[76] = 0x0006;
+        id[80] = 0x01f0;
+        // PACKET command set
+        id[82] = 0x4010;
+        id[83] = 0x4000;
+        id[84] = 0x4000;
+        id[85] = 0x4010;
+        id[87] = 0x4000;
+        id[88] = 0x203f;
+        identify_bytes(&mut id)
+    }
+
+    /// (LBA, sector count) of a READ/WRITE command FIS
+    fn fis_range(fis: &[u8], ext: bool) -> (u64, u64) {
+        if ext {
+            let lba = u64::from_le_bytes([fis[4], fis[5], fis[6], fis[8], fis[9], fis[10], 0, 0]);
+            let count = u16::from_le_bytes([fis[12], fis[13]]) as u64;
+            (lba, if count == 0 { 0x10000 } else { count })
+        } else {
+            let lba = u32::from_le_bytes([fis[4], fis[5], fis[6], fis[7] & 0xf]) as u64;
+            (lba, if fis[12] == 0 { 0x100 } else { fis[12] as u64 })
+        }
+    }
+
+    fn check_sectors(&self, lba: u64, count: u64) -> std::result::Result<(), CommandError> {
+        match lba.checked_add(count) {
+            Some(end) if end <= self.sectors() => Ok(()),
+            _ => Err(CommandError::OutOfRange),
+        }
+    }
+
+    /// Run an ATA command, returns the task file and the bytes transferred
+    fn execute_ata(&mut self, fis: &[u8], prdt: &Prdt, mem: &GuestMem) -> std::result::Result<(TaskFile, usize), CommandError> {
+        let command = fis[2];
+        let mut task_file = TaskFile::ok();
+        let transferred = match command {
+            ATA_IDENTIFY_DEVICE => dma_to_guest(mem, prdt, &self.identify_device())?,
+            ATA_READ_DMA_EXT | ATA_READ_SECTORS_EXT | ATA_READ_MULTIPLE_EXT | ATA_READ_DMA | ATA_READ_SECTORS | ATA_READ_MULTIPLE => {
+                let ext = matches!(command, ATA_READ_DMA_EXT | ATA_READ_SECTORS_EXT | ATA_READ_MULTIPLE_EXT);
+                let (lba, count) = Self::fis_range(fis, ext);
+                self.check_sectors(lba, count)?;
+                disk_to_guest(self.disk.as_mut(), mem, prdt, lba * SECTOR_SIZE, (count * SECTOR_SIZE) as usize)?
+            }
+            ATA_WRITE_DMA_EXT | ATA_WRITE_SECTORS_EXT | ATA_WRITE_MULTIPLE_EXT | ATA_WRITE_DMA | ATA_WRITE_SECTORS | ATA_WRITE_MULTIPLE => {
+                let ext = matches!(command, ATA_WRITE_DMA_EXT | ATA_WRITE_SECTORS_EXT | ATA_WRITE_MULTIPLE_EXT);
+                let (lba, count) = Self::fis_range(fis, ext);
+                self.check_sectors(lba, count)?;
+                let data = dma_from_guest(mem, prdt, (count * SECTOR_SIZE) as usize)?;
+                self.disk.write_at(&data, lba * SECTOR_SIZE)?;
+                data.len()
+            }
+            ATA_READ_VERIFY | ATA_READ_VERIFY_EXT => {
+                let (lba, count) = Self::fis_range(fis, command == ATA_READ_VERIFY_EXT);
+                self.check_sectors(lba, count)?;
+                0
+            }
+            ATA_DATA_SET_MANAGEMENT if fis[3] & DSM_TRIM != 0 && !self.disk.read_only() => {
+                let (_, blocks) = Self::fis_range(fis, true);
+                let ranges = dma_from_guest(mem, prdt, (blocks * SECTOR_SIZE) as usize)?;
+                for range in ranges.chunks_exact(8) {
+                    let range = u64::from_le_bytes(range.try_into().unwrap());
+                    let (lba, count) = (range & 0xffff_ffff_ffff, range >> 48);
+                    if count == 0 {
+                        continue;
+                    }
+                    self.check_sectors(lba, count)?;
+                    self.disk.discard(lba * SECTOR_SIZE, count * SECTOR_SIZE)?;
+                }
+                ranges.len()
+            }
+            ATA_FLUSH_CACHE | ATA_FLUSH_CACHE_EXT => {
+                self.disk.flush()?;
+                0
+            }
+            ATA_CHECK_POWER_MODE => {
+                // Active or idle
+                task_file.count = 0xff;
+                0
+            }
+            ATA_SET_FEATURES | ATA_SET_MULTIPLE_MODE | ATA_INITIALIZE_DEVICE_PARAMETERS | ATA_STANDBY_IMMEDIATE |
+            ATA_IDLE_IMMEDIATE | ATA_STANDBY | ATA_IDLE => 0,
+            command => return Err(CommandError::Unsupported(command)),
+        };
+        Ok((task_file, transferred))
+    }
+
+    fn mode_page(&self, page: u8) -> Option<Vec<u8>> {
+        Some(match page {
+            MODE_PAGE_ERROR_RECOVERY => vec![MODE_PAGE_ERROR_RECOVERY, 0x06, 0x00, 0x05, 0, 0, 0, 0],
+            // Reads CD-R, DVD-ROM; audio play, locking, tray loading mechanism
+            MODE_PAGE_CAPABILITIES => vec![
+                MODE_PAGE_CAPABILITIES, 0x12, 0x09, 0x00, 0x71, 0x00, 0x29, 0x00,
+                0x02, 0xc2, 0x00, 0x02, 0x02, 0x00, 0x02, 0xc2, 0, 0, 0, 0,
+            ],
+            _ => return None,
+        })
+    }
+
+    /// Response of a data-in ATAPI command, `None` for commands without data
+    fn atapi_response(&mut self, cdb: &[u8]) -> std::result::Result<Option<Vec<u8>>, Sense> {
+        let sectors = self.disk.size() / CD_SECTOR_SIZE;
+        let (data, allocation) = match cdb[0] {
+            SCSI_TEST_UNIT_READY | SCSI_START_STOP_UNIT | SCSI_PREVENT_ALLOW_MEDIUM_REMOVAL | SCSI_SET_CD_SPEED => {
+                return Ok(None);
+            }
+            SCSI_REQUEST_SENSE => {
+                let Sense(key, asc, ascq) = std::mem::take(&mut self.sense);
+                let mut data = vec![0u8; 18];
+                data[0] = 0x70;
+                data[2] = key;
+                data[7] = 10;
+                data[12] = asc;
+                data[13] = ascq;
+                (data, cdb[4] as usize)
+            }
+            SCSI_INQUIRY => {
+                // CD/DVD device, removable, SPC-3, response format 2
+                let mut data = vec![0x05, 0x80, 0x05, 0x32, 31, 0, 0, 0];
+                data.extend_from_slice(b"VMM     ");
+                data.extend_from_slice(b"DVD-ROM         ");
+                data.extend_from_slice(b"1.0 ");
+                (data, be16(&cdb[3..]) as usize)
+            }
+            SCSI_READ_CAPACITY => {
+                let mut data = (sectors.saturating_sub(1) as u32).to_be_bytes().to_vec();
+                data.extend_from_slice(&(CD_SECTOR_SIZE as u32).to_be_bytes());
+                (data, 8)
+            }
+            SCSI_READ_TOC => {
+                let use_msf = cdb[1] & 0x02 != 0;
+                let address = |lba: u64| if use_msf { msf(lba) } else { (lba as u32).to_be_bytes() };
+                let mut data = vec![0, 0, 1, 1];
+                match cdb[2] & 0x0f {
+                    // One data track, then the lead-out
+                    0 => {
+                        for (track, lba) in [(1, 0), (0xaa, sectors)] {
+                            data.extend_from_slice(&[0, 0x14, track, 0]);
+                            data.extend_from_slice(&address(lba));
+                        }
+                    }
+                    // Single session starting at track 1
+                    1 => {
+                        data.extend_from_slice(&[0, 0x14, 1, 0]);
+                        data.extend_from_slice(&address(0));
+                    }
+                    _ => return Err(SENSE_INVALID_FIELD),
+                }
+                let len = (data.len() - 2) as u16;
+                data[..2].copy_from_slice(&len.to_be_bytes());
+                (data, be16(&cdb[7..]) as usize)
+            }
+            SCSI_GET_CONFIGURATION => {
+                let profile = if sectors > CD_MAX_SECTORS { PROFILE_DVD_ROM } else { PROFILE_CD_ROM };
+                let mut data = vec![0, 0, 0, 0, 0, 0];
+                data.extend_from_slice(&profile.to_be_bytes());
+                // Profile list feature with the current profile only
+                data.extend_from_slice(&[0, 0, 0x03, 4]);
+                data.extend_from_slice(&profile.to_be_bytes());
+                data.extend_from_slice(&[1, 0]);
+                let len = (data.len() - 4) as u32;
+                data[..4].copy_from_slice(&len.to_be_bytes());
+                (data, be16(&cdb[7..]) as usize)
+            }
+            SCSI_GET_EVENT_STATUS_NOTIFICATION => {
+                const MEDIA_CLASS: u8 = 1 << 4;
+                if cdb[1] & 1 == 0 {
+                    // Asynchronous notification isn't supported
+                    return Err(SENSE_INVALID_FIELD);
+                }
+                let data = if cdb[4] & MEDIA_CLASS != 0 {
+                    // No change, media present
+                    vec![0, 6, 0x04, MEDIA_CLASS, 0, 0x02, 0, 0]
+                } else {
+                    vec![0, 2, 0x80, MEDIA_CLASS]
+                };
+                (data, be16(&cdb[7..]) as usize)
+            }
+            SCSI_READ_DISC_INFORMATION => {
+                // Complete disc, one session with track 1
+                let mut data = vec![0u8; 34];
+                data[1] = 32;
+                data[2] = 0x0e;
+                data[3] = 1;
+                data[4] = 1;
+                data[5] = 1;
+                data[6] = 1;
+                (data, be16(&cdb[7..]) as usize)
+            }
+            SCSI_MODE_SENSE_10 => {
+                let page = cdb[2] & 0x3f;
+                let mut data = vec![0u8; 8];
+                if page == MODE_PAGE_ALL {
+                    for page in [MODE_PAGE_ERROR_RECOVERY, MODE_PAGE_CAPABILITIES] {
+                        data.extend(self.mode_page(page).unwrap());
+                    }
+                } else {
+                    data.extend(self.mode_page(page).ok_or(SENSE_INVALID_FIELD)?);
+                }
+                let len = (data.len() - 2) as u16;
+                data[..2].copy_from_slice(&len.to_be_bytes());
+                (data, be16(&cdb[7..]) as usize)
+            }
+            SCSI_MECHANISM_STATUS => (vec![0u8; 8], be16(&cdb[8..]) as usize),
+            opcode => {
+                debug!("AHCI CD-ROM: unsupported SCSI command 0x{opcode:02x}");
+                return Err(SENSE_INVALID_OPCODE);
+            }
+        };
+        let mut data = data;
+        data.truncate(allocation);
+        Ok(Some(data))
+    }
+
+    /// READ (10) and READ (12), the sectors go from the image to the guest buffers
+    fn atapi_read(
+        &mut self,
+        cdb: &[u8],
+        prdt: &Prdt,
+        mem: &GuestMem,
+    ) -> std::result::Result<std::result::Result<usize, Sense>, CommandError> {
+        let sectors = self.disk.size() / CD_SECTOR_SIZE;
+        let lba = be32(&cdb[2..]) as u64;
+        let count = if cdb[0] == SCSI_READ_10 { be16(&cdb[7..]) as u64 } else { be32(&cdb[6..]) as u64 };
+        if lba.checked_add(count).is_none_or(|end| end > sectors) {
+            return Ok(Err(SENSE_LBA_OUT_OF_RANGE));
+        }
+        match disk_to_guest(self.disk.as_mut(), mem, prdt, lba * CD_SECTOR_SIZE, (count * CD_SECTOR_SIZE) as usize) {
+            Ok(transferred) => Ok(Ok(transferred)),
+            Err(CommandError::Io(e)) => {
+                warn!("AHCI CD-ROM read at sector {lba} failed: {e}");
+                Ok(Err(SENSE_MEDIUM_ERROR))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Run an ATAPI PACKET command
+    fn execute_packet(&mut self, cdb: &[u8], prdt: &Prdt, mem: &GuestMem) -> std::result::Result<(TaskFile, usize), CommandError> {
+        // Interrupt reason: command completed, I/O to host
+        let completed = |task_file: TaskFile| TaskFile { count: 0x3, ..task_file };
+        let transferred = match cdb[0] {
+            SCSI_READ_10 | SCSI_READ_12 => self.atapi_read(cdb, prdt, mem)?,
+            _ => match self.atapi_response(cdb) {
+                Ok(Some(data)) => Ok(dma_to_guest(mem, prdt, &data)?),
+                Ok(None) => Ok(0),
+                Err(sense) => Err(sense),
+            },
+        };
+        match transferred {
+            Ok(transferred) => {
+                self.sense = SENSE_NONE;
+                Ok((completed(TaskFile::ok()), transferred))
+            }
+            Err(sense) => {
+                self.sense = sense;
+                Ok((completed(TaskFile::abort(sense.0 << 4 | ATA_ERROR_ABRT)), 0))
+            }
+        }
+    }
+
+    fn execute(&mut self, fis: &[u8], cdb: &[u8], prdt: &Prdt, mem: &GuestMem) -> std::result::Result<(TaskFile, usize), CommandError> {
+        match (fis[2], self.cdrom) {
+            (ATA_PACKET, true) => self.execute_packet(cdb, prdt, mem),
+            (ATA_IDENTIFY_PACKET_DEVICE, true) => Ok((TaskFile::ok(), dma_to_guest(mem, prdt, &self.identify_packet_device())?)),
+            // How drivers tell ATAPI devices apart
+            (ATA_IDENTIFY_DEVICE, true) => Ok((TaskFile { status: ATA_STATUS_READY | ATA_STATUS_ERR, error: ATA_ERROR_ABRT, ..TaskFile::signature(SIG_ATAPI) }, 0)),
+            (ATA_FLUSH_CACHE | ATA_FLUSH_CACHE_EXT | ATA_SET_FEATURES | ATA_CHECK_POWER_MODE, true) => Ok((TaskFile::ok(), 0)),
+            (command, true) => Err(CommandError::Unsupported(command)),
+            (_, false) => self.execute_ata(fis, prdt, mem),
+        }
+    }
+}
+
+/// A SATA port and its registers
+#[derive(Debug, Default)]
+struct Port {
+    clb: u64,
+    fb: u64,
+    is: u32,
+    ie: u32,
+    cmd: u32,
+    tfd: u32,
+    sig: u32,
+    ssts: u32,
+    sctl: u32,
+    serr: u32,
+    sact: u32,
+    ci: u32,
+    drive: Option<AhciDrive>,
+}
+
+impl Port {
+    fn new(drive: Option<AhciDrive>) -> Self {
+        let mut port = Self { drive, ..Default::default() };
+        port.reset();
+        port
+    }
+
+    /// HBA reset: registers back to their defaults, the drive is kept
+    fn reset(&mut self) {
+        let drive = self.drive.take();
+        *self = Self { drive, ..Default::default() };
+        self.link_up();
+    }
+
+    /// COMRESET done, the drive reports its signature
+    fn link_up(&mut self) {
+        let Some(drive) = self.drive.as_mut() else {
+            return;
+        };
+        drive.sense = SENSE_NONE;
+        self.ssts = SSTS_LINK_UP;
+        self.sig = drive.signature();
+        self.tfd = ATA_STATUS_READY as u32;
+    }
+
+    fn write_fis(&self, mem: &GuestMem, offset: u64, fis: &[u8]) {
+        if self.cmd & PORT_CMD_FRE == 0 {
+            return;
+        }
+        if let Err(e) = mem.write_slice(fis, GuestAddress(self.fb + offset)) {
+            warn!("AHCI: can't write received FIS: {e}");
+        }
+    }
+
+    /// Post the signature D2H FIS, as a drive does once the link is up
+    fn post_signature(&mut self, mem: &GuestMem) {
+        if self.drive.is_some() {
+            let task_file = TaskFile::signature(self.sig);
+            self.write_fis(mem, RFIS_D2H, &task_file.fis(FIS_TYPE_D2H, 0));
+            self.is |= PORT_IS_DHRS;
+        }
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        match offset {
+            PX_CLB => self.clb as u32,
+            PX_CLBU => (self.clb >> 32) as u32,
+            PX_FB => self.fb as u32,
+            PX_FBU => (self.fb >> 32) as u32,
+            PX_IS => self.is,
+            PX_IE => self.ie,
+            PX_CMD => self.cmd,
+            PX_TFD => self.tfd,
+            PX_SIG => self.sig,
+            PX_SSTS => self.ssts,
+            PX_SCTL => self.sctl,
+            PX_SERR => self.serr,
+            PX_SACT => self.sact,
+            PX_CI => self.ci,
+            _ => 0,
+        }
+    }
+
+    /// Returns whether an interrupt condition got raised
+    fn write(&mut self, offset: u64, value: u32, mem: &GuestMem) -> bool {
+        match offset {
+            PX_CLB => self.clb = (self.clb & !0xffff_ffff) | (value & !0x3ff) as u64,
+            PX_CLBU => self.clb = (self.clb & 0xffff_ffff) | (value as u64) << 32,
+            PX_FB => self.fb = (self.fb & !0xffff_ffff) | (value & !0xff) as u64,
+            PX_FBU => self.fb = (self.fb & 0xffff_ffff) | (value as u64) << 32,
+            PX_IS => self.is &= !value,
+            PX_IE => self.ie = value & 0xfdc0_00ff,
+            PX_CMD => return self.write_cmd(value, mem),
+            PX_SCTL => {
+                let comreset_done = self.sctl & SCTL_DET_MASK == SCTL_DET_COMRESET && value & SCTL_DET_MASK != SCTL_DET_COMRESET;
+                self.sctl = value;
+                if value & SCTL_DET_MASK == SCTL_DET_COMRESET {
+                    self.ssts = 0;
+                    self.tfd = ATA_STATUS_BSY as u32;
+                } else if comreset_done {
+                    self.link_up();
+                    self.post_signature(mem);
+                    return true;
+                }
+            }
+            PX_SERR => self.serr &= !value,
+            PX_SACT => self.sact |= value,
+            PX_CI if self.cmd & PORT_CMD_ST != 0 => {
+                self.ci |= value;
+                return self.process(mem);
+            }
+            _ => debug!("AHCI port register write 0x{offset:x} ignored"),
+        }
+        false
+    }
+
+    fn write_cmd(&mut self, value: u32, mem: &GuestMem) -> bool {
+        let fis_enabled = self.cmd & PORT_CMD_FRE == 0 && value & PORT_CMD_FRE != 0;
+        self.cmd = (self.cmd & !PORT_CMD_WRITABLE) | (value & PORT_CMD_WRITABLE);
+        if self.cmd & PORT_CMD_CLO != 0 {
+            self.tfd &= !((ATA_STATUS_BSY | ATA_STATUS_DRQ) as u32);
+            self.cmd &= !PORT_CMD_CLO;
+        }
+        if self.cmd & PORT_CMD_ST != 0 {
+            self.cmd |= PORT_CMD_CR;
+        } else {
+            // Stopping the list drops whatever was issued
+            self.cmd &= !PORT_CMD_CR;
+            self.ci = 0;
+            self.sact = 0;
+        }
+        if self.cmd & PORT_CMD_FRE != 0 {
+            self.cmd |= PORT_CMD_FR;
+        } else {
+            self.cmd &= !PORT_CMD_FR;
+        }
+        if fis_enabled {
+            self.post_signature(mem);
+        }
+        // Commands issued before ST was set run now
+        self.ci != 0 && self.process(mem)
+    }
+
+    /// Run every issued command, returns whether an interrupt condition got raised
+    fn process(&mut self, mem: &GuestMem) -> bool {
+        if self.cmd & PORT_CMD_ST == 0 || self.drive.is_none() {
+            return false;
+        }
+        let mut raised = false;
+        while self.ci != 0 {
+            let slot = self.ci.trailing_zeros();
+            self.cmd = (self.cmd & !(0x1f << PORT_CMD_CCS_SHIFT)) | slot << PORT_CMD_CCS_SHIFT;
+            if let Err(e) = self.run_command(slot, mem) {
+                warn!("AHCI: command slot {slot} failed: {e}");
+            }
+            self.ci &= !(1 << slot);
+            raised = true;
+        }
+        raised
+    }
+
+    fn run_command(&mut self, slot: u32, mem: &GuestMem) -> std::result::Result<(), GuestMemoryError> {
+        let header_addr = self.clb + slot as u64 * COMMAND_HEADER_SIZE;
+        let mut header = [0u8; 16];
+        mem.read_slice(&mut header, GuestAddress(header_addr))?;
+        let flags = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let table = u64::from_le_bytes(header[8..].try_into().unwrap()) & !0x7f;
+        let mut fis = [0u8; FIS_SIZE];
+        mem.read_slice(&mut fis, GuestAddress(table))?;
+
+        if fis[0] != FIS_TYPE_H2D || fis[1] & FIS_H2D_COMMAND == 0 {
+            // Device control FIS: the end of a software reset gets the signature back
+            if fis[0] == FIS_TYPE_H2D && fis[15] & CONTROL_SRST == 0 {
+                self.link_up();
+                self.post_signature(mem);
+            }
+            return mem.write_slice(&0u32.to_le_bytes(), GuestAddress(header_addr + 4));
+        }
+
+        let mut cdb = [0u8; 16];
+        if flags & HEADER_ATAPI != 0 {
+            mem.read_slice(&mut cdb, GuestAddress(table + COMMAND_TABLE_ACMD))?;
+        }
+        let mut prdt = vec![];
+        for i in 0..(flags >> 16) as u64 {
+            let mut prd = [0u8; PRD_SIZE as usize];
+            mem.read_slice(&mut prd, GuestAddress(table + COMMAND_TABLE_PRDT + i * PRD_SIZE))?;
+            let addr = u64::from_le_bytes(prd[..8].try_into().unwrap()) & !1;
+            let len = (u32::from_le_bytes(prd[12..].try_into().unwrap()) & PRD_BYTE_COUNT_MASK) + 1;
+            prdt.push((addr, len as usize));
+        }
+
+        let drive = self.drive.as_mut().unwrap();
+        let command = fis[2];
+        let (task_file, transferred) = match drive.execute(&fis, &cdb, &prdt, mem) {
+            Ok(result) => result,
+            Err(e @ CommandError::Unsupported(_)) => {
+                debug!("AHCI: {e}");
+                (TaskFile::abort(ATA_ERROR_ABRT), 0)
+            }
+            Err(e) => {
+                warn!("AHCI: command 0x{command:02x} failed: {e}");
+                let error = if matches!(e, CommandError::Io(_)) && !self.drive.as_ref().unwrap().cdrom { ATA_ERROR_UNC } else { ATA_ERROR_ABRT };
+                (TaskFile::abort(error), 0)
+            }
+        };
+        mem.write_slice(&(transferred as u32).to_le_bytes(), GuestAddress(header_addr + 4))?;
+
+        self.tfd = (task_file.error as u32) << 8 | task_file.status as u32;
+        // Data-in PIO commands also get a PIO setup FIS
+        let pio_in = matches!(command, ATA_IDENTIFY_DEVICE | ATA_IDENTIFY_PACKET_DEVICE | ATA_PACKET | ATA_READ_SECTORS |
+            ATA_READ_SECTORS_EXT | ATA_READ_MULTIPLE | ATA_READ_MULTIPLE_EXT) && transferred > 0;
+        if pio_in {
+            let mut fis = task_file.fis(FIS_TYPE_PIO_SETUP, FIS_INTERRUPT | FIS_PIO_TO_HOST);
+            fis[15] = task_file.status;
+            fis[16..18].copy_from_slice(&(transferred.min(0xffff) as u16).to_le_bytes());
+            self.write_fis(mem, RFIS_PIO_SETUP, &fis);
+            self.is |= PORT_IS_PSS;
+        }
+        self.write_fis(mem, RFIS_D2H, &task_file.fis(FIS_TYPE_D2H, FIS_INTERRUPT));
+        self.is |= PORT_IS_DHRS;
+        if task_file.status & ATA_STATUS_ERR != 0 {
+            self.is |= PORT_IS_TFES;
+        }
+        Ok(())
+    }
+
+    fn save(&self, state: &mut StateBuf) {
+        state.put_u64(self.clb);
+        state.put_u64(self.fb);
+        for reg in [self.is, self.ie, self.cmd, self.tfd, self.sig, self.ssts, self.sctl, self.serr, self.sact, self.ci] {
+            state.put_u32(reg);
+        }
+        let Sense(key, asc, ascq) = self.drive.as_ref().map_or(SENSE_NONE, |drive| drive.sense);
+        state.put_bytes(&[key, asc, ascq]);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.clb = state.get_u64()?;
+        self.fb = state.get_u64()?;
+        for reg in [
+            &mut self.is, &mut self.ie, &mut self.cmd, &mut self.tfd, &mut self.sig,
+            &mut self.ssts, &mut self.sctl, &mut self.serr, &mut self.sact, &mut self.ci,
+        ] {
+            *reg = state.get_u32()?;
+        }
+        let sense = state.get_bytes()?;
+        if let (Some(drive), [key, asc, ascq]) = (self.drive.as_mut(), sense) {
+            drive.sense = Sense(*key, *asc, *ascq);
+        }
+        Ok(())
+    }
+}
+
+/// ICH9 AHCI function, registers in ABAR (BAR 5)
+pub struct Ahci {
+    config: PciConfig,
+    mem: GuestMem,
+    msi: MsiSender,
+    /// Offset of the MSI capability in config space
+    msi_cap: usize,
+    ghc: u32,
+    is: u32,
+    ports: Vec<Port>,
+}
+
+impl std::fmt::Debug for Ahci {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ahci")
+            .field("ghc", &self.ghc)
+            .field("drives", &self.ports.iter().filter_map(|port| port.drive.as_ref()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Ahci {
+    /// Drive `i` goes on port `i`, the ICH9 has 6 ports
+    pub fn new(drives: Vec<AhciDrive>, mem: GuestMem, msi: MsiSender) -> Self {
+        assert!(drives.len() <= AHCI_MAX_PORTS, "Too many AHCI drives");
+        let mut config = PciConfig::new(ICH9_AHCI_VENDOR_ID, ICH9_AHCI_DEVICE_ID, AHCI_CLASS, 2);
+        config.add_bar(ABAR, PciBar { size: ABAR_SIZE, kind: BarKind::Mem32, prefetchable: false });
+        let mut msi_body = MSI_CONTROL_64BIT.to_le_bytes().to_vec();
+        msi_body.extend_from_slice(&[0; 10]);
+        let msi_cap = config.add_capability(
+            PCI_CAP_ID_MSI,
+            &msi_body,
+            &[0x01, 0x00, 0xfc, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+        let mut drives = drives.into_iter();
+        let ports = (0..AHCI_MAX_PORTS).map(|_| Port::new(drives.next())).collect();
+        Self { config, mem, msi, msi_cap, ghc: GHC_AE, is: 0, ports }
+    }
+
+    fn cap(&self) -> u32 {
+        CAP_S64A | CAP_SAM | CAP_ISS_GEN2 | (COMMAND_SLOTS - 1) << CAP_NCS_SHIFT | (self.ports.len() as u32 - 1)
+    }
+
+    /// Ports with an enabled interrupt condition get their IS bit, then the MSI goes out
+    fn check_irq(&mut self) {
+        for (i, port) in self.ports.iter().enumerate() {
+            if port.is & port.ie != 0 {
+                self.is |= 1 << i;
+            }
+        }
+        let control = self.config.get_u16(self.msi_cap + 2);
+        if self.is == 0 || self.ghc & GHC_IE == 0 || control & MSI_CONTROL_ENABLE == 0 {
+            return;
+        }
+        let address = self.config.get_u32(self.msi_cap + 4) as u64 | (self.config.get_u32(self.msi_cap + 8) as u64) << 32;
+        let data = self.config.get_u16(self.msi_cap + 12) as u32;
+        if let Err(e) = self.msi.send(MsiMessage { address, data }) {
+            error!("AHCI MSI failed: {e}");
+        }
+    }
+
+    fn reset(&mut self) {
+        debug!("AHCI HBA reset");
+        self.ghc = GHC_AE;
+        self.is = 0;
+        self.ports.iter_mut().for_each(Port::reset);
+    }
+
+    fn register_read(&self, offset: u64) -> u32 {
+        match offset {
+            HBA_CAP => self.cap(),
+            HBA_GHC => self.ghc,
+            HBA_IS => self.is,
+            HBA_PI => (1 << self.ports.len()) - 1,
+            HBA_VS => AHCI_VERSION,
+            PORT_BASE.. => {
+                let port = ((offset - PORT_BASE) / PORT_SIZE) as usize;
+                self.ports.get(port).map_or(0, |port| port.read((offset - PORT_BASE) % PORT_SIZE))
+            }
+            _ => 0,
+        }
+    }
+
+    fn register_write(&mut self, offset: u64, value: u32) {
+        match offset {
+            HBA_GHC if value & GHC_HR != 0 => self.reset(),
+            HBA_GHC => {
+                self.ghc = GHC_AE | (value & GHC_IE);
+                self.check_irq();
+            }
+            HBA_IS => self.is &= !value,
+            PORT_BASE.. => {
+                let idx = ((offset - PORT_BASE) / PORT_SIZE) as usize;
+                let Some(port) = self.ports.get_mut(idx) else {
+                    return;
+                };
+                if port.write((offset - PORT_BASE) % PORT_SIZE, value, &self.mem) {
+                    self.check_irq();
+                }
+            }
+            _ => debug!("AHCI register write 0x{offset:x} ignored"),
+        }
+    }
+}
+
+impl PciDevice for Ahci {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn bar_read(&mut self, _bar: usize, offset: u64, data: &mut [u8]) {
+        let value = self.register_read(offset & !3).to_le_bytes();
+        let start = (offset & 3) as usize;
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = value.get(start + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn bar_write(&mut self, _bar: usize, offset: u64, data: &[u8]) {
+        if offset & 3 != 0 || data.len() != 4 {
+            debug!("AHCI: unaligned register write 0x{offset:x}/{} ignored", data.len());
+            return;
+        }
+        self.register_write(offset, u32::from_le_bytes(data.try_into().unwrap()));
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        state.put_u32(self.ghc);
+        state.put_u32(self.is);
+        for port in &self.ports {
+            port.save(state);
+        }
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.ghc = state.get_u32()?;
+        self.is = state.get_u32()?;
+        for port in self.ports.iter_mut() {
+            port.restore(state)?;
+        }
+        Ok(())
+    }
+}
+
+impl Vm {
+    /// Plug an AHCI controller with `drives` on its first ports in `slot` of bus 0
+    pub fn add_ahci(&mut self, slot: u8, drives: Vec<AhciDrive>) -> Result<()> {
+        if drives.len() > AHCI_MAX_PORTS {
+            return Err(kvm_ioctls::Error::new(libc::ENOSPC));
+        }
+        for (port, drive) in drives.iter().enumerate() {
+            info!("AHCI port {port}: {} {}", if drive.cdrom { "CD-ROM" } else { "disk" }, drive.serial);
+        }
+        let ahci = Ahci::new(drives, self.ram.guest_mem_map.clone(), self.msi_sender()?);
+        self.add_pci_device(slot, std::sync::Arc::new(std::sync::Mutex::new(ahci)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmm::disk::check_range;
+
+    /// Disk whose byte N is N mod 251, reads only
+    struct PatternDisk(u64);
+
+    impl DiskBackend for PatternDisk {
+        fn size(&self) -> u64 {
+            self.0
+        }
+
+        fn read_only(&self) -> bool {
+            true
+        }
+
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            check_range(offset, buf.len(), self.0)?;
+            buf.iter_mut().enumerate().for_each(|(i, b)| *b = ((offset + i as u64) % 251) as u8);
+            Ok(())
+        }
+
+        fn write_at(&mut self, _: &[u8], _: u64) -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn disk_reads_stop_at_the_prdt() {
+        let mem = GuestMem::from_ranges(&[(GuestAddress(0), 0x40000)]).unwrap();
+        let mut disk = PatternDisk(1 << 40);
+        // Far more than the PRDT holds, as a guest READ (12) can ask
+        let prdt: Prdt = vec![(0x1000, 0x300), (0x10000, DMA_CHUNK + 0x100)];
+        let offset = 5 * CD_SECTOR_SIZE;
+        let done = disk_to_guest(&mut disk, &mem, &prdt, offset, u32::MAX as usize * CD_SECTOR_SIZE as usize).unwrap();
+        assert_eq!(done, prdt_len(&prdt));
+
+        let mut expected = vec![0u8; done];
+        disk.read_at(&mut expected, offset).unwrap();
+        let mut first = vec![0u8; 0x300];
+        mem.read_slice(&mut first, GuestAddress(0x1000)).unwrap();
+        let mut second = vec![0u8; DMA_CHUNK + 0x100];
+        mem.read_slice(&mut second, GuestAddress(0x10000)).unwrap();
+        assert_eq!([first, second].concat(), expected);
+    }
+
+    fn command_fis(command: u8, lba: u64, count: u16) -> [u8; FIS_SIZE] {
+        let mut fis = [0u8; FIS_SIZE];
+        let lba = lba.to_le_bytes();
+        fis[0] = FIS_TYPE_H2D;
+        fis[1] = FIS_H2D_COMMAND;
+        fis[2] = command;
+        fis[4..7].copy_from_slice(&lba[..3]);
+        fis[7] = 1 << 6;
+        fis[8..11].copy_from_slice(&lba[3..6]);
+        fis[12..14].copy_from_slice(&count.to_le_bytes());
+        fis
+    }
+
+    #[test]
+    fn fis_ranges() {
+        let fis = command_fis(ATA_READ_DMA_EXT, 0x0123_4567_89ab, 0);
+        assert_eq!(AhciDrive::fis_range(&fis, true), (0x0123_4567_89ab, 0x10000));
+        // LBA28 takes bits 24-27 from the device register and a byte count
+        let mut fis = command_fis(ATA_READ_DMA, 0x0056_789a, 0x0100);
+        fis[7] |= 0x0b;
+        assert_eq!(AhciDrive::fis_range(&fis, false), (0x0b56_789a, 0x100));
+        fis[12] = 3;
+        assert_eq!(AhciDrive::fis_range(&fis, false).1, 3);
+    }
+
+    #[test]
+    fn identify_and_range_checks() {
+        let mem = GuestMem::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let sectors = 0x1_2345_6789u64;
+        let mut drive = AhciDrive::disk(Box::new(PatternDisk(sectors * SECTOR_SIZE)), "serial");
+        let prdt: Prdt = vec![(0x1000, IDENTIFY_SIZE)];
+
+        let fis = command_fis(ATA_IDENTIFY_DEVICE, 0, 0);
+        assert_eq!(drive.execute(&fis, &[], &prdt, &mem).unwrap().1, IDENTIFY_SIZE);
+        let mut id = [0u8; IDENTIFY_SIZE];
+        mem.read_slice(&mut id, GuestAddress(0x1000)).unwrap();
+        let word = |i: usize| u16::from_le_bytes([id[2 * i], id[2 * i + 1]]);
+        assert_eq!((word(60), word(61)), (0xffff, 0x0fff));
+        assert_eq!((0..4).map(|i| (word(100 + i) as u64) << (16 * i)).sum::<u64>(), sectors);
+        // ATA strings swap the bytes of each word
+        assert_eq!(&id[20..26], b"esirla");
+        // Read only backend, no TRIM
+        assert_eq!(word(169), 0);
+
+        let fis = command_fis(ATA_READ_DMA_EXT, sectors - 1, 1);
+        let (task_file, done) = drive.execute(&fis, &[], &vec![(0x2000, 0x200)], &mem).unwrap();
+        assert_eq!((task_file.status, done), (ATA_STATUS_READY, 0x200));
+        let fis = command_fis(ATA_READ_DMA_EXT, sectors - 1, 2);
+        assert!(matches!(drive.execute(&fis, &[], &prdt, &mem), Err(CommandError::OutOfRange)));
+        let fis = command_fis(ATA_READ_VERIFY_EXT, u64::MAX >> 16, 0xffff);
+        assert!(matches!(drive.execute(&fis, &[], &prdt, &mem), Err(CommandError::OutOfRange)));
+        let fis = command_fis(ATA_WRITE_DMA_EXT, 0, 1);
+        assert!(matches!(drive.execute(&fis, &[], &prdt, &mem), Err(CommandError::Io(_))));
+        let fis = command_fis(0xff, 0, 0);
+        assert!(matches!(drive.execute(&fis, &[], &prdt, &mem), Err(CommandError::Unsupported(0xff))));
+    }
+
+    #[test]
+    fn atapi_sense_and_capacity() {
+        let mem = GuestMem::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let sectors = 1000;
+        let mut cdrom = AhciDrive::cdrom(Box::new(PatternDisk(sectors * CD_SECTOR_SIZE)), "cd");
+        let prdt: Prdt = vec![(0x1000, 0x1000)];
+        let packet = command_fis(ATA_PACKET, 0, 0);
+
+        // Drivers look for the ATAPI signature in an aborted IDENTIFY DEVICE
+        let (task_file, _) = cdrom.execute(&command_fis(ATA_IDENTIFY_DEVICE, 0, 0), &[], &prdt, &mem).unwrap();
+        assert_eq!(task_file.status & ATA_STATUS_ERR, ATA_STATUS_ERR);
+        assert_eq!((task_file.lba as u32) << 8 | task_file.count as u32, SIG_ATAPI);
+
+        let mut cdb = [0u8; 12];
+        cdb[0] = SCSI_READ_CAPACITY;
+        assert_eq!(cdrom.execute(&packet, &cdb, &prdt, &mem).unwrap().1, 8);
+        let mut capacity = [0u8; 8];
+        mem.read_slice(&mut capacity, GuestAddress(0x1000)).unwrap();
+        assert_eq!(be32(&capacity), sectors as u32 - 1);
+        assert_eq!(be32(&capacity[4..]), CD_SECTOR_SIZE as u32);
+
+        cdb = [0; 12];
+        cdb[0] = SCSI_READ_10;
+        cdb[2..6].copy_from_slice(&(sectors as u32).to_be_bytes());
+        cdb[7..9].copy_from_slice(&1u16.to_be_bytes());
+        let (task_file, done) = cdrom.execute(&packet, &cdb, &prdt, &mem).unwrap();
+        assert_eq!((task_file.error, done), (SENSE_LBA_OUT_OF_RANGE.0 << 4 | ATA_ERROR_ABRT, 0));
+
+        // REQUEST SENSE reports the failure once
+        cdb = [0; 12];
+        cdb[0] = SCSI_REQUEST_SENSE;
+        cdb[4] = 18;
+        for expected in [SENSE_LBA_OUT_OF_RANGE, SENSE_NONE] {
+            assert_eq!(cdrom.execute(&packet, &cdb, &prdt, &mem).unwrap().1, 18);
+            let mut sense = [0u8; 18];
+            mem.read_slice(&mut sense, GuestAddress(0x1000)).unwrap();
+            assert_eq!(Sense(sense[2], sense[12], sense[13]), expected);
+        }
+
+        cdb = [0; 12];
+        cdb[0] = 0xff;
+        cdrom.execute(&packet, &cdb, &prdt, &mem).unwrap();
+        assert_eq!(cdrom.sense, SENSE_INVALID_OPCODE);
+    }
+}
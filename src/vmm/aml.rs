@@ -0,0 +1,263 @@
+//! Just enough AML encoding for our DSDT: names, strings, scopes, devices,
+//! methods, packages, notifications and resource templates.
+//!
+//! Every helper returns the encoded bytes, objects are nested by passing the
+//! encoded children to their parent.
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const METHOD_OP: u8 = 0x14;
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const DEVICE_OP: u8 = 0x82;
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_CHAR: u8 = b'^';
+const NOTIFY_OP: u8 = 0x86;
+
+/// Resource descriptor tags
+const IRQ_NO_FLAGS: u8 = 0x22;
+const IO_PORT: u8 = 0x47;
+const END_TAG: u8 = 0x79;
+const MEMORY32_FIXED: u8 = 0x86;
+const DWORD_ADDRESS_SPACE: u8 = 0x87;
+const WORD_ADDRESS_SPACE: u8 = 0x88;
+const QWORD_ADDRESS_SPACE: u8 = 0x8a;
+
+/// PkgLength of `len` bytes of content, the encoding counting itself
+fn pkg_length(len: usize) -> Vec<u8> {
+    if len + 1 < 0x40 {
+        return vec![(len + 1) as u8];
+    }
+    let (count, total) = if len + 2 < 1 << 12 {
+        (2, len + 2)
+    } else if len + 3 < 1 << 20 {
+        (3, len + 3)
+    } else {
+        (4, len + 4)
+    };
+    let mut out = vec![((count - 1) << 6) as u8 | (total & 0xf) as u8];
+    for i in 1..count {
+        out.push((total >> (4 + 8 * (i - 1))) as u8);
+    }
+    out
+}
+
+/// Object with a PkgLength before its content
+fn package_object(op: &[u8], content: Vec<u8>) -> Vec<u8> {
+    let mut out = op.to_vec();
+    out.extend(pkg_length(content.len()));
+    out.extend(content);
+    out
+}
+
+fn name_seg(seg: &str) -> [u8; 4] {
+    assert!(seg.len() <= 4 && !seg.is_empty(), "Bad AML name segment {seg}");
+    let mut out = [b'_'; 4];
+    out[..seg.len()].copy_from_slice(seg.as_bytes());
+    out
+}
+
+/// NameString of an ASL path like `\_SB.PCI0` or `^COM1`
+pub fn name_string(path: &str) -> Vec<u8> {
+    let mut out = vec![];
+    let mut rest = path;
+    if let Some(stripped) = rest.strip_prefix('\\') {
+        out.push(ROOT_CHAR);
+        rest = stripped;
+    }
+    while let Some(stripped) = rest.strip_prefix('^') {
+        out.push(PARENT_CHAR);
+        rest = stripped;
+    }
+    let segs: Vec<&str> = if rest.is_empty() { vec![] } else { rest.split('.').collect() };
+    match segs.len() {
+        0 => out.push(ZERO_OP),
+        1 => {}
+        2 => out.push(DUAL_NAME_PREFIX),
+        count => out.extend([MULTI_NAME_PREFIX, count as u8]),
+    }
+    for seg in segs {
+        out.extend(name_seg(seg));
+    }
+    out
+}
+
+pub fn integer(value: u64) -> Vec<u8> {
+    match value {
+        0 => vec![ZERO_OP],
+        1 => vec![ONE_OP],
+        2..=0xff => vec![BYTE_PREFIX, value as u8],
+        0x100..=0xffff => [&[WORD_PREFIX][..], &(value as u16).to_le_bytes()].concat(),
+        0x1_0000..=0xffff_ffff => [&[DWORD_PREFIX][..], &(value as u32).to_le_bytes()].concat(),
+        _ => [&[QWORD_PREFIX][..], &value.to_le_bytes()].concat(),
+    }
+}
+
+/// ASCII string, for the ids that don't fit an EISA one
+pub fn string(value: &str) -> Vec<u8> {
+    let mut out = vec![STRING_PREFIX];
+    out.extend(value.as_bytes());
+    out.push(0);
+    out
+}
+
+/// Compressed EISA id of a PNP id like `PNP0A08`
+pub fn eisa_id(id: &str) -> Vec<u8> {
+    let bytes = id.as_bytes();
+    assert!(bytes.len() == 7, "Bad EISA id {id}");
+    let letters = bytes[..3].iter().fold(0u32, |acc, &c| acc << 5 | (c - 0x40) as u32 & 0x1f);
+    let product = u32::from_str_radix(&id[3..], 16).expect("Bad EISA id product");
+    let value = (letters << 16 | product).swap_bytes();
+    [&[DWORD_PREFIX][..], &value.to_le_bytes()].concat()
+}
+
+pub fn name(path: &str, value: Vec<u8>) -> Vec<u8> {
+    let mut out = vec![NAME_OP];
+    out.extend(name_string(path));
+    out.extend(value);
+    out
+}
+
+pub fn scope(path: &str, children: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut content = name_string(path);
+    content.extend(children.concat());
+    package_object(&[SCOPE_OP], content)
+}
+
+pub fn device(path: &str, children: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut content = name_string(path);
+    content.extend(children.concat());
+    package_object(&[EXT_OP_PREFIX, DEVICE_OP], content)
+}
+
+/// Not serialized method taking `args` arguments
+pub fn method(path: &str, args: u8, body: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut content = name_string(path);
+    content.push(args & 0x7);
+    content.extend(body.concat());
+    package_object(&[METHOD_OP], content)
+}
+
+pub fn package(elements: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut content = vec![elements.len() as u8];
+    content.extend(elements.concat());
+    package_object(&[PACKAGE_OP], content)
+}
+
+pub fn notify(path: &str, value: u64) -> Vec<u8> {
+    let mut out = vec![NOTIFY_OP];
+    out.extend(name_string(path));
+    out.extend(integer(value));
+    out
+}
+
+/// Buffer of resource descriptors, with the end tag
+pub fn resource_template(descriptors: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut data = descriptors.concat();
+    data.extend([END_TAG, 0]);
+    let mut content = integer(data.len() as u64);
+    content.extend(data);
+    package_object(&[BUFFER_OP], content)
+}
+
+/// Fixed range of `len` 16 bit decoded ports at `base`
+pub fn io(base: u16, len: u8) -> Vec<u8> {
+    let mut out = vec![IO_PORT, 1];
+    out.extend(base.to_le_bytes());
+    out.extend(base.to_le_bytes());
+    out.extend([1, len]);
+    out
+}
+
+/// Edge triggered, active high ISA interrupt
+pub fn irq(line: u8) -> Vec<u8> {
+    let mut out = vec![IRQ_NO_FLAGS];
+    out.extend((1u16 << line).to_le_bytes());
+    out
+}
+
+/// Read write memory range of a device
+pub fn memory32_fixed(base: u32, len: u32) -> Vec<u8> {
+    let mut out = vec![MEMORY32_FIXED, 9, 0, 1];
+    out.extend(base.to_le_bytes());
+    out.extend(len.to_le_bytes());
+    out
+}
+
+/// Address space a bridge decodes for its children
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory = 0,
+    Io = 1,
+    BusNumber = 2,
+}
+
+impl AddressSpace {
+    /// Non cacheable read write memory, ISA and non ISA ports
+    fn type_flags(self) -> u8 {
+        match self {
+            Self::Memory => 0x01,
+            Self::Io => 0x03,
+            Self::BusNumber => 0,
+        }
+    }
+}
+
+/// Producer, fixed min and max, positive decode
+const ADDRESS_GENERAL_FLAGS: u8 = 0x0c;
+
+fn address_space(tag: u8, space: AddressSpace, fields: Vec<u8>) -> Vec<u8> {
+    let mut out = vec![tag];
+    out.extend(((fields.len() + 3) as u16).to_le_bytes());
+    out.extend([space as u8, ADDRESS_GENERAL_FLAGS, space.type_flags()]);
+    out.extend(fields);
+    out
+}
+
+/// Granularity, min, max, translation and length of a window
+pub fn word_space(space: AddressSpace, min: u16, max: u16) -> Vec<u8> {
+    let fields = [0, min, max, 0, max - min + 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+    address_space(WORD_ADDRESS_SPACE, space, fields)
+}
+
+pub fn dword_space(space: AddressSpace, min: u32, max: u32) -> Vec<u8> {
+    let fields = [0, min, max, 0, max - min + 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+    address_space(DWORD_ADDRESS_SPACE, space, fields)
+}
+
+pub fn qword_space(space: AddressSpace, min: u64, max: u64) -> Vec<u8> {
+    let fields = [0, min, max, 0, max - min + 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+    address_space(QWORD_ADDRESS_SPACE, space, fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pkg_length_counts_itself() {
+        assert_eq!(pkg_length(0x3e), [0x3f]);
+        assert_eq!(pkg_length(0x40), [0x42 & 0xf | 1 << 6, 0x04]);
+        assert_eq!(pkg_length(0x1000), [0x03 | 2 << 6, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn names_and_ids() {
+        assert_eq!(name_string("\\_SB.PCI0"), b"\\\x2e_SB_PCI0");
+        assert_eq!(name_string("^COM1"), b"^COM1");
+        assert_eq!(name_string("\\"), [ROOT_CHAR, ZERO_OP]);
+        assert_eq!(eisa_id("PNP0A03"), [DWORD_PREFIX, 0x41, 0xd0, 0x0a, 0x03]);
+        assert_eq!(integer(0x1234), [WORD_PREFIX, 0x34, 0x12]);
+        assert_eq!(string("MSFT0101"), b"\x0dMSFT0101\0");
+    }
+}
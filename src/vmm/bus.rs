@@ -0,0 +1,134 @@
+//! Port I/O and MMIO address spaces, dispatching guest accesses to emulated devices.
+
+use std::{ collections::BTreeMap, fmt, sync::{ Arc, Mutex } };
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+
+/// Device side of a bus range, offsets are relative to the range start
+pub trait BusDevice: Send {
+    fn read(&mut self, offset: u64, data: &mut [u8]);
+    fn write(&mut self, offset: u64, data: &[u8]);
+}
+
+pub type SharedBusDevice = Arc<Mutex<dyn BusDevice>>;
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum BusError {
+    /// Range 0x{0:x}+0x{1:x} overlaps a registered device
+    Overlap(u64, u64),
+    /// Empty range at 0x{0:x}
+    Empty(u64),
+}
+
+#[derive(Default)]
+pub struct Bus {
+    /// start -> (length, device)
+    ranges: BTreeMap<u64, (u64, SharedBusDevice)>,
+}
+
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.ranges.iter().map(|(start, (len, _))| format!("0x{start:x}+0x{len:x}")))
+            .finish()
+    }
+}
+
+impl Bus {
+    pub fn insert(&mut self, start: u64, len: u64, device: SharedBusDevice) -> Result<(), BusError> {
+        if len == 0 {
+            return Err(BusError::Empty(start));
+        }
+        let end = start.checked_add(len).ok_or(BusError::Overlap(start, len))?;
+        let before = self.ranges.range(..end).next_back();
+        if before.is_some_and(|(&s, &(l, _))| s + l > start) {
+            return Err(BusError::Overlap(start, len));
+        }
+        self.ranges.insert(start, (len, device));
+        Ok(())
+    }
+
+    /// Remove the range starting at `start`
+    pub fn remove(&mut self, start: u64) -> Option<SharedBusDevice> {
+        self.ranges.remove(&start).map(|(_, device)| device)
+    }
+
+    /// Device covering `addr`, with the offset of `addr` in its range
+    fn get(&self, addr: u64) -> Option<(u64, SharedBusDevice)> {
+        let (&start, (len, device)) = self.ranges.range(..=addr).next_back()?;
+        (addr - start < *len).then(|| (addr - start, device.clone()))
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.get(addr).is_some()
+    }
+
+    /// Returns false if no device covers `addr`
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+        match self.get(addr) {
+            Some((offset, device)) => {
+                device.lock().unwrap().read(offset, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns false if no device covers `addr`
+    pub fn write(&self, addr: u64, data: &[u8]) -> bool {
+        match self.get(addr) {
+            Some((offset, device)) => {
+                device.lock().unwrap().write(offset, data);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Remembers the offset of the last access
+    struct Recorder(u64);
+
+    impl BusDevice for Recorder {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn write(&mut self, offset: u64, _data: &[u8]) {
+            self.0 = offset;
+        }
+    }
+
+    fn device() -> Arc<Mutex<Recorder>> {
+        Arc::new(Mutex::new(Recorder(0)))
+    }
+
+    #[test]
+    fn ranges() {
+        let mut bus = Bus::default();
+        let first = device();
+        bus.insert(0x1000, 0x100, first.clone()).unwrap();
+        bus.insert(0x1100, 0x10, device()).unwrap();
+        assert!(matches!(bus.insert(0x10f0, 0x20, device()), Err(BusError::Overlap(0x10f0, 0x20))));
+        assert!(matches!(bus.insert(0x0ff0, 0x20, device()), Err(BusError::Overlap(..))));
+        assert!(matches!(bus.insert(0x2000, 0, device()), Err(BusError::Empty(0x2000))));
+        assert!(matches!(bus.insert(u64::MAX, 2, device()), Err(BusError::Overlap(..))));
+
+        let mut data = [0; 2];
+        assert!(bus.read(0x1042, &mut data));
+        assert_eq!(data, [0x42; 2]);
+        assert!(bus.write(0x10ff, &data));
+        assert_eq!(first.lock().unwrap().0, 0xff);
+        assert!(!bus.read(0x1110, &mut data));
+        assert!(!bus.contains(0xfff));
+
+        assert!(bus.remove(0x1000).is_some());
+        assert!(!bus.contains(0x1000));
+        bus.insert(0x0ff0, 0x20, device()).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+//! Guest CPUID, built from what KVM supports on this host.
+//!
+//! A [`CpuModel`] masks host features down to a named profile, then the
+//! topology leaves are filled for the vCPU and the per-leaf overrides of the
+//! config are applied last.
+
+use kvm_bindings::{ CpuId, kvm_cpuid_entry2, KVM_CPUID_FLAG_SIGNIFCANT_INDEX };
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use serde::Deserialize;
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// (leaf, subleaf, register, bits)
+type FeatureBits = (u32, u32, Reg, u32);
+
+/// AVX, AVX2 and friends, leaving an x86-64-v2 CPU
+const AVX_FEATURES: &[FeatureBits] = &[
+    // FMA, MOVBE, AVX, F16C
+    (0x1, 0, Reg::Ecx, 1 << 12 | 1 << 22 | 1 << 28 | 1 << 29),
+    // BMI1, AVX2, BMI2
+    (0x7, 0, Reg::Ebx, 1 << 3 | 1 << 5 | 1 << 8),
+    // VAES, VPCLMULQDQ
+    (0x7, 0, Reg::Ecx, 1 << 9 | 1 << 10),
+    // AVX-VNNI
+    (0x7, 1, Reg::Eax, 1 << 4),
+    // YMM state
+    (0xd, 0, Reg::Eax, 1 << 2),
+    // LZCNT, XOP, FMA4
+    (0x8000_0001, 0, Reg::Ecx, 1 << 5 | 1 << 11 | 1 << 16),
+];
+
+/// AVX-512 and AMX, leaving an x86-64-v3 CPU
+const AVX512_FEATURES: &[FeatureBits] = &[
+    // AVX512 F, DQ, IFMA, PF, ER, CD, BW, VL
+    (0x7, 0, Reg::Ebx, 1 << 16 | 1 << 17 | 1 << 21 | 1 << 26 | 1 << 27 | 1 << 28 | 1 << 30 | 1 << 31),
+    // AVX512 VBMI, VBMI2, VNNI, BITALG, VPOPCNTDQ
+    (0x7, 0, Reg::Ecx, 1 << 1 | 1 << 6 | 1 << 11 | 1 << 12 | 1 << 14),
+    // AVX512 4VNNIW, 4FMAPS, VP2INTERSECT, AMX-BF16, AVX512-FP16, AMX-TILE, AMX-INT8
+    (0x7, 0, Reg::Edx, 1 << 2 | 1 << 3 | 1 << 8 | 1 << 22 | 1 << 23 | 1 << 24 | 1 << 25),
+    // AVX512-BF16
+    (0x7, 1, Reg::Eax, 1 << 5),
+    // Opmask, ZMM_Hi256, Hi16_ZMM, XTILECFG, XTILEDATA state
+    (0xd, 0, Reg::Eax, 1 << 5 | 1 << 6 | 1 << 7 | 1 << 17 | 1 << 18),
+];
+
+/// Windows 11 refuses to install without these
+const WINDOWS11_FEATURES: &[(&str, FeatureBits)] = &[
+    ("CMPXCHG16B", (0x1, 0, Reg::Ecx, 1 << 13)),
+    ("SSE4.2", (0x1, 0, Reg::Ecx, 1 << 20)),
+    ("POPCNT", (0x1, 0, Reg::Ecx, 1 << 23)),
+    ("LAHF/SAHF", (0x8000_0001, 0, Reg::Ecx, 1 << 0)),
+    ("PREFETCHW", (0x8000_0001, 0, Reg::Ecx, 1 << 8)),
+    ("NX", (0x8000_0001, 0, Reg::Edx, 1 << 20)),
+];
+
+const LEAF_FEATURES: u32 = 0x1;
+const LEAF_CACHE_PARAMS: u32 = 0x4;
+const LEAF_TOPOLOGY: u32 = 0xb;
+const LEAF_TOPOLOGY_V2: u32 = 0x1f;
+const LEAF_ADDRESS_SIZES: u32 = 0x8000_0008;
+
+/// Named CPU profiles, features the host has beyond them are hidden from the guest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum CpuModel {
+    /// Everything KVM can expose on this host
+    #[default]
+    #[serde(rename = "host")]
+    Host,
+    #[serde(rename = "x86-64-v2")]
+    X86_64V2,
+    #[serde(rename = "x86-64-v3")]
+    X86_64V3,
+}
+
+impl CpuModel {
+    fn masked_features(self) -> Vec<FeatureBits> {
+        match self {
+            CpuModel::Host => vec![],
+            CpuModel::X86_64V2 => [AVX_FEATURES, AVX512_FEATURES].concat(),
+            CpuModel::X86_64V3 => AVX512_FEATURES.to_vec(),
+        }
+    }
+}
+
+/// Bits to set and clear in a CPUID register, `clear` goes first
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegOverride {
+    pub set: u32,
+    pub clear: u32,
+}
+
+impl RegOverride {
+    fn apply(&self, reg: &mut u32) {
+        *reg = (*reg & !self.clear) | self.set;
+    }
+}
+
+/// ```toml
+/// [[cpu.cpuid]]
+/// leaf = 0x1
+/// ecx = { clear = 0x80000000 }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CpuidOverride {
+    pub leaf: u32,
+    pub subleaf: u32,
+    pub eax: RegOverride,
+    pub ebx: RegOverride,
+    pub ecx: RegOverride,
+    pub edx: RegOverride,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CpuConfig {
+    pub model: CpuModel,
+    /// Applied in order after the model and topology
+    pub cpuid: Vec<CpuidOverride>,
+}
+
+fn reg_mut(entry: &mut kvm_cpuid_entry2, reg: Reg) -> &mut u32 {
+    match reg {
+        Reg::Eax => &mut entry.eax,
+        Reg::Ebx => &mut entry.ebx,
+        Reg::Ecx => &mut entry.ecx,
+        Reg::Edx => &mut entry.edx,
+    }
+}
+
+fn find(entries: &mut [kvm_cpuid_entry2], leaf: u32, subleaf: u32) -> Option<&mut kvm_cpuid_entry2> {
+    entries.iter_mut().find(|e| {
+        e.function == leaf && (e.flags & KVM_CPUID_FLAG_SIGNIFCANT_INDEX == 0 || e.index == subleaf)
+    })
+}
+
+fn has_feature(entries: &mut [kvm_cpuid_entry2], (leaf, subleaf, reg, bits): FeatureBits) -> bool {
+    find(entries, leaf, subleaf).is_some_and(|e| *reg_mut(e, reg) & bits == bits)
+}
+
+impl CpuConfig {
+    /// CPUID of vCPU `vcpu_id` out of `vcpu_count`, one core per vCPU
+    pub fn cpuid(&self, supported: &CpuId, vcpu_id: u8, vcpu_count: u8) -> Result<CpuId> {
+        let mut entries = supported.as_slice().to_vec();
+        for (leaf, subleaf, reg, bits) in self.model.masked_features() {
+            if let Some(entry) = find(&mut entries, leaf, subleaf) {
+                *reg_mut(entry, reg) &= !bits;
+            }
+        }
+        set_topology(&mut entries, vcpu_id, vcpu_count);
+        for o in &self.cpuid {
+            let entry = match find(&mut entries, o.leaf, o.subleaf) {
+                Some(entry) => entry,
+                None => {
+                    let flags = if o.subleaf != 0 { KVM_CPUID_FLAG_SIGNIFCANT_INDEX } else { 0 };
+                    entries.push(kvm_cpuid_entry2 { function: o.leaf, index: o.subleaf, flags, ..Default::default() });
+                    entries.last_mut().unwrap()
+                }
+            };
+            for (reg, reg_override) in [(Reg::Eax, o.eax), (Reg::Ebx, o.ebx), (Reg::Ecx, o.ecx), (Reg::Edx, o.edx)] {
+                reg_override.apply(reg_mut(entry, reg));
+            }
+            debug!("CPUID override {:x}.{:x}: {entry:x?}", o.leaf, o.subleaf);
+        }
+        for &(name, feature) in WINDOWS11_FEATURES {
+            if !has_feature(&mut entries, feature) {
+                warn!("CPUID lacks {name}, Windows 11 won't install");
+            }
+        }
+        CpuId::from_entries(&entries).map_err(|_| kvm_ioctls::Error::new(libc::E2BIG))
+    }
+}
+
+/// APIC ID and core counts, with every vCPU a single threaded core of one package
+fn set_topology(entries: &mut Vec<kvm_cpuid_entry2>, vcpu_id: u8, vcpu_count: u8) {
+    let apic_id = vcpu_id as u32;
+    let count = vcpu_count.max(1) as u32;
+    let core_bits = count.next_power_of_two().trailing_zeros();
+
+    if let Some(entry) = find(entries, LEAF_FEATURES, 0) {
+        entry.ebx = (entry.ebx & 0xffff) | apic_id << 24 | count << 16;
+        // HTT: the logical processor count above is valid
+        entry.edx |= 1 << 28;
+    }
+    for entry in entries.iter_mut().filter(|e| e.function == LEAF_CACHE_PARAMS) {
+        let level = (entry.eax >> 5) & 0x7;
+        let sharing = if level == 3 { count } else { 1 };
+        entry.eax = (entry.eax & 0x3fff) | (sharing - 1) << 14 | (count - 1) << 26;
+    }
+    for leaf in [LEAF_TOPOLOGY, LEAF_TOPOLOGY_V2] {
+        if leaf == LEAF_TOPOLOGY_V2 && !entries.iter().any(|e| e.function == leaf) {
+            continue;
+        }
+        entries.retain(|e| e.function != leaf);
+        // SMT level, core level, then the invalid level ending the enumeration
+        let levels = [(0, 1, 1), (core_bits, count, 2), (0, 0, 0)];
+        for (subleaf, (shift, processors, level_type)) in levels.into_iter().enumerate() {
+            entries.push(kvm_cpuid_entry2 {
+                function: leaf,
+                index: subleaf as u32,
+                flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+                eax: shift,
+                ebx: processors,
+                ecx: level_type << 8 | subleaf as u32,
+                edx: apic_id,
+                ..Default::default()
+            });
+        }
+    }
+    if let Some(entry) = find(entries, LEAF_ADDRESS_SIZES, 0) {
+        entry.ecx = (entry.ecx & !0xf0ff) | core_bits << 12 | (count - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(function: u32, index: u32, eax: u32, ebx: u32, ecx: u32, edx: u32) -> kvm_cpuid_entry2 {
+        let flags = if [0x4, 0x7, 0xd].contains(&function) { KVM_CPUID_FLAG_SIGNIFCANT_INDEX } else { 0 };
+        kvm_cpuid_entry2 { function, index, flags, eax, ebx, ecx, edx, ..Default::default() }
+    }
+
+    fn host() -> CpuId {
+        CpuId::from_entries(&[
+            entry(0x1, 0, 0x906a3, 0x0001_0800, u32::MAX, 0),
+            entry(0x4, 0, 0x121, 0, 0, 0),
+            entry(0x4, 1, 0x163, 0, 0, 0),
+            entry(0x7, 0, 0, u32::MAX, u32::MAX, u32::MAX),
+            entry(0x7, 1, u32::MAX, 0, 0, 0),
+            entry(0xb, 0, 1, 2, 0x100, 0),
+            entry(0x8000_0001, 0, 0, 0, u32::MAX, u32::MAX),
+            entry(0x8000_0008, 0, 0x3027, 0, 0xffff_ffff, 0),
+        ])
+        .unwrap()
+    }
+
+    fn get(cpuid: &CpuId, leaf: u32, subleaf: u32) -> kvm_cpuid_entry2 {
+        *find(&mut cpuid.as_slice().to_vec(), leaf, subleaf).unwrap()
+    }
+
+    #[test]
+    fn models_mask_features() {
+        let host_cpu = CpuConfig::default().cpuid(&host(), 0, 1).unwrap();
+        assert_eq!(get(&host_cpu, 0x7, 0).ebx, u32::MAX);
+
+        let v3 = CpuConfig { model: CpuModel::X86_64V3, ..Default::default() };
+        let v3 = v3.cpuid(&host(), 0, 1).unwrap();
+        let leaf7 = get(&v3, 0x7, 0);
+        assert_ne!(leaf7.ebx & 1 << 5, 0, "AVX2 kept");
+        assert_eq!(leaf7.ebx & 1 << 16, 0, "AVX512F masked");
+        assert_eq!(get(&v3, 0x7, 1).eax & (1 << 4 | 1 << 5), 1 << 4);
+
+        let v2 = CpuConfig { model: CpuModel::X86_64V2, ..Default::default() };
+        let v2 = v2.cpuid(&host(), 0, 1).unwrap();
+        assert_eq!(get(&v2, 0x1, 0).ecx & 1 << 28, 0, "AVX masked");
+        assert_ne!(get(&v2, 0x1, 0).ecx & 1 << 20, 0, "SSE4.2 kept");
+        assert_eq!(get(&v2, 0x7, 0).ebx & (1 << 5 | 1 << 16), 0);
+    }
+
+    #[test]
+    fn topology_per_vcpu() {
+        let cpuid = CpuConfig::default().cpuid(&host(), 2, 3).unwrap();
+        let leaf1 = get(&cpuid, 0x1, 0);
+        assert_eq!(leaf1.ebx, 2 << 24 | 3 << 16 | 0x0800);
+        assert_ne!(leaf1.edx & 1 << 28, 0);
+        // L1 private, L3 shared between the 3 cores
+        assert_eq!(get(&cpuid, 0x4, 0).eax, 0x121 | 2 << 26);
+        assert_eq!(get(&cpuid, 0x4, 1).eax, 0x163 | 2 << 14 | 2 << 26);
+
+        let leaf_b: Vec<_> = cpuid.as_slice().iter().filter(|e| e.function == LEAF_TOPOLOGY).copied().collect();
+        assert_eq!(leaf_b.len(), 3);
+        assert_eq!((leaf_b[0].eax, leaf_b[0].ebx, leaf_b[0].ecx, leaf_b[0].edx), (0, 1, 0x100, 2));
+        assert_eq!((leaf_b[1].eax, leaf_b[1].ebx, leaf_b[1].ecx, leaf_b[1].edx), (2, 3, 0x201, 2));
+        assert_eq!(leaf_b[2].ecx, 2);
+        // No leaf 0x1f on the host, none made up
+        assert!(!cpuid.as_slice().iter().any(|e| e.function == LEAF_TOPOLOGY_V2));
+        assert_eq!(get(&cpuid, 0x8000_0008, 0).ecx, 0xffff_0f00 | 2 << 12 | 2);
+    }
+
+    #[test]
+    fn config_overrides() {
+        let config: CpuConfig = toml::from_str(
+            "model = \"x86-64-v3\"\n\
+             [[cpuid]]\nleaf = 0x1\necx = { clear = 0x80000000 }\n\
+             [[cpuid]]\nleaf = 0x7\nebx = { set = 0x10000 }\n\
+             [[cpuid]]\nleaf = 0x4000_0000\nsubleaf = 1\neax = { set = 0x1234 }\n",
+        )
+        .unwrap();
+        let cpuid = config.cpuid(&host(), 0, 1).unwrap();
+        assert_eq!(get(&cpuid, 0x1, 0).ecx, 0x7fff_ffff);
+        // Overrides come after the model mask
+        assert_ne!(get(&cpuid, 0x7, 0).ebx & 1 << 16, 0);
+        let added = get(&cpuid, 0x4000_0000, 1);
+        assert_eq!((added.eax, added.flags), (0x1234, KVM_CPUID_FLAG_SIGNIFCANT_INDEX));
+        assert!(toml::from_str::<CpuConfig>("model = \"pentium\"").is_err());
+    }
+}
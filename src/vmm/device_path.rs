@@ -0,0 +1,195 @@
+//! UEFI device paths in the text form of the spec, as the UEFI shell and
+//! OVMF's boot manager print them, e.g.
+//! `PciRoot(0x0)/Pci(0x1F,0x2)/Sata(0x0,0xFFFF,0x0)/HD(1,GPT,...)/\EFI\BOOT\BOOTX64.EFI`.
+//!
+//! Only the nodes OVMF puts in its boot options are named, the others are
+//! printed as `Path(type,subtype,data)`.
+
+use std::fmt::Write;
+
+use super::uefi_vars::Guid;
+
+/// Type, subtype and length of each node
+const NODE_HEADER_SIZE: usize = 4;
+
+const HARDWARE: u8 = 0x01;
+const ACPI: u8 = 0x02;
+const MESSAGING: u8 = 0x03;
+const MEDIA: u8 = 0x04;
+const BBS: u8 = 0x05;
+const END: u8 = 0x7f;
+
+/// Subtype of the end node closing the whole path, the others close one instance
+const END_ENTIRE: u8 = 0xff;
+
+/// EISA ID of PNP0A03 and PNP0A08, PCI and PCIe host bridges
+const PNP0A03: u32 = 0x0a0341d0;
+const PNP0A08: u32 = 0x0a0841d0;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    data.get(offset..offset + 2).map_or(0, |b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4).map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    data.get(offset..offset + 8).map_or(0, |b| u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn guid_at(data: &[u8], offset: usize) -> Guid {
+    data.get(offset..offset + 16).map_or(Guid::default(), Guid::read)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().fold(String::new(), |mut out, b| {
+        let _ = write!(out, "{b:02x}");
+        out
+    })
+}
+
+/// NUL terminated UTF-16 string at the start of `data`, and its size with the NUL
+pub fn utf16_string(data: &[u8]) -> (String, usize) {
+    let units: Vec<u16> =
+        data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).take_while(|&unit| unit != 0).collect();
+    let size = ((units.len() + 1) * 2).min(data.len());
+    (String::from_utf16_lossy(&units), size)
+}
+
+/// `PNPxxxx` of an EISA ID
+fn eisa_id(id: u32) -> String {
+    let vendor = id as u16;
+    let letters: String = [10, 5, 0].iter().map(|shift| (b'@' + ((vendor >> shift) & 0x1f) as u8) as char).collect();
+    format!("{letters}{:04X}", id >> 16)
+}
+
+/// One node, its data following the header
+fn format_node(ty: u8, subtype: u8, data: &[u8]) -> String {
+    match (ty, subtype) {
+        (HARDWARE, 0x01) => format!("Pci(0x{:X},0x{:X})", data.get(1).copied().unwrap_or(0), data.first().copied().unwrap_or(0)),
+        (HARDWARE, 0x04) => format!("VenHw({})", guid_at(data, 0)),
+        (ACPI, 0x01) => match u32_at(data, 0) {
+            PNP0A03 => format!("PciRoot(0x{:X})", u32_at(data, 4)),
+            PNP0A08 => format!("PcieRoot(0x{:X})", u32_at(data, 4)),
+            hid => format!("Acpi({},0x{:X})", eisa_id(hid), u32_at(data, 4)),
+        },
+        (MESSAGING, 0x01) => format!(
+            "Ata({},{},0x{:X})",
+            if data.first() == Some(&0) { "Primary" } else { "Secondary" },
+            if data.get(1) == Some(&0) { "Master" } else { "Slave" },
+            u16_at(data, 2)
+        ),
+        (MESSAGING, 0x02) => format!("Scsi(0x{:X},0x{:X})", u16_at(data, 0), u16_at(data, 2)),
+        (MESSAGING, 0x05) => format!("USB(0x{:X},0x{:X})", data.first().copied().unwrap_or(0), data.get(1).copied().unwrap_or(0)),
+        (MESSAGING, 0x0a) => format!("VenMsg({})", guid_at(data, 0)),
+        (MESSAGING, 0x0b) => {
+            format!("MAC({},0x{:X})", hex(data.get(..6).unwrap_or_default()), data.get(32).copied().unwrap_or(0))
+        }
+        (MESSAGING, 0x12) => {
+            format!("Sata(0x{:X},0x{:X},0x{:X})", u16_at(data, 0), u16_at(data, 2), u16_at(data, 4))
+        }
+        (MESSAGING, 0x17) => {
+            let eui: Vec<String> = u64_at(data, 4).to_le_bytes().iter().map(|b| format!("{b:02X}")).collect();
+            format!("NVMe(0x{:X},{})", u32_at(data, 0), eui.join("-"))
+        }
+        (MESSAGING, 0x18) => format!("Uri({})", String::from_utf8_lossy(data)),
+        (MEDIA, 0x01) => {
+            let signature = match (data.get(36), data.get(37)) {
+                (Some(1), Some(1)) => ("MBR".to_string(), format!("0x{:X}", u32_at(data, 20))),
+                (Some(2), Some(2)) => ("GPT".to_string(), guid_at(data, 20).to_string()),
+                (format, _) => (format!("{}", format.copied().unwrap_or(0)), "0".to_string()),
+            };
+            format!(
+                "HD({},{},{},0x{:X},0x{:X})",
+                u32_at(data, 0),
+                signature.0,
+                signature.1,
+                u64_at(data, 4),
+                u64_at(data, 12)
+            )
+        }
+        (MEDIA, 0x02) => format!("CDROM(0x{:X},0x{:X},0x{:X})", u32_at(data, 0), u64_at(data, 4), u64_at(data, 12)),
+        (MEDIA, 0x03) => format!("VenMedia({})", guid_at(data, 0)),
+        (MEDIA, 0x04) => utf16_string(data).0,
+        (MEDIA, 0x06) => format!("FvFile({})", guid_at(data, 0)),
+        (MEDIA, 0x07) => format!("Fv({})", guid_at(data, 0)),
+        (BBS, 0x01) => format!("BBS(0x{:X},{})", u16_at(data, 0), utf16_string(data.get(4..).unwrap_or_default()).0),
+        _ => format!("Path({ty},{subtype},{})", hex(data)),
+    }
+}
+
+/// Text form of the device path in `data`, instances separated by commas
+pub fn format_device_path(data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut at = 0;
+    let mut first = true;
+    while let Some(header) = data.get(at..at + NODE_HEADER_SIZE) {
+        let (ty, subtype, length) = (header[0], header[1], u16_at(header, 2) as usize);
+        let Some(node) = data.get(at + NODE_HEADER_SIZE..at + length).filter(|_| length >= NODE_HEADER_SIZE) else {
+            out.push_str("/<bad node>");
+            break;
+        };
+        at += length;
+        if ty == END {
+            if subtype == END_ENTIRE {
+                break;
+            }
+            out.push(',');
+            first = true;
+            continue;
+        }
+        if !first {
+            out.push('/');
+        }
+        first = false;
+        out.push_str(&format_node(ty, subtype, node));
+    }
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn node(ty: u8, subtype: u8, data: &[u8]) -> Vec<u8> {
+        let mut node = vec![ty, subtype];
+        node.extend(((NODE_HEADER_SIZE + data.len()) as u16).to_le_bytes());
+        node.extend(data);
+        node
+    }
+
+    /// PciRoot(0x0)/Pci(0x1F,0x2)/Sata(0x0,0xFFFF,0x0)/HD(1,GPT,...)/\EFI\BOOT\BOOTX64.EFI
+    pub(crate) fn disk_boot_path() -> Vec<u8> {
+        let mut hd = 1u32.to_le_bytes().to_vec();
+        hd.extend(0x800u64.to_le_bytes());
+        hd.extend(0x32000u64.to_le_bytes());
+        hd.extend("77fa9abd-0359-4d32-bd60-28f4e78f784b".parse::<Guid>().unwrap().0);
+        hd.extend([2, 2]);
+        let file: Vec<u8> = "\\EFI\\BOOT\\BOOTX64.EFI".encode_utf16().chain([0]).flat_map(u16::to_le_bytes).collect();
+        [
+            node(ACPI, 0x01, &[PNP0A03.to_le_bytes(), 0u32.to_le_bytes()].concat()),
+            node(HARDWARE, 0x01, &[2, 0x1f]),
+            node(MESSAGING, 0x12, &[0, 0, 0xff, 0xff, 0, 0]),
+            node(MEDIA, 0x01, &hd),
+            node(MEDIA, 0x04, &file),
+            node(END, END_ENTIRE, &[]),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn boot_paths() {
+        assert_eq!(
+            format_device_path(&disk_boot_path()),
+            "PciRoot(0x0)/Pci(0x1F,0x2)/Sata(0x0,0xFFFF,0x0)\
+             /HD(1,GPT,77fa9abd-0359-4d32-bd60-28f4e78f784b,0x800,0x32000)/\\EFI\\BOOT\\BOOTX64.EFI"
+        );
+        let serial = [0x0501_41d0u32.to_le_bytes(), 0u32.to_le_bytes()].concat();
+        let two_instances =
+            [node(ACPI, 0x01, &serial), node(END, 0x01, &[]), node(0x42, 7, &[0xab]), node(END, END_ENTIRE, &[])]
+                .concat();
+        assert_eq!(format_device_path(&two_instances), "Acpi(PNP0501,0x0),Path(66,7,ab)");
+        assert_eq!(format_device_path(&[MEDIA, 0x04, 2, 0]), "/<bad node>");
+    }
+}
@@ -0,0 +1,108 @@
+//! Guest page write tracking on top of KVM_GET_DIRTY_LOG.
+//!
+//! KVM clears its bitmap on every read, so several consumers (snapshot base,
+//! diff markers...) can't read it on their own. Instead every sync stamps the
+//! dirty pages with the current epoch, and a consumer only keeps the epoch it
+//! marked: anything stamped later changed since.
+
+use kvm_ioctls::VmFd;
+#[allow(unused)]
+use log::{ debug, info, warn };
+use vm_memory::{ GuestMemory, GuestMemoryRegion };
+
+use super::ram::{ Ram, PAGE_SIZE };
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+#[derive(Debug)]
+pub struct DirtyLog {
+    /// Last epoch each page (host RAM offset / PAGE_SIZE) was seen dirty in, 0 if never
+    epochs: Vec<u32>,
+    epoch: u32,
+}
+
+impl DirtyLog {
+    pub fn new(ram: &Ram) -> Self {
+        Self { epochs: vec![0; ram.page_count() as usize], epoch: 1 }
+    }
+
+    fn stamp_bitmap(&mut self, bitmap: &[u64], first_page: usize) {
+        for (word_idx, &word) in bitmap.iter().enumerate() {
+            let mut word = word;
+            while word != 0 {
+                let page = first_page + word_idx * 64 + (word.trailing_zeros() as usize);
+                if let Some(epoch) = self.epochs.get_mut(page) {
+                    *epoch = self.epoch;
+                }
+                word &= word - 1;
+            }
+        }
+    }
+
+    /// Fold the KVM bitmaps (guest writes) and the vm-memory bitmaps (device writes)
+    /// into the page epochs
+    pub fn sync(&mut self, vm_fd: &VmFd, ram: &Ram) -> Result<()> {
+        for slot in &ram.slots {
+            let bitmap = vm_fd.get_dirty_log(slot.slot, slot.size)?;
+            self.stamp_bitmap(&bitmap, slot.host_offset / PAGE_SIZE);
+        }
+        for region in ram.guest_mem_map.iter() {
+            let bitmap = region.bitmap().get_and_reset();
+            match ram.host_offset(region.start_addr().0) {
+                Some(offset) => self.stamp_bitmap(&bitmap, offset / PAGE_SIZE),
+                None => warn!("Guest memory region @ 0x{:x} is not in RAM", region.start_addr().0),
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a point in time, pages written after it are returned by
+    /// [`DirtyLog::changed_since`] with the returned marker
+    pub fn mark(&mut self, vm_fd: &VmFd, ram: &Ram) -> Result<u32> {
+        self.sync(vm_fd, ram)?;
+        let marker = self.epoch;
+        self.epoch += 1;
+        debug!("Dirty log marker {marker}");
+        Ok(marker)
+    }
+
+    /// Count `pages` as written now, for changes made behind the log's back
+    pub fn touch(&mut self, pages: &[u64]) {
+        for &page in pages {
+            if let Some(epoch) = self.epochs.get_mut(page as usize) {
+                *epoch = self.epoch;
+            }
+        }
+    }
+
+    /// Pages written since `marker`, as of the last sync
+    pub fn changed_since(&self, marker: u32) -> Vec<u64> {
+        self.epochs
+            .iter()
+            .enumerate()
+            .filter(|(_, &epoch)| epoch > marker)
+            .map(|(page, _)| page as u64)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epochs_since_marker() {
+        let mut log = DirtyLog { epochs: vec![0; 130], epoch: 1 };
+        log.stamp_bitmap(&[1 << 3, 1 << 1], 0);
+        // What mark does once synced
+        let marker = log.epoch;
+        log.epoch += 1;
+        assert!(log.changed_since(marker).is_empty());
+
+        // Slot bitmaps start at the slot's first page, bits past RAM are ignored
+        log.stamp_bitmap(&[0b101, 0, 1 << 3], 64);
+        log.touch(&[5, 1000]);
+        assert_eq!(log.changed_since(marker), [5, 64, 66]);
+        assert_eq!(log.changed_since(0), [3, 5, 64, 65, 66]);
+    }
+}
@@ -0,0 +1,232 @@
+//! Disk image backends shared by the block device models.
+
+use std::{
+    fmt,
+    fs::{ File, OpenOptions },
+    io,
+    os::{ fd::AsRawFd, unix::fs::FileExt },
+    path::{ Component, Path, PathBuf },
+    str::FromStr,
+};
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use serde::Deserialize;
+
+use super::qcow2::{ Qcow2Disk, QCOW2_MAGIC };
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Longest backing chain, the image itself included
+const MAX_CHAIN_DEPTH: usize = 16;
+
+/// Controller a disk image is attached to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskInterface {
+    #[default]
+    Virtio,
+    Ahci,
+    /// Namespace of the NVMe controller, shared by every such disk
+    Nvme,
+}
+
+/// Image file format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+}
+
+impl DiskFormat {
+    pub const NAMES: &'static [&'static str] = &["raw", "qcow2"];
+}
+
+impl fmt::Display for DiskFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Raw => "raw",
+            Self::Qcow2 => "qcow2",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for DiskFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "qcow2" => Ok(Self::Qcow2),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, format!("disk format {s}"))),
+        }
+    }
+}
+
+/// How a disk image and its backing files are opened
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskOptions {
+    /// Probed when not set for read only images. Writable ones are raw, the guest
+    /// can write any header, but one with a qcow2 header is refused.
+    pub format: Option<DiskFormat>,
+    pub readonly: bool,
+    /// Backing files may be named by absolute paths or out of the image directory
+    pub backing_anywhere: bool,
+}
+
+/// Images of a backing chain opened so far
+#[derive(Debug, Default)]
+pub(super) struct BackingChain {
+    /// Canonical paths
+    opened: Vec<PathBuf>,
+    backing_anywhere: bool,
+}
+
+impl BackingChain {
+    /// Refuse `path` if the chain already has it or is as long as allowed
+    pub(super) fn enter(&mut self, path: &Path) -> io::Result<()> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if self.opened.len() == MAX_CHAIN_DEPTH {
+            return Err(invalid(format!("backing chain longer than {MAX_CHAIN_DEPTH} images")));
+        }
+        let canonical = path.canonicalize()?;
+        if self.opened.contains(&canonical) {
+            return Err(invalid(format!("backing chain loops back to {}", canonical.to_string_lossy())));
+        }
+        self.opened.push(canonical);
+        Ok(())
+    }
+
+    /// Path of the backing file `name` of the image at `image`
+    pub(super) fn backing_path(&self, image: &Path, name: &str) -> io::Result<PathBuf> {
+        let name = Path::new(name);
+        if !self.backing_anywhere && (name.is_absolute() || name.components().any(|c| c == Component::ParentDir)) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("backing file {} out of the image directory", name.to_string_lossy()),
+            ));
+        }
+        Ok(image.parent().unwrap_or(Path::new(".")).join(name))
+    }
+}
+
+/// Byte addressed storage behind an emulated disk
+pub trait DiskBackend: Send {
+    /// Virtual size in bytes
+    fn size(&self) -> u64;
+    fn read_only(&self) -> bool;
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    /// The guest no longer needs the range, reads may return anything afterwards
+    fn discard(&mut self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Plain image file, sector N at byte N * 512
+#[derive(Debug)]
+pub struct RawDisk {
+    file: File,
+    size: u64,
+    readonly: bool,
+}
+
+impl RawDisk {
+    pub fn open<P: AsRef<Path>>(path: P, readonly: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(!readonly).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { file, size, readonly })
+    }
+}
+
+pub(super) fn check_range(offset: u64, len: usize, size: u64) -> io::Result<()> {
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("0x{offset:x}+0x{len:x} past the disk end"))),
+    }
+}
+
+impl DiskBackend for RawDisk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        self.readonly
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        check_range(offset, buf.len(), self.size)?;
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if self.readonly {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        check_range(offset, buf.len(), self.size)?;
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if self.readonly {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        check_range(offset, len as usize, self.size)?;
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        let ret = unsafe { libc::fallocate(self.file.as_raw_fd(), mode, offset as i64, len as i64) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// `format`, or for read only images the format their header tells. A writable
+/// image with a qcow2 header needs its format, raw writes would wreck the metadata.
+fn resolve_format(path: &Path, format: Option<DiskFormat>, readonly: bool) -> io::Result<DiskFormat> {
+    if let Some(format) = format {
+        return Ok(format);
+    }
+    let mut magic = [0u8; 4];
+    let is_qcow2 = File::open(path)?.read_exact_at(&mut magic, 0).is_ok() && magic == QCOW2_MAGIC;
+    match is_qcow2 {
+        true if readonly => Ok(DiskFormat::Qcow2),
+        true => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has a qcow2 header, set its format to qcow2, or raw", path.to_string_lossy())
+        )),
+        false => Ok(DiskFormat::Raw),
+    }
+}
+
+/// Open an image of a backing chain
+pub(super) fn open_image(
+    path: &Path,
+    format: Option<DiskFormat>,
+    readonly: bool,
+    chain: &mut BackingChain
+) -> io::Result<Box<dyn DiskBackend>> {
+    Ok(match resolve_format(path, format, readonly)? {
+        DiskFormat::Raw => Box::new(RawDisk::open(path, readonly)?),
+        DiskFormat::Qcow2 => Box::new(Qcow2Disk::open_chain(path, readonly, chain)?),
+    })
+}
+
+/// Open a disk image with the backend of its format
+pub fn open_disk<P: AsRef<Path>>(path: P, options: DiskOptions) -> io::Result<Box<dyn DiskBackend>> {
+    let path = path.as_ref();
+    let format = resolve_format(path, options.format, options.readonly)?;
+    let mut chain = BackingChain { opened: vec![], backing_anywhere: options.backing_anywhere };
+    let disk = open_image(path, Some(format), options.readonly, &mut chain)?;
+    let readonly = if options.readonly { ", read only" } else { "" };
+    info!("Disk {} {format}, {} bytes{readonly}", path.to_string_lossy(), disk.size());
+    Ok(disk)
+}
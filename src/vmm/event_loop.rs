@@ -0,0 +1,301 @@
+//! Device I/O off the vCPU thread.
+//!
+//! Guest doorbell writes land on ioeventfds registered with KVM, without
+//! leaving KVM_RUN, and device interrupts go out through irqfds. The event
+//! loop thread waits on the ioeventfds (or any other fd a device cares about,
+//! like a disk completion) with epoll and runs their handler. Saving or
+//! replacing the VM state quiesces the loop first, so that no handler sees or
+//! makes half of it.
+
+use std::{
+    collections::HashMap,
+    io,
+    os::fd::{ AsRawFd, RawFd },
+    sync::{ mpsc::{ channel, Receiver, Sender }, Arc, Mutex },
+    thread::{ self, JoinHandle, ThreadId },
+};
+
+use kvm_ioctls::{ IoEventAddress, NoDatamatch };
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vmm_sys_util::{ epoll::{ ControlOperation, Epoll, EpollEvent, EventSet }, eventfd::{ EventFd, EFD_NONBLOCK } };
+
+use super::Vm;
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+const MAX_EVENTS: usize = 32;
+
+/// Device side of an fd watched by the event loop
+pub trait EventHandler: Send {
+    /// `fd` is readable, runs on the event loop thread. The handler must consume
+    /// what made it readable, e.g. read the eventfd, or it is called again right away.
+    fn handle_event(&mut self, fd: RawFd);
+}
+
+pub type SharedHandler = Arc<Mutex<dyn EventHandler>>;
+
+enum Command {
+    Add(RawFd, SharedHandler),
+    /// Acknowledged once the fd is out of epoll and its handler dropped
+    Remove(RawFd, Sender<()>),
+    /// Acknowledged between two dispatches, the loop then waits until the
+    /// receiver's sender is dropped
+    Quiesce(Sender<()>, Receiver<()>),
+    Stop,
+}
+
+/// The event loop handlers are parked until this is dropped
+#[must_use]
+#[derive(Debug)]
+pub struct Quiesced {
+    _resume: Sender<()>,
+}
+
+/// Epoll thread running device handlers, stopped when dropped
+pub struct EventLoop {
+    commands: Sender<Command>,
+    /// Wakes the thread up to read `commands`
+    wakeup: EventFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for EventLoop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventLoop").field("wakeup", &self.wakeup.as_raw_fd()).finish()
+    }
+}
+
+impl EventLoop {
+    pub fn start() -> io::Result<Self> {
+        let epoll = Epoll::new()?;
+        let wakeup = EventFd::new(EFD_NONBLOCK)?;
+        let wakeup_fd = wakeup.try_clone()?;
+        epoll.ctl(
+            ControlOperation::Add,
+            wakeup_fd.as_raw_fd(),
+            EpollEvent::new(EventSet::IN, wakeup_fd.as_raw_fd() as u64)
+        )?;
+        let (commands, rx) = channel();
+        let thread = thread::Builder::new().name("event-loop".to_string()).spawn(move || {
+            let mut handlers: HashMap<RawFd, SharedHandler> = HashMap::new();
+            let mut events = vec![EpollEvent::default(); MAX_EVENTS];
+            loop {
+                let count = match epoll.wait(-1, &mut events) {
+                    Ok(count) => count,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        error!("Event loop epoll failed: {e}");
+                        return;
+                    }
+                };
+                for event in &events[..count] {
+                    let fd = event.fd();
+                    if fd != wakeup_fd.as_raw_fd() {
+                        match handlers.get(&fd) {
+                            Some(handler) => handler.lock().unwrap().handle_event(fd),
+                            None => debug!("Event on unregistered fd {fd}"),
+                        }
+                        continue;
+                    }
+                    let _ = wakeup_fd.read();
+                    for command in rx.try_iter() {
+                        let res = match command {
+                            Command::Add(fd, handler) => {
+                                handlers.insert(fd, handler);
+                                epoll.ctl(ControlOperation::Add, fd, EpollEvent::new(EventSet::IN, fd as u64))
+                            }
+                            Command::Remove(fd, done) => {
+                                handlers.remove(&fd);
+                                let res = epoll.ctl(ControlOperation::Delete, fd, EpollEvent::default());
+                                let _ = done.send(());
+                                res
+                            }
+                            Command::Quiesce(done, resume) => {
+                                let _ = done.send(());
+                                debug!("Event loop quiesced");
+                                let _ = resume.recv();
+                                Ok(())
+                            }
+                            Command::Stop => return,
+                        };
+                        if let Err(e) = res {
+                            warn!("Event loop fd update failed: {e}");
+                        }
+                    }
+                }
+            }
+        })?;
+        info!("Event loop started");
+        Ok(Self { commands, wakeup, thread: Some(thread) })
+    }
+
+    fn send(&self, command: Command) -> io::Result<()> {
+        self.commands.send(command).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.wakeup.write(1)
+    }
+
+    /// Run `handler` whenever `fd` becomes readable, `fd` must outlive the registration
+    pub fn add(&self, fd: RawFd, handler: SharedHandler) -> io::Result<()> {
+        self.send(Command::Add(fd, handler))
+    }
+
+    /// Stop watching `fd`, once this returns it can be closed. Not callable from a handler,
+    /// it waits for the event loop thread.
+    pub fn remove(&self, fd: RawFd) -> io::Result<()> {
+        if Some(thread::current().id()) == self.thread_id() {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        let (done, acked) = channel();
+        self.send(Command::Remove(fd, done))?;
+        acked.recv().map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    /// Wait for the running handler to return and keep the others from running
+    /// until the result is dropped. Not callable from a handler, and `remove`
+    /// blocks meanwhile.
+    pub fn quiesce(&self) -> io::Result<Quiesced> {
+        if Some(thread::current().id()) == self.thread_id() {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        let (done, acked) = channel();
+        let (resume, resumed) = channel();
+        self.send(Command::Quiesce(done, resumed))?;
+        acked.recv().map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(Quiesced { _resume: resume })
+    }
+
+    fn thread_id(&self) -> Option<ThreadId> {
+        self.thread.as_ref().map(|thread| thread.thread().id())
+    }
+}
+
+impl Drop for EventLoop {
+    fn drop(&mut self) {
+        if self.send(Command::Stop).is_ok() {
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Vm {
+    /// Guest writes of any size to `addr` signal `fd` in the kernel, no exit to userspace
+    pub fn register_ioeventfd(&self, fd: &EventFd, addr: IoEventAddress) -> Result<()> {
+        self.vm_fd.register_ioevent(fd, &addr, NoDatamatch)
+    }
+
+    pub fn unregister_ioeventfd(&self, fd: &EventFd, addr: IoEventAddress) -> Result<()> {
+        self.vm_fd.unregister_ioevent(fd, &addr, NoDatamatch)
+    }
+
+    /// Run `handler` on the event loop thread each time the guest writes to `addr`,
+    /// `fd` being the eventfd the handler drains
+    pub fn add_ioevent_handler(&self, fd: &EventFd, addr: IoEventAddress, handler: SharedHandler) -> Result<()> {
+        self.register_ioeventfd(fd, addr)?;
+        self.event_loop
+            .add(fd.as_raw_fd(), handler)
+            .map_err(|e| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO)))
+    }
+
+    /// Undo [`Vm::add_ioevent_handler`], `fd` can be closed once this returns
+    #[allow(dead_code)]
+    pub fn remove_ioevent_handler(&self, fd: &EventFd, addr: IoEventAddress) -> Result<()> {
+        self.unregister_ioeventfd(fd, addr)?;
+        self.event_loop
+            .remove(fd.as_raw_fd())
+            .map_err(|e| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ sync::mpsc::Receiver, time::Duration };
+
+    use super::*;
+
+    struct Counter {
+        fd: EventFd,
+        seen: Sender<u64>,
+    }
+
+    impl EventHandler for Counter {
+        fn handle_event(&mut self, _fd: RawFd) {
+            if let Ok(count) = self.fd.read() {
+                let _ = self.seen.send(count);
+            }
+        }
+    }
+
+    fn counter() -> (EventFd, Arc<Mutex<Counter>>, Receiver<u64>) {
+        let fd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let (seen, rx) = channel();
+        let handler = Arc::new(Mutex::new(Counter { fd: fd.try_clone().unwrap(), seen }));
+        (fd, handler, rx)
+    }
+
+    #[test]
+    fn handlers_run_until_removed() {
+        let event_loop = EventLoop::start().unwrap();
+        let (fd, handler, seen) = counter();
+        event_loop.add(fd.as_raw_fd(), handler.clone()).unwrap();
+        fd.write(3).unwrap();
+        assert_eq!(seen.recv_timeout(Duration::from_secs(5)), Ok(3));
+
+        event_loop.remove(fd.as_raw_fd()).unwrap();
+        // The loop dropped its handler reference before acknowledging
+        assert_eq!(Arc::strong_count(&handler), 1);
+        fd.write(1).unwrap();
+        assert!(seen.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(event_loop);
+    }
+
+    #[test]
+    fn quiesce_waits_for_the_running_handler() {
+        struct Slow(EventFd, Sender<()>, Arc<Mutex<bool>>);
+        impl EventHandler for Slow {
+            fn handle_event(&mut self, _fd: RawFd) {
+                let _ = self.0.read();
+                let _ = self.1.send(());
+                thread::sleep(Duration::from_millis(100));
+                *self.2.lock().unwrap() = true;
+            }
+        }
+        let event_loop = EventLoop::start().unwrap();
+        let fd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let (started, running) = channel();
+        let done = Arc::new(Mutex::new(false));
+        let handler = Arc::new(Mutex::new(Slow(fd.try_clone().unwrap(), started, done.clone())));
+        event_loop.add(fd.as_raw_fd(), handler).unwrap();
+        fd.write(1).unwrap();
+        running.recv_timeout(Duration::from_secs(5)).unwrap();
+        let quiesced = event_loop.quiesce().unwrap();
+        assert!(*done.lock().unwrap());
+
+        // Parked until resumed
+        fd.write(1).unwrap();
+        assert!(running.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(quiesced);
+        running.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn remove_from_handler_refused() {
+        struct SelfRemove(Arc<EventLoop>, EventFd, Sender<io::ErrorKind>);
+        impl EventHandler for SelfRemove {
+            fn handle_event(&mut self, fd: RawFd) {
+                let _ = self.1.read();
+                let _ = self.2.send(self.0.remove(fd).unwrap_err().kind());
+            }
+        }
+        let event_loop = Arc::new(EventLoop::start().unwrap());
+        let fd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let (tx, rx) = channel();
+        let handler = Arc::new(Mutex::new(SelfRemove(event_loop.clone(), fd.try_clone().unwrap(), tx)));
+        event_loop.add(fd.as_raw_fd(), handler).unwrap();
+        fd.write(1).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(io::ErrorKind::WouldBlock));
+        event_loop.remove(fd.as_raw_fd()).unwrap();
+    }
+}
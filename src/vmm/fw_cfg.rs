@@ -0,0 +1,352 @@
+//! QEMU firmware configuration interface at ports 0x510-0x51b, what OVMF asks
+//! for its platform data and ACPI tables.
+//!
+//! Items are blobs chosen with a 16 bit selector, read a byte at a time from
+//! the data port or copied to guest memory through the DMA interface. Named
+//! items (files) are listed in a directory item. The ACPI tables come as files
+//! along with the table-loader script telling the firmware how to place,
+//! link and checksum them.
+
+use std::{ collections::BTreeMap, sync::{ Arc, Mutex } };
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vm_memory::{ Bytes, GuestAddress, GuestMemoryError };
+
+use super::{
+    bus::BusDevice,
+    snapshot::{ self, Snapshot, StateBuf, StateReader },
+    virtio::GuestMem,
+    Vm,
+};
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+pub const FW_CFG_PORT: u16 = 0x510;
+/// Selector, data, a gap and the two halves of the DMA address
+const FW_CFG_PORTS: u64 = 12;
+const SELECTOR_OFFSET: u64 = 0;
+const DATA_OFFSET: u64 = 1;
+const DMA_HIGH_OFFSET: u64 = 4;
+const DMA_LOW_OFFSET: u64 = 8;
+/// Read back from the DMA address ports
+const DMA_SIGNATURE: &[u8; 8] = b"QEMU CFG";
+
+pub const FW_CFG_SIGNATURE: u16 = 0x00;
+pub const FW_CFG_ID: u16 = 0x01;
+pub const FW_CFG_RAM_SIZE: u16 = 0x03;
+pub const FW_CFG_NB_CPUS: u16 = 0x05;
+pub const FW_CFG_MAX_CPUS: u16 = 0x0f;
+const FW_CFG_FILE_DIR: u16 = 0x19;
+const FW_CFG_FILE_FIRST: u16 = 0x20;
+
+const ID_TRADITIONAL: u32 = 1 << 0;
+const ID_DMA: u32 = 1 << 1;
+
+const DMA_ERROR: u32 = 1 << 0;
+const DMA_READ: u32 = 1 << 1;
+const DMA_SKIP: u32 = 1 << 2;
+const DMA_SELECT: u32 = 1 << 3;
+const DMA_WRITE: u32 = 1 << 4;
+/// FWCfgDmaAccess, big endian control, length and address
+const DMA_ACCESS_SIZE: usize = 16;
+/// Reads past the end of an item are zeroed this much at a time, the guest picks the length
+const DMA_ZERO_CHUNK: usize = 4096;
+
+pub const FILE_NAME_SIZE: usize = 56;
+
+pub struct FwCfg {
+    items: BTreeMap<u16, Vec<u8>>,
+    /// Named items, in selector order
+    files: Vec<(String, u16)>,
+    selector: u16,
+    /// Read position in the selected item
+    offset: usize,
+    /// Upper half of the DMA access address, until the lower one comes
+    dma_high: u32,
+    mem: GuestMem,
+}
+
+impl std::fmt::Debug for FwCfg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FwCfg").field("files", &self.files).field("selector", &self.selector).finish()
+    }
+}
+
+impl FwCfg {
+    pub fn new(mem: GuestMem) -> Self {
+        let mut fw_cfg = FwCfg { items: BTreeMap::new(), files: vec![], selector: 0, offset: 0, dma_high: 0, mem };
+        fw_cfg.add_item(FW_CFG_SIGNATURE, b"QEMU".to_vec());
+        fw_cfg.add_item(FW_CFG_ID, (ID_TRADITIONAL | ID_DMA).to_le_bytes().to_vec());
+        fw_cfg.add_item(FW_CFG_FILE_DIR, 0u32.to_be_bytes().to_vec());
+        fw_cfg
+    }
+
+    pub fn add_item(&mut self, selector: u16, data: Vec<u8>) {
+        self.items.insert(selector, data);
+    }
+
+    pub fn add_file(&mut self, name: &str, data: Vec<u8>) {
+        assert!(name.len() < FILE_NAME_SIZE, "fw_cfg file name too long: {name}");
+        let selector = FW_CFG_FILE_FIRST + self.files.len() as u16;
+        debug!("fw_cfg file {name}: {} bytes", data.len());
+        self.items.insert(selector, data);
+        self.files.push((name.to_string(), selector));
+        // FWCfgFiles: count, then size, select, reserved and name of each
+        let mut dir = (self.files.len() as u32).to_be_bytes().to_vec();
+        for (name, selector) in &self.files {
+            dir.extend_from_slice(&(self.items[selector].len() as u32).to_be_bytes());
+            dir.extend_from_slice(&selector.to_be_bytes());
+            dir.extend_from_slice(&[0; 2]);
+            let mut padded = [0; FILE_NAME_SIZE];
+            padded[..name.len()].copy_from_slice(name.as_bytes());
+            dir.extend_from_slice(&padded);
+        }
+        self.items.insert(FW_CFG_FILE_DIR, dir);
+    }
+
+    fn select(&mut self, selector: u16) {
+        self.selector = selector;
+        self.offset = 0;
+    }
+
+    fn selected(&self) -> &[u8] {
+        self.items.get(&self.selector).map_or(&[], Vec::as_slice)
+    }
+
+    fn read_data(&mut self, data: &mut [u8]) {
+        for byte in data {
+            *byte = self.selected().get(self.offset).copied().unwrap_or(0);
+            self.offset += 1;
+        }
+    }
+
+    /// Run the FWCfgDmaAccess at `address` and write its status back
+    fn dma(&mut self, address: u64) {
+        let mut access = [0u8; DMA_ACCESS_SIZE];
+        if let Err(e) = self.mem.read_slice(&mut access, GuestAddress(address)) {
+            error!("fw_cfg DMA access at 0x{address:x}: {e}");
+            return;
+        }
+        let control = u32::from_be_bytes(access[0..4].try_into().unwrap());
+        let length = u32::from_be_bytes(access[4..8].try_into().unwrap()) as usize;
+        let target = u64::from_be_bytes(access[8..16].try_into().unwrap());
+        if control & DMA_SELECT != 0 {
+            self.select((control >> 16) as u16);
+        }
+        let status = if control & DMA_READ != 0 {
+            let start = self.offset.min(self.selected().len());
+            let end = (self.offset + length).min(self.selected().len());
+            self.offset += length;
+            match self.dma_read(start, end, target, length) {
+                Ok(()) => 0,
+                Err(e) => {
+                    error!("fw_cfg DMA read to 0x{target:x}: {e}");
+                    DMA_ERROR
+                }
+            }
+        } else if control & DMA_SKIP != 0 {
+            self.offset += length;
+            0
+        } else if control & DMA_WRITE != 0 {
+            warn!("fw_cfg DMA write to item 0x{:x} refused", self.selector);
+            DMA_ERROR
+        } else {
+            0
+        };
+        if let Err(e) = self.mem.write_slice(&status.to_be_bytes(), GuestAddress(address)) {
+            error!("fw_cfg DMA status at 0x{address:x}: {e}");
+        }
+    }
+
+    /// Copy `start..end` of the selected item to `target`, then zeros up to `length` bytes
+    fn dma_read(&self, start: usize, end: usize, target: u64, length: usize) -> std::result::Result<(), GuestMemoryError> {
+        self.mem.write_slice(&self.selected()[start..end], GuestAddress(target))?;
+        let zeros = [0u8; DMA_ZERO_CHUNK];
+        let mut done = end - start;
+        while done < length {
+            let chunk = (length - done).min(DMA_ZERO_CHUNK);
+            self.mem.write_slice(&zeros[..chunk], GuestAddress(target + done as u64))?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl BusDevice for FwCfg {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        match offset {
+            DATA_OFFSET => self.read_data(data),
+            DMA_HIGH_OFFSET.. => {
+                for (i, byte) in data.iter_mut().enumerate() {
+                    *byte = DMA_SIGNATURE.get((offset - DMA_HIGH_OFFSET) as usize + i).copied().unwrap_or(0);
+                }
+            }
+            _ => data.fill(0),
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        match (offset, data.len()) {
+            (SELECTOR_OFFSET, 2) => self.select(u16::from_le_bytes([data[0], data[1]])),
+            (DMA_HIGH_OFFSET, 4) => self.dma_high = u32::from_be_bytes(data.try_into().unwrap()),
+            (DMA_LOW_OFFSET, 4) => {
+                let address = (self.dma_high as u64) << 32 | u32::from_be_bytes(data.try_into().unwrap()) as u64;
+                self.dma_high = 0;
+                self.dma(address);
+            }
+            // Writing items through the data port is gone since QEMU 2.4
+            _ => debug!("fw_cfg write ignored at +{offset}: {data:x?}"),
+        }
+    }
+}
+
+impl Snapshot for FwCfg {
+    fn snapshot_id(&self) -> String {
+        "fw_cfg".to_string()
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        state.put_u16(self.selector);
+        state.put_u32(self.offset as u32);
+        state.put_u32(self.dma_high);
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.selector = state.get_u16()?;
+        self.offset = state.get_u32()? as usize;
+        self.dma_high = state.get_u32()?;
+        Ok(())
+    }
+}
+
+const LOADER_ALLOCATE: u32 = 1;
+const LOADER_ADD_POINTER: u32 = 2;
+const LOADER_ADD_CHECKSUM: u32 = 3;
+pub const LOADER_COMMAND_SIZE: usize = 128;
+
+/// Where the firmware allocates a table-loader file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocZone {
+    /// Anywhere below 4G
+    High = 1,
+    /// In the 0xE0000-0xFFFFF segment, where legacy OSes look for the RSDP
+    FSeg = 2,
+}
+
+/// Script of the "etc/table-loader" file
+#[derive(Debug, Default)]
+pub struct TableLoader {
+    commands: Vec<u8>,
+}
+
+fn file_name(name: &str) -> [u8; FILE_NAME_SIZE] {
+    let mut padded = [0; FILE_NAME_SIZE];
+    padded[..name.len()].copy_from_slice(name.as_bytes());
+    padded
+}
+
+impl TableLoader {
+    fn push(&mut self, command: u32, args: &[u8]) {
+        let start = self.commands.len();
+        self.commands.extend_from_slice(&command.to_le_bytes());
+        self.commands.extend_from_slice(args);
+        self.commands.resize(start + LOADER_COMMAND_SIZE, 0);
+    }
+
+    /// Load `file` in guest memory, aligned on `align`
+    pub fn allocate(&mut self, file: &str, align: u32, zone: AllocZone) {
+        let mut args = file_name(file).to_vec();
+        args.extend_from_slice(&align.to_le_bytes());
+        args.push(zone as u8);
+        self.push(LOADER_ALLOCATE, &args);
+    }
+
+    /// Add the address of `src_file` to the `size` bytes little endian value at `offset` in `dest_file`
+    pub fn add_pointer(&mut self, dest_file: &str, offset: u32, size: u8, src_file: &str) {
+        let mut args = file_name(dest_file).to_vec();
+        args.extend_from_slice(&file_name(src_file));
+        args.extend_from_slice(&offset.to_le_bytes());
+        args.push(size);
+        self.push(LOADER_ADD_POINTER, &args);
+    }
+
+    /// Recompute the byte at `offset` in `file` so that `length` bytes from `start` sum to 0
+    pub fn add_checksum(&mut self, file: &str, offset: u32, start: u32, length: u32) {
+        let mut args = file_name(file).to_vec();
+        args.extend_from_slice(&offset.to_le_bytes());
+        args.extend_from_slice(&start.to_le_bytes());
+        args.extend_from_slice(&length.to_le_bytes());
+        self.push(LOADER_ADD_CHECKSUM, &args);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.commands
+    }
+}
+
+impl Vm {
+    /// Plug the interface with the platform items, files come afterwards
+    pub(super) fn add_fw_cfg(&mut self) -> Result<()> {
+        let mut fw_cfg = FwCfg::new(self.ram.guest_mem_map.clone());
+        let cpus = self.vcpu_count() as u16;
+        fw_cfg.add_item(FW_CFG_RAM_SIZE, (self.ram.mem_size as u64).to_le_bytes().to_vec());
+        fw_cfg.add_item(FW_CFG_NB_CPUS, cpus.to_le_bytes().to_vec());
+        fw_cfg.add_item(FW_CFG_MAX_CPUS, cpus.to_le_bytes().to_vec());
+        let fw_cfg = Arc::new(Mutex::new(fw_cfg));
+        self.pio_bus.insert(FW_CFG_PORT as u64, FW_CFG_PORTS, fw_cfg.clone()).map_err(|e| {
+            error!("fw_cfg: {e}");
+            kvm_ioctls::Error::new(libc::EEXIST)
+        })?;
+        self.fw_cfg = Some(fw_cfg);
+        Ok(())
+    }
+
+    /// Hand a named blob to the firmware
+    pub fn add_fw_cfg_file(&mut self, name: &str, data: Vec<u8>) -> Result<()> {
+        let Some(fw_cfg) = self.fw_cfg.as_ref() else {
+            return Err(kvm_ioctls::Error::new(libc::ENODEV));
+        };
+        fw_cfg.lock().unwrap().add_file(name, data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACCESS: u64 = 0x1000;
+    const TARGET: u64 = 0x2000;
+
+    fn dma_read(fw_cfg: &mut FwCfg, selector: u16, length: u32) -> u32 {
+        let control = (selector as u32) << 16 | DMA_SELECT | DMA_READ;
+        let mut access = control.to_be_bytes().to_vec();
+        access.extend_from_slice(&length.to_be_bytes());
+        access.extend_from_slice(&TARGET.to_be_bytes());
+        fw_cfg.mem.write_slice(&access, GuestAddress(ACCESS)).unwrap();
+        fw_cfg.dma(ACCESS);
+        fw_cfg.mem.read_obj::<u32>(GuestAddress(ACCESS)).unwrap()
+    }
+
+    #[test]
+    fn dma_read_zeroes_past_the_item() {
+        let mem = GuestMem::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0xaa; 0x3000], GuestAddress(TARGET)).unwrap();
+        let mut fw_cfg = FwCfg::new(mem);
+        fw_cfg.add_item(FW_CFG_NB_CPUS, vec![1, 2, 3]);
+        assert_eq!(dma_read(&mut fw_cfg, FW_CFG_NB_CPUS, 0x2001), 0);
+        let mut data = vec![0u8; 0x2002];
+        fw_cfg.mem.read_slice(&mut data, GuestAddress(TARGET)).unwrap();
+        assert_eq!(data[..3], [1, 2, 3]);
+        assert!(data[3..0x2001].iter().all(|&b| b == 0));
+        assert_eq!(data[0x2001], 0xaa);
+    }
+
+    #[test]
+    fn dma_read_past_guest_memory() {
+        let mem = GuestMem::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut fw_cfg = FwCfg::new(mem);
+        assert_eq!(dma_read(&mut fw_cfg, FW_CFG_SIGNATURE, u32::MAX), DMA_ERROR.to_be());
+    }
+}
@@ -0,0 +1,403 @@
+//! HPET at 0xfed00000: a 100 MHz main counter and three 64 bit comparators.
+//!
+//! The main counter is not stored while it runs, it is worked out from the
+//! time the guest enabled it. Each comparator has a timerfd on the event loop,
+//! armed while its interrupt is enabled, and raises the IOAPIC pin (20 to 23)
+//! the guest routed it to. There is no legacy replacement route, IRQ 0 and 8
+//! stay with the PIT and the RTC. Level triggered comparators set their status
+//! bit until the guest clears it, the interrupt itself is sent as an edge.
+
+use std::{
+    os::fd::{ AsRawFd, RawFd },
+    sync::{ Arc, Mutex },
+    time::{ Duration, Instant },
+};
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vmm_sys_util::timerfd::TimerFd;
+
+use super::{
+    bus::BusDevice,
+    event_loop::EventHandler,
+    ioapic::IOAPIC_PINS,
+    irq::IrqFd,
+    snapshot::{ self, Snapshot, StateBuf, StateReader },
+    virtio::{ VIRTIO_MMIO_GSI_BASE, VIRTIO_MMIO_MAX_DEVICES },
+    Vm,
+};
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+pub const HPET_BASE: u64 = 0xfed0_0000;
+const HPET_SIZE: u64 = 0x400;
+const TIMERS: usize = 3;
+const NS_PER_TICK: u64 = 10;
+/// Main counter period in femtoseconds
+const PERIOD_FS: u64 = NS_PER_TICK * 1_000_000;
+/// IOAPIC pins the comparators can be routed to, the last four
+const FIRST_GSI: u32 = 20;
+const GSIS: u32 = 4;
+pub const HPET_GSIS: [u32; GSIS as usize] = [FIRST_GSI, FIRST_GSI + 1, FIRST_GSI + 2, FIRST_GSI + 3];
+const _: () = assert!(VIRTIO_MMIO_GSI_BASE + VIRTIO_MMIO_MAX_DEVICES as u32 <= FIRST_GSI);
+const _: () = assert!(FIRST_GSI + GSIS <= IOAPIC_PINS as u32);
+
+/// Low half of the capabilities register: revision 1, the number of the last
+/// comparator, 64 bit counter and Intel vendor ID. Also the ACPI event timer block ID.
+pub const HPET_BLOCK_ID: u32 = 0x8086_0000 | 1 << 13 | ((TIMERS as u32 - 1) << 8) | 1;
+
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_INT_STATUS: u64 = 0x020;
+const REG_COUNTER: u64 = 0x0f0;
+const REG_TIMER_FIRST: u64 = 0x100;
+const TIMER_STRIDE: u64 = 0x20;
+const TIMER_CONFIG: u64 = 0x00;
+const TIMER_COMPARATOR: u64 = 0x08;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+const TN_LEVEL: u64 = 1 << 1;
+const TN_ENABLE: u64 = 1 << 2;
+const TN_PERIODIC: u64 = 1 << 3;
+const TN_PERIODIC_CAP: u64 = 1 << 4;
+const TN_SIZE_CAP: u64 = 1 << 5;
+/// The next comparator write of a periodic timer sets the comparator, not only the period
+const TN_SETVAL: u64 = 1 << 6;
+const TN_32BIT: u64 = 1 << 8;
+const TN_ROUTE_SHIFT: u64 = 9;
+const TN_ROUTE: u64 = 0x1f << TN_ROUTE_SHIFT;
+const TN_WRITABLE: u64 = TN_LEVEL | TN_ENABLE | TN_PERIODIC | TN_SETVAL | TN_32BIT | TN_ROUTE;
+const TN_READ_ONLY: u64 = TN_PERIODIC_CAP | TN_SIZE_CAP | (((1 << GSIS) - 1) << FIRST_GSI) << 32;
+
+struct Comparator {
+    config: u64,
+    comparator: u64,
+    period: u64,
+    timer: TimerFd,
+}
+
+impl Comparator {
+    fn mask(&self) -> u64 {
+        if self.config & TN_32BIT != 0 { u32::MAX as u64 } else { u64::MAX }
+    }
+}
+
+pub struct Hpet {
+    config: u64,
+    int_status: u64,
+    /// Main counter value when the guest last stopped or set it
+    counter: u64,
+    /// When the guest last enabled the main counter, None while it is stopped
+    started: Option<Instant>,
+    comparators: Vec<Comparator>,
+    /// Pins FIRST_GSI and up
+    irqs: Vec<IrqFd>,
+}
+
+impl std::fmt::Debug for Hpet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hpet")
+            .field("config", &self.config)
+            .field("counter", &self.counter())
+            .field("int_status", &self.int_status)
+            .finish()
+    }
+}
+
+impl Hpet {
+    /// One timer per comparator, `irqs` raising pins FIRST_GSI and up
+    pub fn new(timers: Vec<TimerFd>, irqs: Vec<IrqFd>) -> Self {
+        let comparators = timers
+            .into_iter()
+            .map(|timer| Comparator { config: 0, comparator: u64::MAX, period: 0, timer })
+            .collect();
+        Hpet { config: 0, int_status: 0, counter: 0, started: None, comparators, irqs }
+    }
+
+    fn counter(&self) -> u64 {
+        match self.started {
+            Some(started) => self.counter.wrapping_add(started.elapsed().as_nanos() as u64 / NS_PER_TICK),
+            None => self.counter,
+        }
+    }
+
+    fn read_register(&self, reg: u64) -> u64 {
+        match reg {
+            REG_CAPABILITIES => PERIOD_FS << 32 | HPET_BLOCK_ID as u64,
+            REG_CONFIG => self.config,
+            REG_INT_STATUS => self.int_status,
+            REG_COUNTER => self.counter(),
+            REG_TIMER_FIRST.. => {
+                let (n, reg) = ((reg - REG_TIMER_FIRST) / TIMER_STRIDE, (reg - REG_TIMER_FIRST) % TIMER_STRIDE);
+                let Some(comparator) = self.comparators.get(n as usize) else {
+                    return 0;
+                };
+                match reg {
+                    TIMER_CONFIG => comparator.config | TN_READ_ONLY,
+                    TIMER_COMPARATOR => comparator.comparator,
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    /// Write the bytes of `value` set in `mask`
+    fn write_register(&mut self, reg: u64, value: u64, mask: u64) {
+        let merged = self.read_register(reg) & !mask | value & mask;
+        match reg {
+            REG_CONFIG => {
+                let enable = merged & CONFIG_ENABLE != 0;
+                match (self.started, enable) {
+                    (None, true) => self.started = Some(Instant::now()),
+                    (Some(_), false) => {
+                        self.counter = self.counter();
+                        self.started = None;
+                    }
+                    _ => {}
+                }
+                self.config = merged & CONFIG_ENABLE;
+                (0..self.comparators.len()).for_each(|n| self.rearm(n));
+            }
+            // Write 1 to clear
+            REG_INT_STATUS => self.int_status &= !(value & mask),
+            REG_COUNTER => {
+                if self.started.is_some() {
+                    warn!("HPET counter written while running, ignored");
+                    return;
+                }
+                self.counter = merged;
+            }
+            REG_TIMER_FIRST.. => {
+                let (n, reg) = (((reg - REG_TIMER_FIRST) / TIMER_STRIDE) as usize, (reg - REG_TIMER_FIRST) % TIMER_STRIDE);
+                let Some(comparator) = self.comparators.get_mut(n) else {
+                    return;
+                };
+                match reg {
+                    TIMER_CONFIG => {
+                        comparator.config = merged & TN_WRITABLE;
+                        comparator.comparator &= comparator.mask();
+                        comparator.period &= comparator.mask();
+                        if comparator.config & TN_LEVEL == 0 {
+                            self.int_status &= !(1 << n);
+                        }
+                    }
+                    TIMER_COMPARATOR => {
+                        let width = comparator.mask();
+                        if comparator.config & TN_PERIODIC == 0 || comparator.config & TN_SETVAL != 0 {
+                            comparator.comparator = merged & width;
+                        }
+                        comparator.period = (comparator.period & !mask | value & mask) & width;
+                        comparator.config &= !TN_SETVAL;
+                    }
+                    _ => return,
+                }
+                self.rearm(n);
+            }
+            _ => {}
+        }
+    }
+
+    /// Arm the timer of comparator `n` for its next match, if it can raise an interrupt
+    fn rearm(&mut self, n: usize) {
+        let counter = self.counter();
+        let running = self.started.is_some();
+        let comparator = &mut self.comparators[n];
+        let armed = if running && comparator.config & TN_ENABLE != 0 {
+            let ticks = comparator.comparator.wrapping_sub(counter) & comparator.mask();
+            // A zero duration would disarm it
+            comparator.timer.reset(Duration::from_nanos(ticks.max(1).saturating_mul(NS_PER_TICK)), None)
+        } else {
+            comparator.timer.clear()
+        };
+        if let Err(e) = armed {
+            error!("HPET timer {n}: {e}");
+        }
+    }
+
+    /// The timer of comparator `n` went off
+    fn tick(&mut self, n: usize) {
+        // Non blocking, the guest may have rearmed it since it woke us up
+        if !matches!(self.comparators[n].timer.wait(), Ok(expired) if expired > 0) {
+            return;
+        }
+        let counter = self.counter();
+        let comparator = &mut self.comparators[n];
+        if comparator.config & TN_PERIODIC != 0 && comparator.period != 0 {
+            // Past the counter, skipping the periods we were late for
+            let late = counter.wrapping_sub(comparator.comparator) & comparator.mask();
+            let periods = late / comparator.period + 1;
+            comparator.comparator =
+                comparator.comparator.wrapping_add(periods.wrapping_mul(comparator.period)) & comparator.mask();
+        }
+        let route = ((comparator.config & TN_ROUTE) >> TN_ROUTE_SHIFT) as u32;
+        if comparator.config & TN_LEVEL != 0 {
+            self.int_status |= 1 << n;
+        }
+        match route.checked_sub(FIRST_GSI).and_then(|pin| self.irqs.get(pin as usize)) {
+            Some(irq) => {
+                if let Err(e) = irq.trigger() {
+                    error!("HPET interrupt on GSI {route} failed: {e}");
+                }
+            }
+            None => warn!("HPET timer {n} routed to GSI {route}, not one it can use"),
+        }
+        self.rearm(n);
+    }
+}
+
+impl BusDevice for Hpet {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let shift = (offset & 7) as usize;
+        let value = self.read_register(offset & !7).to_le_bytes();
+        let len = data.len().min(8 - shift);
+        data[..len].copy_from_slice(&value[shift..shift + len]);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let shift = (offset & 7) as usize;
+        let len = data.len().min(8 - shift);
+        let mut value = [0u8; 8];
+        let mut mask = [0u8; 8];
+        value[shift..shift + len].copy_from_slice(&data[..len]);
+        mask[shift..shift + len].fill(0xff);
+        self.write_register(offset & !7, u64::from_le_bytes(value), u64::from_le_bytes(mask));
+    }
+}
+
+impl Snapshot for Hpet {
+    fn snapshot_id(&self) -> String {
+        "hpet".to_string()
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        state.put_u64(self.config);
+        state.put_u64(self.int_status);
+        state.put_u64(self.counter());
+        for comparator in &self.comparators {
+            state.put_u64(comparator.config);
+            state.put_u64(comparator.comparator);
+            state.put_u64(comparator.period);
+        }
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.config = state.get_u64()?;
+        self.int_status = state.get_u64()?;
+        self.counter = state.get_u64()?;
+        self.started = (self.config & CONFIG_ENABLE != 0).then(Instant::now);
+        for comparator in &mut self.comparators {
+            comparator.config = state.get_u64()?;
+            comparator.comparator = state.get_u64()?;
+            comparator.period = state.get_u64()?;
+        }
+        (0..self.comparators.len()).for_each(|n| self.rearm(n));
+        Ok(())
+    }
+}
+
+/// Runs the comparator timers on the event loop thread
+struct HpetTimers {
+    hpet: Arc<Mutex<Hpet>>,
+}
+
+impl EventHandler for HpetTimers {
+    fn handle_event(&mut self, fd: RawFd) {
+        let mut hpet = self.hpet.lock().unwrap();
+        if let Some(n) = hpet.comparators.iter().position(|comparator| comparator.timer.as_raw_fd() == fd) {
+            hpet.tick(n);
+        }
+    }
+}
+
+impl Vm {
+    pub(super) fn add_hpet(&mut self) -> Result<()> {
+        let io_error = |e: std::io::Error| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO));
+        let mut timers = vec![];
+        for _ in 0..TIMERS {
+            let timer = TimerFd::new()?;
+            let fd = timer.as_raw_fd();
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+                return Err(kvm_ioctls::Error::last());
+            }
+            timers.push(timer);
+        }
+        let fds: Vec<RawFd> = timers.iter().map(|timer| timer.as_raw_fd()).collect();
+        let irqs = (FIRST_GSI..FIRST_GSI + GSIS).map(|gsi| self.irqfd(gsi)).collect::<Result<Vec<_>>>()?;
+        let hpet = Arc::new(Mutex::new(Hpet::new(timers, irqs)));
+        self.mmio_bus.insert(HPET_BASE, HPET_SIZE, hpet.clone()).map_err(|e| {
+            error!("HPET: {e}");
+            kvm_ioctls::Error::new(libc::EEXIST)
+        })?;
+        let handler = Arc::new(Mutex::new(HpetTimers { hpet: hpet.clone() }));
+        for fd in fds {
+            self.event_loop.add(fd, handler.clone()).map_err(io_error)?;
+        }
+        info!("HPET at 0x{HPET_BASE:x}, {TIMERS} comparators on GSIs {FIRST_GSI}-{}", FIRST_GSI + GSIS - 1);
+        self.hpet = Some(hpet);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(hpet: &mut Hpet, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        hpet.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write(hpet: &mut Hpet, offset: u64, value: u32) {
+        hpet.write(offset, &value.to_le_bytes());
+    }
+
+    fn hpet() -> Hpet {
+        Hpet::new((0..TIMERS).map(|_| TimerFd::new().unwrap()).collect(), vec![])
+    }
+
+    #[test]
+    fn counter_runs_while_enabled() {
+        let mut hpet = hpet();
+        assert_eq!(read(&mut hpet, REG_CAPABILITIES), HPET_BLOCK_ID);
+        assert_eq!(read(&mut hpet, REG_CAPABILITIES + 4), PERIOD_FS as u32);
+        write(&mut hpet, REG_COUNTER + 4, 1);
+        assert_eq!(hpet.counter(), 1 << 32);
+        write(&mut hpet, REG_CONFIG, CONFIG_ENABLE as u32);
+        std::thread::sleep(Duration::from_millis(1));
+        write(&mut hpet, REG_CONFIG, 0);
+        let stopped = hpet.counter();
+        assert!(stopped >= (1 << 32) + 100_000);
+        // Ignored while running only
+        write(&mut hpet, REG_COUNTER, 5);
+        assert_eq!(hpet.counter(), stopped & !0xffff_ffff | 5);
+    }
+
+    #[test]
+    fn periodic_comparator_and_status() {
+        let mut hpet = hpet();
+        let timer1 = REG_TIMER_FIRST + TIMER_STRIDE;
+        write(&mut hpet, timer1 + TIMER_CONFIG, (TN_PERIODIC | TN_SETVAL | TN_LEVEL | TN_ENABLE) as u32);
+        hpet.write(timer1 + TIMER_COMPARATOR, &1000u64.to_le_bytes());
+        // Only the write right after SETVAL moves the comparator, the next ones set the period
+        write(&mut hpet, timer1 + TIMER_COMPARATOR, 300);
+        assert_eq!(hpet.comparators[1].comparator, 1000);
+        assert_eq!(hpet.comparators[1].period, 300);
+        assert_eq!(read(&mut hpet, timer1 + TIMER_CONFIG + 4), (TN_READ_ONLY >> 32) as u32);
+
+        write(&mut hpet, REG_CONFIG, CONFIG_ENABLE as u32);
+        std::thread::sleep(Duration::from_millis(1));
+        hpet.tick(1);
+        assert_eq!(read(&mut hpet, REG_INT_STATUS), 1 << 1);
+        write(&mut hpet, REG_CONFIG, 0);
+        // The next period past the counter when it went off
+        let comparator = hpet.comparators[1].comparator;
+        assert!(comparator > 1000 && comparator <= hpet.counter() + 300);
+        assert!((comparator - 1000).is_multiple_of(300));
+        write(&mut hpet, REG_INT_STATUS, 1 << 1);
+        assert_eq!(read(&mut hpet, REG_INT_STATUS), 0);
+    }
+}
@@ -0,0 +1,978 @@
+//! i8042 keyboard controller at ports 0x60/0x64, with a PS/2 keyboard and mouse.
+//!
+//! The keyboard speaks scan code set 2, the controller translates it to set 1
+//! while the command byte asks for it, as every PC firmware does. Host input
+//! comes from [`InputSource`]s polled by the event loop, so the output buffer
+//! interrupts (IRQ 1 and 12, edge triggered) go out through irqfds.
+
+use std::{
+    collections::VecDeque,
+    io,
+    os::fd::{ AsRawFd, RawFd },
+    sync::{ mpsc::{ channel, Receiver, Sender }, Arc, Mutex },
+};
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vmm_sys_util::eventfd::{ EventFd, EFD_NONBLOCK };
+
+use super::{
+    bus::BusDevice,
+    event_loop::EventHandler,
+    irq::IrqFd,
+    reset::{ ResetLine, ResetSource },
+    snapshot::{ self, Snapshot, StateBuf, StateReader },
+    Vm,
+};
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+pub const I8042_DATA_PORT: u16 = 0x60;
+pub const I8042_COMMAND_PORT: u16 = 0x64;
+pub const KEYBOARD_GSI: u32 = 1;
+pub const MOUSE_GSI: u32 = 12;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_SYSTEM: u8 = 1 << 2;
+/// Last write went to the command port
+const STATUS_COMMAND: u8 = 1 << 3;
+const STATUS_NOT_INHIBITED: u8 = 1 << 4;
+const STATUS_AUX_OUTPUT: u8 = 1 << 5;
+
+/// Command byte, internal RAM byte 0
+const CCB_KEYBOARD_INT: u8 = 1 << 0;
+const CCB_AUX_INT: u8 = 1 << 1;
+const CCB_SYSTEM: u8 = 1 << 2;
+const CCB_KEYBOARD_DISABLED: u8 = 1 << 4;
+const CCB_AUX_DISABLED: u8 = 1 << 5;
+const CCB_TRANSLATE: u8 = 1 << 6;
+const RAM_SIZE: usize = 32;
+
+/// Output port: reset line, active low, and A20 gate
+const OUTPUT_RESET: u8 = 1 << 0;
+const OUTPUT_A20: u8 = 1 << 1;
+
+const CMD_READ_RAM: u8 = 0x20;
+const CMD_WRITE_RAM: u8 = 0x60;
+const CMD_DISABLE_AUX: u8 = 0xa7;
+const CMD_ENABLE_AUX: u8 = 0xa8;
+const CMD_TEST_AUX: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_KEYBOARD: u8 = 0xab;
+const CMD_DISABLE_KEYBOARD: u8 = 0xad;
+const CMD_ENABLE_KEYBOARD: u8 = 0xae;
+const CMD_READ_INPUT_PORT: u8 = 0xc0;
+const CMD_READ_OUTPUT_PORT: u8 = 0xd0;
+const CMD_WRITE_OUTPUT_PORT: u8 = 0xd1;
+const CMD_WRITE_KEYBOARD_OUTPUT: u8 = 0xd2;
+const CMD_WRITE_AUX_OUTPUT: u8 = 0xd3;
+const CMD_WRITE_AUX: u8 = 0xd4;
+const CMD_DISABLE_A20: u8 = 0xdd;
+const CMD_ENABLE_A20: u8 = 0xdf;
+const CMD_READ_TEST_INPUTS: u8 = 0xe0;
+/// 0xf0-0xff pulse the output port bits that are clear in the low nibble
+const CMD_PULSE_OUTPUT: u8 = 0xf0;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+const DEV_ACK: u8 = 0xfa;
+const DEV_RESEND: u8 = 0xfe;
+const DEV_SELF_TEST_PASSED: u8 = 0xaa;
+const DEV_ECHO: u8 = 0xee;
+
+const KBD_SET_LEDS: u8 = 0xed;
+const KBD_ECHO: u8 = 0xee;
+const KBD_SCANCODE_SET: u8 = 0xf0;
+const KBD_IDENTIFY: u8 = 0xf2;
+const KBD_TYPEMATIC: u8 = 0xf3;
+const KBD_ENABLE: u8 = 0xf4;
+const KBD_DISABLE: u8 = 0xf5;
+const KBD_DEFAULTS: u8 = 0xf6;
+const KBD_RESEND: u8 = 0xfe;
+const KBD_RESET: u8 = 0xff;
+/// MF2 keyboard
+const KBD_ID: [u8; 2] = [0xab, 0x83];
+
+const MOUSE_SCALING_1_1: u8 = 0xe6;
+const MOUSE_SCALING_2_1: u8 = 0xe7;
+const MOUSE_RESOLUTION: u8 = 0xe8;
+const MOUSE_STATUS: u8 = 0xe9;
+const MOUSE_STREAM_MODE: u8 = 0xea;
+const MOUSE_READ_DATA: u8 = 0xeb;
+const MOUSE_RESET_WRAP: u8 = 0xec;
+const MOUSE_WRAP_MODE: u8 = 0xee;
+const MOUSE_REMOTE_MODE: u8 = 0xf0;
+const MOUSE_GET_ID: u8 = 0xf2;
+const MOUSE_SAMPLE_RATE: u8 = 0xf3;
+const MOUSE_ENABLE: u8 = 0xf4;
+const MOUSE_DISABLE: u8 = 0xf5;
+const MOUSE_DEFAULTS: u8 = 0xf6;
+const MOUSE_RESET: u8 = 0xff;
+/// Sample rates that switch a mouse to IntelliMouse, with a wheel
+const INTELLIMOUSE_KNOCK: [u8; 3] = [200, 100, 80];
+const MOUSE_ID_INTELLIMOUSE: u8 = 3;
+
+/// Bytes a device holds before it drops input
+const DEVICE_QUEUE_SIZE: usize = 256;
+
+/// Set 2 to set 1 translation of the codes below 0x80
+#[rustfmt::skip]
+const TRANSLATION: [u8; 0x80] = [
+    0xff, 0x43, 0x41, 0x3f, 0x3d, 0x3b, 0x3c, 0x58, 0x64, 0x44, 0x42, 0x40, 0x3e, 0x0f, 0x29, 0x59,
+    0x65, 0x38, 0x2a, 0x70, 0x1d, 0x10, 0x02, 0x5a, 0x66, 0x71, 0x2c, 0x1f, 0x1e, 0x11, 0x03, 0x5b,
+    0x67, 0x2e, 0x2d, 0x20, 0x12, 0x05, 0x04, 0x5c, 0x68, 0x39, 0x2f, 0x21, 0x14, 0x13, 0x06, 0x5d,
+    0x69, 0x31, 0x30, 0x23, 0x22, 0x15, 0x07, 0x5e, 0x6a, 0x72, 0x32, 0x24, 0x16, 0x08, 0x09, 0x5f,
+    0x6b, 0x33, 0x25, 0x17, 0x18, 0x0b, 0x0a, 0x60, 0x6c, 0x34, 0x35, 0x26, 0x27, 0x19, 0x0c, 0x61,
+    0x6d, 0x73, 0x28, 0x74, 0x1a, 0x0d, 0x62, 0x6e, 0x3a, 0x36, 0x1c, 0x1b, 0x75, 0x2b, 0x63, 0x76,
+    0x55, 0x56, 0x77, 0x78, 0x79, 0x7a, 0x0e, 0x7b, 0x7c, 0x4f, 0x7d, 0x4b, 0x47, 0x7e, 0x7f, 0x6f,
+    0x52, 0x53, 0x50, 0x4c, 0x4d, 0x48, 0x01, 0x45, 0x57, 0x4e, 0x51, 0x4a, 0x37, 0x49, 0x46, 0x54,
+];
+
+fn translate(code: u8) -> u8 {
+    match code {
+        0x00..0x80 => TRANSLATION[code as usize],
+        // F7 and SysRq, the only set 2 codes above 0x7f
+        0x83 => 0x41,
+        0x84 => 0x54,
+        _ => code,
+    }
+}
+
+/// Host input, keys in scan code set 2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    /// Make code, 0xe0 prefixed ones as 0xe0xx
+    Key { code: u16, pressed: bool },
+    /// Relative motion, y going up, z the wheel going down
+    MouseMove { dx: i32, dy: i32, dz: i32 },
+    /// Left, right and middle in bits 0, 1 and 2
+    MouseButtons(u8),
+}
+
+/// Where host input comes from: a window, a socket, a replay file...
+pub trait InputSource: Send {
+    /// Readable while events are pending, watched by the event loop
+    fn fd(&self) -> RawFd;
+    /// Drain the pending events
+    fn events(&mut self) -> Vec<InputEvent>;
+}
+
+/// Input source fed from any thread through [`InputSender`]s
+#[derive(Debug)]
+pub struct InputChannel {
+    events: Receiver<InputEvent>,
+    ready: Arc<EventFd>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InputSender {
+    events: Sender<InputEvent>,
+    ready: Arc<EventFd>,
+}
+
+pub fn input_channel() -> io::Result<(InputSender, InputChannel)> {
+    let ready = Arc::new(EventFd::new(EFD_NONBLOCK)?);
+    let (tx, events) = channel();
+    Ok((InputSender { events: tx, ready: ready.clone() }, InputChannel { events, ready }))
+}
+
+impl InputSender {
+    pub fn send(&self, event: InputEvent) -> io::Result<()> {
+        self.events.send(event).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.ready.write(1)
+    }
+}
+
+impl InputSource for InputChannel {
+    fn fd(&self) -> RawFd {
+        self.ready.as_raw_fd()
+    }
+
+    fn events(&mut self) -> Vec<InputEvent> {
+        let _ = self.ready.read();
+        self.events.try_iter().collect()
+    }
+}
+
+#[derive(Debug)]
+struct Keyboard {
+    scanning: bool,
+    scancode_set: u8,
+    leds: u8,
+    typematic: u8,
+    /// Command waiting for its parameter byte
+    command: Option<u8>,
+    last: u8,
+    /// Set 2 bytes, the controller translates them on their way out
+    output: VecDeque<u8>,
+}
+
+impl Keyboard {
+    fn new() -> Self {
+        Self {
+            scanning: true,
+            scancode_set: 2,
+            leds: 0,
+            typematic: 0x2b,
+            command: None,
+            last: 0,
+            output: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        if self.output.len() + bytes.len() > DEVICE_QUEUE_SIZE {
+            debug!("PS/2 keyboard queue full, input dropped");
+            return;
+        }
+        self.output.extend(bytes);
+        self.last = bytes[bytes.len() - 1];
+    }
+
+    fn defaults(&mut self) {
+        self.scancode_set = 2;
+        self.typematic = 0x2b;
+    }
+
+    fn key(&mut self, code: u16, pressed: bool) {
+        if !self.scanning {
+            return;
+        }
+        let mut bytes = vec![];
+        if code >> 8 == 0xe0 {
+            bytes.push(0xe0);
+        }
+        if !pressed {
+            bytes.push(0xf0);
+        }
+        bytes.push(code as u8);
+        self.push(&bytes);
+    }
+
+    fn write(&mut self, byte: u8) {
+        if let Some(command) = self.command.take() {
+            match command {
+                KBD_SET_LEDS => self.leds = byte & 0x7,
+                KBD_SCANCODE_SET if byte == 0 => {
+                    self.push(&[DEV_ACK, self.scancode_set]);
+                    return;
+                }
+                KBD_SCANCODE_SET if byte <= 3 => {
+                    if byte != 2 {
+                        warn!("PS/2 keyboard: scan code set {byte} asked, only set 2 is sent");
+                    }
+                    self.scancode_set = byte;
+                }
+                KBD_TYPEMATIC => self.typematic = byte,
+                _ => {
+                    self.push(&[DEV_RESEND]);
+                    return;
+                }
+            }
+            self.push(&[DEV_ACK]);
+            return;
+        }
+        match byte {
+            KBD_SET_LEDS | KBD_SCANCODE_SET | KBD_TYPEMATIC => {
+                self.command = Some(byte);
+                self.push(&[DEV_ACK]);
+            }
+            KBD_ECHO => self.push(&[DEV_ECHO]),
+            KBD_IDENTIFY => self.push(&[DEV_ACK, KBD_ID[0], KBD_ID[1]]),
+            KBD_ENABLE => {
+                self.output.clear();
+                self.scanning = true;
+                self.push(&[DEV_ACK]);
+            }
+            KBD_DISABLE | KBD_DEFAULTS => {
+                self.output.clear();
+                self.defaults();
+                self.scanning = byte == KBD_DEFAULTS;
+                self.push(&[DEV_ACK]);
+            }
+            // Set 3 key type commands, acknowledged and ignored
+            0xf7..=0xfd => self.push(&[DEV_ACK]),
+            KBD_RESEND => {
+                let last = self.last;
+                self.push(&[last]);
+            }
+            KBD_RESET => {
+                self.output.clear();
+                self.defaults();
+                self.scanning = true;
+                self.leds = 0;
+                self.push(&[DEV_ACK, DEV_SELF_TEST_PASSED]);
+            }
+            _ => {
+                debug!("PS/2 keyboard: unknown command 0x{byte:02x}");
+                self.push(&[DEV_RESEND]);
+            }
+        }
+    }
+
+    fn save(&self, state: &mut StateBuf) {
+        for value in [self.scanning as u8, self.scancode_set, self.leds, self.typematic, self.last] {
+            state.put_u8(value);
+        }
+        state.put_u16(self.command.map_or(0, |command| 0x100 | command as u16));
+        state.put_bytes(&self.output.iter().copied().collect::<Vec<u8>>());
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.scanning = state.get_u8()? != 0;
+        self.scancode_set = state.get_u8()?;
+        self.leds = state.get_u8()?;
+        self.typematic = state.get_u8()?;
+        self.last = state.get_u8()?;
+        let command = state.get_u16()?;
+        self.command = (command & 0x100 != 0).then_some(command as u8);
+        self.output = state.get_bytes()?.iter().copied().collect();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Mouse {
+    reporting: bool,
+    remote: bool,
+    wrap: bool,
+    scaling_2_1: bool,
+    resolution: u8,
+    sample_rate: u8,
+    id: u8,
+    /// Last sample rates set, for the IntelliMouse knock
+    rates: [u8; 3],
+    buttons: u8,
+    /// Motion not reported yet, in remote mode
+    dx: i32,
+    dy: i32,
+    dz: i32,
+    command: Option<u8>,
+    output: VecDeque<u8>,
+}
+
+impl Mouse {
+    fn new() -> Self {
+        Self {
+            reporting: false,
+            remote: false,
+            wrap: false,
+            scaling_2_1: false,
+            resolution: 2,
+            sample_rate: 100,
+            id: 0,
+            rates: [0; 3],
+            buttons: 0,
+            dx: 0,
+            dy: 0,
+            dz: 0,
+            command: None,
+            output: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        if self.output.len() + bytes.len() > DEVICE_QUEUE_SIZE {
+            debug!("PS/2 mouse queue full, input dropped");
+            return;
+        }
+        self.output.extend(bytes);
+    }
+
+    fn defaults(&mut self) {
+        self.reporting = false;
+        self.remote = false;
+        self.scaling_2_1 = false;
+        self.resolution = 2;
+        self.sample_rate = 100;
+        self.dx = 0;
+        self.dy = 0;
+        self.dz = 0;
+    }
+
+    /// Movement packet with what was accumulated
+    fn packet(&mut self) {
+        let dx = self.dx.clamp(-256, 255);
+        let dy = self.dy.clamp(-256, 255);
+        let dz = self.dz.clamp(-8, 7);
+        let mut flags = 0x08 | self.buttons & 0x7;
+        if dx < 0 {
+            flags |= 1 << 4;
+        }
+        if dy < 0 {
+            flags |= 1 << 5;
+        }
+        if self.dx != dx {
+            flags |= 1 << 6;
+        }
+        if self.dy != dy {
+            flags |= 1 << 7;
+        }
+        let mut packet = vec![flags, dx as u8, dy as u8];
+        if self.id == MOUSE_ID_INTELLIMOUSE {
+            packet.push(dz as u8 & 0x0f);
+        }
+        self.push(&packet);
+        self.dx = 0;
+        self.dy = 0;
+        self.dz = 0;
+    }
+
+    fn input(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::MouseMove { dx, dy, dz } => {
+                self.dx = self.dx.saturating_add(dx);
+                self.dy = self.dy.saturating_add(dy);
+                self.dz = self.dz.saturating_add(dz);
+            }
+            InputEvent::MouseButtons(buttons) => self.buttons = buttons,
+            InputEvent::Key { .. } => return,
+        }
+        if self.reporting && !self.remote {
+            self.packet();
+        }
+    }
+
+    fn write(&mut self, byte: u8) {
+        if self.wrap && byte != MOUSE_RESET_WRAP && byte != MOUSE_RESET {
+            self.push(&[byte]);
+            return;
+        }
+        if let Some(command) = self.command.take() {
+            match command {
+                MOUSE_RESOLUTION => self.resolution = byte & 0x3,
+                MOUSE_SAMPLE_RATE => {
+                    self.sample_rate = byte;
+                    self.rates = [self.rates[1], self.rates[2], byte];
+                    if self.rates == INTELLIMOUSE_KNOCK {
+                        self.id = MOUSE_ID_INTELLIMOUSE;
+                    }
+                }
+                _ => {}
+            }
+            self.push(&[DEV_ACK]);
+            return;
+        }
+        match byte {
+            MOUSE_SCALING_1_1 | MOUSE_SCALING_2_1 => {
+                self.scaling_2_1 = byte == MOUSE_SCALING_2_1;
+                self.push(&[DEV_ACK]);
+            }
+            MOUSE_RESOLUTION | MOUSE_SAMPLE_RATE => {
+                self.command = Some(byte);
+                self.push(&[DEV_ACK]);
+            }
+            MOUSE_STATUS => {
+                let status = (self.remote as u8) << 6
+                    | (self.reporting as u8) << 5
+                    | (self.scaling_2_1 as u8) << 4
+                    | (self.buttons & 1) << 2
+                    | (self.buttons >> 2 & 1) << 1
+                    | (self.buttons >> 1 & 1);
+                self.push(&[DEV_ACK, status, self.resolution, self.sample_rate]);
+            }
+            MOUSE_STREAM_MODE | MOUSE_REMOTE_MODE => {
+                self.remote = byte == MOUSE_REMOTE_MODE;
+                self.push(&[DEV_ACK]);
+            }
+            MOUSE_READ_DATA => {
+                self.push(&[DEV_ACK]);
+                self.packet();
+            }
+            MOUSE_RESET_WRAP | MOUSE_WRAP_MODE => {
+                self.wrap = byte == MOUSE_WRAP_MODE;
+                self.push(&[DEV_ACK]);
+            }
+            MOUSE_GET_ID => {
+                let id = self.id;
+                self.push(&[DEV_ACK, id]);
+            }
+            MOUSE_ENABLE => {
+                self.reporting = true;
+                self.push(&[DEV_ACK]);
+            }
+            MOUSE_DISABLE | MOUSE_DEFAULTS => {
+                self.defaults();
+                self.push(&[DEV_ACK]);
+            }
+            MOUSE_RESET => {
+                self.defaults();
+                self.wrap = false;
+                self.id = 0;
+                self.output.clear();
+                self.push(&[DEV_ACK, DEV_SELF_TEST_PASSED, 0x00]);
+            }
+            _ => {
+                debug!("PS/2 mouse: unknown command 0x{byte:02x}");
+                self.push(&[DEV_RESEND]);
+            }
+        }
+    }
+
+    fn save(&self, state: &mut StateBuf) {
+        let flags = self.reporting as u8 | (self.remote as u8) << 1 | (self.wrap as u8) << 2 | (self.scaling_2_1 as u8) << 3;
+        for value in [flags, self.resolution, self.sample_rate, self.id, self.buttons] {
+            state.put_u8(value);
+        }
+        state.put_bytes(&self.rates);
+        for delta in [self.dx, self.dy, self.dz] {
+            state.put_u32(delta as u32);
+        }
+        state.put_u16(self.command.map_or(0, |command| 0x100 | command as u16));
+        state.put_bytes(&self.output.iter().copied().collect::<Vec<u8>>());
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        let flags = state.get_u8()?;
+        self.reporting = flags & 1 != 0;
+        self.remote = flags & 2 != 0;
+        self.wrap = flags & 4 != 0;
+        self.scaling_2_1 = flags & 8 != 0;
+        self.resolution = state.get_u8()?;
+        self.sample_rate = state.get_u8()?;
+        self.id = state.get_u8()?;
+        self.buttons = state.get_u8()?;
+        self.rates = state.get_bytes()?.try_into().map_err(|_| snapshot::SnapshotError::Malformed)?;
+        self.dx = state.get_u32()? as i32;
+        self.dy = state.get_u32()? as i32;
+        self.dz = state.get_u32()? as i32;
+        let command = state.get_u16()?;
+        self.command = (command & 0x100 != 0).then_some(command as u8);
+        self.output = state.get_bytes()?.iter().copied().collect();
+        Ok(())
+    }
+}
+
+pub struct I8042 {
+    /// Byte 0 is the command byte
+    ram: [u8; RAM_SIZE],
+    output_port: u8,
+    /// Byte the guest reads next from port 0x60, and whether it comes from the mouse
+    output: Option<(u8, bool)>,
+    /// Controller command waiting for its data byte
+    command: Option<u8>,
+    /// Replies to controller commands, ahead of the devices
+    replies: VecDeque<(u8, bool)>,
+    last_write_command: bool,
+    keyboard: Keyboard,
+    mouse: Mouse,
+    keyboard_irq: IrqFd,
+    mouse_irq: IrqFd,
+    /// CPU reset line, the vCPU loop acts on it
+    reset: ResetLine,
+}
+
+impl std::fmt::Debug for I8042 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("I8042")
+            .field("command_byte", &self.ram[0])
+            .field("output_port", &self.output_port)
+            .field("output", &self.output)
+            .field("keyboard", &self.keyboard)
+            .field("mouse", &self.mouse)
+            .finish()
+    }
+}
+
+impl I8042 {
+    pub fn new(keyboard_irq: IrqFd, mouse_irq: IrqFd, reset: ResetLine) -> Self {
+        let mut ram = [0u8; RAM_SIZE];
+        ram[0] = CCB_KEYBOARD_INT | CCB_AUX_INT | CCB_SYSTEM | CCB_TRANSLATE;
+        Self {
+            ram,
+            output_port: OUTPUT_RESET | OUTPUT_A20,
+            output: None,
+            command: None,
+            replies: VecDeque::new(),
+            last_write_command: false,
+            keyboard: Keyboard::new(),
+            mouse: Mouse::new(),
+            keyboard_irq,
+            mouse_irq,
+            reset,
+        }
+    }
+
+    fn ccb(&self) -> u8 {
+        self.ram[0]
+    }
+
+    /// Next keyboard byte as the guest sees it, translated to set 1 if asked
+    fn next_keyboard_byte(&mut self) -> Option<u8> {
+        let byte = self.keyboard.output.pop_front()?;
+        if self.ccb() & CCB_TRANSLATE == 0 {
+            return Some(byte);
+        }
+        // Set 2 break codes are 0xf0 prefixed, set 1 ones have bit 7 set
+        if byte == 0xf0 {
+            return self.keyboard.output.pop_front().map(|code| translate(code) | 0x80);
+        }
+        Some(translate(byte))
+    }
+
+    /// Load the output buffer once the guest emptied it, and interrupt
+    fn refill(&mut self) {
+        if self.output.is_some() {
+            return;
+        }
+        let next = if let Some(reply) = self.replies.pop_front() {
+            Some(reply)
+        } else if self.ccb() & CCB_KEYBOARD_DISABLED == 0 && !self.keyboard.output.is_empty() {
+            self.next_keyboard_byte().map(|byte| (byte, false))
+        } else if self.ccb() & CCB_AUX_DISABLED == 0 {
+            self.mouse.output.pop_front().map(|byte| (byte, true))
+        } else {
+            None
+        };
+        let Some((byte, aux)) = next else {
+            return;
+        };
+        self.output = Some((byte, aux));
+        let (enabled, irq) = match aux {
+            false => (self.ccb() & CCB_KEYBOARD_INT != 0, &self.keyboard_irq),
+            true => (self.ccb() & CCB_AUX_INT != 0, &self.mouse_irq),
+        };
+        if enabled {
+            if let Err(e) = irq.trigger() {
+                error!("i8042 IRQ {} failed: {e}", irq.gsi());
+            }
+        }
+    }
+
+    pub fn input(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Key { code, pressed } => self.keyboard.key(code, pressed),
+            event => self.mouse.input(event),
+        }
+        self.refill();
+    }
+
+    fn reply(&mut self, byte: u8) {
+        self.replies.push_back((byte, false));
+    }
+
+    fn write_output_port(&mut self, value: u8) {
+        self.output_port = value;
+        if value & OUTPUT_RESET == 0 {
+            self.request_reset();
+        }
+    }
+
+    fn request_reset(&mut self) {
+        self.output_port |= OUTPUT_RESET;
+        self.reset.pull(ResetSource::I8042);
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = STATUS_NOT_INHIBITED;
+        if self.ccb() & CCB_SYSTEM != 0 {
+            status |= STATUS_SYSTEM;
+        }
+        if let Some((_, aux)) = self.output {
+            status |= STATUS_OUTPUT_FULL | if aux { STATUS_AUX_OUTPUT } else { 0 };
+        }
+        if self.last_write_command {
+            status |= STATUS_COMMAND;
+        }
+        status
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let byte = self.output.take().map_or(0, |(byte, _)| byte);
+        self.refill();
+        byte
+    }
+
+    fn write_command(&mut self, command: u8) {
+        self.last_write_command = true;
+        self.command = None;
+        match command {
+            CMD_READ_RAM..CMD_WRITE_RAM => {
+                let byte = self.ram[(command - CMD_READ_RAM) as usize];
+                self.reply(byte);
+            }
+            CMD_WRITE_RAM..0x80 | CMD_WRITE_OUTPUT_PORT | CMD_WRITE_KEYBOARD_OUTPUT | CMD_WRITE_AUX_OUTPUT | CMD_WRITE_AUX => {
+                self.command = Some(command);
+            }
+            CMD_DISABLE_AUX => self.ram[0] |= CCB_AUX_DISABLED,
+            CMD_ENABLE_AUX => self.ram[0] &= !CCB_AUX_DISABLED,
+            CMD_TEST_AUX | CMD_TEST_KEYBOARD => self.reply(PORT_TEST_PASSED),
+            CMD_SELF_TEST => {
+                self.ram[0] |= CCB_SYSTEM;
+                self.reply(SELF_TEST_PASSED);
+            }
+            CMD_DISABLE_KEYBOARD => self.ram[0] |= CCB_KEYBOARD_DISABLED,
+            CMD_ENABLE_KEYBOARD => self.ram[0] &= !CCB_KEYBOARD_DISABLED,
+            // Keyboard not inhibited
+            CMD_READ_INPUT_PORT => self.reply(0x80),
+            CMD_READ_OUTPUT_PORT => {
+                let port = self.output_port;
+                self.reply(port);
+            }
+            CMD_DISABLE_A20 => self.output_port &= !OUTPUT_A20,
+            CMD_ENABLE_A20 => self.output_port |= OUTPUT_A20,
+            CMD_READ_TEST_INPUTS => self.reply(0),
+            CMD_PULSE_OUTPUT..=0xff => {
+                if command & 1 == 0 {
+                    self.request_reset();
+                }
+            }
+            _ => debug!("i8042: unknown command 0x{command:02x}"),
+        }
+        self.refill();
+    }
+
+    fn write_data(&mut self, byte: u8) {
+        self.last_write_command = false;
+        match self.command.take() {
+            Some(command @ CMD_WRITE_RAM..0x80) => self.ram[(command - CMD_WRITE_RAM) as usize] = byte,
+            Some(CMD_WRITE_OUTPUT_PORT) => self.write_output_port(byte),
+            Some(CMD_WRITE_KEYBOARD_OUTPUT) => self.replies.push_back((byte, false)),
+            Some(CMD_WRITE_AUX_OUTPUT) => self.replies.push_back((byte, true)),
+            Some(CMD_WRITE_AUX) => {
+                self.ram[0] &= !CCB_AUX_DISABLED;
+                self.mouse.write(byte);
+            }
+            _ => {
+                // Talking to the keyboard enables its interface
+                self.ram[0] &= !CCB_KEYBOARD_DISABLED;
+                self.keyboard.write(byte);
+            }
+        }
+        self.refill();
+    }
+}
+
+impl Snapshot for I8042 {
+    fn snapshot_id(&self) -> String {
+        "i8042".to_string()
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        state.put_bytes(&self.ram);
+        state.put_u8(self.output_port);
+        state.put_u16(self.output.map_or(0, |(byte, aux)| 0x100 | (aux as u16) << 9 | byte as u16));
+        state.put_u16(self.command.map_or(0, |command| 0x100 | command as u16));
+        state.put_u8(self.last_write_command as u8);
+        state.put_u32(self.replies.len() as u32);
+        for &(byte, aux) in &self.replies {
+            state.put_u16((aux as u16) << 9 | byte as u16);
+        }
+        self.keyboard.save(state);
+        self.mouse.save(state);
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.ram = state.get_bytes()?.try_into().map_err(|_| snapshot::SnapshotError::Malformed)?;
+        self.output_port = state.get_u8()?;
+        let output = state.get_u16()?;
+        self.output = (output & 0x100 != 0).then_some((output as u8, output & 0x200 != 0));
+        let command = state.get_u16()?;
+        self.command = (command & 0x100 != 0).then_some(command as u8);
+        self.last_write_command = state.get_u8()? != 0;
+        self.replies.clear();
+        for _ in 0..state.get_u32()? {
+            let reply = state.get_u16()?;
+            self.replies.push_back((reply as u8, reply & 0x200 != 0));
+        }
+        self.keyboard.restore(state)?;
+        self.mouse.restore(state)
+    }
+}
+
+/// One of the two ports on the PIO bus, both lead to the controller
+struct I8042Port {
+    i8042: Arc<Mutex<I8042>>,
+    port: u16,
+}
+
+impl BusDevice for I8042Port {
+    fn read(&mut self, _offset: u64, data: &mut [u8]) {
+        let mut i8042 = self.i8042.lock().unwrap();
+        let value = match self.port {
+            I8042_DATA_PORT => i8042.read_data(),
+            _ => i8042.status(),
+        };
+        data.fill(value);
+    }
+
+    fn write(&mut self, _offset: u64, data: &[u8]) {
+        let mut i8042 = self.i8042.lock().unwrap();
+        match self.port {
+            I8042_DATA_PORT => i8042.write_data(data[0]),
+            _ => i8042.write_command(data[0]),
+        }
+    }
+}
+
+/// Feeds an input source to the controller on the event loop thread
+struct InputHandler {
+    source: Box<dyn InputSource>,
+    i8042: Arc<Mutex<I8042>>,
+}
+
+impl EventHandler for InputHandler {
+    fn handle_event(&mut self, _fd: RawFd) {
+        let events = self.source.events();
+        let mut i8042 = self.i8042.lock().unwrap();
+        for event in events {
+            i8042.input(event);
+        }
+    }
+}
+
+impl Vm {
+    /// Plug the controller on its ports, with a channel source for host input
+    pub(super) fn add_i8042(&mut self) -> Result<()> {
+        let i8042 = Arc::new(Mutex::new(I8042::new(self.irqfd(KEYBOARD_GSI)?, self.irqfd(MOUSE_GSI)?, self.reset_line.clone())));
+        for port in [I8042_DATA_PORT, I8042_COMMAND_PORT] {
+            let device = Arc::new(Mutex::new(I8042Port { i8042: i8042.clone(), port }));
+            self.pio_bus.insert(port as u64, 1, device).map_err(|e| {
+                error!("i8042: {e}");
+                kvm_ioctls::Error::new(libc::EEXIST)
+            })?;
+        }
+        self.i8042 = Some(i8042);
+        let (sender, channel) = input_channel().map_err(|e| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO)))?;
+        self.add_input_source(Box::new(channel))?;
+        self.input = Some(sender);
+        Ok(())
+    }
+
+    /// Send host input to the PS/2 keyboard and mouse from `source`
+    pub fn add_input_source(&self, source: Box<dyn InputSource>) -> Result<()> {
+        let Some(i8042) = self.i8042.clone() else {
+            return Err(kvm_ioctls::Error::new(libc::ENODEV));
+        };
+        let fd = source.fd();
+        self.event_loop
+            .add(fd, Arc::new(Mutex::new(InputHandler { source, i8042 })))
+            .map_err(|e| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO)))
+    }
+
+    /// Input sink of the built-in source, usable from any thread
+    pub fn input(&self) -> Option<InputSender> {
+        self.input.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller() -> I8042 {
+        I8042::new(IrqFd::detached(KEYBOARD_GSI), IrqFd::detached(MOUSE_GSI), ResetLine::default())
+    }
+
+    fn read_all(i8042: &mut I8042) -> Vec<u8> {
+        let mut bytes = vec![];
+        while i8042.status() & STATUS_OUTPUT_FULL != 0 {
+            bytes.push(i8042.read_data());
+        }
+        bytes
+    }
+
+    #[test]
+    fn controller_commands() {
+        let mut i8042 = controller();
+        i8042.write_command(CMD_SELF_TEST);
+        assert_eq!(i8042.status() & (STATUS_SYSTEM | STATUS_COMMAND), STATUS_SYSTEM | STATUS_COMMAND);
+        assert_eq!(read_all(&mut i8042), [SELF_TEST_PASSED]);
+
+        i8042.write_command(CMD_WRITE_RAM);
+        i8042.write_data(CCB_SYSTEM | CCB_KEYBOARD_INT);
+        i8042.write_command(CMD_READ_RAM);
+        assert_eq!(read_all(&mut i8042), [CCB_SYSTEM | CCB_KEYBOARD_INT]);
+
+        i8042.write_command(CMD_DISABLE_A20);
+        i8042.write_command(CMD_READ_OUTPUT_PORT);
+        assert_eq!(read_all(&mut i8042), [OUTPUT_RESET]);
+        assert_eq!(i8042.reset.take(), None);
+        // Pulsing bit 0 of the output port resets the CPU
+        i8042.write_command(0xfe);
+        assert_eq!(i8042.reset.take(), Some(ResetSource::I8042));
+        i8042.write_command(CMD_WRITE_OUTPUT_PORT);
+        i8042.write_data(OUTPUT_A20);
+        assert_eq!(i8042.reset.take(), Some(ResetSource::I8042));
+        assert_eq!(i8042.output_port, OUTPUT_RESET | OUTPUT_A20);
+    }
+
+    #[test]
+    fn keys_translated_to_set_1() {
+        let mut i8042 = controller();
+        // A, then cursor up
+        for (code, pressed) in [(0x1c, true), (0x1c, false), (0xe075, true), (0xe075, false)] {
+            i8042.input(InputEvent::Key { code, pressed });
+        }
+        assert!(i8042.keyboard_irq.take_pending());
+        assert_eq!(read_all(&mut i8042), [0x1e, 0x9e, 0xe0, 0x48, 0xe0, 0xc8]);
+
+        i8042.write_command(CMD_WRITE_RAM);
+        i8042.write_data(CCB_SYSTEM);
+        i8042.keyboard_irq.take_pending();
+        i8042.input(InputEvent::Key { code: 0x1c, pressed: false });
+        assert!(!i8042.keyboard_irq.take_pending());
+        assert_eq!(read_all(&mut i8042), [0xf0, 0x1c]);
+
+        // Nothing comes out of a disabled keyboard
+        i8042.write_data(KBD_DISABLE);
+        i8042.input(InputEvent::Key { code: 0x1c, pressed: true });
+        assert_eq!(read_all(&mut i8042), [DEV_ACK]);
+        i8042.write_data(KBD_IDENTIFY);
+        assert_eq!(read_all(&mut i8042), [DEV_ACK, KBD_ID[0], KBD_ID[1]]);
+    }
+
+    #[test]
+    fn mouse_packets() {
+        let mut i8042 = controller();
+        let mouse = |i8042: &mut I8042, byte: u8| {
+            i8042.write_command(CMD_WRITE_AUX);
+            i8042.write_data(byte);
+            let status = i8042.status();
+            let bytes = read_all(i8042);
+            assert!(bytes.is_empty() || status & STATUS_AUX_OUTPUT != 0);
+            bytes
+        };
+        assert_eq!(mouse(&mut i8042, MOUSE_ENABLE), [DEV_ACK]);
+        i8042.input(InputEvent::MouseMove { dx: 10, dy: -300, dz: 0 });
+        i8042.input(InputEvent::MouseButtons(0b101));
+        assert!(i8042.mouse_irq.take_pending());
+        // Y sign and overflow, then the button bits with nothing moved
+        assert_eq!(read_all(&mut i8042), [0x08 | 1 << 5 | 1 << 7, 10, 0x00, 0x08 | 0b101, 0, 0]);
+
+        // The IntelliMouse knock turns the wheel on
+        for rate in INTELLIMOUSE_KNOCK {
+            assert_eq!(mouse(&mut i8042, MOUSE_SAMPLE_RATE), [DEV_ACK]);
+            assert_eq!(mouse(&mut i8042, rate), [DEV_ACK]);
+        }
+        assert_eq!(mouse(&mut i8042, MOUSE_GET_ID), [DEV_ACK, MOUSE_ID_INTELLIMOUSE]);
+        i8042.input(InputEvent::MouseMove { dx: -1, dy: 1, dz: 20 });
+        assert_eq!(read_all(&mut i8042), [0x08 | 0b101 | 1 << 4, 0xff, 1, 0x07]);
+
+        assert_eq!(mouse(&mut i8042, MOUSE_STATUS), [DEV_ACK, 0x20 | 0b110, 2, 80]);
+        assert_eq!(mouse(&mut i8042, MOUSE_RESET), [DEV_ACK, DEV_SELF_TEST_PASSED, 0x00]);
+        i8042.input(InputEvent::MouseMove { dx: 1, dy: 1, dz: 0 });
+        assert!(read_all(&mut i8042).is_empty());
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut i8042 = controller();
+        i8042.write_command(CMD_SELF_TEST);
+        for code in [0x1c, 0x32, 0x21] {
+            i8042.input(InputEvent::Key { code, pressed: true });
+        }
+        i8042.write_command(CMD_WRITE_AUX);
+        i8042.write_data(MOUSE_ENABLE);
+        i8042.write_command(CMD_WRITE_RAM);
+        let mut state = StateBuf::default();
+        i8042.save_state(&mut state);
+
+        let mut restored = controller();
+        restored.restore_state(&mut StateReader::new(&state.0)).unwrap();
+        assert_eq!(format!("{restored:?}"), format!("{i8042:?}"));
+        restored.write_data(CCB_SYSTEM | CCB_TRANSLATE);
+        assert_eq!(read_all(&mut restored), [SELF_TEST_PASSED, 0x1e, 0x30, 0x2e, DEV_ACK]);
+    }
+}
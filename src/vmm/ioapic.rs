@@ -0,0 +1,298 @@
+//! Userspace 82093AA IOAPIC, used with a split irqchip.
+//!
+//! Interrupts leave as MSIs to the in-kernel LAPICs. Level triggered pins keep
+//! their remote IRR until KVM reports the EOI of their vector.
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+
+use super::{ irq::MsiMessage, snapshot::{ self, Snapshot, StateBuf, StateReader } };
+
+pub const IOAPIC_BASE: u64 = 0xfec0_0000;
+pub const IOAPIC_SIZE: u64 = 0x1000;
+pub const IOAPIC_PINS: usize = 24;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_ARBITRATION: u32 = 0x02;
+const REG_REDIRECTION: u32 = 0x10;
+
+/// Version 0x11, highest redirection entry in bits 16-23
+const VERSION: u32 = 0x11 | ((IOAPIC_PINS as u32 - 1) << 16);
+
+const RTE_DELIVERY_MODE_SHIFT: u64 = 8;
+const RTE_DEST_LOGICAL: u64 = 1 << 11;
+const RTE_REMOTE_IRR: u64 = 1 << 14;
+const RTE_LEVEL: u64 = 1 << 15;
+const RTE_MASKED: u64 = 1 << 16;
+const RTE_DEST_SHIFT: u64 = 56;
+/// Bits the guest can't write
+const RTE_READ_ONLY: u64 = 1 << 12 | RTE_REMOTE_IRR;
+
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+
+#[derive(Debug)]
+pub struct Ioapic {
+    id: u32,
+    ioregsel: u32,
+    redirection: [u64; IOAPIC_PINS],
+    /// Current level of every input line
+    lines: u32,
+}
+
+impl Default for Ioapic {
+    fn default() -> Self {
+        Self { id: 0, ioregsel: 0, redirection: [RTE_MASKED; IOAPIC_PINS], lines: 0 }
+    }
+}
+
+impl Ioapic {
+    fn is_level(&self, pin: usize) -> bool {
+        self.redirection[pin] & RTE_LEVEL != 0
+    }
+
+    /// MSI a pin delivers, `None` while masked
+    pub fn pin_msi(&self, pin: usize) -> Option<MsiMessage> {
+        let rte = self.redirection[pin];
+        if rte & RTE_MASKED != 0 {
+            return None;
+        }
+        let dest = (rte >> RTE_DEST_SHIFT) & 0xff;
+        let dest_mode = if rte & RTE_DEST_LOGICAL != 0 { 1 << 2 } else { 0 };
+        let delivery_mode = (rte >> RTE_DELIVERY_MODE_SHIFT) & 0x7;
+        // Level triggered MSIs carry the trigger mode and assert bits
+        let trigger = if rte & RTE_LEVEL != 0 { 1 << 15 | 1 << 14 } else { 0 };
+        Some(MsiMessage {
+            address: MSI_ADDRESS_BASE | dest << 12 | dest_mode,
+            data: (rte & 0xff) as u32 | (delivery_mode << 8) as u32 | trigger,
+        })
+    }
+
+    /// Set an input line, returns the MSI to send if it raised an interrupt
+    pub fn set_irq(&mut self, pin: usize, level: bool) -> Option<MsiMessage> {
+        if pin >= IOAPIC_PINS {
+            warn!("IOAPIC pin {pin} out of range");
+            return None;
+        }
+        let was_high = self.lines & (1 << pin) != 0;
+        if level {
+            self.lines |= 1 << pin;
+        } else {
+            self.lines &= !(1 << pin);
+        }
+        if !level || (!self.is_level(pin) && was_high) {
+            return None;
+        }
+        self.fire(pin)
+    }
+
+    fn fire(&mut self, pin: usize) -> Option<MsiMessage> {
+        let msi = self.pin_msi(pin)?;
+        if self.is_level(pin) {
+            if self.redirection[pin] & RTE_REMOTE_IRR != 0 {
+                return None;
+            }
+            self.redirection[pin] |= RTE_REMOTE_IRR;
+        }
+        Some(msi)
+    }
+
+    /// EOI of `vector` by a LAPIC, returns the MSIs of the lines still asserted
+    pub fn eoi(&mut self, vector: u8) -> Vec<MsiMessage> {
+        let mut msis = vec![];
+        for pin in 0..IOAPIC_PINS {
+            let rte = self.redirection[pin];
+            if rte & 0xff != vector as u64 || rte & RTE_REMOTE_IRR == 0 {
+                continue;
+            }
+            self.redirection[pin] &= !RTE_REMOTE_IRR;
+            if self.lines & (1 << pin) != 0 {
+                msis.extend(self.fire(pin));
+            }
+        }
+        msis
+    }
+
+    pub fn mmio_read(&self, offset: u64, data: &mut [u8]) {
+        let value = match offset {
+            IOREGSEL => self.ioregsel,
+            IOWIN => self.read_reg(self.ioregsel),
+            _ => 0,
+        };
+        let bytes = value.to_le_bytes();
+        let len = data.len().min(4);
+        data[..len].copy_from_slice(&bytes[..len]);
+    }
+
+    /// Returns true when a redirection entry changed and the routes must follow
+    pub fn mmio_write(&mut self, offset: u64, data: &[u8]) -> bool {
+        let mut bytes = [0u8; 4];
+        let len = data.len().min(4);
+        bytes[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(bytes);
+        match offset {
+            IOREGSEL => {
+                self.ioregsel = value & 0xff;
+                false
+            }
+            IOWIN => self.write_reg(self.ioregsel, value),
+            _ => false,
+        }
+    }
+
+    fn read_reg(&self, reg: u32) -> u32 {
+        match reg {
+            REG_ID => self.id << 24,
+            REG_VERSION => VERSION,
+            REG_ARBITRATION => self.id << 24,
+            reg if (REG_REDIRECTION..REG_REDIRECTION + 2 * IOAPIC_PINS as u32).contains(&reg) => {
+                let rte = self.redirection[((reg - REG_REDIRECTION) / 2) as usize];
+                if reg % 2 == 0 { rte as u32 } else { (rte >> 32) as u32 }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, reg: u32, value: u32) -> bool {
+        match reg {
+            REG_ID => {
+                self.id = (value >> 24) & 0xf;
+                false
+            }
+            reg if (REG_REDIRECTION..REG_REDIRECTION + 2 * IOAPIC_PINS as u32).contains(&reg) => {
+                let pin = ((reg - REG_REDIRECTION) / 2) as usize;
+                let old = self.redirection[pin];
+                let (mask, shifted) = if reg % 2 == 0 {
+                    (0xffff_ffff, value as u64)
+                } else {
+                    (0xffff_ffff << 32, (value as u64) << 32)
+                };
+                let writable = mask & !RTE_READ_ONLY;
+                self.redirection[pin] = (old & !writable) | (shifted & writable);
+                if self.redirection[pin] & RTE_LEVEL == 0 {
+                    self.redirection[pin] &= !RTE_REMOTE_IRR;
+                }
+                debug!("IOAPIC pin {pin} RTE 0x{:x}", self.redirection[pin]);
+                self.redirection[pin] != old
+            }
+            _ => false,
+        }
+    }
+
+    /// Lines asserted while their pin was masked, to be delivered once unmasked
+    pub fn pending(&mut self) -> Vec<MsiMessage> {
+        let asserted: Vec<usize> = (0..IOAPIC_PINS)
+            .filter(|&pin| self.is_level(pin) && self.lines & (1 << pin) != 0)
+            .collect();
+        asserted.into_iter().filter_map(|pin| self.fire(pin)).collect()
+    }
+}
+
+impl Snapshot for Ioapic {
+    fn snapshot_id(&self) -> String {
+        format!("ioapic@{IOAPIC_BASE:x}")
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        state.put_u32(self.id);
+        state.put_u32(self.ioregsel);
+        state.put_u32(self.lines);
+        for rte in self.redirection {
+            state.put_u64(rte);
+        }
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.id = state.get_u32()?;
+        self.ioregsel = state.get_u32()?;
+        self.lines = state.get_u32()?;
+        for rte in self.redirection.iter_mut() {
+            *rte = state.get_u64()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_rte(ioapic: &mut Ioapic, pin: u32, rte: u64) -> bool {
+        let mut changed = false;
+        for (half, value) in [(0, rte as u32), (1, (rte >> 32) as u32)] {
+            ioapic.mmio_write(IOREGSEL, &(REG_REDIRECTION + pin * 2 + half).to_le_bytes());
+            changed |= ioapic.mmio_write(IOWIN, &value.to_le_bytes());
+        }
+        changed
+    }
+
+    #[test]
+    fn registers() {
+        let mut ioapic = Ioapic::default();
+        ioapic.mmio_write(IOREGSEL, &[REG_VERSION as u8]);
+        let mut data = [0; 4];
+        ioapic.mmio_read(IOWIN, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x17_0011);
+
+        // Remote IRR and delivery status are read only, edge pins never keep remote IRR
+        assert!(write_rte(&mut ioapic, 3, 0x0200_0000_0000_5031));
+        assert_eq!(ioapic.redirection[3], 0x0200_0000_0000_0031);
+        assert!(!write_rte(&mut ioapic, 3, 0x0200_0000_0000_0031));
+        ioapic.mmio_write(IOREGSEL, &[(REG_REDIRECTION + 7) as u8]);
+        ioapic.mmio_read(IOWIN, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x0200_0000);
+    }
+
+    #[test]
+    fn edge_pin() {
+        let mut ioapic = Ioapic::default();
+        assert_eq!(ioapic.set_irq(2, true), None, "masked");
+        write_rte(&mut ioapic, 2, 0x30 | RTE_DEST_LOGICAL | 3 << 56);
+        ioapic.set_irq(2, false);
+        let msi = ioapic.set_irq(2, true).unwrap();
+        assert_eq!(msi, MsiMessage { address: 0xfee0_3004, data: 0x30 });
+        // Only rising edges fire
+        assert_eq!(ioapic.set_irq(2, true), None);
+        assert_eq!(ioapic.set_irq(IOAPIC_PINS, true), None);
+    }
+
+    #[test]
+    fn level_pin_remote_irr() {
+        let mut ioapic = Ioapic::default();
+        write_rte(&mut ioapic, 9, 0x41 | RTE_LEVEL | RTE_MASKED);
+        assert_eq!(ioapic.set_irq(9, true), None);
+        // Asserted while masked, delivered once unmasked
+        write_rte(&mut ioapic, 9, 0x41 | RTE_LEVEL);
+        let pending = ioapic.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].data, 0x41 | 1 << 15 | 1 << 14);
+        assert_ne!(ioapic.redirection[9] & RTE_REMOTE_IRR, 0);
+        // Nothing more until the EOI, then again while the line stays high
+        assert_eq!(ioapic.set_irq(9, true), None);
+        assert!(ioapic.eoi(0x42).is_empty());
+        assert_eq!(ioapic.eoi(0x41).len(), 1);
+        ioapic.set_irq(9, false);
+        assert!(ioapic.eoi(0x41).is_empty());
+        assert_eq!(ioapic.redirection[9] & RTE_REMOTE_IRR, 0);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut ioapic = Ioapic::default();
+        write_rte(&mut ioapic, 5, 0x55 | RTE_LEVEL);
+        ioapic.set_irq(5, true);
+        ioapic.mmio_write(IOREGSEL, &[REG_ID as u8]);
+        ioapic.mmio_write(IOWIN, &(2u32 << 24).to_le_bytes());
+
+        let mut state = StateBuf::default();
+        ioapic.save_state(&mut state);
+        let mut restored = Ioapic::default();
+        restored.restore_state(&mut StateReader::new(&state.0)).unwrap();
+        assert_eq!(restored.id, 2);
+        assert_eq!(restored.lines, 1 << 5);
+        assert_eq!(restored.redirection, ioapic.redirection);
+    }
+}
@@ -0,0 +1,423 @@
+//! Interrupt delivery: irqchip creation, GSI routing, MSIs and irqfds.
+//!
+//! GSIs 0-23 are the IOAPIC pins (0-15 also the PIC ones with the in-kernel
+//! irqchip), devices send their MSIs directly with KVM_SIGNAL_MSI. With a
+//! split irqchip only the LAPICs stay in the kernel, the IOAPIC pins are MSI
+//! routes kept in sync with our userspace [`Ioapic`] redirection table, and
+//! device lines drive that IOAPIC so that masking and EOIs apply to them.
+
+use std::{ fs::File, io, os::fd::{ AsRawFd, FromRawFd }, sync::{ Arc, Mutex } };
+
+use kvm_bindings::*;
+use kvm_ioctls::VmFd;
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use serde::Deserialize;
+use vmm_sys_util::{ eventfd::{ EventFd, EFD_NONBLOCK }, ioctl::ioctl_with_ref, ioctl_ioc_nr, ioctl_iow_nr };
+
+use super::{ ioapic::{ Ioapic, IOAPIC_BASE, IOAPIC_PINS, IOAPIC_SIZE }, Vm };
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+ioctl_iow_nr!(KVM_SIGNAL_MSI, KVMIO, 0xa5, kvm_msi);
+ioctl_iow_nr!(KVM_IRQ_LINE, KVMIO, 0x61, kvm_irq_level);
+
+/// GSIs with a PIC pin as well, with the in-kernel irqchip
+const PIC_PINS: u32 = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IrqChipMode {
+    /// PIC, IOAPIC, LAPICs and PIT in the kernel
+    #[default]
+    Kernel,
+    /// LAPICs in the kernel, IOAPIC in userspace, no PIC nor PIT
+    Split,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+/// Device interrupt that can be raised from any thread, like the event loop one
+#[derive(Debug)]
+pub struct IrqFd {
+    line: IrqLine,
+    gsi: u32,
+}
+
+#[derive(Debug)]
+enum IrqLine {
+    /// Irqfd registered with KVM, and a duplicate of the VM fd for KVM_IRQ_LINE
+    Kernel { fd: EventFd, vm: File },
+    /// Pin of the userspace IOAPIC, split irqchip only
+    Ioapic { ioapic: Arc<Mutex<Ioapic>>, msi: MsiSender },
+    #[cfg(test)]
+    Detached { fd: EventFd, level: std::sync::atomic::AtomicBool },
+}
+
+impl IrqFd {
+    /// Pulse the line, an edge
+    pub fn trigger(&self) -> io::Result<()> {
+        match &self.line {
+            IrqLine::Kernel { fd, .. } => fd.write(1),
+            IrqLine::Ioapic { ioapic, msi } => {
+                let mut ioapic = ioapic.lock().unwrap();
+                let fired = ioapic.set_irq(self.gsi as usize, true);
+                ioapic.set_irq(self.gsi as usize, false);
+                drop(ioapic);
+                fired.map_or(Ok(()), |fired| msi.send(fired))
+            }
+            #[cfg(test)]
+            IrqLine::Detached { fd, .. } => fd.write(1),
+        }
+    }
+
+    /// Hold the line at `level`, for level triggered interrupts: the IOAPIC
+    /// delivers it again after each EOI until it is lowered
+    pub fn set_level(&self, level: bool) -> io::Result<()> {
+        match &self.line {
+            IrqLine::Kernel { vm, .. } => {
+                let mut irq_level = kvm_irq_level { level: level as u32, ..Default::default() };
+                irq_level.__bindgen_anon_1.irq = self.gsi;
+                if unsafe { ioctl_with_ref(vm, KVM_IRQ_LINE(), &irq_level) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            }
+            IrqLine::Ioapic { ioapic, msi } => {
+                let fired = ioapic.lock().unwrap().set_irq(self.gsi as usize, level);
+                fired.map_or(Ok(()), |fired| msi.send(fired))
+            }
+            #[cfg(test)]
+            IrqLine::Detached { fd, level: held } => {
+                held.store(level, std::sync::atomic::Ordering::SeqCst);
+                if level { fd.write(1) } else { Ok(()) }
+            }
+        }
+    }
+
+    pub fn gsi(&self) -> u32 {
+        self.gsi
+    }
+}
+
+/// Sends MSIs from any thread, for devices programming their own vectors (MSI-X tables)
+#[derive(Debug)]
+pub struct MsiSender {
+    /// Duplicate of the VM fd
+    vm: File,
+}
+
+impl MsiSender {
+    pub fn send(&self, msi: MsiMessage) -> io::Result<()> {
+        let msi = kvm_msi {
+            address_lo: msi.address as u32,
+            address_hi: (msi.address >> 32) as u32,
+            data: msi.data,
+            ..Default::default()
+        };
+        if unsafe { ioctl_with_ref(&self.vm, KVM_SIGNAL_MSI(), &msi) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl IrqFd {
+    /// Interrupt line not routed anywhere
+    pub fn detached(gsi: u32) -> Self {
+        IrqFd { line: IrqLine::Detached { fd: EventFd::new(EFD_NONBLOCK).unwrap(), level: Default::default() }, gsi }
+    }
+
+    /// Whether it was triggered since the last call
+    pub fn take_pending(&self) -> bool {
+        match &self.line {
+            IrqLine::Detached { fd, .. } => fd.read().is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Level last set with [`IrqFd::set_level`]
+    pub fn level(&self) -> bool {
+        match &self.line {
+            IrqLine::Detached { level, .. } => level.load(std::sync::atomic::Ordering::SeqCst),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+impl MsiSender {
+    /// Sender without a VM behind it, every send fails
+    pub fn detached() -> Self {
+        MsiSender { vm: File::open("/dev/null").unwrap() }
+    }
+}
+
+#[derive(Debug)]
+pub struct IrqRouting {
+    mode: IrqChipMode,
+    /// Split irqchip only, shared with the device lines
+    pub ioapic: Option<Arc<Mutex<Ioapic>>>,
+}
+
+fn irqchip_route(gsi: u32, irqchip: u32, pin: u32) -> kvm_irq_routing_entry {
+    let mut entry = kvm_irq_routing_entry { gsi, type_: KVM_IRQ_ROUTING_IRQCHIP, ..Default::default() };
+    entry.u.irqchip = kvm_irq_routing_irqchip { irqchip, pin };
+    entry
+}
+
+fn msi_route(gsi: u32, msi: MsiMessage) -> kvm_irq_routing_entry {
+    let mut entry = kvm_irq_routing_entry { gsi, type_: KVM_IRQ_ROUTING_MSI, ..Default::default() };
+    entry.u.msi.address_lo = msi.address as u32;
+    entry.u.msi.address_hi = (msi.address >> 32) as u32;
+    entry.u.msi.data = msi.data;
+    entry
+}
+
+fn set_gsi_routing(vm_fd: &VmFd, entries: &[kvm_irq_routing_entry]) -> Result<()> {
+    // kvm_irq_routing is a header followed by its entries, both fit in entries sized elements
+    let mut buf = vec![kvm_irq_routing_entry::default(); entries.len() + 1];
+    let routing = buf.as_mut_ptr() as *mut kvm_irq_routing;
+    unsafe {
+        (*routing).nr = entries.len() as u32;
+        (*routing).flags = 0;
+        (*routing).entries.as_mut_slice(entries.len()).copy_from_slice(entries);
+        vm_fd.set_gsi_routing(&*routing)
+    }
+}
+
+impl IrqRouting {
+    /// Create the interrupt controllers, must happen before the vCPUs are
+    pub fn new(vm_fd: &VmFd, mode: IrqChipMode) -> Result<Self> {
+        let ioapic = match mode {
+            IrqChipMode::Kernel => {
+                vm_fd.create_irq_chip()?;
+                vm_fd.create_pit2(kvm_pit_config::default())?;
+                None
+            }
+            IrqChipMode::Split => {
+                let mut cap = kvm_enable_cap { cap: KVM_CAP_SPLIT_IRQCHIP, ..Default::default() };
+                cap.args[0] = IOAPIC_PINS as u64;
+                vm_fd.enable_cap(&cap)?;
+                warn!("Split irqchip, no PIC nor PIT");
+                Some(Arc::new(Mutex::new(Ioapic::default())))
+            }
+        };
+        info!("{mode:?} irqchip created");
+        let routing = Self { mode, ioapic };
+        routing.commit(vm_fd)?;
+        Ok(routing)
+    }
+
+    pub fn mode(&self) -> IrqChipMode {
+        self.mode
+    }
+
+    /// Push the whole routing table to KVM
+    pub fn commit(&self, vm_fd: &VmFd) -> Result<()> {
+        let entries = self.routes();
+        debug!("GSI routing: {} routes", entries.len());
+        set_gsi_routing(vm_fd, &entries)
+    }
+
+    fn routes(&self) -> Vec<kvm_irq_routing_entry> {
+        let mut entries = vec![];
+        match &self.ioapic {
+            None =>
+                for gsi in 0..IOAPIC_PINS as u32 {
+                    entries.push(irqchip_route(gsi, KVM_IRQCHIP_IOAPIC, gsi));
+                    if gsi < PIC_PINS {
+                        let (chip, pin) = if gsi < 8 {
+                            (KVM_IRQCHIP_PIC_MASTER, gsi)
+                        } else {
+                            (KVM_IRQCHIP_PIC_SLAVE, gsi - 8)
+                        };
+                        entries.push(irqchip_route(gsi, chip, pin));
+                    }
+                }
+            // Also how KVM knows which vectors need an EOI exit
+            Some(ioapic) => {
+                let ioapic = ioapic.lock().unwrap();
+                for pin in 0..IOAPIC_PINS {
+                    if let Some(msi) = ioapic.pin_msi(pin) {
+                        entries.push(msi_route(pin as u32, msi));
+                    }
+                }
+            }
+        }
+        entries
+    }
+}
+
+fn signal_msi(vm_fd: &VmFd, msi: MsiMessage) -> Result<()> {
+    let msi = kvm_msi {
+        address_lo: msi.address as u32,
+        address_hi: (msi.address >> 32) as u32,
+        data: msi.data,
+        ..Default::default()
+    };
+    vm_fd.signal_msi(msi).map(|_| ())
+}
+
+impl Vm {
+    /// Writing to `fd` from any thread raises `gsi`, edge triggered
+    pub fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        self.vm_fd.register_irqfd(fd, gsi)
+    }
+
+    /// Interrupt line `gsi`. With the in-kernel irqchip it is an eventfd bound
+    /// to `gsi`, KVM drops the binding once it is closed. With a split irqchip
+    /// the IOAPIC pins go through the userspace IOAPIC.
+    pub fn irqfd(&self, gsi: u32) -> Result<IrqFd> {
+        if let Some(ioapic) = self.irq.ioapic.as_ref().filter(|_| (gsi as usize) < IOAPIC_PINS) {
+            let line = IrqLine::Ioapic { ioapic: ioapic.clone(), msi: self.msi_sender()? };
+            return Ok(IrqFd { line, gsi });
+        }
+        let fd = EventFd::new(EFD_NONBLOCK).map_err(|e| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO)))?;
+        self.register_irqfd(&fd, gsi)?;
+        Ok(IrqFd { line: IrqLine::Kernel { fd, vm: self.dup_vm_fd()? }, gsi })
+    }
+
+    fn dup_vm_fd(&self) -> Result<File> {
+        let fd = unsafe { libc::dup(self.vm_fd.as_raw_fd()) };
+        if fd < 0 {
+            return Err(kvm_ioctls::Error::last());
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub fn msi_sender(&self) -> Result<MsiSender> {
+        Ok(MsiSender { vm: self.dup_vm_fd()? })
+    }
+
+    /// Push the routes again, after the IOAPIC state was restored
+    pub(super) fn sync_irq_routes(&self) -> Result<()> {
+        self.irq.commit(&self.vm_fd)
+    }
+
+    pub(super) fn is_ioapic_mmio(&self, addr: u64) -> bool {
+        self.irq.ioapic.is_some() && (IOAPIC_BASE..IOAPIC_BASE + IOAPIC_SIZE).contains(&addr)
+    }
+
+    pub(super) fn ioapic_mmio_read(&self, addr: u64, data: &mut [u8]) {
+        if let Some(ioapic) = &self.irq.ioapic {
+            ioapic.lock().unwrap().mmio_read(addr - IOAPIC_BASE, data);
+        }
+    }
+
+    pub(super) fn ioapic_mmio_write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        let Some(ioapic) = &self.irq.ioapic else {
+            return Ok(());
+        };
+        let mut ioapic = ioapic.lock().unwrap();
+        if !ioapic.mmio_write(addr - IOAPIC_BASE, data) {
+            return Ok(());
+        }
+        // Level triggered lines asserted while masked fire once unmasked
+        let pending = ioapic.pending();
+        drop(ioapic);
+        self.irq.commit(&self.vm_fd)?;
+        pending.into_iter().try_for_each(|msi| signal_msi(&self.vm_fd, msi))
+    }
+
+    /// A LAPIC EOIed a vector routed through the userspace IOAPIC
+    pub(super) fn ioapic_eoi(&mut self, vector: u8) -> Result<()> {
+        let Some(ioapic) = &self.irq.ioapic else {
+            return Ok(());
+        };
+        let msis = ioapic.lock().unwrap().eoi(vector);
+        msis.into_iter().try_for_each(|msi| signal_msi(&self.vm_fd, msi))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn irqchip_pin(entry: &kvm_irq_routing_entry) -> (u32, u32, u32) {
+        assert_eq!(entry.type_, KVM_IRQ_ROUTING_IRQCHIP);
+        let irqchip = unsafe { entry.u.irqchip };
+        (entry.gsi, irqchip.irqchip, irqchip.pin)
+    }
+
+    #[test]
+    fn kernel_irqchip_routes() {
+        let routing = IrqRouting { mode: IrqChipMode::Kernel, ioapic: None };
+        let routes: Vec<_> = routing.routes().iter().map(irqchip_pin).collect();
+        // Every IOAPIC pin, plus the PIC pins for the first 16 GSIs
+        assert_eq!(routes.len(), IOAPIC_PINS + PIC_PINS as usize);
+        assert!(routes.contains(&(0, KVM_IRQCHIP_IOAPIC, 0)));
+        assert!(routes.contains(&(0, KVM_IRQCHIP_PIC_MASTER, 0)));
+        assert!(routes.contains(&(9, KVM_IRQCHIP_PIC_SLAVE, 1)));
+        assert!(routes.contains(&(23, KVM_IRQCHIP_IOAPIC, 23)));
+        assert!(!routes.iter().any(|&(gsi, chip, _)| gsi >= PIC_PINS && chip != KVM_IRQCHIP_IOAPIC));
+    }
+
+    #[test]
+    fn split_irqchip_routes_follow_ioapic() {
+        let mut ioapic = Ioapic::default();
+        // Pin 4: vector 0x34, level triggered, physical destination APIC 1
+        ioapic.mmio_write(0x00, &[0x18]);
+        ioapic.mmio_write(0x10, &0x0000_8034u32.to_le_bytes());
+        ioapic.mmio_write(0x00, &[0x19]);
+        ioapic.mmio_write(0x10, &0x0100_0000u32.to_le_bytes());
+        let routing = IrqRouting { mode: IrqChipMode::Split, ioapic: Some(Arc::new(Mutex::new(ioapic))) };
+
+        let routes = routing.routes();
+        assert_eq!(routes.len(), 1, "masked pins have no route");
+        assert_eq!((routes[0].gsi, routes[0].type_), (4, KVM_IRQ_ROUTING_MSI));
+        let msi = unsafe { routes[0].u.msi };
+        assert_eq!((msi.address_lo, msi.address_hi), (0xfee0_1000, 0));
+        assert_eq!(msi.data, 0x34 | 1 << 15 | 1 << 14);
+    }
+
+    fn ioapic_line(pin: u32, rte: u64) -> (Arc<Mutex<Ioapic>>, IrqFd) {
+        let mut ioapic = Ioapic::default();
+        ioapic.mmio_write(0x00, &[0x10 + pin as u8 * 2]);
+        ioapic.mmio_write(0x10, &(rte as u32).to_le_bytes());
+        let ioapic = Arc::new(Mutex::new(ioapic));
+        let line = IrqLine::Ioapic { ioapic: ioapic.clone(), msi: MsiSender::detached() };
+        (ioapic, IrqFd { line, gsi: pin })
+    }
+
+    fn unmask(ioapic: &Mutex<Ioapic>, pin: u32, rte: u64) -> Vec<MsiMessage> {
+        let mut ioapic = ioapic.lock().unwrap();
+        ioapic.mmio_write(0x00, &[0x10 + pin as u8 * 2]);
+        ioapic.mmio_write(0x10, &(rte as u32 & !(1 << 16)).to_le_bytes());
+        ioapic.pending()
+    }
+
+    #[test]
+    fn split_line_masked_then_unmasked() {
+        // Pin 9: vector 0x49, level triggered, masked
+        let rte = 0x49 | 1 << 15 | 1 << 16;
+        let (ioapic, line) = ioapic_line(9, rte);
+        // Latched in the IOAPIC, nothing sent (the detached sender fails every send)
+        line.set_level(true).unwrap();
+        assert_eq!(unmask(&ioapic, 9, rte).len(), 1);
+
+        // A pulse isn't latched while masked
+        let (ioapic, line) = ioapic_line(9, rte);
+        line.trigger().unwrap();
+        assert!(unmask(&ioapic, 9, rte).is_empty());
+    }
+
+    #[test]
+    fn split_line_level_redelivered_after_eoi() {
+        let rte = 0x49 | 1 << 15 | 1 << 16;
+        let (ioapic, line) = ioapic_line(9, rte);
+        line.set_level(true).unwrap();
+        assert_eq!(unmask(&ioapic, 9, rte).len(), 1);
+        // Remote IRR set, raising it again sends nothing
+        line.set_level(true).unwrap();
+        assert_eq!(ioapic.lock().unwrap().eoi(0x49).len(), 1, "still asserted");
+        line.set_level(false).unwrap();
+        assert!(ioapic.lock().unwrap().eoi(0x49).is_empty());
+        assert!(line.set_level(true).is_err(), "delivered, to the detached sender");
+    }
+}
@@ -0,0 +1,268 @@
+//! ISO9660 volume and El Torito boot catalog probing, for CD-ROM images.
+//!
+//! The ATAPI device serves the image as is, the firmware finds the boot
+//! image through the catalog itself. This only tells what it will find.
+
+use std::fmt;
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+
+use super::disk::DiskBackend;
+
+pub const ISO_SECTOR_SIZE: u64 = 2048;
+/// The system area comes first
+const FIRST_DESCRIPTOR: u64 = 16;
+/// Volume descriptors looked at before giving up on the terminator
+const MAX_DESCRIPTORS: u64 = 32;
+const STANDARD_ID: &[u8] = b"CD001";
+const EL_TORITO_ID: &[u8] = b"EL TORITO SPECIFICATION";
+
+const DESCRIPTOR_BOOT_RECORD: u8 = 0;
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+const CATALOG_ENTRY_SIZE: usize = 32;
+const HEADER_VALIDATION: u8 = 0x01;
+const HEADER_SECTION: u8 = 0x90;
+const HEADER_LAST_SECTION: u8 = 0x91;
+const ENTRY_BOOTABLE: u8 = 0x88;
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum IsoError {
+    /// Can't read the image: {0}
+    Io(#[from] std::io::Error),
+    /// No ISO9660 primary volume descriptor
+    NotIso9660,
+    /// Bad El Torito boot catalog at sector {0}
+    BadCatalog(u32),
+}
+
+/// Platform id of a boot catalog entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootPlatform {
+    Bios,
+    PowerPc,
+    Mac,
+    Efi,
+    Other(u8),
+}
+
+impl From<u8> for BootPlatform {
+    fn from(id: u8) -> Self {
+        match id {
+            0x00 => Self::Bios,
+            0x01 => Self::PowerPc,
+            0x02 => Self::Mac,
+            0xef => Self::Efi,
+            id => Self::Other(id),
+        }
+    }
+}
+
+impl fmt::Display for BootPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bios => write!(f, "BIOS"),
+            Self::PowerPc => write!(f, "PowerPC"),
+            Self::Mac => write!(f, "Mac"),
+            Self::Efi => write!(f, "UEFI"),
+            Self::Other(id) => write!(f, "platform 0x{id:02x}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IsoInfo {
+    pub volume_id: String,
+    /// Bootable entries of the El Torito catalog, empty without one
+    pub boot: Vec<BootPlatform>,
+}
+
+fn read_sector(disk: &mut dyn DiskBackend, lba: u64) -> Result<Vec<u8>, IsoError> {
+    let mut sector = vec![0u8; ISO_SECTOR_SIZE as usize];
+    disk.read_at(&mut sector, lba * ISO_SECTOR_SIZE)?;
+    Ok(sector)
+}
+
+/// Bootable entries of the catalog at `lba`
+fn read_catalog(disk: &mut dyn DiskBackend, lba: u32) -> Result<Vec<BootPlatform>, IsoError> {
+    let catalog = read_sector(disk, lba as u64)?;
+    let validation = &catalog[..CATALOG_ENTRY_SIZE];
+    let checksum = validation
+        .chunks_exact(2)
+        .fold(0u16, |sum, word| sum.wrapping_add(u16::from_le_bytes([word[0], word[1]])));
+    if validation[0] != HEADER_VALIDATION || validation[30..32] != [0x55, 0xaa] || checksum != 0 {
+        return Err(IsoError::BadCatalog(lba));
+    }
+    let mut boot = vec![];
+    // The initial entry is for the platform of the validation entry
+    if catalog[CATALOG_ENTRY_SIZE] == ENTRY_BOOTABLE {
+        boot.push(BootPlatform::from(validation[1]));
+    }
+    let mut entries = catalog[2 * CATALOG_ENTRY_SIZE..].chunks_exact(CATALOG_ENTRY_SIZE);
+    while let Some(header) = entries.next() {
+        if header[0] != HEADER_SECTION && header[0] != HEADER_LAST_SECTION {
+            break;
+        }
+        let count = u16::from_le_bytes([header[2], header[3]]) as usize;
+        for entry in entries.by_ref().take(count) {
+            if entry[0] == ENTRY_BOOTABLE {
+                boot.push(BootPlatform::from(header[1]));
+            }
+        }
+        if header[0] == HEADER_LAST_SECTION {
+            break;
+        }
+    }
+    Ok(boot)
+}
+
+/// Read the volume descriptors of an image
+pub fn probe(disk: &mut dyn DiskBackend) -> Result<IsoInfo, IsoError> {
+    let mut volume_id = None;
+    let mut catalog = None;
+    for lba in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+        if (lba + 1) * ISO_SECTOR_SIZE > disk.size() {
+            break;
+        }
+        let descriptor = read_sector(disk, lba)?;
+        if &descriptor[1..6] != STANDARD_ID {
+            break;
+        }
+        match descriptor[0] {
+            DESCRIPTOR_PRIMARY => {
+                volume_id = Some(String::from_utf8_lossy(&descriptor[40..72]).trim_end().to_string());
+            }
+            DESCRIPTOR_BOOT_RECORD if descriptor[7..7 + EL_TORITO_ID.len()] == *EL_TORITO_ID => {
+                catalog = Some(u32::from_le_bytes(descriptor[0x47..0x4b].try_into().unwrap()));
+            }
+            DESCRIPTOR_TERMINATOR => break,
+            _ => {}
+        }
+    }
+    let volume_id = volume_id.ok_or(IsoError::NotIso9660)?;
+    let boot = match catalog {
+        Some(lba) => read_catalog(disk, lba)?,
+        None => vec![],
+    };
+    Ok(IsoInfo { volume_id, boot })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::vmm::disk::check_range;
+
+    struct Image(Vec<u8>);
+
+    impl DiskBackend for Image {
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        fn read_only(&self) -> bool {
+            true
+        }
+
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            check_range(offset, buf.len(), self.size())?;
+            buf.copy_from_slice(&self.0[offset as usize..offset as usize + buf.len()]);
+            Ok(())
+        }
+
+        fn write_at(&mut self, _: &[u8], _: u64) -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const CATALOG_LBA: u64 = 20;
+
+    fn sector(image: &mut [u8], lba: u64) -> &mut [u8] {
+        &mut image[(lba * ISO_SECTOR_SIZE) as usize..((lba + 1) * ISO_SECTOR_SIZE) as usize]
+    }
+
+    fn descriptor(image: &mut [u8], lba: u64, kind: u8) -> &mut [u8] {
+        let descriptor = sector(image, lba);
+        descriptor[0] = kind;
+        descriptor[1..6].copy_from_slice(STANDARD_ID);
+        descriptor[6] = 1;
+        descriptor
+    }
+
+    /// Primary volume, El Torito boot record and terminator, then the catalog
+    fn iso(platform: u8, sections: &[(u8, u8, &[u8])]) -> Vec<u8> {
+        let mut image = vec![0u8; (CATALOG_LBA + 1) as usize * ISO_SECTOR_SIZE as usize];
+        let primary = descriptor(&mut image, 16, DESCRIPTOR_PRIMARY);
+        primary[40..72].fill(b' ');
+        primary[40..47].copy_from_slice(b"INSTALL");
+        let boot = descriptor(&mut image, 17, DESCRIPTOR_BOOT_RECORD);
+        boot[7..7 + EL_TORITO_ID.len()].copy_from_slice(EL_TORITO_ID);
+        boot[0x47..0x4b].copy_from_slice(&(CATALOG_LBA as u32).to_le_bytes());
+        descriptor(&mut image, 18, DESCRIPTOR_TERMINATOR);
+
+        let catalog = sector(&mut image, CATALOG_LBA);
+        catalog[0] = HEADER_VALIDATION;
+        catalog[1] = platform;
+        catalog[30..32].copy_from_slice(&[0x55, 0xaa]);
+        let sum = catalog[..CATALOG_ENTRY_SIZE]
+            .chunks_exact(2)
+            .fold(0u16, |sum, word| sum.wrapping_add(u16::from_le_bytes([word[0], word[1]])));
+        catalog[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+        catalog[CATALOG_ENTRY_SIZE] = ENTRY_BOOTABLE;
+        let mut offset = 2 * CATALOG_ENTRY_SIZE;
+        for &(header, platform, entries) in sections {
+            catalog[offset] = header;
+            catalog[offset + 1] = platform;
+            catalog[offset + 2..offset + 4].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+            for &entry in entries {
+                offset += CATALOG_ENTRY_SIZE;
+                catalog[offset] = entry;
+            }
+            offset += CATALOG_ENTRY_SIZE;
+        }
+        image
+    }
+
+    #[test]
+    fn probe_volume_and_catalog() {
+        let image = iso(0x00, &[(HEADER_SECTION, 0xef, &[ENTRY_BOOTABLE, 0x00]), (HEADER_LAST_SECTION, 0x02, &[ENTRY_BOOTABLE])]);
+        let info = probe(&mut Image(image)).unwrap();
+        assert_eq!(info.volume_id, "INSTALL");
+        assert_eq!(info.boot, [BootPlatform::Bios, BootPlatform::Efi, BootPlatform::Mac]);
+        assert_eq!(BootPlatform::from(0x42).to_string(), "platform 0x42");
+
+        // Entries after the last section aren't looked at
+        let image = iso(0xef, &[(HEADER_LAST_SECTION, 0x00, &[]), (HEADER_SECTION, 0x00, &[ENTRY_BOOTABLE])]);
+        assert_eq!(probe(&mut Image(image)).unwrap().boot, [BootPlatform::Efi]);
+    }
+
+    #[test]
+    fn probe_rejects_bad_images() {
+        let mut image = iso(0x00, &[]);
+        sector(&mut image, CATALOG_LBA)[4] ^= 1;
+        assert!(matches!(probe(&mut Image(image)), Err(IsoError::BadCatalog(20))));
+
+        // No boot record, no catalog
+        let mut image = iso(0x00, &[]);
+        sector(&mut image, 17).fill(0);
+        assert!(probe(&mut Image(image)).unwrap().boot.is_empty());
+
+        let mut image = iso(0x00, &[]);
+        sector(&mut image, 16)[1] = b'X';
+        assert!(matches!(probe(&mut Image(image)), Err(IsoError::NotIso9660)));
+        // Too small to hold the descriptors
+        assert!(matches!(probe(&mut Image(vec![0; 0x8000])), Err(IsoError::NotIso9660)));
+
+        // A catalog past the end of the image
+        let mut image = iso(0x00, &[]);
+        sector(&mut image, 17)[0x47..0x4b].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(matches!(probe(&mut Image(image)), Err(IsoError::Io(_))));
+    }
+}
@@ -1,12 +1,65 @@
-use kvm_ioctls::{VcpuFd, VmFd};
+use std::sync::{ atomic::AtomicBool, Arc, Mutex };
+
+use kvm_ioctls::{DeviceFd, VcpuFd, VmFd};
 use self::serial::SerialPort;
 
+use self::bus::Bus;
+use self::dirty::DirtyLog;
+use self::event_loop::EventLoop;
+use self::fw_cfg::FwCfg;
+use self::hpet::Hpet;
+use self::i8042::{ I8042, InputSender };
+use self::irq::IrqRouting;
+use self::msr::MsrExits;
+use self::pci::PciRoot;
+use self::pm::{ AcpiPm, PowerButton };
 use self::ram::Ram;
+use self::reset::{ PowerOnState, ResetConfig, ResetLine };
+use self::rtc::Rtc;
+use self::snapshot::BaseSnapshot;
+use self::tpm::TpmCrb;
+use self::virtio::VirtioMmio;
 
+pub mod acpi;
+pub mod ahci;
+pub mod aml;
+pub mod bus;
+pub mod cpuid;
+pub mod device_path;
+pub mod dirty;
+pub mod disk;
+pub mod event_loop;
+pub mod fw_cfg;
+pub mod hpet;
+pub mod i8042;
+pub mod ioapic;
+pub mod irq;
+pub mod iso9660;
+pub mod msix;
+pub mod msr;
+pub mod nvme;
+pub mod paging;
+pub mod pci;
+pub mod pm;
+pub mod q35;
+pub mod qcow2;
 pub mod vm_builder;
 pub mod vm;
 pub mod ram;
+pub mod registry;
+pub mod reset;
+pub mod rtc;
 pub mod serial;
+pub mod secure_boot;
+pub mod smbios;
+pub mod snapshot;
+pub mod tpm;
+pub mod uefi_vars;
+pub mod vcpu_init;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod watch;
+pub mod write_diff;
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -15,7 +68,43 @@ pub struct Vm {
     vm_fd: VmFd,
     vcpu_fd: VcpuFd,
     pub ram: Ram,
-    pub serial: SerialPort
+    pub serial: SerialPort,
+    irq: IrqRouting,
+    /// Port I/O and MMIO ranges of emulated devices, PCI BARs included
+    pio_bus: Bus,
+    mmio_bus: Bus,
+    pci: PciRoot,
+    virtio_mmio: Vec<Arc<Mutex<VirtioMmio>>>,
+    i8042: Option<Arc<Mutex<I8042>>>,
+    /// Built-in host input source of the PS/2 devices
+    input: Option<InputSender>,
+    rtc: Option<Arc<Mutex<Rtc>>>,
+    hpet: Option<Arc<Mutex<Hpet>>>,
+    fw_cfg: Option<Arc<Mutex<FwCfg>>>,
+    acpi_pm: Option<Arc<Mutex<AcpiPm>>>,
+    power_button: Option<PowerButton>,
+    tpm: Option<Arc<Mutex<TpmCrb>>>,
+    /// Pulled by the reset sources, checked after each exit
+    reset_line: ResetLine,
+    reset_config: ResetConfig,
+    /// Kept bits of the reset control register
+    reset_control: u8,
+    /// Stopped by a reset source, until the monitor resets the VM
+    paused: bool,
+    /// What a reboot puts back, captured when the VM is built
+    power_on: Option<PowerOnState>,
+    /// Set when the guest powers itself off, checked after each exit
+    shutdown_request: Arc<AtomicBool>,
+    /// Runs device I/O signalled through ioeventfds
+    event_loop: EventLoop,
+    /// MSRs saved in snapshots, from KVM_GET_MSR_INDEX_LIST
+    msr_indices: Vec<u32>,
+    dirty: DirtyLog,
+    msr_exits: MsrExits,
+    /// Last full snapshot, incremental snapshots and resets are relative to it
+    base: Option<BaseSnapshot>,
+    /// In-kernel devices from KVM_CREATE_DEVICE, by kind name
+    kvm_devices: Vec<(&'static str, DeviceFd)>,
 }
 
 #[allow(dead_code)]
@@ -0,0 +1,157 @@
+//! MSI-X capability and vector table of a PCI function, for the device models.
+//!
+//! The capability control word lives in config space, the table and pending
+//! bits in a BAR the device forwards here. Vectors are sent with KVM_SIGNAL_MSI,
+//! so any thread holding the device can raise them.
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+
+use super::{
+    irq::{ MsiMessage, MsiSender },
+    pci::PciConfig,
+    snapshot::{ self, StateBuf, StateReader },
+};
+
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+pub const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+/// Pending bits are kept in a single u64
+pub const MSIX_MAX_VECTORS: usize = 64;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct MsixEntry {
+    address: u64,
+    data: u32,
+    control: u32,
+}
+
+#[derive(Debug)]
+pub struct Msix {
+    /// Offset of the capability in config space
+    cap: usize,
+    table: Vec<MsixEntry>,
+    pending: u64,
+    sender: MsiSender,
+}
+
+impl Msix {
+    /// Add the capability to `config`, with table and PBA at these offsets of `bar`
+    pub fn new(config: &mut PciConfig, vectors: usize, bar: u8, table: u64, pba: u64, sender: MsiSender) -> Self {
+        assert!(vectors > 0 && vectors <= MSIX_MAX_VECTORS, "Bad MSI-X vector count");
+        let mut body = ((vectors - 1) as u16).to_le_bytes().to_vec();
+        body.extend_from_slice(&(table as u32 | bar as u32).to_le_bytes());
+        body.extend_from_slice(&(pba as u32 | bar as u32).to_le_bytes());
+        let cap = config.add_capability(PCI_CAP_ID_MSIX, &body, &[0x00, 0xc0]);
+        Self {
+            cap,
+            table: vec![MsixEntry { control: MSIX_VECTOR_MASKED, ..Default::default() }; vectors],
+            pending: 0,
+            sender,
+        }
+    }
+
+    pub fn vectors(&self) -> usize {
+        self.table.len()
+    }
+
+    fn control(&self, config: &PciConfig) -> u16 {
+        config.get_u16(self.cap + 2)
+    }
+
+    pub fn enabled(&self, config: &PciConfig) -> bool {
+        self.control(config) & MSIX_CONTROL_ENABLE != 0
+    }
+
+    fn masked(&self, config: &PciConfig, vector: usize) -> bool {
+        self.control(config) & MSIX_CONTROL_FUNCTION_MASK != 0 || self.table[vector].control & MSIX_VECTOR_MASKED != 0
+    }
+
+    /// Send `vector`, or mark it pending while masked
+    pub fn signal(&mut self, config: &PciConfig, vector: u16) {
+        let vector = vector as usize;
+        if !self.enabled(config) || vector >= self.table.len() {
+            return;
+        }
+        if self.masked(config, vector) {
+            self.pending |= 1 << vector;
+            return;
+        }
+        let entry = self.table[vector];
+        if let Err(e) = self.sender.send(MsiMessage { address: entry.address, data: entry.data }) {
+            error!("MSI-X vector {vector} failed: {e}");
+        }
+    }
+
+    /// Deliver the pending vectors that got unmasked
+    fn deliver_pending(&mut self, config: &PciConfig) {
+        for vector in 0..self.table.len() {
+            if self.pending & (1 << vector) != 0 && !self.masked(config, vector) {
+                self.pending &= !(1 << vector);
+                self.signal(config, vector as u16);
+            }
+        }
+    }
+
+    /// Follow a guest config space write, unmasking the function delivers pending vectors
+    pub fn config_written(&mut self, config: &PciConfig, offset: usize, len: usize) {
+        let control = self.cap + 2;
+        if offset < control + 2 && control < offset + len {
+            self.deliver_pending(config);
+        }
+    }
+
+    pub fn table_read(&self, offset: u64) -> u32 {
+        let Some(entry) = self.table.get((offset / MSIX_ENTRY_SIZE) as usize) else {
+            return 0;
+        };
+        match offset % MSIX_ENTRY_SIZE {
+            0x0 => entry.address as u32,
+            0x4 => (entry.address >> 32) as u32,
+            0x8 => entry.data,
+            _ => entry.control,
+        }
+    }
+
+    pub fn table_write(&mut self, config: &PciConfig, offset: u64, value: u32) {
+        let Some(entry) = self.table.get_mut((offset / MSIX_ENTRY_SIZE) as usize) else {
+            return;
+        };
+        match offset % MSIX_ENTRY_SIZE {
+            0x0 => entry.address = (entry.address & !0xffff_ffff) | value as u64,
+            0x4 => entry.address = (entry.address & 0xffff_ffff) | (value as u64) << 32,
+            0x8 => entry.data = value,
+            _ => {
+                entry.control = value & MSIX_VECTOR_MASKED;
+                self.deliver_pending(config);
+            }
+        }
+    }
+
+    /// Pending bit array, one u64 is enough for every vector
+    pub fn pba(&self) -> u64 {
+        self.pending
+    }
+
+    pub fn save(&self, state: &mut StateBuf) {
+        for entry in &self.table {
+            state.put_u64(entry.address);
+            state.put_u32(entry.data);
+            state.put_u32(entry.control);
+        }
+        state.put_u64(self.pending);
+    }
+
+    pub fn restore(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        for entry in self.table.iter_mut() {
+            entry.address = state.get_u64()?;
+            entry.data = state.get_u32()?;
+            entry.control = state.get_u32()?;
+        }
+        self.pending = state.get_u64()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+//! RDMSR/WRMSR exits to userspace.
+//!
+//! With KVM_CAP_X86_USER_SPACE_MSR and an MSR filter, guest accesses to the
+//! MSRs we picked leave KVM as X86Rdmsr/X86Wrmsr exits. They go through a
+//! user registrable [`MsrHandler`] and every one of them lands in an access log.
+
+use std::{ collections::{ BTreeMap, VecDeque }, fmt, os::fd::AsRawFd };
+
+use kvm_bindings::*;
+use kvm_ioctls::{ Cap, ReadMsrExit, VcpuFd, VmFd, WriteMsrExit };
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vmm_sys_util::{ ioctl::ioctl_with_ref, ioctl_ioc_nr, ioctl_iow_nr };
+
+use super::{ VCpu, Vm };
+use crate::util::parse_u64;
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+ioctl_iow_nr!(KVM_X86_SET_MSR_FILTER, KVMIO, 0xc6, kvm_msr_filter);
+
+pub const MSR_IA32_FEATURE_CONTROL: u32 = 0x3a;
+pub const MSR_IA32_SYSENTER_CS: u32 = 0x174;
+pub const MSR_IA32_SYSENTER_ESP: u32 = 0x175;
+pub const MSR_IA32_SYSENTER_EIP: u32 = 0x176;
+pub const MSR_IA32_DEBUGCTL: u32 = 0x1d9;
+pub const MSR_EFER: u32 = 0xc000_0080;
+pub const MSR_STAR: u32 = 0xc000_0081;
+pub const MSR_LSTAR: u32 = 0xc000_0082;
+pub const MSR_CSTAR: u32 = 0xc000_0083;
+pub const MSR_SYSCALL_MASK: u32 = 0xc000_0084;
+
+/// Syscall entry points and CPU feature locks, what drivers like to touch
+pub const INTERESTING_MSRS: &[u32] = &[
+    MSR_IA32_FEATURE_CONTROL,
+    MSR_IA32_SYSENTER_CS,
+    MSR_IA32_SYSENTER_ESP,
+    MSR_IA32_SYSENTER_EIP,
+    MSR_IA32_DEBUGCTL,
+    MSR_EFER,
+    MSR_STAR,
+    MSR_LSTAR,
+    MSR_CSTAR,
+    MSR_SYSCALL_MASK,
+];
+
+const FEATURE_CONTROL_LOCKED: u64 = 1;
+/// SCE, LME, LMA, NXE, SVME, LMSLE, FFXSR and TCE
+const EFER_VALID_BITS: u64 = 0xfd01;
+
+/// MSRs per filter range, 512 bytes of bitmap
+const FILTER_RANGE_MSRS: u32 = 0x1000;
+/// Access log entries kept
+const MSR_LOG_SIZE: usize = 4096;
+
+/// MSRs whose reads and writes exit to userspace
+#[derive(Debug, Default, Clone)]
+pub struct MsrFilter {
+    pub read: Vec<u32>,
+    pub write: Vec<u32>,
+}
+
+impl MsrFilter {
+    /// Trap both reads and writes of `msrs`
+    pub fn both(msrs: &[u32]) -> Self {
+        Self { read: msrs.to_vec(), write: msrs.to_vec() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read.is_empty() && self.write.is_empty()
+    }
+
+    /// (flags, base, bitmap) filter ranges, a cleared bit denies the MSR to KVM
+    fn bitmaps(&self) -> Vec<(u32, u32, Vec<u8>)> {
+        let mut bitmaps = vec![];
+        for (flags, msrs) in [(KVM_MSR_FILTER_READ, &self.read), (KVM_MSR_FILTER_WRITE, &self.write)] {
+            let mut ranges: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+            for &msr in msrs {
+                let base = msr - msr % FILTER_RANGE_MSRS;
+                let bitmap = ranges
+                    .entry(base)
+                    .or_insert_with(|| vec![0xff; (FILTER_RANGE_MSRS / 8) as usize]);
+                let bit = msr - base;
+                bitmap[(bit / 8) as usize] &= !(1 << (bit % 8));
+            }
+            bitmaps.extend(ranges.into_iter().map(|(base, bitmap)| (flags, base, bitmap)));
+        }
+        bitmaps
+    }
+
+    /// Enable userspace MSR exits on `vm_fd` and deny the filtered MSRs to KVM
+    pub fn apply(&self, vm_fd: &VmFd) -> Result<()> {
+        if !vm_fd.check_extension(Cap::X86UserSpaceMsr) {
+            error!("KVM_CAP_X86_USER_SPACE_MSR not supported");
+            return Err(kvm_ioctls::Error::new(libc::ENOTSUP));
+        }
+        let mut cap = kvm_enable_cap { cap: KVM_CAP_X86_USER_SPACE_MSR, ..Default::default() };
+        cap.args[0] = KVM_MSR_EXIT_REASON_FILTER as u64;
+        vm_fd.enable_cap(&cap)?;
+
+        // Ranges allow everything but the MSRs we trap, KVM copies the bitmaps in the ioctl
+        let mut bitmaps = self.bitmaps();
+        if bitmaps.len() > KVM_MSR_FILTER_MAX_RANGES as usize {
+            error!("MSR filter needs {} ranges, KVM takes {KVM_MSR_FILTER_MAX_RANGES}", bitmaps.len());
+            return Err(kvm_ioctls::Error::new(libc::EINVAL));
+        }
+        let mut filter = kvm_msr_filter { flags: KVM_MSR_FILTER_DEFAULT_ALLOW, ..Default::default() };
+        for (range, (flags, base, bitmap)) in filter.ranges.iter_mut().zip(bitmaps.iter_mut()) {
+            *range = kvm_msr_filter_range {
+                flags: *flags,
+                nmsrs: FILTER_RANGE_MSRS,
+                base: *base,
+                bitmap: bitmap.as_mut_ptr(),
+            };
+        }
+        let ret = unsafe { ioctl_with_ref(&vm_fd.as_raw_fd(), KVM_X86_SET_MSR_FILTER(), &filter) };
+        if ret != 0 {
+            return Err(kvm_ioctls::Error::last());
+        }
+        info!("MSR filter set, trapping {} reads and {} writes", self.read.len(), self.write.len());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrAccess {
+    Read,
+    Write(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrAction {
+    /// Do the access on the vCPU as if it wasn't filtered
+    Passthrough,
+    /// Reads return the value, writes store it instead of the guest one
+    Value(u64),
+    /// Reads return 0, writes are dropped
+    Ignore,
+    /// Fault the guest RDMSR/WRMSR
+    InjectGp,
+}
+
+impl std::str::FromStr for MsrAction {
+    type Err = String;
+
+    /// `pass`, `ignore`, `gp` or a value
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "pass" => Ok(MsrAction::Passthrough),
+            "ignore" => Ok(MsrAction::Ignore),
+            "gp" => Ok(MsrAction::InjectGp),
+            value => parse_u64(value).map(MsrAction::Value).ok_or(format!("Bad MSR action {s}")),
+        }
+    }
+}
+
+/// MSR index, decimal or 0x prefixed hex
+pub fn parse_msr_index(s: &str) -> std::result::Result<u32, String> {
+    parse_u64(s).and_then(|index| u32::try_from(index).ok()).ok_or(format!("Bad MSR index {s}"))
+}
+
+/// Decides what trapped MSR accesses do
+pub trait MsrHandler {
+    fn handle(&mut self, vcpu: &VcpuFd, index: u32, access: MsrAccess) -> MsrAction;
+}
+
+/// Fixed action per MSR, the others are passed through.
+///
+/// Passthrough goes through KVM_GET/SET_MSRS, which are host initiated accesses
+/// that skip some of the checks a guest WRMSR gets, see [`MsrExits::wrmsr`].
+#[derive(Debug, Default, Clone)]
+pub struct RuleMsrHandler {
+    rules: BTreeMap<u32, MsrAction>,
+}
+
+impl RuleMsrHandler {
+    /// Parse `index=action` rules, actions as in [`MsrAction::from_str`]
+    pub fn parse<S: AsRef<str>>(rules: &[S]) -> std::result::Result<Self, String> {
+        let mut handler = Self::default();
+        for rule in rules {
+            let rule = rule.as_ref();
+            let (index, action) = rule.split_once('=').ok_or(format!("Bad MSR rule {rule}"))?;
+            let index = parse_msr_index(index)?;
+            handler.rules.insert(index, action.parse()?);
+        }
+        Ok(handler)
+    }
+
+    /// MSRs with a rule, they need to be in the filter to ever reach the handler
+    pub fn msrs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.rules.keys().copied()
+    }
+}
+
+impl MsrHandler for RuleMsrHandler {
+    fn handle(&mut self, _vcpu: &VcpuFd, index: u32, _access: MsrAccess) -> MsrAction {
+        self.rules.get(&index).copied().unwrap_or(MsrAction::Passthrough)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MsrLogEntry {
+    pub rip: u64,
+    pub index: u32,
+    pub access: MsrAccess,
+    pub action: MsrAction,
+    /// Value read by or written to the vCPU, `None` when the access faulted
+    pub value: Option<u64>,
+}
+
+impl fmt::Display for MsrLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.access {
+            MsrAccess::Read => write!(f, "rip=0x{:x} rdmsr 0x{:x}", self.rip, self.index)?,
+            MsrAccess::Write(value) => write!(f, "rip=0x{:x} wrmsr 0x{:x}=0x{value:x}", self.rip, self.index)?,
+        }
+        write!(f, " -> {:?}", self.action)?;
+        match self.value {
+            Some(value) => write!(f, " (0x{value:x})"),
+            None => write!(f, " (#GP)"),
+        }
+    }
+}
+
+/// MSR exit handling state of a VM
+pub struct MsrExits {
+    handler: Box<dyn MsrHandler>,
+    log: VecDeque<MsrLogEntry>,
+}
+
+impl Default for MsrExits {
+    fn default() -> Self {
+        Self { handler: Box::new(RuleMsrHandler::default()), log: VecDeque::new() }
+    }
+}
+
+impl fmt::Debug for MsrExits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MsrExits").field("log_len", &self.log.len()).finish()
+    }
+}
+
+fn read_vcpu_msr(vcpu: &VcpuFd, index: u32) -> Option<u64> {
+    let mut msrs = Msrs::from_entries(&[kvm_msr_entry { index, ..Default::default() }]).ok()?;
+    match vcpu.get_msrs(&mut msrs) {
+        Ok(1) => Some(msrs.as_slice()[0].data),
+        _ => None,
+    }
+}
+
+/// Whether a guest WRMSR of `value` to `index` faults on hardware while KVM_SET_MSRS,
+/// being host initiated, would take it. `current` reads the MSR's value.
+fn write_faults(index: u32, value: u64, current: impl FnOnce() -> Option<u64>) -> bool {
+    match index {
+        // Read only until reset once locked
+        MSR_IA32_FEATURE_CONTROL => current().is_none_or(|current| current & FEATURE_CONTROL_LOCKED != 0),
+        MSR_EFER => value & !EFER_VALID_BITS != 0,
+        MSR_IA32_SYSENTER_ESP | MSR_IA32_SYSENTER_EIP | MSR_LSTAR | MSR_CSTAR => !is_canonical(value),
+        _ => false,
+    }
+}
+
+fn is_canonical(addr: u64) -> bool {
+    (((addr << 16) as i64) >> 16) as u64 == addr
+}
+
+fn write_vcpu_msr(vcpu: &VcpuFd, index: u32, data: u64) -> bool {
+    let Ok(msrs) = Msrs::from_entries(&[kvm_msr_entry { index, data, ..Default::default() }]) else {
+        return false;
+    };
+    matches!(vcpu.set_msrs(&msrs), Ok(1))
+}
+
+impl MsrExits {
+    fn record(&mut self, entry: MsrLogEntry) {
+        info!("MSR {entry}");
+        if self.log.len() == MSR_LOG_SIZE {
+            self.log.pop_front();
+        }
+        self.log.push_back(entry);
+    }
+
+    pub fn rdmsr(&mut self, vcpu: &VcpuFd, exit: ReadMsrExit) {
+        let action = self.handler.handle(vcpu, exit.index, MsrAccess::Read);
+        let value = match action {
+            MsrAction::Passthrough => read_vcpu_msr(vcpu, exit.index),
+            MsrAction::Value(value) => Some(value),
+            MsrAction::Ignore => Some(0),
+            MsrAction::InjectGp => None,
+        };
+        *exit.data = value.unwrap_or(0);
+        *exit.error = value.is_none() as u8;
+        let rip = vcpu.get_rip().unwrap_or_default();
+        self.record(MsrLogEntry { rip, index: exit.index, access: MsrAccess::Read, action, value });
+    }
+
+    /// Passed through writes hardware would reject fault here, KVM_SET_MSRS takes them
+    pub fn wrmsr(&mut self, vcpu: &VcpuFd, exit: WriteMsrExit) {
+        let access = MsrAccess::Write(exit.data);
+        let action = self.handler.handle(vcpu, exit.index, access);
+        let value = match action {
+            MsrAction::Passthrough =>
+                (!write_faults(exit.index, exit.data, || read_vcpu_msr(vcpu, exit.index))).then_some(exit.data),
+            MsrAction::Value(value) => Some(value),
+            MsrAction::Ignore => None,
+            MsrAction::InjectGp => None,
+        };
+        let ok = match (action, value) {
+            (MsrAction::Ignore, _) => true,
+            (_, Some(value)) => write_vcpu_msr(vcpu, exit.index, value),
+            (_, None) => false,
+        };
+        *exit.error = !ok as u8;
+        let rip = vcpu.get_rip().unwrap_or_default();
+        let value = if ok { value.or(Some(exit.data)) } else { None };
+        self.record(MsrLogEntry { rip, index: exit.index, access, action, value });
+    }
+}
+
+impl Vm {
+    /// Replace the handler deciding what trapped MSR accesses do
+    pub fn set_msr_handler(&mut self, handler: Box<dyn MsrHandler>) {
+        self.msr_exits.handler = handler;
+    }
+
+    /// Trapped MSR accesses, oldest first
+    pub fn msr_log(&self) -> impl Iterator<Item = &MsrLogEntry> {
+        self.msr_exits.log.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn denied(bitmap: &[u8], bit: u32) -> bool {
+        bitmap[(bit / 8) as usize] & (1 << (bit % 8)) == 0
+    }
+
+    #[test]
+    fn filter_bitmaps() {
+        let filter = MsrFilter {
+            read: vec![MSR_IA32_FEATURE_CONTROL, MSR_EFER, MSR_IA32_SYSENTER_CS],
+            write: vec![MSR_LSTAR],
+        };
+        let bitmaps = filter.bitmaps();
+        let ranges: Vec<_> = bitmaps.iter().map(|(flags, base, _)| (*flags, *base)).collect();
+        assert_eq!(ranges, [
+            (KVM_MSR_FILTER_READ, 0),
+            (KVM_MSR_FILTER_READ, 0xc000_0000),
+            (KVM_MSR_FILTER_WRITE, 0xc000_0000),
+        ]);
+        let low = &bitmaps[0].2;
+        assert_eq!(low.len(), (FILTER_RANGE_MSRS / 8) as usize);
+        assert!(denied(low, 0x3a) && denied(low, 0x174));
+        assert_eq!(low.iter().map(|b| b.count_zeros()).sum::<u32>(), 2);
+        assert!(denied(&bitmaps[1].2, 0x80) && !denied(&bitmaps[1].2, 0x82));
+        assert!(denied(&bitmaps[2].2, 0x82) && !denied(&bitmaps[2].2, 0x80));
+        assert!(MsrFilter::default().bitmaps().is_empty());
+    }
+
+    #[test]
+    fn rejected_writes() {
+        assert!(write_faults(MSR_IA32_FEATURE_CONTROL, 5, || Some(1)));
+        assert!(!write_faults(MSR_IA32_FEATURE_CONTROL, 5, || Some(0)));
+        assert!(write_faults(MSR_EFER, 1 << 9, || None));
+        assert!(!write_faults(MSR_EFER, 0xd01, || None));
+        assert!(write_faults(MSR_LSTAR, 0x0000_8000_0000_0000, || None));
+        assert!(!write_faults(MSR_LSTAR, 0xffff_8000_0000_1000, || None));
+        assert!(!write_faults(MSR_STAR, u64::MAX, || None));
+    }
+
+    #[test]
+    fn parse_rules() {
+        let handler = RuleMsrHandler::parse(&["0x3a=gp", "0x1d9=ignore", "0xc0000082=0x1000", "0x174=pass"]).unwrap();
+        assert_eq!(handler.msrs().collect::<Vec<_>>(), [0x3a, 0x174, 0x1d9, 0xc000_0082]);
+        assert_eq!(handler.rules[&0x3a], MsrAction::InjectGp);
+        assert_eq!(handler.rules[&0x1d9], MsrAction::Ignore);
+        assert_eq!(handler.rules[&0xc000_0082], MsrAction::Value(0x1000));
+        assert_eq!(handler.rules[&0x174], MsrAction::Passthrough);
+        assert!(RuleMsrHandler::parse(&["0x3a"]).is_err());
+        assert!(RuleMsrHandler::parse(&["0x100000000=gp"]).is_err());
+        assert_eq!(parse_msr_index("372"), Ok(MSR_IA32_SYSENTER_CS));
+        assert_eq!(parse_msr_index("0x100000174"), Err("Bad MSR index 0x100000174".to_string()));
+        assert!(RuleMsrHandler::parse(&["0x3a=maybe"]).is_err());
+    }
+}
@@ -0,0 +1,1098 @@
+//! NVMe 1.3 controller: admin and I/O queue pairs, one namespace per disk backend.
+//!
+//! Like AHCI, commands run on the vCPU thread when the guest rings a
+//! submission queue doorbell, and a completion queue interrupt goes out
+//! through MSI-X once the whole batch is done. PRPs only, no SGLs.
+
+use std::collections::BTreeMap;
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vm_memory::{ Bytes, GuestAddress, GuestMemoryError };
+
+use super::{
+    disk::{ DiskBackend, SECTOR_SIZE },
+    irq::MsiSender,
+    msix::Msix,
+    pci::{ BarKind, PciBar, PciConfig, PciDevice },
+    snapshot::{ self, StateBuf, StateReader },
+    virtio::GuestMem,
+    Vm,
+};
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+const NVME_VENDOR_ID: u16 = 0x8086;
+const NVME_DEVICE_ID: u16 = 0x0953;
+/// Mass storage, non-volatile memory, NVM Express
+const NVME_CLASS: u32 = 0x01_08_02;
+
+/// Layout of BAR 0
+const REGS_DOORBELLS: u64 = 0x1000;
+const MSIX_TABLE: u64 = 0x2000;
+const MSIX_PBA: u64 = 0x3000;
+const BAR_SIZE: u64 = 0x4000;
+
+const REG_CAP: u64 = 0x00;
+const REG_VS: u64 = 0x08;
+const REG_INTMS: u64 = 0x0c;
+const REG_INTMC: u64 = 0x10;
+const REG_CC: u64 = 0x14;
+const REG_CSTS: u64 = 0x1c;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+
+const NVME_VERSION: u32 = 0x0001_0300;
+/// Largest queue, 0's based
+const MAX_QUEUE_ENTRIES: u64 = 1023;
+/// In 500 ms units
+const READY_TIMEOUT: u64 = 15;
+const CAP_CQR: u64 = 1 << 16;
+const CAP_CSS_NVM: u64 = 1 << 37;
+/// Largest memory page size, as 4K << MPSMAX, the smallest one being 4K too
+const CAP_MPSMAX: u64 = 0;
+
+const CC_EN: u32 = 1 << 0;
+const CC_MPS_SHIFT: u32 = 7;
+const CC_SHN_MASK: u32 = 3 << 14;
+const CSTS_RDY: u32 = 1 << 0;
+/// Controller fatal status
+const CSTS_CFS: u32 = 1 << 1;
+const CSTS_SHST_COMPLETE: u32 = 2 << 2;
+
+/// I/O queue pairs, the admin pair comes on top
+const MAX_IO_QUEUES: usize = 16;
+const SQ_ENTRY_SIZE: u64 = 64;
+const CQ_ENTRY_SIZE: u64 = 16;
+/// 2^MDTS minimum pages per transfer
+const MDTS: u8 = 7;
+const MIN_PAGE_SIZE: u64 = 4096;
+const IDENTIFY_SIZE: usize = 4096;
+/// Outstanding Asynchronous Event Requests, 0's based
+const AERL: u8 = 3;
+
+const ADMIN_DELETE_SQ: u8 = 0x00;
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const ADMIN_DELETE_CQ: u8 = 0x04;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_ABORT: u8 = 0x08;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+const ADMIN_GET_FEATURES: u8 = 0x0a;
+const ADMIN_ASYNC_EVENT_REQUEST: u8 = 0x0c;
+
+const NVM_FLUSH: u8 = 0x00;
+const NVM_WRITE: u8 = 0x01;
+const NVM_READ: u8 = 0x02;
+const NVM_WRITE_ZEROES: u8 = 0x08;
+const NVM_DATASET_MANAGEMENT: u8 = 0x09;
+
+const CNS_NAMESPACE: u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+const CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+const CNS_NAMESPACE_DESCRIPTORS: u32 = 0x03;
+
+const LOG_ERROR: u8 = 0x01;
+const LOG_SMART: u8 = 0x02;
+const LOG_FIRMWARE_SLOT: u8 = 0x03;
+const ERROR_LOG_ENTRY_SIZE: usize = 64;
+const LOG_PAGE_SIZE: usize = 512;
+
+const FEATURE_ARBITRATION: u8 = 0x01;
+const FEATURE_POWER_MANAGEMENT: u8 = 0x02;
+const FEATURE_TEMPERATURE_THRESHOLD: u8 = 0x04;
+const FEATURE_ERROR_RECOVERY: u8 = 0x05;
+const FEATURE_VOLATILE_WRITE_CACHE: u8 = 0x06;
+const FEATURE_NUMBER_OF_QUEUES: u8 = 0x07;
+const FEATURE_INTERRUPT_COALESCING: u8 = 0x08;
+const FEATURE_INTERRUPT_VECTOR_CONFIG: u8 = 0x09;
+const FEATURE_WRITE_ATOMICITY: u8 = 0x0a;
+const FEATURE_ASYNC_EVENT_CONFIG: u8 = 0x0b;
+
+const DSM_DEALLOCATE: u32 = 1 << 2;
+const DSM_RANGE_SIZE: usize = 16;
+/// ONCS: Dataset Management and Write Zeroes
+const ONCS: u16 = (1 << 2) | (1 << 3);
+
+/// Composite temperature reported in SMART, Kelvin
+const TEMPERATURE: u16 = 310;
+const WARNING_TEMPERATURE: u16 = 343;
+const CRITICAL_TEMPERATURE: u16 = 373;
+
+/// Status code type << 8 | status code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Status(u16);
+
+impl Status {
+    const SUCCESS: Self = Self(0x000);
+    const INVALID_OPCODE: Self = Self(0x001);
+    const INVALID_FIELD: Self = Self(0x002);
+    const DATA_TRANSFER_ERROR: Self = Self(0x004);
+    const INTERNAL_ERROR: Self = Self(0x006);
+    const INVALID_NAMESPACE: Self = Self(0x00b);
+    const INVALID_PRP_OFFSET: Self = Self(0x013);
+    const LBA_OUT_OF_RANGE: Self = Self(0x080);
+    const INVALID_COMPLETION_QUEUE: Self = Self(0x100);
+    const INVALID_QUEUE_ID: Self = Self(0x101);
+    const INVALID_QUEUE_SIZE: Self = Self(0x102);
+    const INVALID_INTERRUPT_VECTOR: Self = Self(0x108);
+    const INVALID_QUEUE_DELETION: Self = Self(0x10c);
+    const WRITE_TO_READ_ONLY: Self = Self(0x182);
+    const UNRECOVERED_READ_ERROR: Self = Self(0x281);
+    const WRITE_FAULT: Self = Self(0x280);
+}
+
+impl From<GuestMemoryError> for Status {
+    fn from(e: GuestMemoryError) -> Self {
+        warn!("NVMe: guest memory access failed: {e}");
+        Status::DATA_TRANSFER_ERROR
+    }
+}
+
+/// Submission queue entry
+#[derive(Debug, Clone, Copy, Default)]
+struct Command {
+    opcode: u8,
+    /// PRP or SGL, bits 7:6
+    psdt: u8,
+    cid: u16,
+    nsid: u32,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+}
+
+impl Command {
+    fn parse(raw: &[u8; SQ_ENTRY_SIZE as usize]) -> Self {
+        let dword = |i: usize| u32::from_le_bytes(raw[i * 4..i * 4 + 4].try_into().unwrap());
+        let qword = |i: usize| dword(i) as u64 | (dword(i + 1) as u64) << 32;
+        Self {
+            opcode: raw[0],
+            psdt: raw[1] >> 6,
+            cid: u16::from_le_bytes([raw[2], raw[3]]),
+            nsid: dword(1),
+            prp1: qword(6),
+            prp2: qword(8),
+            cdw10: dword(10),
+            cdw11: dword(11),
+            cdw12: dword(12),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct SubmissionQueue {
+    addr: u64,
+    /// Entries, not 0's based
+    size: u16,
+    head: u16,
+    tail: u16,
+    cqid: u16,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct CompletionQueue {
+    addr: u64,
+    size: u16,
+    head: u16,
+    tail: u16,
+    phase: bool,
+    vector: u16,
+    interrupts: bool,
+}
+
+impl CompletionQueue {
+    fn full(&self) -> bool {
+        (self.tail + 1) % self.size == self.head
+    }
+}
+
+/// A disk backend exposed as a namespace with 512 byte LBAs
+pub struct NvmeNamespace {
+    disk: Box<dyn DiskBackend>,
+    /// EUI-64 and NGUID, stable for a given controller serial and namespace id
+    eui64: [u8; 8],
+}
+
+impl NvmeNamespace {
+    pub fn new(disk: Box<dyn DiskBackend>) -> Self {
+        Self { disk, eui64: [0; 8] }
+    }
+
+    fn sectors(&self) -> u64 {
+        self.disk.size() / SECTOR_SIZE
+    }
+
+    fn check_range(&self, slba: u64, nlb: u64) -> std::result::Result<(), Status> {
+        match slba.checked_add(nlb) {
+            Some(end) if end <= self.sectors() => Ok(()),
+            _ => Err(Status::LBA_OUT_OF_RANGE),
+        }
+    }
+
+    fn identify(&self) -> Vec<u8> {
+        let mut id = vec![0u8; IDENTIFY_SIZE];
+        let sectors = self.sectors().to_le_bytes();
+        // Size, capacity and utilization
+        for offset in [0, 8, 16] {
+            id[offset..offset + 8].copy_from_slice(&sectors);
+        }
+        // Write protected
+        id[99] = self.disk.read_only() as u8;
+        id[104..112].copy_from_slice(&self.eui64);
+        id[112..120].copy_from_slice(&self.eui64.map(|b| !b));
+        id[120..128].copy_from_slice(&self.eui64);
+        // LBA format 0: no metadata, 2^9 byte LBAs
+        id[128..132].copy_from_slice(&(9u32 << 16).to_le_bytes());
+        id
+    }
+}
+
+impl std::fmt::Debug for NvmeNamespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NvmeNamespace")
+            .field("size", &self.disk.size())
+            .field("read_only", &self.disk.read_only())
+            .finish()
+    }
+}
+
+/// FNV-1a, for identifiers that must not change between runs
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Space padded ASCII field
+fn put_string(field: &mut [u8], s: &str) {
+    field.fill(b' ');
+    let len = s.len().min(field.len());
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+pub struct Nvme {
+    config: PciConfig,
+    mem: GuestMem,
+    /// One vector per completion queue
+    msix: Msix,
+    serial: String,
+    namespaces: Vec<NvmeNamespace>,
+    cc: u32,
+    csts: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    /// By queue id, 0 being the admin one
+    sqs: Vec<Option<SubmissionQueue>>,
+    cqs: Vec<Option<CompletionQueue>>,
+    features: BTreeMap<u8, u32>,
+    /// Asynchronous Event Requests held until an event, never as nothing is reported
+    aers: Vec<u16>,
+}
+
+impl std::fmt::Debug for Nvme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Nvme")
+            .field("serial", &self.serial)
+            .field("namespaces", &self.namespaces)
+            .field("cc", &self.cc)
+            .field("csts", &self.csts)
+            .finish()
+    }
+}
+
+fn default_features() -> BTreeMap<u8, u32> {
+    BTreeMap::from([
+        (FEATURE_ARBITRATION, 0),
+        (FEATURE_POWER_MANAGEMENT, 0),
+        (FEATURE_TEMPERATURE_THRESHOLD, WARNING_TEMPERATURE as u32),
+        (FEATURE_ERROR_RECOVERY, 0),
+        (FEATURE_VOLATILE_WRITE_CACHE, 1),
+        (FEATURE_NUMBER_OF_QUEUES, ((MAX_IO_QUEUES - 1) * 0x10001) as u32),
+        (FEATURE_INTERRUPT_COALESCING, 0),
+        (FEATURE_INTERRUPT_VECTOR_CONFIG, 0),
+        (FEATURE_WRITE_ATOMICITY, 0),
+        (FEATURE_ASYNC_EVENT_CONFIG, 0),
+    ])
+}
+
+/// Guest ranges of a PRP1/PRP2 pair covering `len` bytes, `page` being the controller memory page size
+fn prp_ranges(mem: &GuestMem, page: u64, prp1: u64, prp2: u64, len: usize) -> std::result::Result<Vec<(u64, usize)>, Status> {
+    let first = ((page - (prp1 & (page - 1))) as usize).min(len);
+    let mut ranges = vec![(prp1, first)];
+    let mut remaining = len - first;
+    if remaining == 0 {
+        return Ok(ranges);
+    }
+    if remaining as u64 <= page {
+        ranges.push((prp2, remaining));
+        return Ok(ranges);
+    }
+    // Every list page gives at least one data page, a guest list looping on
+    // itself or too short to hold one would never end otherwise
+    let mut list = prp2;
+    for _ in 0..len.div_ceil(page as usize) {
+        if list & 7 != 0 {
+            return Err(Status::INVALID_PRP_OFFSET);
+        }
+        let count = ((page - (list & (page - 1))) / 8) as usize;
+        if count < 2 && remaining as u64 > page {
+            return Err(Status::INVALID_PRP_OFFSET);
+        }
+        let mut raw = vec![0u8; count * 8];
+        mem.read_slice(&mut raw, GuestAddress(list))?;
+        for (i, entry) in raw.chunks_exact(8).enumerate() {
+            let entry = u64::from_le_bytes(entry.try_into().unwrap());
+            // The last entry of a list page points to the next one
+            if i == count - 1 && remaining as u64 > page {
+                list = entry;
+                break;
+            }
+            let chunk = remaining.min(page as usize);
+            ranges.push((entry, chunk));
+            remaining -= chunk;
+            if remaining == 0 {
+                return Ok(ranges);
+            }
+        }
+    }
+    Err(Status::INVALID_PRP_OFFSET)
+}
+
+impl Nvme {
+    /// Namespace ids follow the order of `namespaces`, from 1
+    pub fn new(mut namespaces: Vec<NvmeNamespace>, serial: &str, mem: GuestMem, msi: MsiSender) -> Self {
+        let mut config = PciConfig::new(NVME_VENDOR_ID, NVME_DEVICE_ID, NVME_CLASS, 1);
+        config.set_subsystem(NVME_VENDOR_ID, 0x370d);
+        config.add_bar(0, PciBar { size: BAR_SIZE, kind: BarKind::Mem64, prefetchable: false });
+        let msix = Msix::new(&mut config, MAX_IO_QUEUES + 1, 0, MSIX_TABLE, MSIX_PBA, msi);
+        let serial: String = serial.chars().take(20).collect();
+        for (i, ns) in namespaces.iter_mut().enumerate() {
+            let mut key = serial.as_bytes().to_vec();
+            key.extend_from_slice(&(i as u32 + 1).to_le_bytes());
+            ns.eui64 = fnv1a(&key).to_be_bytes();
+        }
+        Self {
+            config,
+            mem,
+            msix,
+            serial,
+            namespaces,
+            cc: 0,
+            csts: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+            sqs: vec![None; MAX_IO_QUEUES + 1],
+            cqs: vec![None; MAX_IO_QUEUES + 1],
+            features: default_features(),
+            aers: vec![],
+        }
+    }
+
+    fn cap(&self) -> u64 {
+        MAX_QUEUE_ENTRIES | CAP_CQR | READY_TIMEOUT << 24 | CAP_CSS_NVM | CAP_MPSMAX << 52
+    }
+
+    fn page_size(&self) -> u64 {
+        MIN_PAGE_SIZE << ((self.cc >> CC_MPS_SHIFT) & 0xf)
+    }
+
+    fn reset(&mut self) {
+        debug!("NVMe controller reset");
+        self.sqs.fill(None);
+        self.cqs.fill(None);
+        self.features = default_features();
+        self.aers.clear();
+        self.csts = 0;
+    }
+
+    fn write_cc(&mut self, value: u32) {
+        let old = std::mem::replace(&mut self.cc, value);
+        if old & CC_EN == 0 && value & CC_EN != 0 {
+            let mps = (value >> CC_MPS_SHIFT) & 0xf;
+            if mps as u64 > CAP_MPSMAX {
+                warn!("NVMe enabled with a {} byte page size, past CAP.MPSMAX", MIN_PAGE_SIZE << mps);
+                self.csts |= CSTS_CFS;
+                return;
+            }
+            let sq_size = (self.aqa & 0xfff) as u16 + 1;
+            let cq_size = ((self.aqa >> 16) & 0xfff) as u16 + 1;
+            self.sqs[0] = Some(SubmissionQueue { addr: self.asq & !0xfff, size: sq_size, cqid: 0, ..Default::default() });
+            self.cqs[0] = Some(CompletionQueue {
+                addr: self.acq & !0xfff,
+                size: cq_size,
+                phase: true,
+                interrupts: true,
+                ..Default::default()
+            });
+            self.csts |= CSTS_RDY;
+            info!("NVMe controller enabled, {} namespaces", self.namespaces.len());
+        } else if old & CC_EN != 0 && value & CC_EN == 0 {
+            self.reset();
+        }
+        if value & CC_SHN_MASK != 0 && old & CC_SHN_MASK == 0 {
+            for ns in self.namespaces.iter_mut() {
+                if let Err(e) = ns.disk.flush() {
+                    warn!("NVMe shutdown flush failed: {e}");
+                }
+            }
+            self.csts |= CSTS_SHST_COMPLETE;
+        }
+    }
+
+    /// Guest ranges of a PRP1/PRP2 pair covering `len` bytes
+    fn prp_ranges(&self, prp1: u64, prp2: u64, len: usize) -> std::result::Result<Vec<(u64, usize)>, Status> {
+        prp_ranges(&self.mem, self.page_size(), prp1, prp2, len)
+    }
+
+    fn write_guest(&self, cmd: &Command, data: &[u8]) -> std::result::Result<(), Status> {
+        let mut done = 0;
+        for (addr, len) in self.prp_ranges(cmd.prp1, cmd.prp2, data.len())? {
+            self.mem.write_slice(&data[done..done + len], GuestAddress(addr))?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn read_guest(&self, cmd: &Command, len: usize) -> std::result::Result<Vec<u8>, Status> {
+        let mut data = vec![0u8; len];
+        let mut done = 0;
+        for (addr, chunk) in self.prp_ranges(cmd.prp1, cmd.prp2, len)? {
+            self.mem.read_slice(&mut data[done..done + chunk], GuestAddress(addr))?;
+            done += chunk;
+        }
+        Ok(data)
+    }
+
+    fn identify_controller(&self) -> Vec<u8> {
+        let mut id = vec![0u8; IDENTIFY_SIZE];
+        id[0..2].copy_from_slice(&NVME_VENDOR_ID.to_le_bytes());
+        id[2..4].copy_from_slice(&NVME_VENDOR_ID.to_le_bytes());
+        put_string(&mut id[4..24], &self.serial);
+        put_string(&mut id[24..64], "VMM NVMe SSD");
+        put_string(&mut id[64..72], "1.0");
+        // Recommended arbitration burst, IEEE OUI
+        id[72] = 6;
+        id[73..76].copy_from_slice(&[0xe4, 0xd2, 0x5c]);
+        id[77] = MDTS;
+        id[78..80].copy_from_slice(&1u16.to_le_bytes());
+        id[80..84].copy_from_slice(&NVME_VERSION.to_le_bytes());
+        // Abort and AER limits, one read only firmware slot
+        id[258] = 3;
+        id[259] = AERL;
+        id[260] = 0x03;
+        id[266..268].copy_from_slice(&WARNING_TEMPERATURE.to_le_bytes());
+        id[268..270].copy_from_slice(&CRITICAL_TEMPERATURE.to_le_bytes());
+        // 64 byte submission and 16 byte completion entries
+        id[512] = 0x66;
+        id[513] = 0x44;
+        id[516..520].copy_from_slice(&(self.namespaces.len() as u32).to_le_bytes());
+        id[520..522].copy_from_slice(&ONCS.to_le_bytes());
+        // Volatile write cache present
+        id[525] = 1;
+        id[530] = 1;
+        put_string(&mut id[768..1024], &format!("nqn.2014-08.org.nvmexpress:uuid:{:016x}", fnv1a(self.serial.as_bytes())));
+        id[768 + 256 - 1] = 0;
+        // Power state 0: 25 W
+        id[2048..2050].copy_from_slice(&2500u16.to_le_bytes());
+        id
+    }
+
+    fn identify(&self, cmd: &Command) -> std::result::Result<u32, Status> {
+        let data = match cmd.cdw10 & 0xff {
+            CNS_NAMESPACE => match self.namespaces.get((cmd.nsid as usize).wrapping_sub(1)) {
+                Some(ns) => ns.identify(),
+                None => return Err(Status::INVALID_NAMESPACE),
+            },
+            CNS_CONTROLLER => self.identify_controller(),
+            CNS_ACTIVE_NAMESPACES => {
+                let mut data = vec![0u8; IDENTIFY_SIZE];
+                let active = (1..=self.namespaces.len() as u32).filter(|&nsid| nsid > cmd.nsid);
+                for (i, nsid) in active.enumerate() {
+                    data[i * 4..i * 4 + 4].copy_from_slice(&nsid.to_le_bytes());
+                }
+                data
+            }
+            CNS_NAMESPACE_DESCRIPTORS => {
+                let ns = self.namespaces.get((cmd.nsid as usize).wrapping_sub(1)).ok_or(Status::INVALID_NAMESPACE)?;
+                let mut data = vec![0u8; IDENTIFY_SIZE];
+                // EUI-64 descriptor
+                data[..4].copy_from_slice(&[1, 8, 0, 0]);
+                data[4..12].copy_from_slice(&ns.eui64);
+                data
+            }
+            cns => {
+                debug!("NVMe: unsupported identify CNS {cns}");
+                return Err(Status::INVALID_FIELD);
+            }
+        };
+        self.write_guest(cmd, &data)?;
+        Ok(0)
+    }
+
+    /// Largest data transfer of a command, MDTS in controller pages
+    fn max_transfer(&self) -> usize {
+        (self.page_size() << MDTS) as usize
+    }
+
+    /// The transfer stops at the end of the log and at MDTS: the guest
+    /// asks for up to 16 GiB, there are a few hundred bytes to give
+    fn get_log_page(&self, cmd: &Command) -> std::result::Result<u32, Status> {
+        let requested = ((cmd.cdw10 >> 16) as usize | (cmd.cdw11 as usize & 0xffff) << 16) * 4 + 4;
+        let mut data = match cmd.cdw10 as u8 {
+            // ELPE is 0, a single empty entry
+            LOG_ERROR => vec![0u8; ERROR_LOG_ENTRY_SIZE],
+            LOG_SMART => {
+                let mut data = vec![0u8; LOG_PAGE_SIZE];
+                data[1..3].copy_from_slice(&TEMPERATURE.to_le_bytes());
+                // Available spare and its threshold
+                data[3..5].copy_from_slice(&[100, 10]);
+                data
+            }
+            LOG_FIRMWARE_SLOT => {
+                let mut data = vec![0u8; LOG_PAGE_SIZE];
+                data[0] = 1;
+                data[8..16].copy_from_slice(b"1.0     ");
+                data
+            }
+            lid => {
+                debug!("NVMe: unsupported log page 0x{lid:02x}");
+                return Err(Status::INVALID_FIELD);
+            }
+        };
+        data.truncate(requested.min(self.max_transfer()));
+        self.write_guest(cmd, &data)?;
+        Ok(0)
+    }
+
+    fn create_cq(&mut self, cmd: &Command) -> std::result::Result<u32, Status> {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        let size = (cmd.cdw10 >> 16) as u64 + 1;
+        let vector = (cmd.cdw11 >> 16) as u16;
+        if qid == 0 || qid >= self.cqs.len() || self.cqs[qid].is_some() {
+            return Err(Status::INVALID_QUEUE_ID);
+        }
+        if !(2..=MAX_QUEUE_ENTRIES + 1).contains(&size) {
+            return Err(Status::INVALID_QUEUE_SIZE);
+        }
+        if vector as usize >= self.msix.vectors() {
+            return Err(Status::INVALID_INTERRUPT_VECTOR);
+        }
+        self.cqs[qid] = Some(CompletionQueue {
+            addr: cmd.prp1 & !0xfff,
+            size: size as u16,
+            phase: true,
+            vector,
+            interrupts: cmd.cdw11 & 2 != 0,
+            ..Default::default()
+        });
+        Ok(0)
+    }
+
+    fn create_sq(&mut self, cmd: &Command) -> std::result::Result<u32, Status> {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        let size = (cmd.cdw10 >> 16) as u64 + 1;
+        let cqid = (cmd.cdw11 >> 16) as u16;
+        if qid == 0 || qid >= self.sqs.len() || self.sqs[qid].is_some() {
+            return Err(Status::INVALID_QUEUE_ID);
+        }
+        if !(2..=MAX_QUEUE_ENTRIES + 1).contains(&size) {
+            return Err(Status::INVALID_QUEUE_SIZE);
+        }
+        if cqid == 0 || self.cqs.get(cqid as usize).is_none_or(|cq| cq.is_none()) {
+            return Err(Status::INVALID_COMPLETION_QUEUE);
+        }
+        self.sqs[qid] = Some(SubmissionQueue { addr: cmd.prp1 & !0xfff, size: size as u16, cqid, ..Default::default() });
+        Ok(0)
+    }
+
+    fn delete_queue(&mut self, cmd: &Command) -> std::result::Result<u32, Status> {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        if qid == 0 || qid >= self.sqs.len() {
+            return Err(Status::INVALID_QUEUE_ID);
+        }
+        if cmd.opcode == ADMIN_DELETE_SQ {
+            self.sqs[qid].take().ok_or(Status::INVALID_QUEUE_ID)?;
+        } else {
+            if self.cqs[qid].is_none() {
+                return Err(Status::INVALID_QUEUE_ID);
+            }
+            // Its submission queues go first
+            if self.sqs.iter().flatten().any(|sq| sq.cqid as usize == qid) {
+                return Err(Status::INVALID_QUEUE_DELETION);
+            }
+            self.cqs[qid] = None;
+        }
+        Ok(0)
+    }
+
+    fn features(&mut self, cmd: &Command) -> std::result::Result<u32, Status> {
+        let fid = cmd.cdw10 as u8;
+        let Some(value) = self.features.get_mut(&fid) else {
+            debug!("NVMe: unsupported feature 0x{fid:02x}");
+            return Err(Status::INVALID_FIELD);
+        };
+        if cmd.opcode == ADMIN_GET_FEATURES {
+            return Ok(*value);
+        }
+        match fid {
+            // Allocated once and for all, whatever was asked
+            FEATURE_NUMBER_OF_QUEUES => {}
+            FEATURE_VOLATILE_WRITE_CACHE => *value = cmd.cdw11 & 1,
+            _ => *value = cmd.cdw11,
+        }
+        Ok(*value)
+    }
+
+    /// Returns the completion dword 0, `None` to hold the completion back
+    fn admin_command(&mut self, cmd: &Command) -> std::result::Result<Option<u32>, Status> {
+        let result = match cmd.opcode {
+            ADMIN_DELETE_SQ | ADMIN_DELETE_CQ => self.delete_queue(cmd)?,
+            ADMIN_CREATE_SQ => self.create_sq(cmd)?,
+            ADMIN_CREATE_CQ => self.create_cq(cmd)?,
+            ADMIN_GET_LOG_PAGE => self.get_log_page(cmd)?,
+            ADMIN_IDENTIFY => self.identify(cmd)?,
+            // Too late, every command already completed
+            ADMIN_ABORT => 1,
+            ADMIN_SET_FEATURES | ADMIN_GET_FEATURES => self.features(cmd)?,
+            ADMIN_ASYNC_EVENT_REQUEST => {
+                if self.aers.len() > AERL as usize {
+                    // Asynchronous Event Request Limit Exceeded
+                    return Err(Status(0x105));
+                }
+                self.aers.push(cmd.cid);
+                return Ok(None);
+            }
+            opcode => {
+                debug!("NVMe: unsupported admin command 0x{opcode:02x}");
+                return Err(Status::INVALID_OPCODE);
+            }
+        };
+        Ok(Some(result))
+    }
+
+    fn io_command(&mut self, cmd: &Command) -> std::result::Result<(), Status> {
+        let max_transfer = self.max_transfer();
+        let idx = (cmd.nsid as usize).wrapping_sub(1);
+        if idx >= self.namespaces.len() {
+            return Err(Status::INVALID_NAMESPACE);
+        }
+        let slba = cmd.cdw10 as u64 | (cmd.cdw11 as u64) << 32;
+        let nlb = (cmd.cdw12 & 0xffff) as u64 + 1;
+        match cmd.opcode {
+            NVM_FLUSH => self.namespaces[idx].disk.flush().map_err(|e| {
+                warn!("NVMe: flush failed: {e}");
+                Status::WRITE_FAULT
+            }),
+            NVM_READ => {
+                self.namespaces[idx].check_range(slba, nlb)?;
+                let len = (nlb * SECTOR_SIZE) as usize;
+                if len > max_transfer {
+                    return Err(Status::INVALID_FIELD);
+                }
+                let mut data = vec![0u8; len];
+                self.namespaces[idx].disk.read_at(&mut data, slba * SECTOR_SIZE).map_err(|e| {
+                    warn!("NVMe: read at LBA {slba} failed: {e}");
+                    Status::UNRECOVERED_READ_ERROR
+                })?;
+                self.write_guest(cmd, &data)
+            }
+            NVM_WRITE | NVM_WRITE_ZEROES => {
+                let ns = &self.namespaces[idx];
+                ns.check_range(slba, nlb)?;
+                if ns.disk.read_only() {
+                    return Err(Status::WRITE_TO_READ_ONLY);
+                }
+                let len = (nlb * SECTOR_SIZE) as usize;
+                let data = if cmd.opcode == NVM_WRITE {
+                    if len > max_transfer {
+                        return Err(Status::INVALID_FIELD);
+                    }
+                    self.read_guest(cmd, len)?
+                } else {
+                    vec![0u8; len]
+                };
+                self.namespaces[idx].disk.write_at(&data, slba * SECTOR_SIZE).map_err(|e| {
+                    warn!("NVMe: write at LBA {slba} failed: {e}");
+                    Status::WRITE_FAULT
+                })
+            }
+            NVM_DATASET_MANAGEMENT => {
+                if cmd.cdw11 & DSM_DEALLOCATE == 0 || self.namespaces[idx].disk.read_only() {
+                    return Ok(());
+                }
+                let ranges = self.read_guest(cmd, ((cmd.cdw10 & 0xff) as usize + 1) * DSM_RANGE_SIZE)?;
+                let ns = &mut self.namespaces[idx];
+                for range in ranges.chunks_exact(DSM_RANGE_SIZE) {
+                    let nlb = u32::from_le_bytes(range[4..8].try_into().unwrap()) as u64;
+                    let slba = u64::from_le_bytes(range[8..16].try_into().unwrap());
+                    ns.check_range(slba, nlb)?;
+                    ns.disk.discard(slba * SECTOR_SIZE, nlb * SECTOR_SIZE).map_err(|e| {
+                        warn!("NVMe: deallocate at LBA {slba} failed: {e}");
+                        Status::INTERNAL_ERROR
+                    })?;
+                }
+                Ok(())
+            }
+            opcode => {
+                debug!("NVMe: unsupported I/O command 0x{opcode:02x}");
+                Err(Status::INVALID_OPCODE)
+            }
+        }
+    }
+
+    fn post_completion(&mut self, cqid: u16, result: u32, sqid: u16, sq_head: u16, cid: u16, status: Status) {
+        let Some(cq) = self.cqs[cqid as usize].as_mut() else {
+            return;
+        };
+        let mut entry = [0u8; CQ_ENTRY_SIZE as usize];
+        entry[0..4].copy_from_slice(&result.to_le_bytes());
+        entry[8..12].copy_from_slice(&(sq_head as u32 | (sqid as u32) << 16).to_le_bytes());
+        let status = cid as u32 | (cq.phase as u32) << 16 | (status.0 as u32) << 17;
+        entry[12..16].copy_from_slice(&status.to_le_bytes());
+        if let Err(e) = self.mem.write_slice(&entry, GuestAddress(cq.addr + cq.tail as u64 * CQ_ENTRY_SIZE)) {
+            warn!("NVMe: can't post completion: {e}");
+        }
+        cq.tail = (cq.tail + 1) % cq.size;
+        if cq.tail == 0 {
+            cq.phase = !cq.phase;
+        }
+    }
+
+    /// Run the commands of submission queue `sqid`, returns the completion queue to interrupt
+    fn process_sq(&mut self, sqid: usize) -> Option<u16> {
+        let mut posted = None;
+        loop {
+            let sq = self.sqs[sqid]?;
+            let cq = self.cqs[sq.cqid as usize]?;
+            // A full completion queue holds the rest back until its head moves
+            if sq.head == sq.tail || cq.full() {
+                return posted;
+            }
+            let mut raw = [0u8; SQ_ENTRY_SIZE as usize];
+            if let Err(e) = self.mem.read_slice(&mut raw, GuestAddress(sq.addr + sq.head as u64 * SQ_ENTRY_SIZE)) {
+                error!("NVMe: can't read submission queue {sqid}: {e}");
+                return posted;
+            }
+            let head = (sq.head + 1) % sq.size;
+            self.sqs[sqid].as_mut().unwrap().head = head;
+            let cmd = Command::parse(&raw);
+            let completion = if cmd.psdt != 0 {
+                Err(Status::INVALID_FIELD)
+            } else if sqid == 0 {
+                self.admin_command(&cmd)
+            } else {
+                self.io_command(&cmd).map(|_| Some(0))
+            };
+            let (result, status) = match completion {
+                Ok(None) => continue,
+                Ok(Some(result)) => (result, Status::SUCCESS),
+                Err(status) => (0, status),
+            };
+            // The admin command may have deleted the queues
+            if self.sqs[sqid].is_none() {
+                return posted;
+            }
+            self.post_completion(sq.cqid, result, sqid as u16, head, cmd.cid, status);
+            posted = Some(sq.cqid);
+        }
+    }
+
+    fn interrupt(&mut self, cqid: u16) {
+        let Some(cq) = self.cqs[cqid as usize] else {
+            return;
+        };
+        if cq.interrupts {
+            self.msix.signal(&self.config, cq.vector);
+        }
+    }
+
+    fn doorbell(&mut self, idx: usize, value: u32) {
+        let qid = idx / 2;
+        if self.csts & CSTS_RDY == 0 || qid >= self.sqs.len() {
+            return;
+        }
+        let value = value as u16;
+        let cqids: Vec<u16> = if idx.is_multiple_of(2) {
+            let Some(sq) = self.sqs[qid].as_mut().filter(|sq| value < sq.size) else {
+                warn!("NVMe: bad submission queue {qid} doorbell {value}");
+                return;
+            };
+            sq.tail = value;
+            self.process_sq(qid).into_iter().collect()
+        } else {
+            let Some(cq) = self.cqs[qid].as_mut().filter(|cq| value < cq.size) else {
+                warn!("NVMe: bad completion queue {qid} doorbell {value}");
+                return;
+            };
+            cq.head = value;
+            // Room was made for the submission queues that were stuck
+            let stuck: Vec<usize> = (0..self.sqs.len())
+                .filter(|&sqid| self.sqs[sqid].is_some_and(|sq| sq.cqid as usize == qid && sq.head != sq.tail))
+                .collect();
+            stuck.into_iter().filter_map(|sqid| self.process_sq(sqid)).collect()
+        };
+        let mut cqids = cqids;
+        cqids.dedup();
+        for cqid in cqids {
+            self.interrupt(cqid);
+        }
+    }
+
+    fn register_read(&self, offset: u64) -> u32 {
+        match offset {
+            REG_CAP => self.cap() as u32,
+            0x04 => (self.cap() >> 32) as u32,
+            REG_VS => NVME_VERSION,
+            REG_INTMS | REG_INTMC => 0,
+            REG_CC => self.cc,
+            REG_CSTS => self.csts,
+            REG_AQA => self.aqa,
+            REG_ASQ => self.asq as u32,
+            0x2c => (self.asq >> 32) as u32,
+            REG_ACQ => self.acq as u32,
+            0x34 => (self.acq >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn register_write(&mut self, offset: u64, value: u32) {
+        match offset {
+            REG_CC => self.write_cc(value),
+            REG_AQA => self.aqa = value & 0x0fff_0fff,
+            REG_ASQ => self.asq = (self.asq & !0xffff_ffff) | value as u64,
+            0x2c => self.asq = (self.asq & 0xffff_ffff) | (value as u64) << 32,
+            REG_ACQ => self.acq = (self.acq & !0xffff_ffff) | value as u64,
+            0x34 => self.acq = (self.acq & 0xffff_ffff) | (value as u64) << 32,
+            // Pin based and MSI interrupt masks don't apply to MSI-X
+            REG_INTMS | REG_INTMC => {}
+            _ => debug!("NVMe register write 0x{offset:x} ignored"),
+        }
+    }
+}
+
+fn put_value(data: &mut [u8], value: u64) {
+    let len = data.len().min(8);
+    data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+}
+
+fn get_u32(data: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    let len = data.len().min(4);
+    bytes[..len].copy_from_slice(&data[..len]);
+    u32::from_le_bytes(bytes)
+}
+
+impl PciDevice for Nvme {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn bar_read(&mut self, _bar: usize, offset: u64, data: &mut [u8]) {
+        let value = match offset {
+            REG_CAP if data.len() == 8 => self.cap(),
+            REG_ASQ | REG_ACQ if data.len() == 8 => self.register_read(offset) as u64 | (self.register_read(offset + 4) as u64) << 32,
+            0..REGS_DOORBELLS => self.register_read(offset) as u64,
+            MSIX_TABLE..MSIX_PBA => self.msix.table_read(offset - MSIX_TABLE) as u64,
+            MSIX_PBA..BAR_SIZE if offset == MSIX_PBA => self.msix.pba(),
+            _ => 0,
+        };
+        put_value(data, value);
+    }
+
+    fn bar_write(&mut self, _bar: usize, offset: u64, data: &[u8]) {
+        match offset {
+            0..REGS_DOORBELLS => {
+                self.register_write(offset, get_u32(data));
+                if data.len() == 8 {
+                    self.register_write(offset + 4, get_u32(&data[4..]));
+                }
+            }
+            REGS_DOORBELLS..MSIX_TABLE => self.doorbell(((offset - REGS_DOORBELLS) / 4) as usize, get_u32(data)),
+            MSIX_TABLE..MSIX_PBA => self.msix.table_write(&self.config, offset - MSIX_TABLE, get_u32(data)),
+            _ => debug!("NVMe BAR write 0x{offset:x} ignored"),
+        }
+    }
+
+    fn config_written(&mut self, offset: usize, len: usize) {
+        self.msix.config_written(&self.config, offset, len);
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        for reg in [self.cc, self.csts, self.aqa] {
+            state.put_u32(reg);
+        }
+        state.put_u64(self.asq);
+        state.put_u64(self.acq);
+        for sq in &self.sqs {
+            let sq = sq.unwrap_or_default();
+            state.put_u64(sq.addr);
+            for value in [sq.size, sq.head, sq.tail, sq.cqid] {
+                state.put_u16(value);
+            }
+        }
+        for cq in &self.cqs {
+            let cq = cq.unwrap_or_default();
+            state.put_u64(cq.addr);
+            for value in [cq.size, cq.head, cq.tail, cq.vector] {
+                state.put_u16(value);
+            }
+            state.put_u8(cq.phase as u8 | (cq.interrupts as u8) << 1);
+        }
+        state.put_u32(self.features.len() as u32);
+        for (&fid, &value) in &self.features {
+            state.put_u8(fid);
+            state.put_u32(value);
+        }
+        state.put_u32(self.aers.len() as u32);
+        for &cid in &self.aers {
+            state.put_u16(cid);
+        }
+        self.msix.save(state);
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.cc = state.get_u32()?;
+        self.csts = state.get_u32()?;
+        self.aqa = state.get_u32()?;
+        self.asq = state.get_u64()?;
+        self.acq = state.get_u64()?;
+        for sq in self.sqs.iter_mut() {
+            let addr = state.get_u64()?;
+            let (size, head, tail, cqid) = (state.get_u16()?, state.get_u16()?, state.get_u16()?, state.get_u16()?);
+            // Size 0 marks a queue that doesn't exist
+            *sq = (size != 0).then_some(SubmissionQueue { addr, size, head, tail, cqid });
+        }
+        for cq in self.cqs.iter_mut() {
+            let addr = state.get_u64()?;
+            let (size, head, tail, vector) = (state.get_u16()?, state.get_u16()?, state.get_u16()?, state.get_u16()?);
+            let flags = state.get_u8()?;
+            *cq = (size != 0).then_some(CompletionQueue {
+                addr,
+                size,
+                head,
+                tail,
+                vector,
+                phase: flags & 1 != 0,
+                interrupts: flags & 2 != 0,
+            });
+        }
+        self.features.clear();
+        for _ in 0..state.get_u32()? {
+            let fid = state.get_u8()?;
+            self.features.insert(fid, state.get_u32()?);
+        }
+        self.aers = (0..state.get_u32()?).map(|_| state.get_u16()).collect::<snapshot::Result<_>>()?;
+        self.msix.restore(state)
+    }
+}
+
+impl Vm {
+    /// Plug an NVMe controller with these namespaces in `slot` of bus 0
+    pub fn add_nvme(&mut self, slot: u8, namespaces: Vec<NvmeNamespace>, serial: &str) -> Result<()> {
+        for (i, ns) in namespaces.iter().enumerate() {
+            info!("NVMe namespace {}: {} bytes{}", i + 1, ns.disk.size(), if ns.disk.read_only() { ", read only" } else { "" });
+        }
+        let nvme = Nvme::new(namespaces, serial, self.ram.guest_mem_map.clone(), self.msi_sender()?);
+        self.add_pci_device(slot, std::sync::Arc::new(std::sync::Mutex::new(nvme)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: u64 = 0x1000;
+
+    fn mem_with(entries: &[(u64, u64)]) -> GuestMem {
+        let mem = GuestMem::from_ranges(&[(GuestAddress(0), 0x100000)]).unwrap();
+        for &(addr, value) in entries {
+            mem.write_obj(value, GuestAddress(addr)).unwrap();
+        }
+        mem
+    }
+
+    #[test]
+    fn prp_list_chained() {
+        // 600 pages: 511 in the first list page, its last entry pointing to the second one
+        let mut entries: Vec<(u64, u64)> = (0..511).map(|i| (0x10000 + i * 8, 0x80000 + i * PAGE)).collect();
+        entries.push((0x10000 + 511 * 8, 0x20000));
+        entries.extend((0..88).map(|i| (0x20000 + i * 8, 0x40000 + i * PAGE)));
+        let mem = mem_with(&entries);
+        let ranges = prp_ranges(&mem, PAGE, 0x7000, 0x10000, 600 * PAGE as usize).unwrap();
+        assert_eq!(ranges.len(), 600);
+        assert_eq!(ranges[1], (0x80000, PAGE as usize));
+        assert_eq!(ranges[511], (0x80000 + 510 * PAGE, PAGE as usize));
+        assert_eq!(ranges[512], (0x40000, PAGE as usize));
+        assert_eq!(ranges.iter().map(|(_, len)| len).sum::<usize>(), 600 * PAGE as usize);
+    }
+
+    #[test]
+    fn prp_list_looping_on_itself() {
+        // Two entries before the end of the page, one data page and a pointer back to the list
+        let mem = mem_with(&[(0x10ff0, 0x40000), (0x10ff8, 0x10ff0)]);
+        let ranges = prp_ranges(&mem, PAGE, 0x7000, 0x10ff0, 0x100000).unwrap();
+        assert_eq!(ranges.len(), 256);
+        assert!(ranges[1..255].iter().all(|&range| range == (0x40000, PAGE as usize)));
+        // The last page fits in the pointer slot
+        assert_eq!(ranges[255], (0x10ff0, PAGE as usize));
+    }
+
+    #[test]
+    fn prp_list_without_room_for_data() {
+        // A single entry left in the page, it can only chain
+        let mem = mem_with(&[(0x10ff8, 0x10ff8)]);
+        assert_eq!(prp_ranges(&mem, PAGE, 0x7000, 0x10ff8, 4 * PAGE as usize), Err(Status::INVALID_PRP_OFFSET));
+        assert_eq!(prp_ranges(&mem, PAGE, 0x7000, 0x10ff4, 4 * PAGE as usize), Err(Status::INVALID_PRP_OFFSET));
+    }
+
+    #[test]
+    fn enable_with_a_page_size_too_large() {
+        let mem = GuestMem::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut nvme = Nvme::new(vec![], "serial", mem, MsiSender::detached());
+        nvme.write_cc(CC_EN | 1 << CC_MPS_SHIFT);
+        assert_eq!(nvme.csts, CSTS_CFS);
+        // Cleared by the reset of the next disable
+        nvme.write_cc(0);
+        assert_eq!(nvme.csts, 0);
+        nvme.write_cc(CC_EN);
+        assert_eq!(nvme.csts, CSTS_RDY);
+        assert_eq!(nvme.page_size(), MIN_PAGE_SIZE);
+    }
+
+    #[test]
+    fn log_pages_stop_at_their_size() {
+        let mem = GuestMem::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let nvme = Nvme::new(vec![], "serial", mem.clone(), MsiSender::detached());
+        let get_log = |lid: u8, dwords: u32| {
+            mem.write_slice(&[0xaa; 0x2000], GuestAddress(0x1000)).unwrap();
+            // PRP2 only matters past the first page, an invalid one catches that
+            let cmd = Command {
+                opcode: ADMIN_GET_LOG_PAGE,
+                prp1: 0x1000,
+                prp2: 0xffff_ffff_0000,
+                cdw10: ((dwords - 1) & 0xffff) << 16 | lid as u32,
+                cdw11: (dwords - 1) >> 16,
+                ..Default::default()
+            };
+            nvme.get_log_page(&cmd)?;
+            let mut data = vec![0u8; 0x2000];
+            mem.read_slice(&mut data, GuestAddress(0x1000)).unwrap();
+            // No log holds 0xaa, the first one is where the transfer stopped
+            Ok(data.iter().position(|&b| b == 0xaa).unwrap())
+        };
+        assert_eq!(get_log(LOG_SMART, 1), Ok(4));
+        assert_eq!(get_log(LOG_SMART, u32::MAX), Ok(LOG_PAGE_SIZE));
+        assert_eq!(get_log(LOG_ERROR, 0x10000), Ok(ERROR_LOG_ENTRY_SIZE));
+        assert_eq!(get_log(LOG_FIRMWARE_SLOT, 4), Ok(16));
+        assert_eq!(get_log(0xc0, 1), Err(Status::INVALID_FIELD));
+    }
+}
@@ -0,0 +1,268 @@
+//! Guest virtual memory, walking the guest page tables straight from RAM.
+//! Only the address space of the current CR3 is visible.
+
+use kvm_bindings::kvm_sregs;
+use vm_memory::{ Bytes, GuestAddress };
+
+use super::ram::Ram;
+
+const CR0_PG: u64 = 1 << 31;
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+const EFER_LMA: u64 = 1 << 10;
+
+const PTE_PRESENT: u64 = 1;
+const PTE_PS: u64 = 1 << 7;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Bits 31:22 of a 4 MiB page, PSE-36 puts bits 39:32 in PDE bits 20:13
+const PDE_4M_ADDR_MASK: u64 = 0xffc0_0000;
+const PDE_4M_HIGH_MASK: u64 = 0x001f_e000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    /// Paging disabled, virtual == physical
+    Off,
+    Legacy32,
+    Pae,
+    Long4,
+    Long5,
+}
+
+impl PagingMode {
+    pub fn from_sregs(sregs: &kvm_sregs) -> Self {
+        if sregs.cr0 & CR0_PG == 0 {
+            PagingMode::Off
+        } else if sregs.efer & EFER_LMA != 0 {
+            if sregs.cr4 & CR4_LA57 != 0 { PagingMode::Long5 } else { PagingMode::Long4 }
+        } else if sregs.cr4 & CR4_PAE != 0 {
+            PagingMode::Pae
+        } else {
+            PagingMode::Legacy32
+        }
+    }
+
+    /// (shift, index bits, leaf allowed with PS) for each level, root first
+    fn levels(self) -> &'static [(u32, u32, bool)] {
+        match self {
+            PagingMode::Off => &[],
+            PagingMode::Legacy32 => &[(22, 10, true), (12, 10, true)],
+            PagingMode::Pae => &[(30, 2, false), (21, 9, true), (12, 9, true)],
+            PagingMode::Long4 => &[(39, 9, false), (30, 9, true), (21, 9, true), (12, 9, true)],
+            PagingMode::Long5 =>
+                &[(48, 9, false), (39, 9, false), (30, 9, true), (21, 9, true), (12, 9, true)],
+        }
+    }
+
+    fn entry_size(self) -> u64 {
+        if self == PagingMode::Legacy32 { 4 } else { 8 }
+    }
+
+    /// Sign extend to a canonical address
+    fn canonical(self, virt: u64) -> u64 {
+        let bits = match self {
+            PagingMode::Long4 => 48,
+            PagingMode::Long5 => 57,
+            _ => return virt,
+        };
+        (((virt << (64 - bits)) as i64) >> (64 - bits)) as u64
+    }
+}
+
+/// A present leaf translation
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub virt: u64,
+    pub phys: u64,
+    pub size: u64,
+}
+
+pub struct PageWalker<'a> {
+    ram: &'a Ram,
+    mode: PagingMode,
+    root: u64,
+    /// 32-bit paging only maps 4 MiB pages with CR4.PSE, the other modes always honour PS
+    large_pages: bool,
+}
+
+impl<'a> PageWalker<'a> {
+    pub fn new(ram: &'a Ram, sregs: &kvm_sregs) -> Self {
+        let mode = PagingMode::from_sregs(sregs);
+        let root = match mode {
+            PagingMode::Pae => sregs.cr3 & 0xffff_ffe0,
+            _ => sregs.cr3 & PTE_ADDR_MASK,
+        };
+        let large_pages = mode != PagingMode::Legacy32 || sregs.cr4 & CR4_PSE != 0;
+        Self { ram, mode, root, large_pages }
+    }
+
+    fn read_entry(&self, table: u64, index: u64) -> Option<u64> {
+        let addr = GuestAddress(table + index * self.mode.entry_size());
+        if self.mode.entry_size() == 4 {
+            self.ram.guest_mem_map.read_obj::<u32>(addr).ok().map(u64::from)
+        } else {
+            self.ram.guest_mem_map.read_obj::<u64>(addr).ok()
+        }
+    }
+
+    /// Whether `entry` at `level` maps a page rather than pointing to the next table
+    fn is_leaf(&self, level: usize, entry: u64) -> bool {
+        let levels = self.mode.levels();
+        level == levels.len() - 1 || (levels[level].2 && self.large_pages && entry & PTE_PS != 0)
+    }
+
+    /// Physical address of the page a leaf entry maps, pages being `1 << shift` bytes
+    fn leaf_addr(&self, entry: u64, shift: u32) -> u64 {
+        if self.mode == PagingMode::Legacy32 && shift == 22 {
+            (entry & PDE_4M_ADDR_MASK) | ((entry & PDE_4M_HIGH_MASK) << 19)
+        } else {
+            entry & PTE_ADDR_MASK & !((1u64 << shift) - 1)
+        }
+    }
+
+    pub fn translate(&self, virt: u64) -> Option<u64> {
+        if self.mode == PagingMode::Off {
+            return Some(virt);
+        }
+        let mut table = self.root;
+        for (level, &(shift, bits, _)) in self.mode.levels().iter().enumerate() {
+            let index = (virt >> shift) & ((1 << bits) - 1);
+            let entry = self.read_entry(table, index)?;
+            if entry & PTE_PRESENT == 0 {
+                return None;
+            }
+            if self.is_leaf(level, entry) {
+                return Some(self.leaf_addr(entry, shift) | (virt & ((1u64 << shift) - 1)));
+            }
+            table = entry & PTE_ADDR_MASK;
+        }
+        None
+    }
+
+    /// Read guest virtual memory, `None` if any byte is unmapped
+    pub fn read_virt(&self, virt: u64, buf: &mut [u8]) -> Option<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let addr = virt.wrapping_add(done as u64);
+            let chunk = (0x1000 - (addr & 0xfff) as usize).min(buf.len() - done);
+            let phys = self.translate(addr)?;
+            self.ram.guest_mem_map
+                .read_slice(&mut buf[done..done + chunk], GuestAddress(phys))
+                .ok()?;
+            done += chunk;
+        }
+        Some(())
+    }
+
+    /// Call `f` on every present leaf mapping
+    pub fn for_each_mapping<F: FnMut(Mapping)>(&self, mut f: F) {
+        if self.mode == PagingMode::Off {
+            f(Mapping { virt: 0, phys: 0, size: self.ram.mem_size as u64 });
+            return;
+        }
+        self.walk_table(self.root, 0, 0, &mut f);
+    }
+
+    fn walk_table<F: FnMut(Mapping)>(&self, table: u64, level: usize, virt: u64, f: &mut F) {
+        let levels = self.mode.levels();
+        let (shift, bits, _) = levels[level];
+        for index in 0..1u64 << bits {
+            let Some(entry) = self.read_entry(table, index) else {
+                return;
+            };
+            if entry & PTE_PRESENT == 0 {
+                continue;
+            }
+            let virt = virt | (index << shift);
+            if self.is_leaf(level, entry) {
+                let phys = self.leaf_addr(entry, shift);
+                f(Mapping { virt: self.mode.canonical(virt), phys, size: 1u64 << shift });
+            } else {
+                self.walk_table(entry & PTE_ADDR_MASK, level + 1, virt, f);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CR0_PE: u64 = 1;
+
+    fn write_entry(ram: &Ram, table: u64, index: u64, entry: u64, size: u64) {
+        let addr = GuestAddress(table + index * size);
+        if size == 4 {
+            ram.guest_mem_map.write_obj(entry as u32, addr).unwrap();
+        } else {
+            ram.guest_mem_map.write_obj(entry, addr).unwrap();
+        }
+    }
+
+    fn sregs(cr4: u64, efer: u64) -> kvm_sregs {
+        kvm_sregs { cr0: CR0_PE | CR0_PG, cr3: 0x1000, cr4, efer, ..Default::default() }
+    }
+
+    #[test]
+    fn legacy32_large_pages() {
+        let ram = Ram::anonymous(0x80_0000);
+        // 4 KiB page through a page table
+        write_entry(&ram, 0x1000, 0, 0x2000 | PTE_PRESENT, 4);
+        write_entry(&ram, 0x2000, 5, 0x7000 | PTE_PRESENT, 4);
+        // 4 MiB pages, the second one above 4 GiB with PSE-36
+        write_entry(&ram, 0x1000, 1, 0x40_0000 | PTE_PS | PTE_PRESENT, 4);
+        write_entry(&ram, 0x1000, 2, 0xc0_0000 | (0x12 << 13) | PTE_PS | PTE_PRESENT, 4);
+
+        let pse = sregs(CR4_PSE, 0);
+        let walker = PageWalker::new(&ram, &pse);
+        assert_eq!(PagingMode::from_sregs(&pse), PagingMode::Legacy32);
+        assert_eq!(walker.translate(0x5abc), Some(0x7abc));
+        assert_eq!(walker.translate(0x41_2345), Some(0x41_2345));
+        assert_eq!(walker.translate(0x80_0010), Some(0x12_00c0_0010));
+        assert_eq!(walker.translate(0xc0_0000), None);
+        let mut mappings = vec![];
+        walker.for_each_mapping(|m| mappings.push((m.virt, m.phys, m.size)));
+        assert_eq!(mappings, [(0x5000, 0x7000, 0x1000), (0x40_0000, 0x40_0000, 0x40_0000), (0x80_0000, 0x12_00c0_0000, 0x40_0000)]);
+
+        // Without CR4.PSE the PS bit is ignored, the entry points to a page table
+        write_entry(&ram, 0x40_0000, 0x12, 0x3000 | PTE_PRESENT, 4);
+        let no_pse = sregs(0, 0);
+        let walker = PageWalker::new(&ram, &no_pse);
+        assert_eq!(walker.translate(0x41_2345), Some(0x3345));
+        assert_eq!(walker.translate(0x40_0000), None);
+    }
+
+    #[test]
+    fn long_mode_walk() {
+        let ram = Ram::anonymous(0x10_0000);
+        write_entry(&ram, 0x1000, 0, 0x2000 | PTE_PRESENT, 8);
+        write_entry(&ram, 0x1000, 511, 0x2000 | PTE_PRESENT, 8);
+        write_entry(&ram, 0x2000, 0, 0x3000 | PTE_PRESENT, 8);
+        write_entry(&ram, 0x3000, 0, 0x4000 | PTE_PRESENT, 8);
+        write_entry(&ram, 0x3000, 1, 0x20_0000 | PTE_PS | PTE_PRESENT, 8);
+        write_entry(&ram, 0x4000, 3, 0x9000 | PTE_PRESENT, 8);
+
+        let long4 = sregs(CR4_PAE, EFER_LMA);
+        let walker = PageWalker::new(&ram, &long4);
+        assert_eq!(PagingMode::from_sregs(&long4), PagingMode::Long4);
+        assert_eq!(walker.translate(0x3010), Some(0x9010));
+        assert_eq!(walker.translate(0x2f_ffff), Some(0x2f_ffff));
+        assert_eq!(walker.translate(0xffff_ff80_0000_3010), Some(0x9010));
+        assert_eq!(walker.translate(0x4000), None);
+        let mut mappings = vec![];
+        walker.for_each_mapping(|m| mappings.push((m.virt, m.phys, m.size)));
+        assert_eq!(mappings, [
+            (0x3000, 0x9000, 0x1000),
+            (0x20_0000, 0x20_0000, 0x20_0000),
+            (0xffff_ff80_0000_3000, 0x9000, 0x1000),
+            (0xffff_ff80_0020_0000, 0x20_0000, 0x20_0000),
+        ]);
+
+        let mut buf = [0u8; 4];
+        ram.guest_mem_map.write_slice(b"walk", GuestAddress(0x9ffe)).unwrap();
+        assert_eq!(walker.read_virt(0x3ffe, &mut buf), None);
+        write_entry(&ram, 0x4000, 4, 0xa000 | PTE_PRESENT, 8);
+        assert_eq!(walker.read_virt(0x3ffe, &mut buf), Some(()));
+        assert_eq!(&buf, b"walk");
+    }
+}
@@ -0,0 +1,634 @@
+//! PCI bus 0: config space through 0xCF8/0xCFC and ECAM, BARs mapped on the VM buses.
+//!
+//! Firmware sizes and places BARs itself. Every config write is followed by a
+//! look at where the enabled BARs of the device now live, and the PIO/MMIO bus
+//! ranges are moved to match.
+
+use std::{ collections::BTreeMap, sync::{ Arc, Mutex } };
+
+use kvm_ioctls::IoEventAddress;
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vmm_sys_util::eventfd::EventFd;
+
+use super::{
+    bus::BusDevice,
+    q35::{ Ich9Lpc, Q35Host, LPC_DEVICE },
+    snapshot::{ self, Snapshot, StateBuf, StateReader },
+    Vm,
+};
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+pub const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+pub const PCI_CONFIG_DATA: u16 = 0xcfc;
+
+/// Config space size of a PCIe function, the legacy mechanism reaches the first 256 bytes
+pub const PCI_CONFIG_SPACE_SIZE: usize = 0x1000;
+const PCI_LEGACY_CONFIG_SIZE: usize = 0x100;
+
+pub const PCI_VENDOR_ID: usize = 0x00;
+pub const PCI_COMMAND: usize = 0x04;
+pub const PCI_STATUS: usize = 0x06;
+pub const PCI_CLASS_REVISION: usize = 0x08;
+pub const PCI_HEADER_TYPE: usize = 0x0e;
+pub const PCI_BAR0: usize = 0x10;
+pub const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+pub const PCI_CAPABILITY_LIST: usize = 0x34;
+pub const PCI_INTERRUPT_LINE: usize = 0x3c;
+
+pub const PCI_COMMAND_IO: u16 = 1 << 0;
+pub const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+pub const PCI_COMMAND_MASTER: u16 = 1 << 2;
+pub const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEM64: u32 = 0x2 << 1;
+const BAR_PREFETCH: u32 = 1 << 3;
+
+/// First capability offset, right after the type 0 header
+const CAPABILITIES_START: usize = 0x40;
+
+pub const PCI_BAR_COUNT: usize = 6;
+/// Devices on bus 0
+pub const PCI_SLOTS: u8 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarKind {
+    Io,
+    Mem32,
+    /// Takes the next BAR register for the upper address bits
+    Mem64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciBar {
+    /// Power of two
+    pub size: u64,
+    pub kind: BarKind,
+    pub prefetchable: bool,
+}
+
+/// Where a BAR is mapped in its address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarMapping {
+    pub kind: BarKind,
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// Type 0 config space with per byte write masks
+#[derive(Debug, Clone)]
+pub struct PciConfig {
+    data: Vec<u8>,
+    writable: Vec<u8>,
+    bars: [Option<PciBar>; PCI_BAR_COUNT],
+    /// Mapping of every BAR on the VM buses, as last applied
+    mapped: [Option<BarMapping>; PCI_BAR_COUNT],
+    next_capability: usize,
+    last_capability: Option<usize>,
+}
+
+impl PciConfig {
+    /// `class` is class << 16 | subclass << 8 | programming interface
+    pub fn new(vendor: u16, device: u16, class: u32, revision: u8) -> Self {
+        let mut config = Self {
+            data: vec![0; PCI_CONFIG_SPACE_SIZE],
+            writable: vec![0; PCI_CONFIG_SPACE_SIZE],
+            bars: [None; PCI_BAR_COUNT],
+            mapped: [None; PCI_BAR_COUNT],
+            next_capability: CAPABILITIES_START,
+            last_capability: None,
+        };
+        config.set_u16(PCI_VENDOR_ID, vendor);
+        config.set_u16(PCI_VENDOR_ID + 2, device);
+        config.set_u32(PCI_CLASS_REVISION, class << 8 | revision as u32);
+        let command = PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER | PCI_COMMAND_INTX_DISABLE;
+        config.set_writable(PCI_COMMAND, &command.to_le_bytes());
+        // Cache line size, latency timer and interrupt line are plain storage
+        config.set_writable(0x0c, &[0xff, 0xff]);
+        config.set_writable(PCI_INTERRUPT_LINE, &[0xff]);
+        config
+    }
+
+    pub fn get_u8(&self, offset: usize) -> u8 {
+        self.data[offset]
+    }
+
+    pub fn get_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    pub fn get_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Set from the device side, write masks don't apply
+    pub fn set_u16(&mut self, offset: usize, value: u16) {
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn set_u32(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Bits of `mask` at `offset` become guest writable
+    pub fn set_writable(&mut self, offset: usize, mask: &[u8]) {
+        self.writable[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+
+    pub fn set_header_type(&mut self, header_type: u8) {
+        self.data[PCI_HEADER_TYPE] = header_type;
+    }
+
+    pub fn set_subsystem(&mut self, vendor: u16, id: u16) {
+        self.set_u16(PCI_SUBSYSTEM_VENDOR_ID, vendor);
+        self.set_u16(PCI_SUBSYSTEM_VENDOR_ID + 2, id);
+    }
+
+    pub fn command(&self) -> u16 {
+        self.get_u16(PCI_COMMAND)
+    }
+
+    /// Declare BAR `idx`, a 64 bit one also takes `idx + 1`
+    pub fn add_bar(&mut self, idx: usize, bar: PciBar) {
+        assert!(bar.size.is_power_of_two(), "BAR size must be a power of two");
+        let offset = PCI_BAR0 + idx * 4;
+        let (flags, min_size) = match bar.kind {
+            BarKind::Io => (BAR_IO, 4),
+            BarKind::Mem32 => (0, 16),
+            BarKind::Mem64 => (BAR_MEM64, 16),
+        };
+        let flags = if bar.prefetchable { flags | BAR_PREFETCH } else { flags };
+        let mask = !(bar.size.max(min_size) - 1);
+        self.set_u32(offset, flags);
+        let flag_bits = if bar.kind == BarKind::Io { 0x3 } else { 0xf };
+        self.set_writable(offset, &(mask as u32 & !flag_bits).to_le_bytes());
+        if bar.kind == BarKind::Mem64 {
+            self.set_writable(offset + 4, &((mask >> 32) as u32).to_le_bytes());
+        }
+        self.bars[idx] = Some(bar);
+    }
+
+    /// Address the guest programmed in BAR `idx`
+    pub fn bar_address(&self, idx: usize) -> Option<u64> {
+        let bar = self.bars[idx]?;
+        let low = self.get_u32(PCI_BAR0 + idx * 4) as u64;
+        Some(match bar.kind {
+            BarKind::Io => low & !0x3,
+            BarKind::Mem32 => low & !0xf,
+            BarKind::Mem64 => (low & !0xf) | (self.get_u32(PCI_BAR0 + (idx + 1) * 4) as u64) << 32,
+        })
+    }
+
+    /// Append a capability, `body` follows the id and next pointer bytes. Returns its offset.
+    pub fn add_capability(&mut self, id: u8, body: &[u8], writable: &[u8]) -> usize {
+        let offset = self.next_capability;
+        assert!(offset + 2 + body.len() <= PCI_LEGACY_CONFIG_SIZE, "PCI capabilities overflow");
+        self.data[offset] = id;
+        self.data[offset + 1] = 0;
+        self.data[offset + 2..offset + 2 + body.len()].copy_from_slice(body);
+        self.set_writable(offset + 2, writable);
+        match self.last_capability {
+            Some(last) => self.data[last + 1] = offset as u8,
+            None => {
+                self.data[PCI_CAPABILITY_LIST] = offset as u8;
+                let status = self.get_u16(PCI_STATUS) | PCI_STATUS_CAP_LIST;
+                self.set_u16(PCI_STATUS, status);
+            }
+        }
+        self.last_capability = Some(offset);
+        self.next_capability = (offset + 2 + body.len() + 3) & !3;
+        offset
+    }
+
+    pub fn read(&self, offset: usize, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.data.get(offset + i).copied().unwrap_or(0xff);
+        }
+    }
+
+    /// Guest write, only the writable bits change
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            let Some(mask) = self.writable.get(offset + i).copied() else {
+                return;
+            };
+            let old = self.data[offset + i];
+            self.data[offset + i] = (old & !mask) | (byte & mask);
+        }
+    }
+
+    /// Where BAR `idx` should be mapped now, `None` while decoding is off or it is being sized
+    fn current_mapping(&self, idx: usize) -> Option<BarMapping> {
+        let bar = self.bars[idx]?;
+        let enable = if bar.kind == BarKind::Io { PCI_COMMAND_IO } else { PCI_COMMAND_MEMORY };
+        let addr = self.bar_address(idx)?;
+        let sizing_mask = !(bar.size - 1) & if bar.kind == BarKind::Mem64 { u64::MAX } else { 0xffff_fff0 };
+        if self.command() & enable == 0 || addr == 0 || addr & sizing_mask == sizing_mask {
+            return None;
+        }
+        Some(BarMapping { kind: bar.kind, addr, size: bar.size })
+    }
+
+    /// BARs whose mapping changed since the last call, as (bar, old, new)
+    pub fn update_mappings(&mut self) -> Vec<(usize, Option<BarMapping>, Option<BarMapping>)> {
+        let mut changes = vec![];
+        for idx in 0..PCI_BAR_COUNT {
+            let new = self.current_mapping(idx);
+            if new != self.mapped[idx] {
+                changes.push((idx, self.mapped[idx], new));
+                self.mapped[idx] = new;
+            }
+        }
+        changes
+    }
+
+    /// BAR `idx` couldn't be mapped where [`PciConfig::update_mappings`] said, it stays unmapped
+    pub fn mapping_failed(&mut self, idx: usize) {
+        self.mapped[idx] = None;
+    }
+
+    pub fn save(&self, state: &mut StateBuf) {
+        state.put_bytes(&self.data);
+    }
+
+    pub fn restore(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        let data = state.get_bytes()?;
+        if data.len() != self.data.len() {
+            return Err(snapshot::SnapshotError::Malformed);
+        }
+        self.data.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// A function on bus 0
+pub trait PciDevice: Send {
+    fn config(&self) -> &PciConfig;
+    fn config_mut(&mut self) -> &mut PciConfig;
+    fn bar_read(&mut self, bar: usize, offset: u64, data: &mut [u8]);
+    fn bar_write(&mut self, bar: usize, offset: u64, data: &[u8]);
+    /// The guest wrote `len` bytes of config space at `offset`, e.g. to follow capabilities
+    fn config_written(&mut self, _offset: usize, _len: usize) {}
+    /// Eventfds to signal on guest writes at these offsets of `bar`, instead of exiting
+    fn ioeventfds(&self, _bar: usize) -> Vec<(u64, EventFd)> {
+        vec![]
+    }
+    /// Device state beyond config space, for snapshots
+    fn save_state(&self, _state: &mut StateBuf) {}
+    fn restore_state(&mut self, _state: &mut StateReader) -> snapshot::Result<()> {
+        Ok(())
+    }
+}
+
+pub type SharedPciDevice = Arc<Mutex<dyn PciDevice>>;
+
+/// Bus range of a mapped BAR
+struct BarHandler {
+    device: SharedPciDevice,
+    bar: usize,
+}
+
+impl BusDevice for BarHandler {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        self.device.lock().unwrap().bar_read(self.bar, offset, data);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        self.device.lock().unwrap().bar_write(self.bar, offset, data);
+    }
+}
+
+/// Host bridge and the devices of bus 0, by device number (function 0 only)
+pub struct PciRoot {
+    pub host: Q35Host,
+    devices: BTreeMap<u8, SharedPciDevice>,
+    /// Last value written to 0xCF8
+    config_address: u32,
+}
+
+impl Default for PciRoot {
+    /// Q35 chipset: the host bridge and the LPC bridge
+    fn default() -> Self {
+        let lpc: SharedPciDevice = Arc::new(Mutex::new(Ich9Lpc::new()));
+        Self { host: Q35Host::new(), devices: BTreeMap::from([(LPC_DEVICE, lpc)]), config_address: 0 }
+    }
+}
+
+impl std::fmt::Debug for PciRoot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PciRoot")
+            .field("devices", &self.devices.keys().collect::<Vec<_>>())
+            .field("config_address", &self.config_address)
+            .finish()
+    }
+}
+
+/// (device, register) addressed by 0xCF8, `None` when disabled or not on bus 0 function 0
+fn decode_config_address(address: u32) -> Option<(u8, usize)> {
+    let enabled = address & 1 << 31 != 0;
+    let bus = (address >> 16) & 0xff;
+    let function = (address >> 8) & 0x7;
+    (enabled && bus == 0 && function == 0).then_some((((address >> 11) & 0x1f) as u8, (address & 0xfc) as usize))
+}
+
+/// (device, register) of an ECAM offset
+fn decode_ecam_offset(offset: u64) -> Option<(u8, usize)> {
+    let bus = offset >> 20;
+    let function = (offset >> 12) & 0x7;
+    (bus == 0 && function == 0).then_some((((offset >> 15) & 0x1f) as u8, (offset & 0xfff) as usize))
+}
+
+impl PciRoot {
+    fn config_read(&self, device: u8, offset: usize, data: &mut [u8]) {
+        if device == 0 {
+            return self.host.config().read(offset, data);
+        }
+        match self.devices.get(&device) {
+            Some(dev) => dev.lock().unwrap().config().read(offset, data),
+            None => data.fill(0xff),
+        }
+    }
+
+    /// Returns the BAR mapping changes of the device
+    fn config_write(&mut self, device: u8, offset: usize, data: &[u8]) -> Vec<(usize, Option<BarMapping>, Option<BarMapping>)> {
+        if device == 0 {
+            self.host.config_write(offset, data);
+            return vec![];
+        }
+        let Some(dev) = self.devices.get(&device) else {
+            return vec![];
+        };
+        let mut dev = dev.lock().unwrap();
+        dev.config_mut().write(offset, data);
+        dev.config_written(offset, data.len());
+        dev.config_mut().update_mappings()
+    }
+}
+
+impl Snapshot for PciRoot {
+    fn snapshot_id(&self) -> String {
+        "pci0".to_string()
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        state.put_u32(self.config_address);
+        self.host.config().save(state);
+        state.put_u32(self.devices.len() as u32);
+        for (&device, dev) in &self.devices {
+            state.put_u8(device);
+            let dev = dev.lock().unwrap();
+            dev.config().save(state);
+            let mut device_state = StateBuf::default();
+            dev.save_state(&mut device_state);
+            state.put_bytes(&device_state.0);
+        }
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.config_address = state.get_u32()?;
+        self.host.config_mut().restore(state)?;
+        for _ in 0..state.get_u32()? {
+            let device = state.get_u8()?;
+            let dev = self.devices
+                .get(&device)
+                .ok_or_else(|| snapshot::SnapshotError::MissingDevice(format!("pci device {device}")))?;
+            let mut dev = dev.lock().unwrap();
+            dev.config_mut().restore(state)?;
+            dev.restore_state(&mut StateReader::new(state.get_bytes()?))?;
+        }
+        Ok(())
+    }
+}
+
+fn ioevent_address(mapping: BarMapping, offset: u64) -> IoEventAddress {
+    match mapping.kind {
+        BarKind::Io => IoEventAddress::Pio(mapping.addr + offset),
+        _ => IoEventAddress::Mmio(mapping.addr + offset),
+    }
+}
+
+impl Vm {
+    /// Plug `device` in `slot` of bus 0
+    pub fn add_pci_device(&mut self, slot: u8, device: SharedPciDevice) -> Result<()> {
+        if slot == 0 || slot >= PCI_SLOTS {
+            return Err(kvm_ioctls::Error::new(libc::EINVAL));
+        }
+        if self.pci.devices.contains_key(&slot) {
+            return Err(kvm_ioctls::Error::new(libc::EBUSY));
+        }
+        {
+            let dev = device.lock().unwrap();
+            let config = dev.config();
+            info!("PCI 00:{slot:02x}.0 {:04x}:{:04x}", config.get_u16(PCI_VENDOR_ID), config.get_u16(PCI_VENDOR_ID + 2));
+        }
+        self.pci.devices.insert(slot, device);
+        Ok(())
+    }
+
+    pub(super) fn is_pci_config_io(&self, port: u16) -> bool {
+        (PCI_CONFIG_ADDRESS..PCI_CONFIG_DATA + 4).contains(&port)
+    }
+
+    pub(super) fn pci_io_read(&self, port: u16, data: &mut [u8]) {
+        if port == PCI_CONFIG_ADDRESS && data.len() == 4 {
+            data.copy_from_slice(&self.pci.config_address.to_le_bytes());
+            return;
+        }
+        match decode_config_address(self.pci.config_address) {
+            Some((device, reg)) if port >= PCI_CONFIG_DATA => {
+                self.pci.config_read(device, reg + (port - PCI_CONFIG_DATA) as usize, data)
+            }
+            _ => data.fill(0xff),
+        }
+    }
+
+    pub(super) fn pci_io_write(&mut self, port: u16, data: &[u8]) -> Result<()> {
+        if port == PCI_CONFIG_ADDRESS && data.len() == 4 {
+            self.pci.config_address = u32::from_le_bytes(data.try_into().unwrap());
+            return Ok(());
+        }
+        match decode_config_address(self.pci.config_address) {
+            Some((device, reg)) if port >= PCI_CONFIG_DATA => {
+                let offset = reg + (port - PCI_CONFIG_DATA) as usize;
+                self.pci_config_write(device, offset, data)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn is_pci_ecam(&self, addr: u64) -> bool {
+        self.pci.host.ecam_window().is_some_and(|(base, size)| (base..base + size).contains(&addr))
+    }
+
+    pub(super) fn pci_ecam_read(&self, addr: u64, data: &mut [u8]) {
+        let (base, _) = self.pci.host.ecam_window().unwrap();
+        match decode_ecam_offset(addr - base) {
+            Some((device, offset)) => self.pci.config_read(device, offset, data),
+            None => data.fill(0xff),
+        }
+    }
+
+    pub(super) fn pci_ecam_write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        let (base, _) = self.pci.host.ecam_window().unwrap();
+        match decode_ecam_offset(addr - base) {
+            Some((device, offset)) => self.pci_config_write(device, offset, data),
+            None => Ok(()),
+        }
+    }
+
+    fn pci_config_write(&mut self, device: u8, offset: usize, data: &[u8]) -> Result<()> {
+        let changes = self.pci.config_write(device, offset, data);
+        self.apply_bar_changes(device, changes)
+    }
+
+    /// Move the bus ranges of `device` BARs to where the guest put them
+    fn apply_bar_changes(&mut self, device: u8, changes: Vec<(usize, Option<BarMapping>, Option<BarMapping>)>) -> Result<()> {
+        let Some(dev) = self.pci.devices.get(&device).cloned() else {
+            return Ok(());
+        };
+        for (bar, old, new) in changes {
+            let ioeventfds = dev.lock().unwrap().ioeventfds(bar);
+            if let Some(old) = old {
+                let bus = if old.kind == BarKind::Io { &mut self.pio_bus } else { &mut self.mmio_bus };
+                bus.remove(old.addr);
+                for (offset, fd) in &ioeventfds {
+                    self.unregister_ioeventfd(fd, ioevent_address(old, *offset))?;
+                }
+            }
+            if let Some(new) = new {
+                let bus = if new.kind == BarKind::Io { &mut self.pio_bus } else { &mut self.mmio_bus };
+                let handler = Arc::new(Mutex::new(BarHandler { device: dev.clone(), bar }));
+                if let Err(e) = bus.insert(new.addr, new.size, handler) {
+                    // The range is someone else's, the next remap mustn't remove it
+                    warn!("PCI 00:{device:02x}.0 BAR{bar} not mapped: {e}");
+                    dev.lock().unwrap().config_mut().mapping_failed(bar);
+                    continue;
+                }
+                for (offset, fd) in &ioeventfds {
+                    self.register_ioeventfd(fd, ioevent_address(new, *offset))?;
+                }
+                debug!("PCI 00:{device:02x}.0 BAR{bar} @ 0x{:x}+0x{:x}", new.addr, new.size);
+            }
+        }
+        Ok(())
+    }
+
+    /// Map the BARs again after their config space was restored
+    pub(super) fn sync_pci_bars(&mut self) -> Result<()> {
+        let devices: Vec<(u8, SharedPciDevice)> = self.pci.devices.iter().map(|(&d, dev)| (d, dev.clone())).collect();
+        for (device, dev) in devices {
+            let changes = dev.lock().unwrap().config_mut().update_mappings();
+            self.apply_bar_changes(device, changes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_u32(config: &mut PciConfig, offset: usize, value: u32) {
+        config.write(offset, &value.to_le_bytes());
+    }
+
+    #[test]
+    fn bar_sizing_and_mapping() {
+        let mut config = PciConfig::new(0x1af4, 0x1042, 0x01_00_00, 1);
+        config.add_bar(0, PciBar { size: 0x4000, kind: BarKind::Mem64, prefetchable: true });
+        config.add_bar(2, PciBar { size: 0x20, kind: BarKind::Io, prefetchable: false });
+
+        // Sizing: all ones reads back the size mask and the read only flags
+        write_u32(&mut config, PCI_BAR0, u32::MAX);
+        write_u32(&mut config, PCI_BAR0 + 4, u32::MAX);
+        write_u32(&mut config, PCI_BAR0 + 8, u32::MAX);
+        assert_eq!(config.get_u32(PCI_BAR0), 0xffff_c000 | BAR_MEM64 | BAR_PREFETCH);
+        assert_eq!(config.get_u32(PCI_BAR0 + 4), u32::MAX);
+        assert_eq!(config.get_u32(PCI_BAR0 + 8), 0xffff_ffe0 | BAR_IO);
+        write_u32(&mut config, PCI_COMMAND, (PCI_COMMAND_MEMORY | PCI_COMMAND_IO) as u32);
+        assert!(config.update_mappings().is_empty(), "BARs being sized aren't mapped");
+
+        write_u32(&mut config, PCI_BAR0, 0x8000_4000);
+        write_u32(&mut config, PCI_BAR0 + 4, 0x1);
+        write_u32(&mut config, PCI_BAR0 + 8, 0xc040);
+        let mem = BarMapping { kind: BarKind::Mem64, addr: 0x1_8000_4000, size: 0x4000 };
+        let io = BarMapping { kind: BarKind::Io, addr: 0xc040, size: 0x20 };
+        assert_eq!(config.update_mappings(), [(0, None, Some(mem)), (2, None, Some(io))]);
+        assert!(config.update_mappings().is_empty());
+
+        // Turning memory decoding off unmaps only the memory BAR
+        write_u32(&mut config, PCI_COMMAND, PCI_COMMAND_IO as u32);
+        assert_eq!(config.update_mappings(), [(0, Some(mem), None)]);
+
+        // A BAR that couldn't be mapped has nothing to unmap when it moves
+        write_u32(&mut config, PCI_BAR0 + 8, 0xc080);
+        let moved = BarMapping { addr: 0xc080, ..io };
+        assert_eq!(config.update_mappings(), [(2, Some(io), Some(moved))]);
+        config.mapping_failed(2);
+        write_u32(&mut config, PCI_BAR0 + 8, 0xc040);
+        assert_eq!(config.update_mappings(), [(2, None, Some(io))]);
+    }
+
+    #[test]
+    fn write_masks_and_capabilities() {
+        let mut config = PciConfig::new(0x8086, 0x1234, 0x02_00_00, 0);
+        write_u32(&mut config, PCI_VENDOR_ID, 0);
+        assert_eq!(config.get_u32(PCI_VENDOR_ID), 0x1234_8086);
+        write_u32(&mut config, PCI_COMMAND, u32::MAX);
+        assert_eq!(config.command(), 0x407);
+        assert_eq!(config.get_u16(PCI_STATUS), 0);
+
+        let first = config.add_capability(0x05, &[0; 12], &[0xff, 0xff]);
+        let second = config.add_capability(0x11, &[0; 9], &[]);
+        assert_eq!((first, second), (0x40, 0x50));
+        assert_ne!(config.get_u16(PCI_STATUS) & PCI_STATUS_CAP_LIST, 0);
+        assert_eq!(config.get_u8(PCI_CAPABILITY_LIST), 0x40);
+        assert_eq!([config.get_u8(0x40), config.get_u8(0x41)], [0x05, 0x50]);
+        assert_eq!([config.get_u8(0x50), config.get_u8(0x51)], [0x11, 0]);
+        config.write(0x42, &[0xaa, 0xbb, 0xcc]);
+        assert_eq!(config.get_u32(0x40) >> 16, 0xbbaa);
+
+        let mut data = [0; 4];
+        config.read(PCI_CONFIG_SPACE_SIZE - 2, &mut data);
+        assert_eq!(data, [0, 0, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn config_addresses() {
+        assert_eq!(decode_config_address(0x8000_f8a0), Some((0x1f, 0xa0)));
+        assert_eq!(decode_config_address(0x8000_0806), Some((1, 0x04)));
+        assert_eq!(decode_config_address(0x0000_f8a0), None, "disabled");
+        assert_eq!(decode_config_address(0x8001_0000), None, "bus 1");
+        assert_eq!(decode_config_address(0x8000_0100), None, "function 1");
+        assert_eq!(decode_ecam_offset(0xf_8123), Some((0x1f, 0x123)));
+        assert_eq!(decode_ecam_offset(0x10_0000), None);
+        assert_eq!(decode_ecam_offset(0x1000), None);
+    }
+
+    #[test]
+    fn q35_chipset_on_bus() {
+        let mut root = PciRoot::default();
+        let mut data = [0; 4];
+        root.config_read(0, PCI_VENDOR_ID, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x29c0_8086);
+        root.config_read(LPC_DEVICE, PCI_VENDOR_ID, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x2918_8086);
+        root.config_read(LPC_DEVICE, PCI_CLASS_REVISION, &mut data);
+        assert_eq!(u32::from_le_bytes(data) >> 8, 0x06_01_00);
+        root.config_read(3, PCI_VENDOR_ID, &mut data);
+        assert_eq!(data, [0xff; 4]);
+
+        // What OVMF does on Q35: ECAM first, then the ACPI PM base
+        root.config_write(0, 0x60, &0xb000_0001u32.to_le_bytes());
+        assert_eq!(root.host.ecam_window(), Some((0xb000_0000, 256 << 20)));
+        root.config_write(LPC_DEVICE, 0x40, &0x601u32.to_le_bytes());
+        root.config_write(LPC_DEVICE, 0x44, &[0x80]);
+        root.config_read(LPC_DEVICE, 0x40, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x601);
+        root.config_read(LPC_DEVICE, 0x60, &mut data);
+        assert_eq!(data, [0x80; 4]);
+    }
+}
@@ -0,0 +1,368 @@
+//! ACPI PM block at PM_BASE: PM1 event and control registers, the PM timer
+//! and GPE0, with the SCI on IRQ 9.
+//!
+//! The guest shuts down by writing SLP_TYP and SLP_EN to PM1_CNT, which only
+//! flags the request, the vCPU loop stops the VM after the exit. The power
+//! button is a control method one: pressing it sets its GPE0 status bit, the
+//! DSDT _E00 method notifies \_SB.PWRB. There is no SMI_CMD port, the block
+//! is in ACPI mode from the start with SCI_EN set. The FADT reset register is
+//! here too. The SCI is level triggered, held up while any enabled status bit
+//! is set, so events latched before the guest clears the first one aren't lost.
+
+use std::{
+    io,
+    os::fd::{ AsRawFd, RawFd },
+    sync::{ atomic::{ AtomicBool, Ordering }, Arc, Mutex, OnceLock },
+    time::Instant,
+};
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vmm_sys_util::{ eventfd::{ EventFd, EFD_NONBLOCK }, signal::register_signal_handler };
+
+use super::{
+    acpi::{
+        ACPI_RESET_REG, GPE0_BLK, GPE0_BLK_LEN, GPE_POWER_BUTTON, PM1_CNT_BLK, PM_BASE, PM_TMR_BLK, RESET_VALUE, SCI_IRQ,
+        SLP_TYP_S5,
+    },
+    bus::BusDevice,
+    event_loop::EventHandler,
+    irq::IrqFd,
+    reset::{ ResetLine, ResetSource },
+    snapshot::{ self, Snapshot, StateBuf, StateReader },
+    Vm,
+};
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+/// The ICH9 PMBASE range
+const PM_SIZE: u64 = 0x80;
+
+const PM1_STS: u64 = 0x00;
+const PM1_EN: u64 = 0x02;
+const PM1_CNT: u64 = (PM1_CNT_BLK - PM_BASE) as u64;
+const PM_TMR: u64 = (PM_TMR_BLK - PM_BASE) as u64;
+const GPE0_STS: u64 = (GPE0_BLK - PM_BASE) as u64;
+/// Each half of GPE0_BLK, status then enable
+const GPE0_LEN: usize = GPE0_BLK_LEN as usize / 2;
+const GPE0_EN: u64 = GPE0_STS + GPE0_LEN as u64;
+const RESET_REG: u64 = (ACPI_RESET_REG - PM_BASE) as u64;
+
+/// Timer overflow, power button and wake status, the only PM1 events we know of
+const PM1_STS_MASK: u16 = 1 << 0 | 1 << 8 | 1 << 15;
+const PM1_EN_MASK: u16 = 1 << 0 | 1 << 8;
+const CNT_SCI_EN: u16 = 1 << 0;
+const CNT_SLP_TYP_SHIFT: u16 = 10;
+const CNT_SLP_TYP: u16 = 0x7 << CNT_SLP_TYP_SHIFT;
+/// Write only, starts the sleep transition
+const CNT_SLP_EN: u16 = 1 << 13;
+
+/// The 3.579545 MHz ACPI PM timer
+const PM_TIMER_HZ: u128 = 3_579_545;
+
+pub struct AcpiPm {
+    pm1_sts: u16,
+    pm1_en: u16,
+    pm1_cnt: u16,
+    gpe0_sts: [u8; GPE0_LEN],
+    gpe0_en: [u8; GPE0_LEN],
+    /// PM timer value when `timer_start` was taken
+    timer_base: u32,
+    timer_start: Instant,
+    /// Whether an enabled status bit is up, the level of the SCI line
+    sci_level: bool,
+    sci: IrqFd,
+    /// Set when the guest enters S5, checked by the vCPU loop
+    shutdown_request: Arc<AtomicBool>,
+    reset: ResetLine,
+}
+
+impl std::fmt::Debug for AcpiPm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AcpiPm")
+            .field("pm1_sts", &self.pm1_sts)
+            .field("pm1_en", &self.pm1_en)
+            .field("pm1_cnt", &self.pm1_cnt)
+            .field("gpe0_sts", &self.gpe0_sts)
+            .field("gpe0_en", &self.gpe0_en)
+            .finish()
+    }
+}
+
+impl AcpiPm {
+    pub fn new(sci: IrqFd, shutdown_request: Arc<AtomicBool>, reset: ResetLine) -> Self {
+        AcpiPm {
+            pm1_sts: 0,
+            pm1_en: 0,
+            pm1_cnt: CNT_SCI_EN,
+            gpe0_sts: [0; GPE0_LEN],
+            gpe0_en: [0; GPE0_LEN],
+            timer_base: 0,
+            timer_start: Instant::now(),
+            sci_level: false,
+            sci,
+            shutdown_request,
+            reset,
+        }
+    }
+
+    fn timer(&self) -> u32 {
+        let ticks = self.timer_start.elapsed().as_nanos() * PM_TIMER_HZ / 1_000_000_000;
+        self.timer_base.wrapping_add(ticks as u32)
+    }
+
+    /// Byte at `offset` in the block, `timer` latched once per access
+    fn read_byte(&self, offset: u64, timer: u32) -> u8 {
+        match offset {
+            PM1_STS..=0x01 => self.pm1_sts.to_le_bytes()[(offset - PM1_STS) as usize],
+            PM1_EN..=0x03 => self.pm1_en.to_le_bytes()[(offset - PM1_EN) as usize],
+            PM1_CNT..=0x05 => (self.pm1_cnt & !CNT_SLP_EN).to_le_bytes()[(offset - PM1_CNT) as usize],
+            PM_TMR..=0x0b => timer.to_le_bytes()[(offset - PM_TMR) as usize],
+            GPE0_STS..=0x27 => self.gpe0_sts[(offset - GPE0_STS) as usize],
+            GPE0_EN..=0x2f => self.gpe0_en[(offset - GPE0_EN) as usize],
+            _ => 0,
+        }
+    }
+
+    fn write_byte(&mut self, offset: u64, value: u8) {
+        let set_byte = |reg: u16, byte: u64, value: u8| {
+            let mut bytes = reg.to_le_bytes();
+            bytes[byte as usize] = value;
+            u16::from_le_bytes(bytes)
+        };
+        match offset {
+            // Write 1 to clear
+            PM1_STS..=0x01 => self.pm1_sts &= !set_byte(0, offset - PM1_STS, value),
+            PM1_EN..=0x03 => self.pm1_en = set_byte(self.pm1_en, offset - PM1_EN, value) & PM1_EN_MASK,
+            PM1_CNT..=0x05 => {
+                let cnt = set_byte(self.pm1_cnt, offset - PM1_CNT, value);
+                // SCI_EN stays set, there is no legacy mode to go back to
+                self.pm1_cnt = cnt & !CNT_SLP_EN | CNT_SCI_EN;
+                if cnt & CNT_SLP_EN != 0 {
+                    self.sleep(((cnt & CNT_SLP_TYP) >> CNT_SLP_TYP_SHIFT) as u8);
+                }
+            }
+            GPE0_STS..=0x27 => self.gpe0_sts[(offset - GPE0_STS) as usize] &= !value,
+            GPE0_EN..=0x2f => self.gpe0_en[(offset - GPE0_EN) as usize] = value,
+            RESET_REG if value == RESET_VALUE => self.reset.pull(ResetSource::Acpi),
+            _ => debug!("ACPI PM write ignored at +0x{offset:x}: 0x{value:x}"),
+        }
+    }
+
+    fn sleep(&mut self, slp_typ: u8) {
+        if slp_typ == SLP_TYP_S5 {
+            info!("Guest entered S5, powering off");
+            self.shutdown_request.store(true, Ordering::SeqCst);
+        } else {
+            // The DSDT only has \_S5
+            warn!("Guest asked for unknown sleep type {slp_typ}, ignored");
+        }
+    }
+
+    /// Hold the SCI up while an enabled status bit is set
+    fn update_sci(&mut self) {
+        let gpe = self.gpe0_sts.iter().zip(&self.gpe0_en).any(|(sts, en)| sts & en != 0);
+        let level = gpe || self.pm1_sts & self.pm1_en & PM1_STS_MASK != 0;
+        if level != self.sci_level {
+            if let Err(e) = self.sci.set_level(level) {
+                error!("SCI failed: {e}");
+            }
+        }
+        self.sci_level = level;
+    }
+
+    /// Raise the power button GPE, the guest decides what to do with it
+    pub fn press_power_button(&mut self) {
+        info!("Power button pressed");
+        let bit = GPE_POWER_BUTTON as usize;
+        self.gpe0_sts[bit / 8] |= 1 << (bit % 8);
+        self.update_sci();
+    }
+}
+
+impl BusDevice for AcpiPm {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let timer = self.timer();
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.read_byte(offset + i as u64, timer);
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            self.write_byte(offset + i as u64, byte);
+        }
+        self.update_sci();
+    }
+}
+
+impl Snapshot for AcpiPm {
+    fn snapshot_id(&self) -> String {
+        "acpi-pm".to_string()
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        state.put_u16(self.pm1_sts);
+        state.put_u16(self.pm1_en);
+        state.put_u16(self.pm1_cnt);
+        state.put_bytes(&self.gpe0_sts);
+        state.put_bytes(&self.gpe0_en);
+        state.put_u32(self.timer());
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.pm1_sts = state.get_u16()?;
+        self.pm1_en = state.get_u16()?;
+        self.pm1_cnt = state.get_u16()?;
+        self.gpe0_sts = state.get_bytes()?.try_into().map_err(|_| snapshot::SnapshotError::Malformed)?;
+        self.gpe0_en = state.get_bytes()?.try_into().map_err(|_| snapshot::SnapshotError::Malformed)?;
+        self.timer_base = state.get_u32()?;
+        self.timer_start = Instant::now();
+        // Drive the line again from the restored status bits
+        self.sci_level = false;
+        self.update_sci();
+        Ok(())
+    }
+}
+
+/// Presses the power button from any thread
+#[derive(Debug, Clone)]
+pub struct PowerButton {
+    pressed: Arc<EventFd>,
+}
+
+/// Button pressed by [`PowerButton::press_on_signal`]
+static SIGNAL_BUTTON: OnceLock<PowerButton> = OnceLock::new();
+
+extern "C" fn press_handler(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // Only an eventfd write, safe in a signal handler
+    if let Some(button) = SIGNAL_BUTTON.get() {
+        let _ = button.press();
+    }
+}
+
+impl PowerButton {
+    pub fn press(&self) -> io::Result<()> {
+        self.pressed.write(1)
+    }
+
+    /// Press the button each time the process gets `signum`
+    pub fn press_on_signal(&self, signum: libc::c_int) -> io::Result<()> {
+        SIGNAL_BUTTON.set(self.clone()).map_err(|_| io::Error::from(io::ErrorKind::AlreadyExists))?;
+        register_signal_handler(signum, press_handler).map_err(io::Error::from)
+    }
+}
+
+/// Hands the presses over to the PM block on the event loop thread
+struct PowerButtonHandler {
+    pressed: Arc<EventFd>,
+    pm: Arc<Mutex<AcpiPm>>,
+}
+
+impl EventHandler for PowerButtonHandler {
+    fn handle_event(&mut self, _fd: RawFd) {
+        if self.pressed.read().is_ok() {
+            self.pm.lock().unwrap().press_power_button();
+        }
+    }
+}
+
+impl Vm {
+    pub(super) fn add_acpi_pm(&mut self) -> Result<()> {
+        let io_error = |e: std::io::Error| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO));
+        let pm = Arc::new(Mutex::new(AcpiPm::new(self.irqfd(SCI_IRQ as u32)?, self.shutdown_request.clone(), self.reset_line.clone())));
+        self.pio_bus.insert(PM_BASE as u64, PM_SIZE, pm.clone()).map_err(|e| {
+            error!("ACPI PM: {e}");
+            kvm_ioctls::Error::new(libc::EEXIST)
+        })?;
+        let pressed = Arc::new(EventFd::new(EFD_NONBLOCK).map_err(io_error)?);
+        let handler = Arc::new(Mutex::new(PowerButtonHandler { pressed: pressed.clone(), pm: pm.clone() }));
+        self.event_loop.add(pressed.as_raw_fd(), handler).map_err(io_error)?;
+        self.power_button = Some(PowerButton { pressed });
+        self.acpi_pm = Some(pm);
+        Ok(())
+    }
+
+    /// Power button of the machine, usable from any thread
+    pub fn power_button(&self) -> Option<PowerButton> {
+        self.power_button.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pm() -> AcpiPm {
+        AcpiPm::new(IrqFd::detached(SCI_IRQ as u32), Arc::new(AtomicBool::new(false)), ResetLine::default())
+    }
+
+    fn read_u16(pm: &mut AcpiPm, offset: u64) -> u16 {
+        let mut data = [0u8; 2];
+        pm.read(offset, &mut data);
+        u16::from_le_bytes(data)
+    }
+
+    #[test]
+    fn power_button_raises_the_sci_once_enabled() {
+        let mut pm = pm();
+        pm.press_power_button();
+        assert_eq!(pm.gpe0_sts[0], 1 << GPE_POWER_BUTTON);
+        assert!(!pm.sci.take_pending(), "GPE not enabled yet");
+        pm.write(GPE0_EN, &[1 << GPE_POWER_BUTTON]);
+        assert!(pm.sci.take_pending());
+        assert!(pm.sci.level());
+        pm.write(GPE0_STS, &[1 << GPE_POWER_BUTTON]);
+        assert_eq!(pm.gpe0_sts[0], 0);
+        assert!(!pm.sci.level());
+        pm.press_power_button();
+        assert!(pm.sci.take_pending());
+    }
+
+    #[test]
+    fn sci_held_while_an_event_is_left() {
+        let mut pm = pm();
+        pm.write(GPE0_EN, &[1 << GPE_POWER_BUTTON]);
+        pm.write(PM1_EN, &(1u16 << 8).to_le_bytes());
+        pm.press_power_button();
+        // A second event while the SCI is up
+        pm.pm1_sts |= 1 << 8;
+        pm.write(GPE0_STS, &[1 << GPE_POWER_BUTTON]);
+        assert!(pm.sci.level(), "PM1 power button still pending");
+        pm.write(PM1_STS, &(1u16 << 8).to_le_bytes());
+        assert!(!pm.sci.level());
+    }
+
+    #[test]
+    fn s5_requests_shutdown() {
+        let mut pm = pm();
+        assert_eq!(read_u16(&mut pm, PM1_CNT), CNT_SCI_EN);
+        let slp_typ = 5 << CNT_SLP_TYP_SHIFT;
+        pm.write(PM1_CNT, &(slp_typ | CNT_SLP_EN).to_le_bytes());
+        assert!(!pm.shutdown_request.load(Ordering::SeqCst), "S5 is type 0");
+        assert_eq!(read_u16(&mut pm, PM1_CNT), slp_typ | CNT_SCI_EN, "SLP_EN reads as 0");
+        pm.write(PM1_CNT, &((SLP_TYP_S5 as u16) << CNT_SLP_TYP_SHIFT | CNT_SLP_EN).to_le_bytes());
+        assert!(pm.shutdown_request.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn reset_register() {
+        let mut pm = pm();
+        pm.write(RESET_REG, &[0x02]);
+        assert_eq!(pm.reset.take(), None);
+        pm.write(RESET_REG, &[RESET_VALUE]);
+        assert_eq!(pm.reset.take(), Some(ResetSource::Acpi));
+    }
+
+    #[test]
+    fn pm_timer_counts() {
+        let mut pm = pm();
+        let mut before = [0u8; 4];
+        pm.read(PM_TMR, &mut before);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let mut after = [0u8; 4];
+        pm.read(PM_TMR, &mut after);
+        let ticks = u32::from_le_bytes(after).wrapping_sub(u32::from_le_bytes(before));
+        assert!(ticks >= 3579, "{ticks} ticks in 1ms");
+    }
+}
@@ -0,0 +1,189 @@
+//! Q35 MCH host bridge at 00:00.0, owner of the PCIEXBAR placing the ECAM window,
+//! and the ICH9 LPC bridge at 00:1f.0.
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+
+use super::pci::{ PciConfig, PciDevice };
+
+const MCH_VENDOR_ID: u16 = 0x8086;
+const MCH_DEVICE_ID: u16 = 0x29c0;
+const CLASS_HOST_BRIDGE: u32 = 0x06_00_00;
+
+/// PCI Express register range base address, 64 bit
+const PCIEXBAR: usize = 0x60;
+const PCIEXBAR_ENABLE: u64 = 1 << 0;
+const PCIEXBAR_LENGTH_SHIFT: u64 = 1;
+/// Where OVMF places the ECAM window, described by the MCFG table
+pub const Q35_ECAM_BASE: u64 = 0xb000_0000;
+pub const Q35_ECAM_BUSES: u16 = 256;
+/// Device specific registers kept as plain storage (PAM, SMRAM, TOLUD...)
+const MCH_REGS: std::ops::Range<usize> = 0x40..0x100;
+
+#[derive(Debug, Clone)]
+pub struct Q35Host {
+    config: PciConfig,
+}
+
+impl Default for Q35Host {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Q35Host {
+    pub fn new() -> Self {
+        let mut config = PciConfig::new(MCH_VENDOR_ID, MCH_DEVICE_ID, CLASS_HOST_BRIDGE, 0);
+        config.set_subsystem(0x1af4, 0x1100);
+        config.set_writable(MCH_REGS.start, &[0xff; MCH_REGS.end - MCH_REGS.start]);
+        Self { config }
+    }
+
+    pub fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    pub fn config_write(&mut self, offset: usize, data: &[u8]) {
+        let before = self.ecam_window();
+        self.config.write(offset, data);
+        let after = self.ecam_window();
+        if before != after {
+            match after {
+                Some((base, size)) => info!("ECAM at 0x{base:x}+0x{size:x}"),
+                None => info!("ECAM disabled"),
+            }
+        }
+    }
+
+    /// Base and size of the MMCONFIG window, while enabled
+    pub fn ecam_window(&self) -> Option<(u64, u64)> {
+        let bar = self.config.get_u32(PCIEXBAR) as u64 | (self.config.get_u32(PCIEXBAR + 4) as u64) << 32;
+        if bar & PCIEXBAR_ENABLE == 0 {
+            return None;
+        }
+        // 256, 128 or 64 buses, base aligned on the window size
+        let size: u64 = match (bar >> PCIEXBAR_LENGTH_SHIFT) & 0x3 {
+            0 => 256 << 20,
+            1 => 128 << 20,
+            2 => 64 << 20,
+            _ => return None,
+        };
+        let base = bar & 0xf_ffff_ffff & !(size - 1);
+        Some((base, size))
+    }
+}
+
+const LPC_DEVICE_ID: u16 = 0x2918;
+const CLASS_ISA_BRIDGE: u32 = 0x06_01_00;
+/// Device number of the ICH9 LPC bridge on bus 0
+pub const LPC_DEVICE: u8 = 0x1f;
+
+/// ACPI I/O base, 128 bytes
+const LPC_PMBASE: usize = 0x40;
+const LPC_ACPI_CNTL: usize = 0x44;
+const ACPI_CNTL_ACPI_EN: u8 = 1 << 7;
+const LPC_GEN_PMCON: std::ops::Range<usize> = 0xa0..0xa8;
+/// PIRQA-D and PIRQE-H routing
+const LPC_PIRQ_ROUT: [usize; 2] = [0x60, 0x68];
+const PIRQ_ROUT_DISABLED: u8 = 0x80;
+/// Root complex base address
+const LPC_RCBA: usize = 0xf0;
+
+/// ICH9 LPC bridge at 00:1f.0, where firmware programs the ACPI PM I/O base and PIRQ routing.
+/// The PM block itself stays where the ACPI tables say it is.
+#[derive(Debug, Clone)]
+pub struct Ich9Lpc {
+    config: PciConfig,
+}
+
+impl Default for Ich9Lpc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ich9Lpc {
+    pub fn new() -> Self {
+        let mut config = PciConfig::new(MCH_VENDOR_ID, LPC_DEVICE_ID, CLASS_ISA_BRIDGE, 2);
+        config.set_header_type(0x80);
+        config.set_subsystem(0x1af4, 0x1100);
+        config.set_u32(LPC_PMBASE, 1);
+        config.set_writable(LPC_PMBASE, &0xff80u32.to_le_bytes());
+        config.set_writable(LPC_ACPI_CNTL, &[ACPI_CNTL_ACPI_EN]);
+        config.set_writable(LPC_GEN_PMCON.start, &[0xff; LPC_GEN_PMCON.end - LPC_GEN_PMCON.start]);
+        for rout in LPC_PIRQ_ROUT {
+            config.set_u32(rout, u32::from_le_bytes([PIRQ_ROUT_DISABLED; 4]));
+            config.set_writable(rout, &[0x8f; 4]);
+        }
+        config.set_writable(LPC_RCBA, &0xffff_c001u32.to_le_bytes());
+        Self { config }
+    }
+
+    /// ACPI PM I/O base, while decoding is enabled
+    pub fn pm_base(&self) -> Option<u16> {
+        (self.config.get_u8(LPC_ACPI_CNTL) & ACPI_CNTL_ACPI_EN != 0)
+            .then(|| (self.config.get_u32(LPC_PMBASE) & 0xff80) as u16)
+    }
+}
+
+impl PciDevice for Ich9Lpc {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn bar_read(&mut self, _bar: usize, _offset: u64, data: &mut [u8]) {
+        data.fill(0xff);
+    }
+
+    fn bar_write(&mut self, _bar: usize, _offset: u64, _data: &[u8]) {}
+
+    fn config_written(&mut self, offset: usize, len: usize) {
+        let written = offset..offset + len;
+        if written.contains(&LPC_ACPI_CNTL) || written.start < LPC_PMBASE + 4 && written.end > LPC_PMBASE {
+            match self.pm_base() {
+                Some(base) => info!("ACPI PM I/O base 0x{base:x}"),
+                None => debug!("ACPI PM I/O decoding off"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ecam_window_sizes() {
+        let mut host = Q35Host::new();
+        assert_eq!(host.ecam_window(), None);
+        host.config_write(PCIEXBAR, &0xe000_0005u32.to_le_bytes());
+        assert_eq!(host.ecam_window(), Some((0xe000_0000, 64 << 20)));
+        host.config_write(PCIEXBAR, &0xe400_0003u32.to_le_bytes());
+        assert_eq!(host.ecam_window(), Some((0xe000_0000, 128 << 20)), "base aligned on the size");
+        host.config_write(PCIEXBAR + 4, &0x1u32.to_le_bytes());
+        assert_eq!(host.ecam_window(), Some((0x1_e000_0000, 128 << 20)));
+        host.config_write(PCIEXBAR, &0xe000_0007u32.to_le_bytes());
+        assert_eq!(host.ecam_window(), None, "reserved length");
+    }
+
+    #[test]
+    fn lpc_pm_base() {
+        let mut lpc = Ich9Lpc::new();
+        assert_eq!(lpc.config().get_u8(0x0e), 0x80);
+        assert_eq!(lpc.pm_base(), None);
+        lpc.config_mut().write(LPC_PMBASE, &0xffffu32.to_le_bytes());
+        assert_eq!(lpc.config().get_u32(LPC_PMBASE), 0xff81, "128 byte aligned, I/O space bit kept");
+        lpc.config_mut().write(LPC_PMBASE, &0x600u32.to_le_bytes());
+        lpc.config_mut().write(LPC_ACPI_CNTL, &[0xff]);
+        assert_eq!(lpc.config().get_u8(LPC_ACPI_CNTL), ACPI_CNTL_ACPI_EN);
+        assert_eq!(lpc.pm_base(), Some(0x600));
+    }
+}
@@ -0,0 +1,929 @@
+//! qcow2 images, versions 2 and 3: L1/L2 cluster mapping, refcounts, backing chains and zero clusters.
+//!
+//! New clusters are appended to the file and never reused, so a crash can at
+//! worst leak space. Preallocated zero clusters are written in place in the
+//! host cluster they hold. Compressed (deflate) clusters are read only, a
+//! write copies the cluster to a plain one. Internal snapshots can be read but not
+//! written to, encryption, external data files and extended L2 entries are
+//! not supported.
+
+use std::{
+    collections::HashMap,
+    fs::{ File, OpenOptions },
+    io,
+    os::{ fd::AsRawFd, unix::fs::FileExt },
+    path::Path,
+};
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+
+use super::disk::{ check_range, open_image, BackingChain, DiskBackend };
+
+pub const QCOW2_MAGIC: [u8; 4] = *b"QFI\xfb";
+
+const L1_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFCOUNT_OFFSET_MASK: u64 = !0x1ff;
+/// Refcount is exactly one, the cluster can be written in place
+const OFLAG_COPIED: u64 = 1 << 63;
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+/// Version 3 only, the cluster reads as zeros
+const OFLAG_ZERO: u64 = 1 << 0;
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+const INCOMPAT_SUPPORTED: u64 = INCOMPAT_DIRTY | INCOMPAT_CORRUPT | INCOMPAT_COMPRESSION_TYPE;
+
+const HEADER_V2_SIZE: usize = 72;
+const HEADER_V3_SIZE: usize = 104;
+const HEADER_REFCOUNT_TABLE_OFFSET: u64 = 48;
+const HEADER_AUTOCLEAR: u64 = 88;
+
+const EXT_END: u32 = 0;
+const EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+/// L2 tables kept in memory
+const L2_CACHE_SIZE: usize = 64;
+/// Table size limits of QEMU, anything larger is a corrupt header
+const MAX_L1_BYTES: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_BYTES: u64 = 8 << 20;
+const MAX_BACKING_NAME: u32 = 1023;
+/// Format names are short, like `qcow2` or `raw`
+const MAX_BACKING_FORMAT: u64 = 16;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn unsupported(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, msg.into())
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Check a `bytes` long table at `offset` is within `max` and the file before reading it
+fn check_table(what: &str, offset: u64, bytes: Option<u64>, max: u64, file_len: u64) -> io::Result<()> {
+    match bytes {
+        Some(bytes) if bytes <= max && offset.checked_add(bytes).is_some_and(|end| end <= file_len) => Ok(()),
+        _ => Err(invalid(format!("qcow2 {what} table out of bounds"))),
+    }
+}
+
+fn read_table(file: &File, offset: u64, entries: usize) -> io::Result<Vec<u64>> {
+    let mut raw = vec![0u8; entries * 8];
+    file.read_exact_at(&mut raw, offset)?;
+    Ok(raw.chunks_exact(8).map(|entry| u64::from_be_bytes(entry.try_into().unwrap())).collect())
+}
+
+/// Where the data of a guest cluster lives
+#[derive(Debug, Clone, Copy)]
+enum Mapping {
+    /// In the backing file, or zeros without one
+    Unallocated,
+    /// Reads as zeros, with the host cluster preallocated for it if any
+    Zero(Option<u64>),
+    Data(u64),
+    Compressed { offset: u64, len: usize },
+}
+
+pub struct Qcow2Disk {
+    file: File,
+    readonly: bool,
+    version: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_table_offset: u64,
+    l1: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    refcount_order: u32,
+    /// By L2 table offset
+    l2_cache: HashMap<u64, Vec<u64>>,
+    /// Last decompressed cluster, by host offset
+    compressed_cache: Option<(u64, Vec<u8>)>,
+    backing: Option<Box<dyn DiskBackend>>,
+    /// Where the next cluster gets allocated
+    file_end: u64,
+}
+
+impl std::fmt::Debug for Qcow2Disk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Qcow2Disk")
+            .field("version", &self.version)
+            .field("cluster_bits", &self.cluster_bits)
+            .field("size", &self.size)
+            .field("readonly", &self.readonly)
+            .field("backing", &self.backing.is_some())
+            .finish()
+    }
+}
+
+impl Qcow2Disk {
+    /// Open an image of `chain`, and its own backing chain
+    pub(super) fn open_chain(path: &Path, readonly: bool, chain: &mut BackingChain) -> io::Result<Self> {
+        chain.enter(path)?;
+        let file = OpenOptions::new().read(true).write(!readonly).open(path)?;
+        let mut header = [0u8; HEADER_V3_SIZE];
+        let file_len = file.metadata()?.len();
+        let header_len = (file_len as usize).min(HEADER_V3_SIZE);
+        file.read_exact_at(&mut header[..header_len], 0)?;
+        if header[..4] != QCOW2_MAGIC {
+            return Err(invalid("not a qcow2 image"));
+        }
+        let version = be_u32(&header, 4);
+        let cluster_bits = be_u32(&header, 20);
+        if !(2..=3).contains(&version) {
+            return Err(unsupported(format!("qcow2 version {version}")));
+        }
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(invalid(format!("qcow2 cluster_bits {cluster_bits}")));
+        }
+        if be_u32(&header, 32) != 0 {
+            return Err(unsupported("encrypted qcow2"));
+        }
+        let (incompatible, autoclear, refcount_order, header_length) = if version == 3 {
+            (be_u64(&header, 72), be_u64(&header, HEADER_AUTOCLEAR as usize), be_u32(&header, 96), be_u32(&header, 100) as usize)
+        } else {
+            (0, 0, 4, HEADER_V2_SIZE)
+        };
+        if incompatible & !INCOMPAT_SUPPORTED != 0 {
+            return Err(unsupported(format!("qcow2 incompatible features 0x{incompatible:x}")));
+        }
+        if incompatible & INCOMPAT_COMPRESSION_TYPE != 0 && header_length > HEADER_V3_SIZE - 8 && header[HEADER_V3_SIZE - 8] != 0 {
+            return Err(unsupported("qcow2 compression type other than deflate"));
+        }
+        if incompatible & INCOMPAT_CORRUPT != 0 && !readonly {
+            return Err(invalid("qcow2 image marked corrupt, open it read only"));
+        }
+        if incompatible & INCOMPAT_DIRTY != 0 {
+            warn!("qcow2 {} was not closed cleanly, refcounts may leak space", path.to_string_lossy());
+        }
+        if refcount_order > 6 {
+            return Err(invalid(format!("qcow2 refcount_order {refcount_order}")));
+        }
+        if be_u32(&header, 60) != 0 && !readonly {
+            return Err(unsupported("writing qcow2 images with internal snapshots"));
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let size = be_u64(&header, 24);
+        let l1_table_offset = be_u64(&header, 40);
+        let l1_size = be_u32(&header, 36) as u64;
+        // Each L1 entry covers an L2 table of cluster_size / 8 clusters
+        let l1_needed = size.div_ceil(cluster_size * (cluster_size / 8));
+        if l1_size < l1_needed {
+            return Err(invalid(format!("qcow2 L1 table of {l1_size} entries for a {size} bytes image")));
+        }
+        check_table("L1", l1_table_offset, Some(l1_size * 8), MAX_L1_BYTES, file_len)?;
+        let l1 = read_table(&file, l1_table_offset, l1_size as usize)?;
+        let refcount_table_offset = be_u64(&header, HEADER_REFCOUNT_TABLE_OFFSET as usize);
+        let refcount_bytes = (be_u32(&header, 56) as u64).checked_mul(cluster_size);
+        check_table("refcount", refcount_table_offset, refcount_bytes, MAX_REFCOUNT_TABLE_BYTES, file_len)?;
+        let refcount_table = read_table(&file, refcount_table_offset, refcount_bytes.unwrap_or(0) as usize / 8)?;
+
+        let backing_format = Self::backing_format(&file, header_length, cluster_size)?;
+        let backing = match be_u64(&header, 8) {
+            0 => None,
+            offset => {
+                let name_len = be_u32(&header, 16);
+                if name_len > MAX_BACKING_NAME {
+                    return Err(invalid(format!("qcow2 backing file name of {name_len} bytes")));
+                }
+                let mut name = vec![0u8; name_len as usize];
+                file.read_exact_at(&mut name, offset)?;
+                let name = String::from_utf8(name).map_err(|_| invalid("qcow2 backing file name"))?;
+                let backing_path = chain.backing_path(path, &name)?;
+                info!("qcow2 backing file {}", backing_path.to_string_lossy());
+                // Never probe an image declared raw, its first sector is guest data
+                let format = backing_format.as_deref().map(str::parse).transpose()?;
+                Some(open_image(&backing_path, format, true, chain)?)
+            }
+        };
+
+        if !readonly && autoclear != 0 {
+            // Autoclear features we don't maintain must be dropped once we write
+            file.write_all_at(&0u64.to_be_bytes(), HEADER_AUTOCLEAR)?;
+        }
+        let file_end = file.metadata()?.len().next_multiple_of(cluster_size);
+        Ok(Self {
+            file,
+            readonly,
+            version,
+            cluster_bits,
+            size,
+            l1_table_offset,
+            l1,
+            refcount_table_offset,
+            refcount_table,
+            refcount_order,
+            l2_cache: HashMap::new(),
+            compressed_cache: None,
+            backing,
+            file_end,
+        })
+    }
+
+    /// Backing file format header extension, if present
+    fn backing_format(file: &File, header_length: usize, cluster_size: u64) -> io::Result<Option<String>> {
+        let mut offset = header_length as u64;
+        let mut ext = [0u8; 8];
+        while offset + 8 <= cluster_size {
+            file.read_exact_at(&mut ext, offset)?;
+            let (kind, len) = (be_u32(&ext, 0), be_u32(&ext, 4) as u64);
+            match kind {
+                EXT_END => break,
+                EXT_BACKING_FORMAT => {
+                    if len > MAX_BACKING_FORMAT || offset + 8 + len > cluster_size {
+                        return Err(invalid(format!("qcow2 backing format extension of {len} bytes")));
+                    }
+                    let mut format = vec![0u8; len as usize];
+                    file.read_exact_at(&mut format, offset + 8)?;
+                    return Ok(Some(String::from_utf8_lossy(&format).into_owned()));
+                }
+                _ => {}
+            }
+            offset += 8 + len.next_multiple_of(8);
+        }
+        Ok(None)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    /// (L1 index, L2 index) of a guest offset
+    fn table_indices(&self, offset: u64) -> (usize, usize) {
+        let cluster = offset >> self.cluster_bits;
+        ((cluster / self.l2_entries()) as usize, (cluster % self.l2_entries()) as usize)
+    }
+
+    fn l2_entry(&mut self, l2_offset: u64, idx: usize) -> io::Result<u64> {
+        if !self.l2_cache.contains_key(&l2_offset) {
+            if self.l2_cache.len() >= L2_CACHE_SIZE {
+                self.l2_cache.clear();
+            }
+            let table = read_table(&self.file, l2_offset, self.l2_entries() as usize)?;
+            self.l2_cache.insert(l2_offset, table);
+        }
+        Ok(self.l2_cache[&l2_offset][idx])
+    }
+
+    fn set_l2_entry(&mut self, l2_offset: u64, idx: usize, entry: u64) -> io::Result<()> {
+        self.file.write_all_at(&entry.to_be_bytes(), l2_offset + idx as u64 * 8)?;
+        if let Some(table) = self.l2_cache.get_mut(&l2_offset) {
+            table[idx] = entry;
+        }
+        Ok(())
+    }
+
+    fn lookup(&mut self, offset: u64) -> io::Result<Mapping> {
+        let (l1_idx, l2_idx) = self.table_indices(offset);
+        let l2_offset = self.l1.get(l1_idx).map_or(0, |entry| entry & L1_OFFSET_MASK);
+        if l2_offset == 0 {
+            return Ok(Mapping::Unallocated);
+        }
+        let entry = self.l2_entry(l2_offset, l2_idx)?;
+        if entry & OFLAG_COMPRESSED != 0 {
+            let size_shift = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << size_shift) - 1);
+            let sectors = ((entry >> size_shift) & ((1 << (self.cluster_bits - 8)) - 1)) + 1;
+            let len = (sectors * 512 - (offset & 511)) as usize;
+            return Ok(Mapping::Compressed { offset, len });
+        }
+        if self.version >= 3 && entry & OFLAG_ZERO != 0 {
+            return Ok(Mapping::Zero(Some(entry & L2_OFFSET_MASK).filter(|&host| host != 0)));
+        }
+        Ok(match entry & L2_OFFSET_MASK {
+            0 => Mapping::Unallocated,
+            data => Mapping::Data(data),
+        })
+    }
+
+    fn read_backing(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let Some(backing) = self.backing.as_mut() else {
+            buf.fill(0);
+            return Ok(());
+        };
+        // The overlay may be bigger than its backing file
+        let available = backing.size().saturating_sub(offset).min(buf.len() as u64) as usize;
+        if available > 0 {
+            backing.read_at(&mut buf[..available], offset)?;
+        }
+        buf[available..].fill(0);
+        Ok(())
+    }
+
+    fn decompress(&mut self, offset: u64, len: usize) -> io::Result<&[u8]> {
+        if self.compressed_cache.as_ref().is_none_or(|(cached, _)| *cached != offset) {
+            // The last compressed cluster may stop short of its sector count
+            let len = (len as u64).min(self.file.metadata()?.len().saturating_sub(offset)) as usize;
+            let mut compressed = vec![0u8; len];
+            self.file.read_exact_at(&mut compressed, offset)?;
+            let cluster_size = self.cluster_size() as usize;
+            let mut data = miniz_oxide::inflate::decompress_to_vec_with_limit(&compressed, cluster_size)
+                .map_err(|e| invalid(format!("qcow2 compressed cluster at 0x{offset:x}: {e:?}")))?;
+            data.resize(cluster_size, 0);
+            self.compressed_cache = Some((offset, data));
+        }
+        Ok(&self.compressed_cache.as_ref().unwrap().1)
+    }
+
+    /// Read `buf` from a single guest cluster, `offset` being the guest offset
+    fn read_mapping(&mut self, mapping: Mapping, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let in_cluster = (offset & (self.cluster_size() - 1)) as usize;
+        match mapping {
+            Mapping::Unallocated => self.read_backing(buf, offset),
+            Mapping::Zero(_) => {
+                buf.fill(0);
+                Ok(())
+            }
+            Mapping::Data(host) => self.file.read_exact_at(buf, host + in_cluster as u64),
+            Mapping::Compressed { offset, len } => {
+                let data = self.decompress(offset, len)?;
+                buf.copy_from_slice(&data[in_cluster..in_cluster + buf.len()]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Byte offset and bit shift of a refcount entry inside its block
+    fn refcount_position(&self, idx: u64) -> (u64, u32) {
+        let bits = 1u64 << self.refcount_order;
+        ((idx * bits) / 8, ((idx * bits) % 8) as u32)
+    }
+
+    fn refcount_block(&self, host_offset: u64) -> (usize, u64) {
+        let per_block = (self.cluster_size() * 8) >> self.refcount_order;
+        let cluster = host_offset >> self.cluster_bits;
+        ((cluster / per_block) as usize, cluster % per_block)
+    }
+
+    fn get_refcount(&self, host_offset: u64) -> io::Result<u64> {
+        let (table_idx, idx) = self.refcount_block(host_offset);
+        let block = self.refcount_table.get(table_idx).map_or(0, |entry| entry & REFCOUNT_OFFSET_MASK);
+        if block == 0 {
+            return Ok(0);
+        }
+        let bits = 1u32 << self.refcount_order;
+        let (byte, shift) = self.refcount_position(idx);
+        let mut raw = vec![0u8; (bits as usize).div_ceil(8)];
+        self.file.read_exact_at(&mut raw, block + byte)?;
+        Ok(if bits < 8 {
+            ((raw[0] >> shift) & ((1u8 << bits) - 1)) as u64
+        } else {
+            raw.iter().fold(0u64, |value, &b| value << 8 | b as u64)
+        })
+    }
+
+    fn set_refcount(&mut self, host_offset: u64, value: u64) -> io::Result<()> {
+        let (table_idx, idx) = self.refcount_block(host_offset);
+        if table_idx >= self.refcount_table.len() {
+            self.grow_refcount_table(table_idx + 1)?;
+        }
+        if self.refcount_table[table_idx] & REFCOUNT_OFFSET_MASK == 0 {
+            let block = self.file_end;
+            self.file_end += self.cluster_size();
+            self.file.write_all_at(&vec![0u8; self.cluster_size() as usize], block)?;
+            self.refcount_table[table_idx] = block;
+            self.file.write_all_at(&block.to_be_bytes(), self.refcount_table_offset + table_idx as u64 * 8)?;
+            // The new block may well hold its own refcount
+            self.set_refcount(block, 1)?;
+        }
+        let block = self.refcount_table[table_idx] & REFCOUNT_OFFSET_MASK;
+        let bits = 1u32 << self.refcount_order;
+        let (byte, shift) = self.refcount_position(idx);
+        let raw = if bits < 8 {
+            let mut old = [0u8; 1];
+            self.file.read_exact_at(&mut old, block + byte)?;
+            let mask = ((1u8 << bits) - 1) << shift;
+            vec![(old[0] & !mask) | (((value as u8) << shift) & mask)]
+        } else {
+            value.to_be_bytes()[8 - bits as usize / 8..].to_vec()
+        };
+        self.file.write_all_at(&raw, block + byte)
+    }
+
+    fn decrement_refcount(&mut self, host_offset: u64) -> io::Result<()> {
+        let refcount = self.get_refcount(host_offset)?;
+        self.set_refcount(host_offset, refcount.saturating_sub(1))
+    }
+
+    /// Drop the reference a guest cluster held on its host clusters
+    fn release(&mut self, mapping: Mapping) -> io::Result<()> {
+        let cluster_size = self.cluster_size();
+        match mapping {
+            Mapping::Data(host) | Mapping::Zero(Some(host)) => self.decrement_refcount(host),
+            // Each host cluster holding part of the compressed data has one reference for it
+            Mapping::Compressed { offset, len } => {
+                for host in ((offset & !(cluster_size - 1))..offset + len as u64).step_by(cluster_size as usize) {
+                    self.decrement_refcount(host)?;
+                }
+                Ok(())
+            }
+            Mapping::Unallocated | Mapping::Zero(None) => Ok(()),
+        }
+    }
+
+    /// Move the refcount table to a bigger spot at the end of the file
+    fn grow_refcount_table(&mut self, min_entries: usize) -> io::Result<()> {
+        let per_cluster = self.l2_entries() as usize;
+        let old_clusters = self.refcount_table.len().div_ceil(per_cluster) as u64;
+        let clusters = (min_entries.div_ceil(per_cluster) as u64).max(old_clusters * 2);
+        let offset = self.file_end;
+        self.file_end += clusters * self.cluster_size();
+
+        let mut table = self.refcount_table.clone();
+        table.resize(clusters as usize * per_cluster, 0);
+        let raw: Vec<u8> = table.iter().flat_map(|entry| entry.to_be_bytes()).collect();
+        self.file.write_all_at(&raw, offset)?;
+        let mut header = offset.to_be_bytes().to_vec();
+        header.extend_from_slice(&(clusters as u32).to_be_bytes());
+        self.file.write_all_at(&header, HEADER_REFCOUNT_TABLE_OFFSET)?;
+        debug!("qcow2 refcount table moved to 0x{offset:x}, {clusters} clusters");
+
+        let old_offset = self.refcount_table_offset;
+        self.refcount_table = table;
+        self.refcount_table_offset = offset;
+        for i in 0..clusters {
+            self.set_refcount(offset + i * self.cluster_size(), 1)?;
+        }
+        for i in 0..old_clusters {
+            self.decrement_refcount(old_offset + i * self.cluster_size())?;
+        }
+        Ok(())
+    }
+
+    fn alloc_cluster(&mut self) -> io::Result<u64> {
+        let offset = self.file_end;
+        self.file_end += self.cluster_size();
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    /// L2 table of `l1_idx` with a refcount of one, allocated or copied as needed
+    fn l2_table_for_write(&mut self, l1_idx: usize) -> io::Result<u64> {
+        let entry = *self.l1.get(l1_idx).ok_or_else(|| invalid("qcow2 L1 table too small for the image size"))?;
+        let old = entry & L1_OFFSET_MASK;
+        if old != 0 && entry & OFLAG_COPIED != 0 {
+            return Ok(old);
+        }
+        let table = if old == 0 {
+            vec![0u8; self.cluster_size() as usize]
+        } else {
+            let mut table = vec![0u8; self.cluster_size() as usize];
+            self.file.read_exact_at(&mut table, old)?;
+            table
+        };
+        let new = self.alloc_cluster()?;
+        self.file.write_all_at(&table, new)?;
+        self.l1[l1_idx] = new | OFLAG_COPIED;
+        self.file.write_all_at(&self.l1[l1_idx].to_be_bytes(), self.l1_table_offset + l1_idx as u64 * 8)?;
+        if old != 0 {
+            self.decrement_refcount(old)?;
+        }
+        Ok(new)
+    }
+
+    /// Write `data` within a single guest cluster at guest `offset`
+    fn write_cluster(&mut self, data: &[u8], offset: u64) -> io::Result<()> {
+        let cluster_size = self.cluster_size();
+        let in_cluster = offset & (cluster_size - 1);
+        let (l1_idx, l2_idx) = self.table_indices(offset);
+        let l2_offset = self.l2_table_for_write(l1_idx)?;
+        let entry = self.l2_entry(l2_offset, l2_idx)?;
+        let mapping = self.lookup(offset)?;
+        match mapping {
+            Mapping::Data(host) if entry & OFLAG_COPIED != 0 => {
+                return self.file.write_all_at(data, host + in_cluster);
+            }
+            // A preallocated zero cluster is written in place, zeros around the data
+            Mapping::Zero(Some(host)) if entry & OFLAG_COPIED != 0 => {
+                let mut cluster = vec![0u8; cluster_size as usize];
+                cluster[in_cluster as usize..in_cluster as usize + data.len()].copy_from_slice(data);
+                self.file.write_all_at(&cluster, host)?;
+                return self.set_l2_entry(l2_offset, l2_idx, host | OFLAG_COPIED);
+            }
+            _ => {}
+        }
+        // Copy on write: the rest of the cluster comes from wherever it lived
+        let mut cluster = vec![0u8; cluster_size as usize];
+        if data.len() as u64 != cluster_size {
+            self.read_mapping(mapping, &mut cluster, offset - in_cluster)?;
+        }
+        cluster[in_cluster as usize..in_cluster as usize + data.len()].copy_from_slice(data);
+        let host = self.alloc_cluster()?;
+        self.file.write_all_at(&cluster, host)?;
+        self.set_l2_entry(l2_offset, l2_idx, host | OFLAG_COPIED)?;
+        self.release(mapping)
+    }
+
+    /// Call `f` for every piece of [offset, offset + len) that stays within a cluster
+    fn for_each_cluster(&self, offset: u64, len: usize, mut f: impl FnMut(u64, std::ops::Range<usize>) -> io::Result<()>) -> io::Result<()> {
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let chunk = ((self.cluster_size() - (pos & (self.cluster_size() - 1))) as usize).min(len - done);
+            f(pos, done..done + chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl DiskBackend for Qcow2Disk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        self.readonly
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        check_range(offset, buf.len(), self.size)?;
+        let mut pieces = vec![];
+        self.for_each_cluster(offset, buf.len(), |pos, range| {
+            pieces.push((pos, range));
+            Ok(())
+        })?;
+        for (pos, range) in pieces {
+            let mapping = self.lookup(pos)?;
+            self.read_mapping(mapping, &mut buf[range], pos)?;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if self.readonly {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        check_range(offset, buf.len(), self.size)?;
+        let mut pieces = vec![];
+        self.for_each_cluster(offset, buf.len(), |pos, range| {
+            pieces.push((pos, range));
+            Ok(())
+        })?;
+        for (pos, range) in pieces {
+            self.write_cluster(&buf[range], pos)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Whole clusters only: they become zero clusters (v3) or unallocated (v2 without backing file)
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if self.readonly {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        check_range(offset, len as usize, self.size)?;
+        let cluster_size = self.cluster_size();
+        let (start, end) = (offset.next_multiple_of(cluster_size), (offset + len) & !(cluster_size - 1));
+        let replacement = match (self.version, &self.backing) {
+            (3.., _) => OFLAG_ZERO,
+            (_, None) => 0,
+            // Unallocated would show the backing file again
+            (_, Some(_)) => return Ok(()),
+        };
+        for pos in (start..end).step_by(cluster_size as usize) {
+            let (l1_idx, l2_idx) = self.table_indices(pos);
+            let l1_entry = self.l1.get(l1_idx).copied().unwrap_or(0);
+            if l1_entry & L1_OFFSET_MASK == 0 || l1_entry & OFLAG_COPIED == 0 {
+                continue;
+            }
+            let l2_offset = l1_entry & L1_OFFSET_MASK;
+            let mapping = self.lookup(pos)?;
+            self.set_l2_entry(l2_offset, l2_idx, replacement)?;
+            self.release(mapping)?;
+            match mapping {
+                // A cluster still shared with a snapshot keeps its data
+                Mapping::Data(host) | Mapping::Zero(Some(host)) if self.get_refcount(host)? == 0 => {
+                    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+                    unsafe { libc::fallocate(self.file.as_raw_fd(), mode, host as i64, cluster_size as i64) };
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ fs, path::PathBuf, sync::atomic::{ AtomicUsize, Ordering } };
+
+    use super::*;
+    use crate::vmm::disk::{ open_disk, DiskFormat, DiskOptions, RawDisk };
+
+    const CLUSTER_BITS: u32 = 12;
+    const CLUSTER: u64 = 1 << CLUSTER_BITS;
+    const SIZE: u64 = 1 << 20;
+
+    /// Images of a test, in a directory of their own removed on drop
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!("qcow2-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+            let dir = std::env::temp_dir().join(name);
+            fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+
+        fn file(&self, name: &str, content: &[u8]) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, content).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    impl Qcow2Disk {
+        /// Backing files are only looked for in the image directory
+        fn open<P: AsRef<Path>>(path: P, readonly: bool) -> io::Result<Self> {
+            Self::open_chain(path.as_ref(), readonly, &mut BackingChain::default())
+        }
+    }
+
+    /// `content` as the only image of a test directory
+    fn image_file(content: &[u8]) -> (TestDir, PathBuf) {
+        let dir = TestDir::new();
+        let path = dir.file("disk.qcow2", content);
+        (dir, path)
+    }
+
+    /// Empty version 3 image: header, L1 table, refcount table and refcount block
+    /// in the first four clusters, `backing` being the backing file name
+    fn image(backing: Option<&str>) -> Vec<u8> {
+        let mut image = vec![0u8; 4 * CLUSTER as usize];
+        let mut put = |offset: usize, bytes: &[u8]| image[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(0, &QCOW2_MAGIC);
+        put(4, &3u32.to_be_bytes());
+        if let Some(name) = backing {
+            put(8, &512u64.to_be_bytes());
+            put(16, &(name.len() as u32).to_be_bytes());
+            put(512, name.as_bytes());
+        }
+        put(20, &CLUSTER_BITS.to_be_bytes());
+        put(24, &SIZE.to_be_bytes());
+        put(36, &1u32.to_be_bytes());
+        put(40, &CLUSTER.to_be_bytes());
+        put(48, &(2 * CLUSTER).to_be_bytes());
+        put(56, &1u32.to_be_bytes());
+        put(96, &4u32.to_be_bytes());
+        put(100, &(HEADER_V3_SIZE as u32).to_be_bytes());
+        put(2 * CLUSTER as usize, &(3 * CLUSTER).to_be_bytes());
+        for cluster in 0..4 {
+            put(3 * CLUSTER as usize + cluster * 2, &1u16.to_be_bytes());
+        }
+        image
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    fn read(disk: &mut Qcow2Disk, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        disk.read_at(&mut buf, offset).unwrap();
+        buf
+    }
+
+    #[test]
+    fn corrupt_headers_rejected() {
+        type Patch = fn(&mut [u8]);
+        let open = |patch: Patch| {
+            let mut image = image(None);
+            patch(&mut image);
+            let (_dir, path) = image_file(&image);
+            Qcow2Disk::open(&path, true).map(|_| ())
+        };
+        assert!(open(|_| ()).is_ok());
+        let cases: [(&str, Patch); 8] = [
+            ("huge L1", |image| image[36..40].copy_from_slice(&u32::MAX.to_be_bytes())),
+            ("L1 past the file end", |image| image[40..48].copy_from_slice(&(4 * CLUSTER - 4).to_be_bytes())),
+            ("L1 too small for the size", |image| image[24..32].copy_from_slice(&(1u64 << 40).to_be_bytes())),
+            ("huge refcount table", |image| image[56..60].copy_from_slice(&u32::MAX.to_be_bytes())),
+            ("refcount table offset overflow", |image| image[48..56].copy_from_slice(&(u64::MAX - 8).to_be_bytes())),
+            ("huge backing name", |image| {
+                image[8..16].copy_from_slice(&512u64.to_be_bytes());
+                image[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+            }),
+            ("huge backing format", |image| {
+                image[HEADER_V3_SIZE..HEADER_V3_SIZE + 4].copy_from_slice(&EXT_BACKING_FORMAT.to_be_bytes());
+                image[HEADER_V3_SIZE + 4..HEADER_V3_SIZE + 8].copy_from_slice(&u32::MAX.to_be_bytes());
+            }),
+            ("backing format past the header cluster", |image| {
+                let at = CLUSTER as usize - 16;
+                image[HEADER_V3_SIZE..HEADER_V3_SIZE + 4].copy_from_slice(&0x1234u32.to_be_bytes());
+                image[HEADER_V3_SIZE + 4..HEADER_V3_SIZE + 8].copy_from_slice(&((at - HEADER_V3_SIZE - 8) as u32).to_be_bytes());
+                image[at..at + 4].copy_from_slice(&EXT_BACKING_FORMAT.to_be_bytes());
+                image[at + 4..at + 8].copy_from_slice(&12u32.to_be_bytes());
+            }),
+        ];
+        for (what, patch) in cases {
+            let err = open(patch).expect_err(what);
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{what}");
+        }
+    }
+
+    #[test]
+    fn allocate_write_read_back() {
+        let (_dir, path) = image_file(&image(None));
+        let mut disk = Qcow2Disk::open(&path, false).unwrap();
+        assert_eq!(read(&mut disk, 0, 2 * CLUSTER as usize), vec![0; 2 * CLUSTER as usize]);
+
+        // Across a cluster boundary, not cluster aligned
+        let data = pattern(CLUSTER as usize + 100, 7);
+        let offset = 5 * CLUSTER - 50;
+        disk.write_at(&data, offset).unwrap();
+        assert_eq!(read(&mut disk, offset, data.len()), data);
+        assert_eq!(read(&mut disk, offset - 10, 10), vec![0; 10]);
+        for guest in [5 * CLUSTER - 50, 5 * CLUSTER] {
+            let Mapping::Data(host) = disk.lookup(guest).unwrap() else {
+                panic!("0x{guest:x} not allocated");
+            };
+            assert_eq!(disk.get_refcount(host).unwrap(), 1);
+        }
+
+        // Rewriting allocated clusters doesn't allocate again
+        let end = disk.file_end;
+        disk.write_at(&pattern(64, 9), offset).unwrap();
+        assert_eq!(disk.file_end, end);
+
+        drop(disk);
+        let mut disk = Qcow2Disk::open(&path, true).unwrap();
+        let mut expected = data;
+        expected[..64].copy_from_slice(&pattern(64, 9));
+        assert_eq!(read(&mut disk, offset, expected.len()), expected);
+    }
+
+    #[test]
+    fn refcount_table_growth() {
+        let (_dir, path) = image_file(&image(None));
+        let mut disk = Qcow2Disk::open(&path, false).unwrap();
+        disk.write_at(&pattern(CLUSTER as usize, 1), 0).unwrap();
+        let old_offset = disk.refcount_table_offset;
+        let entries = disk.refcount_table.len();
+
+        disk.grow_refcount_table(entries + 1).unwrap();
+        assert!(disk.refcount_table.len() > entries);
+        assert_eq!(disk.get_refcount(old_offset).unwrap(), 0);
+        assert_eq!(disk.get_refcount(disk.refcount_table_offset).unwrap(), 1);
+        // Writes keep working with the moved table, and a reopen finds it
+        disk.write_at(&pattern(CLUSTER as usize, 2), CLUSTER).unwrap();
+        let moved = disk.refcount_table_offset;
+        drop(disk);
+        let mut disk = Qcow2Disk::open(&path, false).unwrap();
+        assert_eq!(disk.refcount_table_offset, moved);
+        assert_eq!(read(&mut disk, 0, CLUSTER as usize), pattern(CLUSTER as usize, 1));
+        let Mapping::Data(host) = disk.lookup(CLUSTER).unwrap() else {
+            panic!("cluster 1 not allocated");
+        };
+        assert_eq!(disk.get_refcount(host).unwrap(), 1);
+    }
+
+    #[test]
+    fn backing_file_fallthrough() {
+        let backing = pattern(SIZE as usize, 3);
+        let dir = TestDir::new();
+        let backing_path = dir.file("base.raw", &backing);
+        // Backing names are relative to the overlay directory
+        let path = dir.file("overlay.qcow2", &image(Some("base.raw")));
+        let mut disk = Qcow2Disk::open(&path, false).unwrap();
+        assert_eq!(read(&mut disk, 3 * CLUSTER, 100), backing[3 * CLUSTER as usize..][..100]);
+
+        // The rest of a partially written cluster is copied from the backing file
+        let data = pattern(16, 11);
+        disk.write_at(&data, 3 * CLUSTER + 8).unwrap();
+        let mut expected = backing[3 * CLUSTER as usize..4 * CLUSTER as usize].to_vec();
+        expected[8..24].copy_from_slice(&data);
+        assert_eq!(read(&mut disk, 3 * CLUSTER, CLUSTER as usize), expected);
+        assert_eq!(read(&mut disk, 4 * CLUSTER, 32), backing[4 * CLUSTER as usize..][..32]);
+        let mut untouched = vec![0u8; 32];
+        RawDisk::open(&backing_path, true).unwrap().read_at(&mut untouched, 3 * CLUSTER + 8).unwrap();
+        assert_eq!(untouched, backing[(3 * CLUSTER + 8) as usize..][..32]);
+    }
+
+    #[test]
+    fn preallocated_zero_cluster_written_in_place() {
+        let (_dir, path) = image_file(&image(None));
+        let mut disk = Qcow2Disk::open(&path, false).unwrap();
+        disk.write_at(&[1], 0).unwrap();
+        let l2_offset = disk.l1[0] & L1_OFFSET_MASK;
+        let host = disk.alloc_cluster().unwrap();
+        disk.file.write_all_at(&vec![0xaa; CLUSTER as usize], host).unwrap();
+        disk.set_l2_entry(l2_offset, 2, host | OFLAG_COPIED | OFLAG_ZERO).unwrap();
+        assert_eq!(read(&mut disk, 2 * CLUSTER, CLUSTER as usize), vec![0; CLUSTER as usize]);
+
+        let end = disk.file_end;
+        disk.write_at(&[5; 10], 2 * CLUSTER + 100).unwrap();
+        assert_eq!(disk.file_end, end);
+        assert_eq!(disk.l2_entry(l2_offset, 2).unwrap(), host | OFLAG_COPIED);
+        assert_eq!(disk.get_refcount(host).unwrap(), 1);
+        let mut expected = vec![0u8; CLUSTER as usize];
+        expected[100..110].fill(5);
+        assert_eq!(read(&mut disk, 2 * CLUSTER, CLUSTER as usize), expected);
+    }
+
+    #[test]
+    fn compressed_cluster_read_and_copy_on_write() {
+        let (_dir, path) = image_file(&image(None));
+        let mut disk = Qcow2Disk::open(&path, false).unwrap();
+        disk.write_at(&[1], 0).unwrap();
+        let l2_offset = disk.l1[0] & L1_OFFSET_MASK;
+
+        let data = pattern(CLUSTER as usize, 0).iter().map(|b| b & 0x0f).collect::<Vec<_>>();
+        let compressed = miniz_oxide::deflate::compress_to_vec(&data, 6);
+        let host = disk.alloc_cluster().unwrap();
+        disk.file.write_all_at(&compressed, host).unwrap();
+        let size_shift = 62 - (CLUSTER_BITS - 8);
+        let sectors = (compressed.len() as u64).div_ceil(512);
+        disk.set_l2_entry(l2_offset, 1, OFLAG_COMPRESSED | (sectors - 1) << size_shift | host).unwrap();
+        assert_eq!(read(&mut disk, CLUSTER, CLUSTER as usize), data);
+
+        disk.write_at(&[0xff; 4], CLUSTER + 4).unwrap();
+        let Mapping::Data(copy) = disk.lookup(CLUSTER).unwrap() else {
+            panic!("compressed cluster not copied on write");
+        };
+        assert_ne!(copy, host);
+        assert_eq!(disk.get_refcount(host).unwrap(), 0);
+        let mut expected = data;
+        expected[4..8].fill(0xff);
+        assert_eq!(read(&mut disk, CLUSTER, CLUSTER as usize), expected);
+    }
+
+    #[test]
+    fn backing_chain_loops_and_depth() {
+        let dir = TestDir::new();
+        let path = dir.file("self.qcow2", &image(Some("self.qcow2")));
+        assert_eq!(Qcow2Disk::open(&path, true).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        dir.file("a.qcow2", &image(Some("b.qcow2")));
+        let path = dir.file("b.qcow2", &image(Some("a.qcow2")));
+        assert_eq!(Qcow2Disk::open(&path, true).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // layer0 is the base, the image names the one below it
+        let chain = |layers: usize| {
+            dir.file("layer0.qcow2", &image(None));
+            for layer in 1..layers {
+                dir.file(&format!("layer{layer}.qcow2"), &image(Some(&format!("layer{}.qcow2", layer - 1))));
+            }
+            Qcow2Disk::open(dir.0.join(format!("layer{}.qcow2", layers - 1)), true).map(|_| ())
+        };
+        assert!(chain(16).is_ok());
+        assert_eq!(chain(17).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn backing_names_stay_in_the_image_directory() {
+        let outer = TestDir::new();
+        outer.file("base.raw", &pattern(SIZE as usize, 1));
+        let dir = outer.0.join("images");
+        fs::create_dir(&dir).unwrap();
+        let absolute = outer.0.join("base.raw").to_string_lossy().into_owned();
+        for name in ["../base.raw", absolute.as_str()] {
+            let path = dir.join("overlay.qcow2");
+            fs::write(&path, image(Some(name))).unwrap();
+            let err = Qcow2Disk::open(&path, true).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{name}");
+            let options = DiskOptions { format: Some(DiskFormat::Qcow2), readonly: true, backing_anywhere: true };
+            assert!(open_disk(&path, options).is_ok(), "{name}");
+        }
+    }
+
+    #[test]
+    fn writable_qcow2_needs_its_format() {
+        let (_dir, path) = image_file(&image(None));
+        let writable = open_disk(&path, DiskOptions { readonly: false, ..Default::default() });
+        assert_eq!(writable.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        // Raw only when asked for, the header being guest data
+        let options = DiskOptions { format: Some(DiskFormat::Raw), ..Default::default() };
+        assert_eq!(open_disk(&path, options).unwrap().size(), 4 * CLUSTER);
+        let readonly = open_disk(&path, DiskOptions { readonly: true, ..Default::default() }).unwrap();
+        assert_eq!(readonly.size(), SIZE);
+        let options = DiskOptions { format: Some(DiskFormat::Qcow2), ..Default::default() };
+        assert_eq!(open_disk(&path, options).unwrap().size(), SIZE);
+    }
+}
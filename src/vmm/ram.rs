@@ -1,9 +1,18 @@
+use core::slice;
 use std::ptr::null_mut;
 
-use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
-use kvm_ioctls::VmFd;
-use log::{debug, warn};
-use vm_memory::{GuestMemoryMmap, GuestAddress};
+use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_READONLY};
+use kvm_ioctls::{Cap, VmFd};
+use log::{debug, info, warn};
+use vm_memory::{bitmap::AtomicBitmap, GuestAddress, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// Guest physical range left to MMIO (ECAM, PCI BARs, IOAPIC, firmware), RAM past it goes above 4G
+pub const PCI_HOLE_START: u64 = 0xb000_0000;
+pub const PCI_HOLE_END: u64 = 0x1_0000_0000;
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
 
 #[derive(Debug)]
 #[allow(unused)]
@@ -11,13 +20,216 @@ pub struct Ram {
     pub load_addr: u64,
     pub mem_size: usize,
     pub guest_phys_addr: u64,       
-    pub guest_mem_map: GuestMemoryMmap,
+    /// Devices go through this map, the bitmap records their writes for the dirty log
+    pub guest_mem_map: GuestMemoryMmap<AtomicBitmap>,
+    /// KVM slots currently registered, `layout` split around the write watched ranges
+    pub slots: Vec<MemSlot>,
+    /// Where RAM lives in the guest physical space
+    layout: Vec<MemSlot>,
+    /// Page aligned [start, end) guest physical ranges mapped read only
+    watched: Vec<(u64, u64)>,
+}
+
+/// A KVM memory slot backed by `size` bytes of RAM starting at `host_offset`
+#[derive(Debug, Clone, Copy)]
+pub struct MemSlot {
+    pub slot: u32,
+    pub guest_addr: u64,
+    pub size: usize,
+    pub host_offset: usize,
+    /// Guest writes exit to userspace as MMIO writes
+    pub readonly: bool,
+}
+
+fn set_kvm_slot(vm_fd: &VmFd, load_addr: u64, slot: &MemSlot, memory_size: u64) -> Result<()> {
+    let flags = if slot.readonly { KVM_MEM_LOG_DIRTY_PAGES | KVM_MEM_READONLY } else { KVM_MEM_LOG_DIRTY_PAGES };
+    let mem_region = kvm_userspace_memory_region {
+        slot: slot.slot,
+        userspace_addr: load_addr + slot.host_offset as u64,
+        memory_size,
+        guest_phys_addr: slot.guest_addr,
+        flags,
+    };
+    unsafe { vm_fd.set_user_memory_region(mem_region) }
+}
+
+impl Ram {
+    /// Host view of the whole guest RAM
+    pub fn host_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.load_addr as *const u8, self.mem_size) }
+    }
+
+    /// Host view of a single guest page, `None` if out of RAM
+    pub fn page(&self, page_idx: u64) -> Option<&[u8]> {
+        let start = (page_idx as usize).checked_mul(PAGE_SIZE)?;
+        self.host_slice().get(start..start + PAGE_SIZE)
+    }
+
+    pub fn page_count(&self) -> u64 {
+        self.mem_size.div_ceil(PAGE_SIZE) as u64
+    }
+
+    /// Overwrite a guest page from the host side, not recorded in any dirty log
+    pub fn write_page(&self, page_idx: u64, data: &[u8]) {
+        let start = page_idx as usize * PAGE_SIZE;
+        assert!(start + PAGE_SIZE <= self.mem_size && data.len() == PAGE_SIZE);
+        unsafe {
+            let dst = (self.load_addr as *mut u8).add(start);
+            std::ptr::copy_nonoverlapping(data.as_ptr(), dst, PAGE_SIZE);
+        }
+    }
+
+    /// Guest physical address of an offset in the host mapping
+    pub fn guest_addr(&self, host_offset: usize) -> Option<u64> {
+        self.slots
+            .iter()
+            .find(|s| (s.host_offset..s.host_offset + s.size).contains(&host_offset))
+            .map(|s| s.guest_addr + (host_offset - s.host_offset) as u64)
+    }
+
+    /// Write protect the pages holding [guest_addr, guest_addr + size), guest writes to them
+    /// then exit as MMIO writes. The dirty log of the RAM must be synced before.
+    pub fn watch(&mut self, vm_fd: &VmFd, guest_addr: u64, size: usize) -> Result<()> {
+        if !vm_fd.check_extension(Cap::ReadonlyMem) {
+            warn!("KVM_CAP_READONLY_MEM not supported, can't watch writes");
+            return Err(kvm_ioctls::Error::new(libc::ENOTSUP));
+        }
+        let (start, end) = page_range(guest_addr, size).ok_or(kvm_ioctls::Error::new(libc::EINVAL))?;
+        self.add_watched(start, end);
+        info!("Watching writes to 0x{start:x}..0x{end:x}");
+        self.rebuild_slots(vm_fd)
+    }
+
+    fn add_watched(&mut self, start: u64, end: u64) {
+        self.watched.push((start, end));
+        self.watched.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for &(start, end) in &self.watched {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.watched = merged;
+    }
+
+    /// Stop watching writes to the pages holding [guest_addr, guest_addr + size)
+    pub fn unwatch(&mut self, vm_fd: &VmFd, guest_addr: u64, size: usize) -> Result<()> {
+        let (start, end) = page_range(guest_addr, size).ok_or(kvm_ioctls::Error::new(libc::EINVAL))?;
+        self.remove_watched(start, end);
+        self.rebuild_slots(vm_fd)
+    }
+
+    fn remove_watched(&mut self, start: u64, end: u64) {
+        let mut remaining = vec![];
+        for &(w_start, w_end) in &self.watched {
+            if w_start < start {
+                remaining.push((w_start, w_end.min(start)));
+            }
+            if w_end > end {
+                remaining.push((w_start.max(end), w_end));
+            }
+        }
+        self.watched = remaining;
+    }
+
+    /// RAM bytes mapped below and above 4G
+    pub fn split_4g(&self) -> (u64, u64) {
+        self.layout.iter().fold((0, 0), |(low, high), slot| {
+            if slot.guest_addr < PCI_HOLE_END {
+                (low + slot.size as u64, high)
+            } else {
+                (low, high + slot.size as u64)
+            }
+        })
+    }
+
+    pub fn watched(&self) -> &[(u64, u64)] {
+        &self.watched
+    }
+
+    pub fn is_watched(&self, guest_addr: u64) -> bool {
+        self.watched.iter().any(|&(start, end)| (start..end).contains(&guest_addr))
+    }
+
+    /// Drop every KVM slot and register the layout again, split around the watched ranges
+    fn rebuild_slots(&mut self, vm_fd: &VmFd) -> Result<()> {
+        for slot in &self.slots {
+            set_kvm_slot(vm_fd, self.load_addr, slot, 0)?;
+        }
+        let slots = self.split_layout();
+        for slot in &slots {
+            debug!("KVM slot {} @ guest:0x{:x} size 0x{:x} ro={}", slot.slot, slot.guest_addr, slot.size, slot.readonly);
+            set_kvm_slot(vm_fd, self.load_addr, slot, slot.size as u64)?;
+        }
+        self.slots = slots;
+        Ok(())
+    }
+
+    /// The layout cut in read only slots for the watched ranges and writable ones around them
+    fn split_layout(&self) -> Vec<MemSlot> {
+        let mut slots = vec![];
+        for region in &self.layout {
+            let region_end = region.guest_addr + region.size as u64;
+            let mut cursor = region.guest_addr;
+            let mut push = |start: u64, end: u64, readonly: bool| {
+                if start < end {
+                    slots.push(MemSlot {
+                        slot: slots.len() as u32,
+                        guest_addr: start,
+                        size: (end - start) as usize,
+                        host_offset: region.host_offset + (start - region.guest_addr) as usize,
+                        readonly,
+                    });
+                }
+            };
+            for &(start, end) in &self.watched {
+                let (start, end) = (start.max(cursor), end.min(region_end));
+                if start >= end {
+                    continue;
+                }
+                push(cursor, start, false);
+                push(start, end, true);
+                cursor = end;
+            }
+            push(cursor, region_end, false);
+        }
+        slots
+    }
+
+    /// Offset in the host mapping of a guest physical address
+    pub fn host_offset(&self, guest_addr: u64) -> Option<usize> {
+        self.slots
+            .iter()
+            .find(|s| (s.guest_addr..s.guest_addr + s.size as u64).contains(&guest_addr))
+            .map(|s| s.host_offset + (guest_addr - s.guest_addr) as usize)
+    }
+}
+
+#[cfg(test)]
+impl Ram {
+    /// Guest RAM at guest physical 0 without a VM behind it
+    pub fn anonymous(mem_size: usize) -> Self {
+        use vm_memory::GuestMemory;
+
+        let guest_mem_map = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), mem_size)]).unwrap();
+        let load_addr = guest_mem_map.get_host_address(GuestAddress(0)).unwrap() as u64;
+        let layout = vec![MemSlot { slot: 0, guest_addr: 0, size: mem_size, host_offset: 0, readonly: false }];
+        Ram { load_addr, mem_size, guest_phys_addr: 0, guest_mem_map, slots: layout.clone(), layout, watched: vec![] }
+    }
 }
 
 pub trait BuildRam {
     fn create_ram(&self, mem_size: usize) -> RamBuilder;
 }
 
+/// Pages holding [guest_addr, guest_addr + size), `None` if they run past the address space
+fn page_range(guest_addr: u64, size: usize) -> Option<(u64, u64)> {
+    let page_mask = PAGE_SIZE as u64 - 1;
+    let end = guest_addr.checked_add(size as u64)?.checked_add(page_mask)?;
+    Some((guest_addr & !page_mask, end & !page_mask))
+}
+
 impl BuildRam for VmFd {
     fn create_ram(&self, mut mem_size: usize) -> RamBuilder {
         debug!("making RAM with size: 0x{mem_size:x}");
@@ -49,32 +261,72 @@ impl<'a> RamBuilder<'a> {
         self
     }
 
+    /// RAM layout, the `add_region` ones or the low and high RAM around the PCI hole
+    fn layout(&self) -> Vec<MemSlot> {
+        let regions = if self.regions.is_empty() {
+            let low = self.mem_size.min(PCI_HOLE_START as usize);
+            let mut regions = vec![(GuestAddress(0), low)];
+            if self.mem_size > low {
+                regions.push((GuestAddress(PCI_HOLE_END), self.mem_size - low));
+            }
+            regions
+        } else {
+            self.regions.clone()
+        };
+        let mut host_offset = 0;
+        let mut layout = vec![];
+        for (slot, (start, size)) in regions.into_iter().enumerate() {
+            layout.push(MemSlot { slot: slot as u32, guest_addr: start.0, size, host_offset, readonly: false });
+            host_offset += size;
+        }
+        assert_eq!(host_offset, self.mem_size, "RAM regions don't add up to the RAM size");
+        layout
+    }
+
     pub fn build(self) -> Ram {
-        let host_userspace_addr = self.kvm_allocate_region(0, None, 0, self.mem_size as u64);
+        let layout = self.layout();
+        let host_userspace_addr = self.kvm_allocate_region(&layout[0], None);
+        for slot in &layout[1..] {
+            self.kvm_allocate_region(slot, Some(host_userspace_addr));
+        }
+        // The mapping stays owned by us, vm-memory only borrows it to give devices a safe view
+        let regions = layout
+            .iter()
+            .map(|slot| {
+                let region = unsafe {
+                    MmapRegion::build_raw(
+                        (host_userspace_addr as usize + slot.host_offset) as *mut u8,
+                        slot.size,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE
+                    ).expect("Could not wrap guest RAM mapping")
+                };
+                GuestRegionMmap::new(region, GuestAddress(slot.guest_addr)).expect("Could not build guest memory region")
+            })
+            .collect();
+        let guest_mem_map = GuestMemoryMmap::from_regions(regions).expect("Could not build guest memory map");
         Ram {
             load_addr: host_userspace_addr,
             mem_size: self.mem_size,
             guest_phys_addr: 0,
-            guest_mem_map: GuestMemoryMmap::new() 
+            guest_mem_map,
+            slots: layout.clone(),
+            layout,
+            watched: vec![],
         }
     }
 
-    fn kvm_allocate_region(
-        &self,
-        slot: u32,
-        userspace_addr: Option<u64>,
-        guest_phys_addr: u64,
-        size: u64) -> u64
-    {
+    fn kvm_allocate_region(&self, slot: &MemSlot, userspace_addr: Option<u64>) -> u64 {
         let vm_fd = &self.vm_fd;
-        debug!("KVM Allocation for 0x{size:x} bytes @ guest:0x{guest_phys_addr:x} slot 0");
+        let size = slot.size as u64;
+        debug!("KVM Allocation for 0x{size:x} bytes @ guest:0x{:x} slot {}", slot.guest_addr, slot.slot);
         let userspace_addr: u64 = unsafe {
             match userspace_addr {
                 Some(addr) => addr,
                 None =>  
                 libc::mmap(
                     null_mut(),
-                    size as usize,
+                    self.mem_size,
                     libc::PROT_READ | libc::PROT_WRITE,
                     libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
                     -1,
@@ -87,15 +339,44 @@ impl<'a> RamBuilder<'a> {
         }
 
         debug!("Addr: {:x?}", userspace_addr as *mut u8);
-        
-        let mem_region = kvm_userspace_memory_region {
-            slot,
-            userspace_addr,
-            memory_size: size,
-            guest_phys_addr,
-            flags: KVM_MEM_LOG_DIRTY_PAGES
-        };
-        unsafe { vm_fd.set_user_memory_region(mem_region).unwrap() }
+
+        set_kvm_slot(vm_fd, userspace_addr, slot, size).unwrap();
         userspace_addr
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watched_ranges_split_slots() {
+        let mut ram = Ram::anonymous(0x10000);
+        let (start, end) = page_range(0x2ffc, 8).unwrap();
+        assert_eq!((start, end), (0x2000, 0x4000));
+        assert_eq!(page_range(u64::MAX - 0xfff, 0x1000), None);
+        assert_eq!(page_range(u64::MAX - 4, 8), None);
+        ram.add_watched(start, end);
+        ram.add_watched(0x3000, 0x5000);
+        ram.add_watched(0x8000, 0x9000);
+        assert_eq!(ram.watched(), [(0x2000, 0x5000), (0x8000, 0x9000)]);
+        assert!(ram.is_watched(0x4fff) && !ram.is_watched(0x5000));
+
+        ram.remove_watched(0x3000, 0x4000);
+        assert_eq!(ram.watched(), [(0x2000, 0x3000), (0x4000, 0x5000), (0x8000, 0x9000)]);
+        let slots: Vec<_> = ram.split_layout().iter().map(|s| (s.slot, s.guest_addr, s.size, s.host_offset, s.readonly)).collect();
+        assert_eq!(slots, [
+            (0, 0, 0x2000, 0, false),
+            (1, 0x2000, 0x1000, 0x2000, true),
+            (2, 0x3000, 0x1000, 0x3000, false),
+            (3, 0x4000, 0x1000, 0x4000, true),
+            (4, 0x5000, 0x3000, 0x5000, false),
+            (5, 0x8000, 0x1000, 0x8000, true),
+            (6, 0x9000, 0x7000, 0x9000, false),
+        ]);
+
+        ram.remove_watched(0, 0x10000);
+        assert!(ram.watched().is_empty());
+        assert_eq!(ram.split_layout().len(), 1);
+    }
+}
@@ -0,0 +1,591 @@
+//! Device models a VM can be given by name, from the builder or the config file.
+//!
+//! Each kind declares the options it takes, which are checked before the
+//! model is created, and the bus and interrupts it needs, which the builder
+//! allocates as devices are added. In-kernel KVM devices are not models: the
+//! irqchip and PIT are created by their own ioctls with the interrupt routing,
+//! see [`IrqChipMode`], the others with KVM_CREATE_DEVICE from
+//! [`KVM_DEVICE_KINDS`]. Nothing of them is on our buses or in our snapshots.
+//!
+//! [`IrqChipMode`]: super::irq::IrqChipMode
+
+use std::{ collections::BTreeMap, fmt, io, path::PathBuf };
+
+use kvm_bindings::{ kvm_device_type, kvm_device_type_KVM_DEV_TYPE_VFIO };
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use serde::Deserialize;
+use toml::{ Table, Value };
+
+use super::{
+    acpi::SCI_IRQ,
+    disk::{ DiskFormat, DiskOptions },
+    hpet::HPET_GSIS,
+    i8042::{ KEYBOARD_GSI, MOUSE_GSI },
+    pci::PCI_SLOTS,
+    q35::LPC_DEVICE,
+    rtc::RTC_GSI,
+    virtio::{ VirtioTransport, VIRTIO_MMIO_GSI_BASE, VIRTIO_MMIO_MAX_DEVICES },
+    vm_builder::VmBuilder,
+};
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum DeviceError {
+    /// Unknown device kind {0}
+    UnknownKind(String),
+    /// {0} is part of the machine, it can't be added
+    Platform(&'static str),
+    /// {0} takes no {1} option
+    UnknownOption(&'static str, String),
+    /// {0} needs a {1} option
+    MissingOption(&'static str, &'static str),
+    /// {0} option {1} must be a {2}
+    BadOption(&'static str, &'static str, OptionType),
+    /// Can't create {0}: {1}
+    Create(&'static str, std::io::Error),
+    /// {0} is an in-kernel KVM device, not a model
+    InKernel(String),
+}
+
+/// Where a device model sits, what the builder allocates for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceBus {
+    /// Fixed I/O ports
+    Pio,
+    /// Fixed MMIO window
+    Mmio,
+    /// Slot of PCI bus 0 of the named function, shared by the devices naming the same one
+    Pci(&'static str),
+    /// The builder's virtio transport: a PCI slot of its own, or an MMIO
+    /// window with a GSI of the virtio-mmio range
+    Virtio,
+}
+
+impl fmt::Display for DeviceBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pio => write!(f, "I/O ports"),
+            Self::Mmio => write!(f, "MMIO"),
+            Self::Pci(function) => write!(f, "PCI, {function} function"),
+            Self::Virtio => write!(f, "virtio transport"),
+        }
+    }
+}
+
+/// Interrupts a device model raises
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceIrq {
+    None,
+    /// Fixed GSIs, the ISA IRQ of the same number for the first 16
+    Lines(&'static [u32]),
+    /// MSI or MSI-X, or the GSI of its MMIO window on the virtio-mmio transport
+    Msi,
+}
+
+impl fmt::Display for DeviceIrq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "no interrupt"),
+            Self::Lines(gsis) => {
+                let gsis: Vec<String> = gsis.iter().map(u32::to_string).collect();
+                write!(f, "GSI {}", gsis.join(", "))
+            }
+            Self::Msi => write!(f, "MSI"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionType {
+    Path,
+    Bool,
+    /// One of these strings
+    Choice(&'static [&'static str]),
+}
+
+impl fmt::Display for OptionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Path => "path",
+            Self::Bool => "boolean",
+            Self::Choice(choices) => return write!(f, "{}", choices.join("|")),
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug)]
+pub struct DeviceOption {
+    pub name: &'static str,
+    pub ty: OptionType,
+    pub required: bool,
+    pub doc: &'static str,
+}
+
+type CreateFn = fn(VmBuilder, &DeviceOptions) -> std::io::Result<VmBuilder>;
+
+pub struct DeviceKind {
+    pub name: &'static str,
+    pub doc: &'static str,
+    pub bus: DeviceBus,
+    pub irq: DeviceIrq,
+    pub options: &'static [DeviceOption],
+    /// None for the devices every VM has
+    create: Option<CreateFn>,
+}
+
+impl fmt::Debug for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceKind")
+            .field("name", &self.name)
+            .field("bus", &self.bus)
+            .field("irq", &self.irq)
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+/// Options of a device, checked against its kind
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct DeviceOptions {
+    #[serde(flatten)]
+    values: Table,
+    /// Path options given as paths, which TOML strings can't all be
+    #[serde(skip)]
+    paths: BTreeMap<String, PathBuf>,
+}
+
+impl From<Table> for DeviceOptions {
+    fn from(values: Table) -> Self {
+        Self { values, paths: BTreeMap::new() }
+    }
+}
+
+impl DeviceOptions {
+    pub fn set<V: Into<Value>>(&mut self, name: &str, value: V) {
+        self.values.insert(name.to_string(), value.into());
+    }
+
+    /// Set a path option as is
+    pub fn set_path(&mut self, name: &str, path: PathBuf) {
+        self.paths.insert(name.to_string(), path);
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.optional_path(name).unwrap_or_default()
+    }
+
+    /// None when the option isn't set
+    pub fn optional_path(&self, name: &str) -> Option<PathBuf> {
+        self.paths.get(name).cloned().or_else(|| self.values.get(name).and_then(Value::as_str).map(PathBuf::from))
+    }
+
+    pub fn bool(&self, name: &str) -> bool {
+        self.values.get(name).and_then(Value::as_bool).unwrap_or(false)
+    }
+
+    /// None when the option isn't set
+    pub fn choice(&self, name: &str) -> Option<&str> {
+        self.values.get(name).and_then(Value::as_str)
+    }
+
+    /// Format, read only and backing file options of disk kinds
+    fn disk(&self) -> std::io::Result<DiskOptions> {
+        Ok(DiskOptions {
+            format: self.choice("format").map(str::parse).transpose()?,
+            readonly: self.bool("readonly"),
+            backing_anywhere: self.bool("backing_anywhere"),
+        })
+    }
+}
+
+const PATH: DeviceOption = DeviceOption { name: "path", ty: OptionType::Path, required: true, doc: "image file" };
+const READONLY: DeviceOption =
+    DeviceOption { name: "readonly", ty: OptionType::Bool, required: false, doc: "refuse guest writes, false by default" };
+const FORMAT: DeviceOption = DeviceOption {
+    name: "format",
+    ty: OptionType::Choice(DiskFormat::NAMES),
+    required: false,
+    doc: "image format, raw for writable images when not set, probed for read only ones",
+};
+const BACKING_ANYWHERE: DeviceOption = DeviceOption {
+    name: "backing_anywhere",
+    ty: OptionType::Bool,
+    required: false,
+    doc: "allow qcow2 backing files out of the image directory, false by default",
+};
+
+pub const DEVICE_KINDS: &[DeviceKind] = &[
+    DeviceKind {
+        name: "virtio-blk",
+        doc: "virtio block device on a disk image",
+        bus: DeviceBus::Virtio,
+        irq: DeviceIrq::Msi,
+        options: &[PATH, READONLY, FORMAT, BACKING_ANYWHERE],
+        create: Some(|builder, options| builder.disk(options.path("path"), options.disk()?)),
+    },
+    DeviceKind {
+        name: "ahci-disk",
+        doc: "SATA disk on the next AHCI port",
+        bus: DeviceBus::Pci("ahci"),
+        irq: DeviceIrq::Msi,
+        options: &[PATH, READONLY, FORMAT, BACKING_ANYWHERE],
+        create: Some(|builder, options| builder.ahci_disk(options.path("path"), options.disk()?)),
+    },
+    DeviceKind {
+        name: "cdrom",
+        doc: "read only ATAPI CD-ROM on the next AHCI port",
+        bus: DeviceBus::Pci("ahci"),
+        irq: DeviceIrq::Msi,
+        options: &[PATH],
+        create: Some(|builder, options| builder.cdrom(options.path("path"))),
+    },
+    DeviceKind {
+        name: "nvme",
+        doc: "next namespace of the NVMe controller",
+        bus: DeviceBus::Pci("nvme"),
+        irq: DeviceIrq::Msi,
+        options: &[PATH, READONLY, FORMAT, BACKING_ANYWHERE],
+        create: Some(|builder, options| builder.nvme_disk(options.path("path"), options.disk()?)),
+    },
+    DeviceKind {
+        name: "tpm-crb",
+        doc: "TPM 2.0 with the CRB interface",
+        bus: DeviceBus::Mmio,
+        irq: DeviceIrq::None,
+        options: &[DeviceOption {
+            name: "socket",
+            ty: OptionType::Path,
+            required: false,
+            doc: "swtpm control socket, the built-in stand-in TPM when not set",
+        }],
+        create: Some(|builder, options| builder.tpm(options.optional_path("socket").as_deref())),
+    },
+    DeviceKind {
+        name: "i8042",
+        doc: "keyboard controller with a PS/2 keyboard and mouse",
+        bus: DeviceBus::Pio,
+        irq: DeviceIrq::Lines(&[KEYBOARD_GSI, MOUSE_GSI]),
+        options: &[],
+        create: None,
+    },
+    DeviceKind {
+        name: "rtc",
+        doc: "MC146818 real time clock and CMOS, set up by the [rtc] table",
+        bus: DeviceBus::Pio,
+        irq: DeviceIrq::Lines(&[RTC_GSI]),
+        options: &[],
+        create: None,
+    },
+    DeviceKind {
+        name: "hpet",
+        doc: "high precision event timer with three comparators",
+        bus: DeviceBus::Mmio,
+        irq: DeviceIrq::Lines(&HPET_GSIS),
+        options: &[],
+        create: None,
+    },
+    DeviceKind {
+        name: "acpi-pm",
+        doc: "ACPI PM block with the PM timer, S5 power off and the power button",
+        bus: DeviceBus::Pio,
+        irq: DeviceIrq::Lines(&[SCI_IRQ as u32]),
+        options: &[],
+        create: None,
+    },
+    DeviceKind {
+        name: "fw_cfg",
+        doc: "firmware configuration interface carrying the ACPI tables",
+        bus: DeviceBus::Pio,
+        irq: DeviceIrq::None,
+        options: &[],
+        create: None,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static DeviceKind> {
+    DEVICE_KINDS.iter().find(|kind| kind.name == name)
+}
+
+/// Device KVM emulates in the kernel, created with KVM_CREATE_DEVICE
+#[derive(Debug)]
+pub struct KvmDeviceKind {
+    pub name: &'static str,
+    pub doc: &'static str,
+    pub device_type: kvm_device_type,
+}
+
+pub const KVM_DEVICE_KINDS: &[KvmDeviceKind] = &[KvmDeviceKind {
+    name: "vfio",
+    doc: "KVM side of VFIO, tracking the groups of assigned host devices",
+    device_type: kvm_device_type_KVM_DEV_TYPE_VFIO,
+}];
+
+pub fn find_kvm(name: &str) -> Option<&'static KvmDeviceKind> {
+    KVM_DEVICE_KINDS.iter().find(|kind| kind.name == name)
+}
+
+impl fmt::Display for KvmDeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}, in-kernel", self.name, self.doc)
+    }
+}
+
+/// Where the builder puts a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Slot of PCI bus 0
+    Pci(u8),
+    /// GSI of a virtio-mmio device, its window following those of the previous ones
+    Mmio(u32),
+    /// On the ports, window and lines of its kind
+    Fixed,
+}
+
+/// PCI slots and GSIs handed out to the devices so far
+#[derive(Debug, Default)]
+pub struct Allocations {
+    /// Function of each PCI slot taken
+    pci: BTreeMap<u8, &'static str>,
+    /// GSIs of the virtio-mmio devices
+    mmio_gsis: Vec<u32>,
+    /// Kinds on fixed resources already there
+    fixed: Vec<&'static str>,
+}
+
+impl Allocations {
+    /// Allocate what the bus and IRQ of `kind` need
+    pub fn place(&mut self, kind: &DeviceKind, transport: VirtioTransport) -> io::Result<Placement> {
+        match (kind.bus, transport) {
+            (DeviceBus::Pci(function), _) => {
+                match self.pci.iter().find(|(_, owner)| **owner == function) {
+                    Some((&slot, _)) => Ok(Placement::Pci(slot)),
+                    None => self.pci_slot(function),
+                }
+            }
+            (DeviceBus::Virtio, VirtioTransport::Pci) => self.pci_slot(kind.name),
+            (DeviceBus::Virtio, VirtioTransport::Mmio) => {
+                if self.mmio_gsis.len() >= VIRTIO_MMIO_MAX_DEVICES {
+                    return Err(io::Error::other(format!("no more than {VIRTIO_MMIO_MAX_DEVICES} virtio-mmio devices")));
+                }
+                let gsi = VIRTIO_MMIO_GSI_BASE + self.mmio_gsis.len() as u32;
+                self.mmio_gsis.push(gsi);
+                Ok(Placement::Mmio(gsi))
+            }
+            (DeviceBus::Pio | DeviceBus::Mmio, _) => {
+                if self.fixed.contains(&kind.name) {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, "only one fits on its resources"));
+                }
+                self.fixed.push(kind.name);
+                Ok(Placement::Fixed)
+            }
+        }
+    }
+
+    fn pci_slot(&mut self, function: &'static str) -> io::Result<Placement> {
+        let slot = (1..PCI_SLOTS)
+            .find(|slot| *slot != LPC_DEVICE && !self.pci.contains_key(slot))
+            .ok_or_else(|| io::Error::other("no free PCI slot"))?;
+        self.pci.insert(slot, function);
+        Ok(Placement::Pci(slot))
+    }
+
+    /// Slot of a PCI function, once a device on it was placed
+    pub fn pci_function(&self, function: &str) -> Option<u8> {
+        self.pci.iter().find(|(_, owner)| **owner == function).map(|(&slot, _)| slot)
+    }
+}
+
+fn type_matches(ty: OptionType, value: &Value) -> bool {
+    match ty {
+        OptionType::Path => value.is_str(),
+        OptionType::Bool => value.is_bool(),
+        OptionType::Choice(choices) => value.as_str().is_some_and(|value| choices.contains(&value)),
+    }
+}
+
+impl DeviceKind {
+    fn option(&self, name: &str) -> Result<&DeviceOption, DeviceError> {
+        self.options
+            .iter()
+            .find(|option| option.name == name)
+            .ok_or_else(|| DeviceError::UnknownOption(self.name, name.to_string()))
+    }
+
+    pub fn check(&self, options: DeviceOptions) -> Result<DeviceOptions, DeviceError> {
+        for (name, value) in &options.values {
+            let option = self.option(name)?;
+            if !type_matches(option.ty, value) {
+                return Err(DeviceError::BadOption(self.name, option.name, option.ty));
+            }
+        }
+        for name in options.paths.keys() {
+            let option = self.option(name)?;
+            if option.ty != OptionType::Path {
+                return Err(DeviceError::BadOption(self.name, option.name, option.ty));
+            }
+        }
+        let set = |name: &str| options.values.contains_key(name) || options.paths.contains_key(name);
+        if let Some(missing) = self.options.iter().find(|option| option.required && !set(option.name)) {
+            return Err(DeviceError::MissingOption(self.name, missing.name));
+        }
+        Ok(options)
+    }
+
+    /// Check `options` and add the device to `builder`
+    pub fn create(&self, builder: VmBuilder, options: &DeviceOptions) -> Result<VmBuilder, DeviceError> {
+        let create = self.create.ok_or(DeviceError::Platform(self.name))?;
+        let options = self.check(options.clone())?;
+        debug!("Creating {} device {options:?}", self.name);
+        create(builder, &options).map_err(|e| DeviceError::Create(self.name, e))
+    }
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}{}", self.name, self.doc, if self.create.is_none() { ", always present" } else { "" })?;
+        writeln!(f, "    on {}, {}", self.bus, self.irq)?;
+        for option in self.options {
+            let required = if option.required { ", required" } else { "" };
+            writeln!(f, "    {} ({}{required}): {}", option.name, option.ty, option.doc)?;
+        }
+        Ok(())
+    }
+}
+
+impl VmBuilder {
+    /// Add a device model by kind name, with its options
+    pub fn device(self, kind: &str, options: &DeviceOptions) -> Result<Self, DeviceError> {
+        if find_kvm(kind).is_some() {
+            return Err(DeviceError::InKernel(kind.to_string()));
+        }
+        find(kind).ok_or_else(|| DeviceError::UnknownKind(kind.to_string()))?.create(self, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ DeviceConfig, DiskConfig };
+
+    fn table(toml: &str) -> DeviceOptions {
+        toml.parse::<Table>().unwrap().into()
+    }
+
+    #[test]
+    fn options_checked_against_the_kind() {
+        let nvme = find("nvme").unwrap();
+        let options = nvme.check(table("path = \"disk.raw\"")).unwrap();
+        assert_eq!(options.path("path"), PathBuf::from("disk.raw"));
+        assert!(!options.bool("readonly"));
+        assert!(nvme.check(table("path = \"disk.raw\"\nreadonly = true")).unwrap().bool("readonly"));
+
+        assert!(matches!(nvme.check(table("readonly = true")), Err(DeviceError::MissingOption("nvme", "path"))));
+        assert!(matches!(
+            nvme.check(table("path = \"disk.raw\"\nreadonly = 1")),
+            Err(DeviceError::BadOption("nvme", "readonly", OptionType::Bool))
+        ));
+        assert!(matches!(
+            find("cdrom").unwrap().check(table("path = \"cd.iso\"\nreadonly = true")),
+            Err(DeviceError::UnknownOption("cdrom", name)) if name == "readonly"
+        ));
+        assert!(nvme.check(table("path = \"disk.qcow2\"\nformat = \"qcow2\"")).is_ok());
+        assert!(matches!(
+            nvme.check(table("path = \"disk.vmdk\"\nformat = \"vmdk\"")),
+            Err(DeviceError::BadOption("nvme", "format", OptionType::Choice(_)))
+        ));
+        assert!(find("vfio").is_none());
+    }
+
+    #[test]
+    fn disks_map_to_kinds() {
+        let disk = |toml: &str| {
+            let disk: DiskConfig = toml::from_str(toml).unwrap();
+            let device = disk.device();
+            find(&device.kind).unwrap().check(device.options.clone()).unwrap();
+            (device.kind, device.options)
+        };
+        let (kind, options) = disk("path = \"a.raw\"\nreadonly = true");
+        assert_eq!(kind, "virtio-blk");
+        assert_eq!(options.path("path"), PathBuf::from("a.raw"));
+        assert!(options.bool("readonly"));
+        assert_eq!(disk("path = \"a.raw\"\ninterface = \"nvme\"").0, "nvme");
+        let (kind, options) = disk("path = \"a.iso\"\ninterface = \"nvme\"\ncdrom = true");
+        assert_eq!(kind, "cdrom");
+        assert_eq!(options.path("path"), PathBuf::from("a.iso"));
+        assert!(options.choice("readonly").is_none());
+    }
+
+    #[test]
+    fn device_tables_parsed() {
+        let device: DeviceConfig = toml::from_str("kind = \"nvme\"\npath = \"disk.raw\"\nreadonly = true").unwrap();
+        assert_eq!(device.kind, "nvme");
+        assert_eq!(device.options, table("path = \"disk.raw\"\nreadonly = true"));
+    }
+
+    #[test]
+    fn virtio_mmio_limit() {
+        let mut allocations = Allocations::default();
+        for gsi in VIRTIO_MMIO_GSI_BASE..VIRTIO_MMIO_GSI_BASE + VIRTIO_MMIO_MAX_DEVICES as u32 {
+            assert_eq!(allocations.place(find("virtio-blk").unwrap(), VirtioTransport::Mmio).unwrap(), Placement::Mmio(gsi));
+        }
+        let e = allocations.place(find("virtio-blk").unwrap(), VirtioTransport::Mmio).unwrap_err();
+        assert_eq!(e.to_string(), "no more than 4 virtio-mmio devices");
+        // The HPET keeps its pins
+        assert!(HPET_GSIS.iter().all(|gsi| !allocations.mmio_gsis.contains(gsi)));
+        // PCI ones still fit
+        assert_eq!(allocations.place(find("virtio-blk").unwrap(), VirtioTransport::Pci).unwrap(), Placement::Pci(1));
+    }
+
+    #[test]
+    fn paths_kept_as_paths() {
+        use std::{ ffi::OsStr, os::unix::ffi::OsStrExt };
+
+        let nvme = find("nvme").unwrap();
+        let path = PathBuf::from(OsStr::from_bytes(b"disk-\xff.raw"));
+        let mut options = DeviceOptions::default();
+        options.set_path("path", path.clone());
+        assert_eq!(nvme.check(options.clone()).unwrap().path("path"), path);
+
+        options.set_path("readonly", path);
+        assert!(matches!(nvme.check(options), Err(DeviceError::BadOption("nvme", "readonly", OptionType::Bool))));
+    }
+
+    #[test]
+    fn list_format() {
+        assert_eq!(
+            find("cdrom").unwrap().to_string(),
+            "cdrom: read only ATAPI CD-ROM on the next AHCI port\n    on PCI, ahci function, MSI\n    path (path, required): image file\n"
+        );
+        assert_eq!(
+            find("i8042").unwrap().to_string(),
+            "i8042: keyboard controller with a PS/2 keyboard and mouse, always present\n    on I/O ports, GSI 1, 12\n"
+        );
+        assert_eq!(find_kvm("vfio").unwrap().to_string(), "vfio: KVM side of VFIO, tracking the groups of assigned host devices, in-kernel\n");
+    }
+
+    #[test]
+    fn placement_from_bus() {
+        let kind = |name| find(name).unwrap();
+        let mut allocations = Allocations::default();
+        assert_eq!(allocations.place(kind("nvme"), VirtioTransport::Pci).unwrap(), Placement::Pci(1));
+        assert_eq!(allocations.place(kind("virtio-blk"), VirtioTransport::Pci).unwrap(), Placement::Pci(2));
+        assert_eq!(allocations.place(kind("cdrom"), VirtioTransport::Pci).unwrap(), Placement::Pci(3));
+        // Behind the controller already there
+        assert_eq!(allocations.place(kind("ahci-disk"), VirtioTransport::Pci).unwrap(), Placement::Pci(3));
+        assert_eq!(allocations.place(kind("nvme"), VirtioTransport::Pci).unwrap(), Placement::Pci(1));
+        assert_eq!(allocations.pci_function("ahci"), Some(3));
+
+        assert_eq!(allocations.place(kind("virtio-blk"), VirtioTransport::Mmio).unwrap(), Placement::Mmio(VIRTIO_MMIO_GSI_BASE));
+        assert_eq!(allocations.place(kind("tpm-crb"), VirtioTransport::Pci).unwrap(), Placement::Fixed);
+        assert_eq!(allocations.place(kind("tpm-crb"), VirtioTransport::Pci).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        // Every slot but the host and LPC bridges
+        let mut allocations = Allocations::default();
+        for _ in 0..PCI_SLOTS - 2 {
+            let placement = allocations.place(kind("virtio-blk"), VirtioTransport::Pci).unwrap();
+            assert!(!matches!(placement, Placement::Pci(0 | LPC_DEVICE)));
+        }
+        assert!(allocations.place(kind("virtio-blk"), VirtioTransport::Pci).is_err());
+    }
+}
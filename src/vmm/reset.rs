@@ -0,0 +1,253 @@
+//! System reset: the sources pulling the reset line, what each one does, and
+//! bringing the machine back to its power-on state in the same process.
+//!
+//! The power-on state is captured once the VM is built, before it first runs:
+//! in-kernel irqchips and PIT, vCPU and emulated devices, as a snapshot would
+//! save them, plus the firmware image. A reboot puts all of it back and
+//! reloads the firmware, guest RAM is otherwise left as is like on a warm
+//! reset. The RTC keeps its CMOS and time, they are battery backed. The TPM
+//! is powered on again.
+
+use std::sync::{ atomic::{ AtomicU8, Ordering }, Arc };
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use serde::Deserialize;
+use vm_memory::{ Bytes, GuestAddress };
+
+use super::{
+    snapshot::{ self, complete_pending_io, StateBuf, StateReader, VcpuState, VmState },
+    Vm,
+};
+
+/// ICH9 reset control register
+pub const RESET_CONTROL_PORT: u16 = 0xcf9;
+/// Hard reset rather than a CPU only one, kept for the guest to read back
+const RST_SYS: u8 = 1 << 1;
+/// Resets the machine when written, reads back as 0
+const RST_CPU: u8 = 1 << 2;
+const RST_FULL: u8 = 1 << 3;
+
+/// Devices that survive a reset
+const KEPT_DEVICES: &[&str] = &["rtc"];
+
+/// What pulled the reset line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetSource {
+    /// RST_CPU of the reset control register at 0xcf9
+    ResetControl = 1,
+    /// Pulse on the i8042 output port, the 0xfe command
+    I8042,
+    /// The reset register of the FADT
+    Acpi,
+    /// Shutdown exit of the vCPU
+    TripleFault,
+}
+
+impl ResetSource {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::ResetControl),
+            2 => Some(Self::I8042),
+            3 => Some(Self::Acpi),
+            4 => Some(Self::TripleFault),
+            _ => None,
+        }
+    }
+}
+
+/// What a reset source does
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResetAction {
+    /// Back to the power-on state and run the firmware again
+    #[default]
+    Reboot,
+    /// Stop the VM and the process
+    Exit,
+    /// Stop running the vCPU, the monitor can still inspect the VM and reset it
+    Pause,
+}
+
+/// Action of each reset source, the `[reset]` table of the config file
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResetConfig {
+    pub reset_control: ResetAction,
+    pub i8042: ResetAction,
+    pub acpi: ResetAction,
+    pub triple_fault: ResetAction,
+}
+
+impl ResetConfig {
+    pub fn action(&self, source: ResetSource) -> ResetAction {
+        match source {
+            ResetSource::ResetControl => self.reset_control,
+            ResetSource::I8042 => self.i8042,
+            ResetSource::Acpi => self.acpi,
+            ResetSource::TripleFault => self.triple_fault,
+        }
+    }
+}
+
+/// Reset line devices pull from any thread, checked by the vCPU loop after each exit
+#[derive(Debug, Clone, Default)]
+pub struct ResetLine(Arc<AtomicU8>);
+
+impl ResetLine {
+    pub fn pull(&self, source: ResetSource) {
+        info!("Reset requested by {source:?}");
+        self.0.store(source as u8, Ordering::SeqCst);
+    }
+
+    /// The source of a pending reset, the line is released
+    pub fn take(&self) -> Option<ResetSource> {
+        ResetSource::from_u8(self.0.swap(0, Ordering::SeqCst))
+    }
+}
+
+/// Everything a reboot puts back
+pub struct PowerOnState {
+    vm: VmState,
+    vcpu: VcpuState,
+    /// (snapshot_id, state) of the devices reset with the machine
+    devices: Vec<(String, Vec<u8>)>,
+    /// Where the builder loaded the firmware image
+    firmware_addr: GuestAddress,
+    /// Reloaded at `firmware_addr`, empty for a VM restored from a snapshot without one
+    firmware: Vec<u8>,
+}
+
+impl std::fmt::Debug for PowerOnState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PowerOnState").field("devices", &self.devices.len()).field("firmware", &self.firmware.len()).finish()
+    }
+}
+
+impl Vm {
+    /// Keep the state of the VM as built for the reboots, it must not have run yet
+    pub(super) fn capture_power_on(&mut self, firmware_addr: GuestAddress, firmware: Vec<u8>) -> snapshot::Result<()> {
+        let _quiesced = self.event_loop.quiesce()?;
+        let vm = VmState::save(&self.vm_fd, self.irq.mode())?;
+        let vcpu = VcpuState::save(&self.vcpu_fd, &self.msr_indices)?;
+        let devices = self
+            .snapshot_devices()
+            .into_iter()
+            .filter(|device| !KEPT_DEVICES.contains(&device.snapshot_id().as_str()))
+            .map(|device| {
+                let mut state = StateBuf::default();
+                device.save_state(&mut state);
+                (device.snapshot_id(), state.0)
+            })
+            .collect();
+        self.power_on = Some(PowerOnState { vm, vcpu, devices, firmware_addr, firmware });
+        Ok(())
+    }
+
+    /// Firmware image reboots reload and its address, None if there is none
+    pub(super) fn power_on_firmware(&self) -> Option<(GuestAddress, &[u8])> {
+        let power_on = self.power_on.as_ref()?;
+        (!power_on.firmware.is_empty()).then_some((power_on.firmware_addr, power_on.firmware.as_slice()))
+    }
+
+    /// Reload `firmware` at `addr` on reboots, for a VM restored from a snapshot
+    pub(super) fn set_power_on_firmware(&mut self, addr: GuestAddress, firmware: Vec<u8>) {
+        if let Some(power_on) = self.power_on.as_mut() {
+            power_on.firmware_addr = addr;
+            power_on.firmware = firmware;
+        }
+    }
+
+    /// Put the machine back in its power-on state, the vCPU starts over from its entry point
+    pub fn reset(&mut self) -> snapshot::Result<()> {
+        let power_on = self.power_on.take().expect("Power-on state not captured");
+        let reset = self.event_loop.quiesce().map_err(snapshot::SnapshotError::from).and_then(|_quiesced| {
+            self.restore_power_on(&power_on)
+        });
+        self.power_on = Some(power_on);
+        reset?;
+        self.paused = false;
+        info!("System reset done");
+        Ok(())
+    }
+
+    fn restore_power_on(&mut self, power_on: &PowerOnState) -> snapshot::Result<()> {
+        complete_pending_io(&self.vcpu_fd)?;
+        // The guest time goes on across the reset
+        power_on.vm.restore_irqchips(&self.vm_fd)?;
+        power_on.vcpu.restore(&self.vcpu_fd)?;
+        for device in self.snapshot_devices() {
+            let id = device.snapshot_id();
+            if let Some((_, state)) = power_on.devices.iter().find(|(saved_id, _)| *saved_id == id) {
+                device.restore_state(&mut StateReader::new(state))?;
+            }
+        }
+        self.sync_irq_routes()?;
+        self.sync_pci_bars()?;
+        if let Some(tpm) = self.tpm.as_ref() {
+            if let Err(e) = tpm.lock().unwrap().power_on() {
+                error!("TPM power on failed: {e}");
+            }
+        }
+        if power_on.firmware.is_empty() {
+            warn!("No firmware image to reload, the guest restarts on what RAM holds");
+        } else if let Err(e) = self.ram.guest_mem_map.write_slice(&power_on.firmware, power_on.firmware_addr) {
+            error!("Firmware reload failed: {e}");
+        }
+        self.reset_control = 0;
+        Ok(())
+    }
+
+    /// Act on a reset according to the config, returns whether the VM keeps running
+    pub(super) fn handle_reset(&mut self, source: ResetSource) -> snapshot::Result<bool> {
+        match self.reset_config.action(source) {
+            ResetAction::Reboot => {
+                self.reset()?;
+                Ok(true)
+            }
+            ResetAction::Exit => {
+                info!("{source:?} reset, stopping");
+                Ok(false)
+            }
+            ResetAction::Pause => {
+                warn!("{source:?} reset, VM paused until the monitor reset command");
+                self.paused = true;
+                Ok(true)
+            }
+        }
+    }
+
+    pub(super) fn reset_control_read(&self) -> u8 {
+        self.reset_control
+    }
+
+    pub(super) fn reset_control_write(&mut self, value: u8) {
+        self.reset_control = value & (RST_SYS | RST_FULL);
+        if value & RST_CPU != 0 {
+            self.reset_line.pull(ResetSource::ResetControl);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_line_keeps_the_source() {
+        let line = ResetLine::default();
+        assert_eq!(line.take(), None);
+        line.clone().pull(ResetSource::Acpi);
+        assert_eq!(line.take(), Some(ResetSource::Acpi));
+        assert_eq!(line.take(), None);
+    }
+
+    #[test]
+    fn config_per_source() {
+        let config: ResetConfig = toml::from_str("triple_fault = \"pause\"\ni8042 = \"exit\"").unwrap();
+        assert_eq!(config.action(ResetSource::TripleFault), ResetAction::Pause);
+        assert_eq!(config.action(ResetSource::I8042), ResetAction::Exit);
+        assert_eq!(config.action(ResetSource::ResetControl), ResetAction::Reboot);
+        assert!(toml::from_str::<ResetConfig>("hlt = \"exit\"").is_err());
+    }
+}
@@ -0,0 +1,715 @@
+//! MC146818 RTC and CMOS NVRAM at ports 0x70/0x71.
+//!
+//! The time registers are not stored, they are worked out from the clock base
+//! plus the offset the guest set, on each read. The periodic, alarm and
+//! update-ended interrupts (IRQ 8, edge triggered) come from a timerfd on the
+//! event loop, armed only while the guest enables one of them.
+
+use std::{
+    os::fd::{ AsRawFd, RawFd },
+    sync::{ Arc, Mutex },
+    time::{ Duration, Instant, SystemTime, UNIX_EPOCH },
+};
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use serde::{ Deserialize, Deserializer };
+use vmm_sys_util::timerfd::TimerFd;
+
+use super::{
+    bus::BusDevice,
+    event_loop::EventHandler,
+    irq::IrqFd,
+    snapshot::{ self, Snapshot, StateBuf, StateReader },
+    Vm,
+};
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+pub const RTC_PORT: u16 = 0x70;
+pub const RTC_GSI: u32 = 8;
+const CMOS_SIZE: usize = 128;
+
+const REG_SECONDS: usize = 0x00;
+const REG_SECONDS_ALARM: usize = 0x01;
+const REG_MINUTES: usize = 0x02;
+const REG_MINUTES_ALARM: usize = 0x03;
+const REG_HOURS: usize = 0x04;
+const REG_HOURS_ALARM: usize = 0x05;
+const REG_WEEKDAY: usize = 0x06;
+const REG_DAY: usize = 0x07;
+const REG_MONTH: usize = 0x08;
+const REG_YEAR: usize = 0x09;
+const REG_A: usize = 0x0a;
+const REG_B: usize = 0x0b;
+const REG_C: usize = 0x0c;
+const REG_D: usize = 0x0d;
+/// Where the FADT tells the guest to find the century
+pub const REG_CENTURY: usize = 0x32;
+
+const A_UIP: u8 = 1 << 7;
+/// 32.768 kHz time base, divider running
+const A_DV_NORMAL: u8 = 0x20;
+const A_RATE_MASK: u8 = 0x0f;
+const B_SET: u8 = 1 << 7;
+const B_PIE: u8 = 1 << 6;
+const B_AIE: u8 = 1 << 5;
+const B_UIE: u8 = 1 << 4;
+const B_BINARY: u8 = 1 << 2;
+const B_24H: u8 = 1 << 1;
+const C_IRQF: u8 = 1 << 7;
+const C_PF: u8 = 1 << 6;
+const C_AF: u8 = 1 << 5;
+const C_UF: u8 = 1 << 4;
+/// Valid RAM and time, the battery is fine
+const D_VRT: u8 = 1 << 7;
+/// 12 hour format PM flag, in the hour registers
+const HOUR_PM: u8 = 0x80;
+/// Alarm register values matching any time
+const ALARM_DONT_CARE: u8 = 0xc0;
+
+/// UIP is set this long before each update
+const UPDATE_CYCLE_NS: i64 = 244_000;
+const NS_PER_SEC: i64 = 1_000_000_000;
+const SECS_PER_DAY: i64 = 86400;
+
+/// Where the RTC time comes from, the guest may set it anyway
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RtcClock {
+    /// Host time, UTC like Linux guests expect
+    #[default]
+    Utc,
+    /// Host time in the host time zone, like Windows guests expect
+    Localtime,
+    /// Starts at `start` and runs
+    Fixed,
+    /// Stays at `start`, for deterministic runs
+    Frozen,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RtcConfig {
+    pub clock: RtcClock,
+    /// RFC 3339 date of the fixed and frozen clocks, 2000-01-01 by default
+    #[serde(deserialize_with = "parse_start")]
+    pub start: Option<SystemTime>,
+}
+
+fn parse_start<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<SystemTime>, D::Error> {
+    let start = String::deserialize(deserializer)?;
+    humantime::parse_rfc3339_weak(&start).map(Some).map_err(serde::de::Error::custom)
+}
+
+/// 2000-01-01T00:00:00Z
+const DEFAULT_START: i64 = 946684800;
+
+/// Calendar fields of a time, weekday 0 is Sunday
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DateTime {
+    year: i64,
+    month: u8,
+    day: u8,
+    weekday: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+impl DateTime {
+    fn from_secs(secs: i64) -> Self {
+        let days = secs.div_euclid(SECS_PER_DAY);
+        let time = secs.rem_euclid(SECS_PER_DAY);
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let day_of_era = z - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+        DateTime {
+            year,
+            month,
+            day,
+            weekday: (days + 4).rem_euclid(7) as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    fn to_secs(self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * SECS_PER_DAY
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+}
+
+fn unix_ns(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+/// Offset of the host time zone at `secs`, daylight saving included
+fn local_offset(secs: i64) -> i64 {
+    let time = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
+
+/// Time the guest sees before it sets the RTC
+#[derive(Debug)]
+struct ClockBase {
+    clock: RtcClock,
+    start_ns: i64,
+    started: Instant,
+}
+
+impl ClockBase {
+    fn new(config: RtcConfig) -> Self {
+        let start_ns = config.start.map_or(DEFAULT_START * NS_PER_SEC, unix_ns);
+        ClockBase { clock: config.clock, start_ns, started: Instant::now() }
+    }
+
+    fn save(&self, state: &mut StateBuf) {
+        state.put_u8(self.clock as u8);
+        state.put_u64(self.now_ns() as u64);
+    }
+
+    /// A fixed clock goes on from where it was saved, a frozen one stays there
+    fn restore(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.clock = match state.get_u8()? {
+            0 => RtcClock::Utc,
+            1 => RtcClock::Localtime,
+            2 => RtcClock::Fixed,
+            3 => RtcClock::Frozen,
+            _ => return Err(snapshot::SnapshotError::Malformed),
+        };
+        self.start_ns = state.get_u64()? as i64;
+        self.started = Instant::now();
+        Ok(())
+    }
+
+    /// Nanoseconds since the epoch, local time counted as UTC
+    fn now_ns(&self) -> i64 {
+        match self.clock {
+            RtcClock::Utc => unix_ns(SystemTime::now()),
+            RtcClock::Localtime => {
+                let now = unix_ns(SystemTime::now());
+                now + local_offset(now.div_euclid(NS_PER_SEC)) * NS_PER_SEC
+            }
+            RtcClock::Fixed => self.start_ns + self.started.elapsed().as_nanos() as i64,
+            RtcClock::Frozen => self.start_ns,
+        }
+    }
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+pub struct Rtc {
+    cmos: [u8; CMOS_SIZE],
+    /// Last index written to port 0x70, NMI disable bit included
+    index: u8,
+    base: ClockBase,
+    /// Guest time minus the clock base time
+    offset_ns: i64,
+    /// Guest second of the last update cycle, for UF and AF
+    last_second: i64,
+    /// Pending C_PF, C_AF, C_UF and C_IRQF
+    flags: u8,
+    timer: TimerFd,
+    irq: IrqFd,
+}
+
+impl std::fmt::Debug for Rtc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Rtc")
+            .field("base", &self.base)
+            .field("offset_ns", &self.offset_ns)
+            .field("flags", &self.flags)
+            .finish()
+    }
+}
+
+impl Rtc {
+    /// `low_ram` and `high_ram` are the RAM sizes below and above 4G, for the CMOS memory hints
+    pub fn new(config: RtcConfig, low_ram: u64, high_ram: u64, timer: TimerFd, irq: IrqFd) -> Self {
+        let mut cmos = [0; CMOS_SIZE];
+        cmos[REG_A] = A_DV_NORMAL | 0x06;
+        cmos[REG_B] = B_24H;
+        cmos[REG_D] = D_VRT;
+        // Base memory, 640K
+        cmos[0x15..0x17].copy_from_slice(&640u16.to_le_bytes());
+        // Extended memory above 1M in KB, up to 63M
+        let extended = (low_ram.saturating_sub(1 << 20) >> 10).min(0xfc00) as u16;
+        cmos[0x17..0x19].copy_from_slice(&extended.to_le_bytes());
+        cmos[0x30..0x32].copy_from_slice(&extended.to_le_bytes());
+        // Memory above 16M and above 4G in 64K units, what OVMF looks at without fw_cfg
+        let above_16m = (low_ram.saturating_sub(16 << 20) >> 16).min(0xffff) as u16;
+        cmos[0x34..0x36].copy_from_slice(&above_16m.to_le_bytes());
+        cmos[0x5b..0x5e].copy_from_slice(&((high_ram >> 16) as u32).to_le_bytes()[..3]);
+        // Number of CPUs minus one
+        cmos[0x5f] = 0;
+        let checksum = cmos[0x10..0x2e].iter().map(|&b| b as u16).sum::<u16>();
+        cmos[0x2e..0x30].copy_from_slice(&checksum.to_be_bytes());
+        let base = ClockBase::new(config);
+        let now = DateTime::from_secs(base.now_ns().div_euclid(NS_PER_SEC));
+        info!("RTC {:?} clock at {now:?}", config.clock);
+        let mut rtc = Rtc { cmos, index: 0, base, offset_ns: 0, last_second: 0, flags: 0, timer, irq };
+        rtc.last_second = rtc.now_ns().div_euclid(NS_PER_SEC);
+        rtc
+    }
+
+    fn now_ns(&self) -> i64 {
+        self.base.now_ns().saturating_add(self.offset_ns)
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.cmos[REG_B] & B_BINARY != 0 { value } else { to_bcd(value) }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.cmos[REG_B] & B_BINARY != 0 { value } else { from_bcd(value) }
+    }
+
+    fn encode_hour(&self, hour: u8) -> u8 {
+        if self.cmos[REG_B] & B_24H != 0 {
+            return self.encode(hour);
+        }
+        let pm = if hour >= 12 { HOUR_PM } else { 0 };
+        let hour = match hour % 12 {
+            0 => 12,
+            hour => hour,
+        };
+        self.encode(hour) | pm
+    }
+
+    fn decode_hour(&self, value: u8) -> u8 {
+        if self.cmos[REG_B] & B_24H != 0 {
+            return self.decode(value);
+        }
+        let hour = self.decode(value & !HOUR_PM) % 12;
+        if value & HOUR_PM != 0 { hour + 12 } else { hour }
+    }
+
+    /// Write the current time to the time registers
+    fn latch(&mut self) {
+        let now = DateTime::from_secs(self.now_ns().div_euclid(NS_PER_SEC));
+        self.cmos[REG_SECONDS] = self.encode(now.second);
+        self.cmos[REG_MINUTES] = self.encode(now.minute);
+        self.cmos[REG_HOURS] = self.encode_hour(now.hour);
+        self.cmos[REG_WEEKDAY] = self.encode(now.weekday + 1);
+        self.cmos[REG_DAY] = self.encode(now.day);
+        self.cmos[REG_MONTH] = self.encode(now.month);
+        self.cmos[REG_YEAR] = self.encode(now.year.rem_euclid(100) as u8);
+        self.cmos[REG_CENTURY] = self.encode(now.year.div_euclid(100) as u8);
+    }
+
+    /// Take the time registers as the new time, `subsec_ns` into the second
+    fn unlatch(&mut self, subsec_ns: i64) {
+        let century = self.decode(self.cmos[REG_CENTURY]) as i64;
+        let time = DateTime {
+            year: century * 100 + self.decode(self.cmos[REG_YEAR]) as i64,
+            month: self.decode(self.cmos[REG_MONTH]).clamp(1, 12),
+            day: self.decode(self.cmos[REG_DAY]).clamp(1, 31),
+            weekday: 0,
+            hour: self.decode_hour(self.cmos[REG_HOURS]),
+            minute: self.decode(self.cmos[REG_MINUTES]),
+            second: self.decode(self.cmos[REG_SECONDS]),
+        };
+        // Nanoseconds since the epoch only reach year 2262
+        let offset_ns = time
+            .to_secs()
+            .checked_mul(NS_PER_SEC)
+            .and_then(|ns| ns.checked_add(subsec_ns))
+            .and_then(|ns| ns.checked_sub(self.base.now_ns()));
+        match offset_ns {
+            Some(offset_ns) => {
+                debug!("RTC set to {time:?}");
+                self.offset_ns = offset_ns;
+            }
+            None => warn!("RTC set to {time:?}, out of range, keeping the current time"),
+        }
+        self.last_second = self.now_ns().div_euclid(NS_PER_SEC);
+    }
+
+    fn update_in_progress(&self) -> bool {
+        if self.cmos[REG_B] & B_SET != 0 || self.base.clock == RtcClock::Frozen {
+            return false;
+        }
+        self.now_ns().rem_euclid(NS_PER_SEC) >= NS_PER_SEC - UPDATE_CYCLE_NS
+    }
+
+    fn alarm_matches(&self, now: &DateTime) -> bool {
+        let matches = |alarm: u8, value: u8| alarm & ALARM_DONT_CARE == ALARM_DONT_CARE || alarm == value;
+        matches(self.cmos[REG_SECONDS_ALARM], self.encode(now.second))
+            && matches(self.cmos[REG_MINUTES_ALARM], self.encode(now.minute))
+            && matches(self.cmos[REG_HOURS_ALARM], self.encode_hour(now.hour))
+    }
+
+    /// Flag the update cycles that went by since the last look
+    fn run_updates(&mut self) {
+        if self.cmos[REG_B] & B_SET != 0 {
+            return;
+        }
+        let second = self.now_ns().div_euclid(NS_PER_SEC);
+        if second <= self.last_second {
+            return;
+        }
+        self.last_second = second;
+        self.flags |= C_UF;
+        if self.alarm_matches(&DateTime::from_secs(second)) {
+            self.flags |= C_AF;
+        }
+    }
+
+    /// Raise IRQ 8 for newly pending enabled flags
+    fn update_irq(&mut self) {
+        let enabled = self.cmos[REG_B] & (B_PIE | B_AIE | B_UIE);
+        if self.flags & C_IRQF == 0 && self.flags & enabled != 0 {
+            self.flags |= C_IRQF;
+            if let Err(e) = self.irq.trigger() {
+                error!("RTC interrupt failed: {e}");
+            }
+        }
+    }
+
+    fn periodic_rate(&self) -> Option<Duration> {
+        let rate = self.cmos[REG_A] & A_RATE_MASK;
+        let shift = match rate {
+            0 => return None,
+            // 256 and 128 Hz, what rates 8 and 9 give
+            1 | 2 => rate + 6,
+            rate => rate - 1,
+        };
+        Some(Duration::from_nanos((NS_PER_SEC as u64) << shift >> 15))
+    }
+
+    /// Arm the timer for the interrupts the guest enabled
+    fn rearm(&mut self) {
+        let b = self.cmos[REG_B];
+        let periodic = self.periodic_rate().filter(|_| b & B_PIE != 0);
+        let armed = if let Some(period) = periodic {
+            self.timer.reset(period, Some(period))
+        } else if b & (B_AIE | B_UIE) != 0 && b & B_SET == 0 && self.base.clock != RtcClock::Frozen {
+            let to_next = NS_PER_SEC - self.now_ns().rem_euclid(NS_PER_SEC);
+            self.timer.reset(Duration::from_nanos(to_next as u64), Some(Duration::from_secs(1)))
+        } else {
+            self.timer.clear()
+        };
+        if let Err(e) = armed {
+            error!("RTC timer: {e}");
+        }
+    }
+
+    /// The timer went off
+    fn tick(&mut self) {
+        // Non blocking, the guest may have rearmed it since it woke us up
+        let Ok(expired) = self.timer.wait() else {
+            return;
+        };
+        if expired > 0 && self.cmos[REG_B] & B_PIE != 0 && self.periodic_rate().is_some() {
+            self.flags |= C_PF;
+        }
+        self.run_updates();
+        self.update_irq();
+    }
+
+    fn read_register(&mut self, index: usize) -> u8 {
+        match index {
+            REG_SECONDS | REG_MINUTES | REG_HOURS | REG_WEEKDAY | REG_DAY | REG_MONTH | REG_YEAR | REG_CENTURY => {
+                if self.cmos[REG_B] & B_SET == 0 {
+                    self.latch();
+                }
+                self.cmos[index]
+            }
+            REG_A => self.cmos[REG_A] | if self.update_in_progress() { A_UIP } else { 0 },
+            REG_C => {
+                self.run_updates();
+                std::mem::take(&mut self.flags)
+            }
+            REG_D => D_VRT,
+            index => self.cmos[index],
+        }
+    }
+
+    fn write_register(&mut self, index: usize, value: u8) {
+        match index {
+            REG_SECONDS | REG_MINUTES | REG_HOURS | REG_WEEKDAY | REG_DAY | REG_MONTH | REG_YEAR | REG_CENTURY => {
+                if self.cmos[REG_B] & B_SET != 0 {
+                    self.cmos[index] = value;
+                    return;
+                }
+                // A running clock takes the write and goes on from there
+                let subsec_ns = self.now_ns().rem_euclid(NS_PER_SEC);
+                self.latch();
+                self.cmos[index] = value;
+                self.unlatch(subsec_ns);
+                self.rearm();
+            }
+            REG_A => {
+                self.cmos[REG_A] = value & !A_UIP;
+                self.rearm();
+            }
+            REG_B => {
+                let old = self.cmos[REG_B];
+                if value & B_SET != 0 && old & B_SET == 0 {
+                    self.latch();
+                }
+                // Setting the clock stops update interrupts
+                self.cmos[REG_B] = if value & B_SET != 0 { value & !B_UIE } else { value };
+                if value & B_SET == 0 && old & B_SET != 0 {
+                    // The divider chain restarts at the top of the second
+                    self.unlatch(0);
+                }
+                self.rearm();
+                self.update_irq();
+            }
+            REG_C | REG_D => {}
+            index => self.cmos[index] = value,
+        }
+    }
+}
+
+impl BusDevice for Rtc {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        data[0] = match offset {
+            // The index port is write only
+            0 => 0xff,
+            _ => self.read_register((self.index & 0x7f) as usize),
+        };
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        match offset {
+            0 => self.index = data[0],
+            _ => self.write_register((self.index & 0x7f) as usize, data[0]),
+        }
+    }
+}
+
+impl Snapshot for Rtc {
+    fn snapshot_id(&self) -> String {
+        "rtc".to_string()
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        state.put_bytes(&self.cmos);
+        state.put_u8(self.index);
+        self.base.save(state);
+        state.put_u64(self.offset_ns as u64);
+        state.put_u8(self.flags);
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.cmos = state.get_bytes()?.try_into().map_err(|_| snapshot::SnapshotError::Malformed)?;
+        self.index = state.get_u8()?;
+        self.base.restore(state)?;
+        self.offset_ns = state.get_u64()? as i64;
+        self.flags = state.get_u8()?;
+        self.last_second = self.now_ns().div_euclid(NS_PER_SEC);
+        self.rearm();
+        Ok(())
+    }
+}
+
+/// Runs the RTC timer on the event loop thread
+struct RtcTimer {
+    rtc: Arc<Mutex<Rtc>>,
+}
+
+impl EventHandler for RtcTimer {
+    fn handle_event(&mut self, _fd: RawFd) {
+        self.rtc.lock().unwrap().tick();
+    }
+}
+
+impl Vm {
+    pub(super) fn add_rtc(&mut self, config: RtcConfig) -> Result<()> {
+        let io_error = |e: std::io::Error| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO));
+        let timer = TimerFd::new()?;
+        let fd = timer.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(kvm_ioctls::Error::last());
+        }
+        let (low_ram, high_ram) = self.ram.split_4g();
+        let rtc = Arc::new(Mutex::new(Rtc::new(config, low_ram, high_ram, timer, self.irqfd(RTC_GSI)?)));
+        self.pio_bus.insert(RTC_PORT as u64, 2, rtc.clone()).map_err(|e| {
+            error!("RTC: {e}");
+            kvm_ioctls::Error::new(libc::EEXIST)
+        })?;
+        self.event_loop.add(fd, Arc::new(Mutex::new(RtcTimer { rtc: rtc.clone() }))).map_err(io_error)?;
+        self.rtc = Some(rtc);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frozen clock at `start`, RFC 3339
+    fn rtc(start: &str) -> Rtc {
+        let config = RtcConfig { clock: RtcClock::Frozen, start: Some(humantime::parse_rfc3339(start).unwrap()) };
+        Rtc::new(config, 1 << 30, 0, TimerFd::new().unwrap(), IrqFd::detached(RTC_GSI))
+    }
+
+    fn time_registers(rtc: &mut Rtc) -> [u8; 8] {
+        [REG_CENTURY, REG_YEAR, REG_MONTH, REG_DAY, REG_WEEKDAY, REG_HOURS, REG_MINUTES, REG_SECONDS].map(|reg| rtc.read_register(reg))
+    }
+
+    #[test]
+    fn civil_dates() {
+        for (secs, date) in [
+            (0, (1970, 1, 1, 4)),
+            (DEFAULT_START, (2000, 1, 1, 6)),
+            // Leap days, 2000 has one and 2100 doesn't
+            (951782400, (2000, 2, 29, 2)),
+            (4107456000, (2100, 2, 28, 0)),
+            (4107542400, (2100, 3, 1, 1)),
+            (-1, (1969, 12, 31, 3)),
+        ] {
+            let time = DateTime::from_secs(secs);
+            assert_eq!((time.year, time.month, time.day, time.weekday), date, "{secs}");
+            assert_eq!(time.to_secs(), secs);
+        }
+    }
+
+    #[test]
+    fn bcd_binary_and_12_hour() {
+        let mut rtc = rtc("2024-07-15T21:05:09Z");
+        // Monday is day 2
+        assert_eq!(time_registers(&mut rtc), [0x20, 0x24, 0x07, 0x15, 0x02, 0x21, 0x05, 0x09]);
+        rtc.write_register(REG_B, B_24H | B_BINARY);
+        assert_eq!(time_registers(&mut rtc), [20, 24, 7, 15, 2, 21, 5, 9]);
+        rtc.write_register(REG_B, B_BINARY);
+        assert_eq!(rtc.read_register(REG_HOURS), HOUR_PM | 9);
+        rtc.write_register(REG_B, 0);
+        assert_eq!(rtc.read_register(REG_HOURS), HOUR_PM | 0x09);
+
+        // Midnight and noon are 12 in 12 hour mode
+        for (hour, encoded) in [(0, 0x12), (11, 0x11), (12, HOUR_PM | 0x12), (23, HOUR_PM | 0x11)] {
+            assert_eq!(rtc.encode_hour(hour), encoded);
+            assert_eq!(rtc.decode_hour(encoded), hour);
+        }
+    }
+
+    #[test]
+    fn set_and_roll_over() {
+        let mut rtc = rtc("2000-01-01T00:00:00Z");
+        // Set 2099-12-31 23:59:59 with SET held, the way firmware does
+        rtc.write_register(REG_B, B_24H | B_SET);
+        for (reg, value) in [(REG_CENTURY, 0x20), (REG_YEAR, 0x99), (REG_MONTH, 0x12), (REG_DAY, 0x31), (REG_HOURS, 0x23), (REG_MINUTES, 0x59), (REG_SECONDS, 0x59)] {
+            rtc.write_register(reg, value);
+        }
+        rtc.write_register(REG_B, B_24H);
+        assert_eq!(time_registers(&mut rtc), [0x20, 0x99, 0x12, 0x31, 0x05, 0x23, 0x59, 0x59]);
+        rtc.offset_ns += NS_PER_SEC;
+        assert_eq!(time_registers(&mut rtc), [0x21, 0x00, 0x01, 0x01, 0x06, 0x00, 0x00, 0x00]);
+
+        // Writing a register of a running clock keeps the others
+        rtc.write_register(REG_MINUTES, 0x30);
+        assert_eq!(time_registers(&mut rtc), [0x21, 0x00, 0x01, 0x01, 0x06, 0x00, 0x30, 0x00]);
+        // A frozen clock never updates
+        assert!(!rtc.update_in_progress());
+    }
+
+    #[test]
+    fn periodic_rates() {
+        let mut rtc = rtc("2000-01-01T00:00:00Z");
+        for (rate, period_ns) in [(0, None), (1, Some(3_906_250)), (2, Some(7_812_500)), (3, Some(122_070)), (15, Some(500_000_000))] {
+            rtc.cmos[REG_A] = (rtc.cmos[REG_A] & !A_RATE_MASK) | rate;
+            assert_eq!(rtc.periodic_rate(), period_ns.map(Duration::from_nanos), "rate {rate}");
+        }
+    }
+
+    #[test]
+    fn out_of_range_date_refused() {
+        let mut rtc = rtc("2024-07-15T21:05:09Z");
+        rtc.write_register(REG_B, B_24H | B_BINARY | B_SET);
+        rtc.write_register(REG_CENTURY, 0xff);
+        rtc.write_register(REG_B, B_24H | B_BINARY);
+        assert_eq!(time_registers(&mut rtc), [20, 24, 7, 15, 2, 21, 5, 9]);
+
+        // BCD 0x99 is year 9999, still in range of the century byte but not of the clock
+        rtc.write_register(REG_B, B_24H | B_SET);
+        rtc.write_register(REG_CENTURY, 0x99);
+        rtc.write_register(REG_B, B_24H);
+        assert_eq!(time_registers(&mut rtc), [0x20, 0x24, 0x07, 0x15, 0x02, 0x21, 0x05, 0x09]);
+    }
+
+    #[test]
+    fn alarm_matching() {
+        let mut rtc = rtc("2024-07-15T21:05:09Z");
+        rtc.write_register(REG_SECONDS_ALARM, 0x10);
+        rtc.write_register(REG_MINUTES_ALARM, ALARM_DONT_CARE);
+        rtc.write_register(REG_HOURS_ALARM, 0x21);
+        rtc.write_register(REG_B, B_24H | B_AIE);
+        assert_eq!(rtc.read_register(REG_C), 0);
+
+        rtc.offset_ns += NS_PER_SEC;
+        rtc.run_updates();
+        rtc.update_irq();
+        assert!(rtc.irq.take_pending());
+        assert_eq!(rtc.read_register(REG_C), C_IRQF | C_AF | C_UF);
+        assert_eq!(rtc.read_register(REG_C), 0);
+
+        // The next second only ends an update, not enabled
+        rtc.offset_ns += NS_PER_SEC;
+        rtc.run_updates();
+        rtc.update_irq();
+        assert!(!rtc.irq.take_pending());
+        assert_eq!(rtc.read_register(REG_C), C_UF);
+
+        // An hour later the hours no longer match
+        rtc.offset_ns += 3599 * NS_PER_SEC;
+        assert_eq!(rtc.read_register(REG_C), C_UF);
+        rtc.offset_ns += 3600 * NS_PER_SEC;
+        rtc.write_register(REG_B, B_24H | B_AIE | B_BINARY);
+        rtc.write_register(REG_HOURS_ALARM, 23);
+        rtc.write_register(REG_SECONDS_ALARM, 10);
+        assert_eq!(rtc.read_register(REG_C) & C_AF, C_AF);
+    }
+
+    #[test]
+    fn state_keeps_the_clock_base() {
+        let mut rtc = rtc("2024-07-15T21:05:09Z");
+        rtc.offset_ns = 42 * NS_PER_SEC;
+        rtc.write_register(0x40, 0x5a);
+        let mut state = StateBuf::default();
+        rtc.save_state(&mut state);
+
+        let mut restored = Rtc::new(RtcConfig::default(), 1 << 30, 0, TimerFd::new().unwrap(), IrqFd::detached(RTC_GSI));
+        restored.restore_state(&mut StateReader::new(&state.0)).unwrap();
+        assert_eq!(restored.base.clock, RtcClock::Frozen);
+        assert_eq!(time_registers(&mut restored), time_registers(&mut rtc));
+        assert_eq!(restored.read_register(0x40), 0x5a);
+    }
+}
@@ -0,0 +1,250 @@
+//! Secure Boot keys enrolled offline in a variable store, what OVMF's
+//! EnrollDefaultKeys application does from inside the guest.
+//!
+//! PK, KEK, db and dbx each get an EFI_SIGNATURE_LIST per certificate, from
+//! DER or PEM X.509 files, `.esl` files being taken as signature lists as
+//! they are. The variables are time based authenticated ones stamped with the
+//! enrollment time. Once PK is there the firmware leaves setup mode and
+//! enforces Secure Boot from the next boot on.
+
+use std::{ fs, path::{ Path, PathBuf }, time::SystemTime };
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+
+use super::uefi_vars::{
+    Guid, Result, VarStore, VarStoreError, Variable, EFI_GLOBAL_VARIABLE, EFI_IMAGE_SECURITY_DATABASE_GUID,
+    EFI_VARIABLE_BOOTSERVICE_ACCESS, EFI_VARIABLE_NON_VOLATILE, EFI_VARIABLE_RUNTIME_ACCESS,
+    EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS,
+};
+
+pub const EFI_CERT_X509_GUID: Guid =
+    Guid::new(0xa5c059a1, 0x94e4, 0x4aa7, [0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72]);
+pub const EFI_CERT_SHA256_GUID: Guid =
+    Guid::new(0xc1c41626, 0x504c, 0x4092, [0xac, 0xa9, 0x41, 0xf9, 0x36, 0x93, 0x43, 0x28]);
+
+const KEY_ATTRIBUTES: u32 = EFI_VARIABLE_NON_VOLATILE
+    | EFI_VARIABLE_BOOTSERVICE_ACCESS
+    | EFI_VARIABLE_RUNTIME_ACCESS
+    | EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS;
+
+/// Type, list size, header size and signature size
+const LIST_HEADER_SIZE: usize = 28;
+/// Signature owner before each signature
+const OWNER_SIZE: usize = 16;
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+/// Signature type and signatures, each with its owner first
+pub type SignatureList<'a> = (Guid, Vec<&'a [u8]>);
+
+/// One EFI_SIGNATURE_LIST holding `signature`
+pub fn signature_list(ty: Guid, owner: Guid, signature: &[u8]) -> Vec<u8> {
+    let signature_size = OWNER_SIZE + signature.len();
+    let mut list = ty.0.to_vec();
+    list.extend(((LIST_HEADER_SIZE + signature_size) as u32).to_le_bytes());
+    list.extend(0u32.to_le_bytes());
+    list.extend((signature_size as u32).to_le_bytes());
+    list.extend(owner.0);
+    list.extend(signature);
+    list
+}
+
+pub fn parse_signature_lists(data: &[u8]) -> std::result::Result<Vec<SignatureList<'_>>, String> {
+    let mut lists = vec![];
+    let mut at = 0;
+    while at < data.len() {
+        let header = data.get(at..at + LIST_HEADER_SIZE).ok_or("truncated list header")?;
+        let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap()) as usize;
+        let (list_size, header_size, signature_size) = (u32_at(16), u32_at(20), u32_at(24));
+        let signatures = list_size.checked_sub(LIST_HEADER_SIZE + header_size).ok_or("list smaller than its header")?;
+        if at + list_size > data.len() {
+            return Err(format!("list of {list_size} bytes past the end"));
+        }
+        if signature_size < OWNER_SIZE || signatures % signature_size != 0 {
+            return Err(format!("{signatures} bytes of signatures of {signature_size} bytes"));
+        }
+        let start = at + LIST_HEADER_SIZE + header_size;
+        lists.push((Guid::read(header), data[start..at + list_size].chunks(signature_size).collect()));
+        at += list_size;
+    }
+    Ok(lists)
+}
+
+/// Signature count of each list by type, like `X509 x1, SHA256 x30`
+pub fn describe_signature_lists(data: &[u8]) -> String {
+    match parse_signature_lists(data) {
+        Ok(lists) if lists.is_empty() => "no signatures".to_string(),
+        Ok(lists) => {
+            let lists: Vec<String> = lists
+                .iter()
+                .map(|(ty, signatures)| {
+                    let ty = match *ty {
+                        EFI_CERT_X509_GUID => "X509".to_string(),
+                        EFI_CERT_SHA256_GUID => "SHA256".to_string(),
+                        ty => ty.to_string(),
+                    };
+                    format!("{ty} x{}", signatures.len())
+                })
+                .collect();
+            lists.join(", ")
+        }
+        Err(e) => format!("bad signature lists: {e}"),
+    }
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()).take_while(|&c| c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6 | value as u32) & 0xffff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// DER certificates of a PEM file, or the file itself if it is DER already
+fn certificates(data: &[u8]) -> std::result::Result<Vec<Vec<u8>>, String> {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return match data.first() {
+            // An ASN.1 SEQUENCE
+            Some(0x30) => Ok(vec![data.to_vec()]),
+            _ => Err("neither a DER nor a PEM certificate".to_string()),
+        };
+    };
+    let mut certs = vec![];
+    for block in text.split(PEM_BEGIN).skip(1) {
+        let body = block.split(PEM_END).next().unwrap_or_default();
+        certs.push(base64_decode(body).ok_or("bad base64 in PEM certificate")?);
+    }
+    if certs.is_empty() {
+        return Err("no certificate in PEM file".to_string());
+    }
+    Ok(certs)
+}
+
+/// Signature lists of a certificate or `.esl` file
+pub fn load_signature_lists(path: &Path, owner: Guid) -> Result<Vec<u8>> {
+    let bad = |e: String| VarStoreError::SignatureList(path.to_string_lossy().into_owned(), e);
+    let data = fs::read(path)?;
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("esl")) {
+        parse_signature_lists(&data).map_err(bad)?;
+        return Ok(data);
+    }
+    Ok(certificates(&data).map_err(bad)?.iter().flat_map(|cert| signature_list(EFI_CERT_X509_GUID, owner, cert)).collect())
+}
+
+/// EFI_TIME of `time`, UTC
+fn efi_time(time: SystemTime) -> [u8; 16] {
+    // YYYY-MM-DDTHH:MM:SSZ
+    let text = humantime::format_rfc3339_seconds(time).to_string();
+    let field = |range: std::ops::Range<usize>| text[range].parse::<u16>().unwrap_or(0);
+    let mut efi = [0; 16];
+    efi[0..2].copy_from_slice(&field(0..4).to_le_bytes());
+    for (i, range) in [5..7, 8..10, 11..13, 14..16, 17..19].into_iter().enumerate() {
+        efi[2 + i] = field(range) as u8;
+    }
+    efi
+}
+
+/// Files of each key variable, those without any are left as they are
+#[derive(Debug, Default, Clone)]
+pub struct SecureBootKeys {
+    pub pk: Option<PathBuf>,
+    pub kek: Vec<PathBuf>,
+    pub db: Vec<PathBuf>,
+    pub dbx: Vec<PathBuf>,
+}
+
+impl VarStore {
+    /// Replace the key variables of `keys`, the signatures owned by `owner`
+    pub fn enroll(&mut self, keys: &SecureBootKeys, owner: Guid) -> Result<()> {
+        let timestamp = efi_time(SystemTime::now());
+        // PK last, as the firmware wants it
+        let variables = [
+            (EFI_IMAGE_SECURITY_DATABASE_GUID, "dbx", keys.dbx.as_slice()),
+            (EFI_IMAGE_SECURITY_DATABASE_GUID, "db", keys.db.as_slice()),
+            (EFI_GLOBAL_VARIABLE, "KEK", keys.kek.as_slice()),
+            (EFI_GLOBAL_VARIABLE, "PK", keys.pk.as_slice()),
+        ];
+        for (guid, name, files) in variables {
+            if files.is_empty() {
+                continue;
+            }
+            let mut data = vec![];
+            for file in files {
+                data.extend(load_signature_lists(file, owner)?);
+            }
+            info!("Enrolling {name}: {} signature lists", parse_signature_lists(&data).map_or(0, |lists| lists.len()));
+            let mut variable = Variable::new(guid, name, KEY_ATTRIBUTES, data);
+            variable.timestamp = timestamp;
+            self.set(variable);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmm::uefi_vars::tests::blank_image;
+
+    /// Not a real certificate, only its outer SEQUENCE
+    const CERT: &[u8] = &[0x30, 0x82, 0x00, 0x04, 1, 2, 3, 4];
+
+    #[test]
+    fn pem_and_der() {
+        let pem = format!("{PEM_BEGIN}\nMIIABAECAwQ=\n{PEM_END}\n{PEM_BEGIN}\nMIIABAECAwQ=\n{PEM_END}\n");
+        assert_eq!(certificates(pem.as_bytes()).unwrap(), [CERT, CERT]);
+        assert_eq!(certificates(CERT).unwrap(), [CERT]);
+        assert!(certificates(b"not a certificate").is_err());
+    }
+
+    #[test]
+    fn keys_enrolled_as_signature_lists() {
+        let dir = std::env::temp_dir().join(format!("secure-boot-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("pk.der");
+        fs::write(&cert, CERT).unwrap();
+        let esl = dir.join("dbx.esl");
+        fs::write(&esl, signature_list(EFI_CERT_SHA256_GUID, Guid::default(), &[0xaa; 32])).unwrap();
+        let bad_esl = dir.join("bad.esl");
+        fs::write(&bad_esl, &CERT[..4]).unwrap();
+
+        let owner: Guid = "77fa9abd-0359-4d32-bd60-28f4e78f784b".parse().unwrap();
+        let mut store = VarStore::parse(blank_image(0x1000)).unwrap();
+        let keys = SecureBootKeys { pk: Some(cert.clone()), kek: vec![cert.clone()], db: vec![], dbx: vec![esl] };
+        store.enroll(&keys, owner).unwrap();
+        let store = VarStore::parse(store.to_bytes().unwrap()).unwrap();
+        let names: Vec<&str> = store.variables().iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["dbx", "KEK", "PK"]);
+
+        let pk = store.get(EFI_GLOBAL_VARIABLE, "PK").unwrap();
+        assert_eq!(pk.attributes, KEY_ATTRIBUTES);
+        assert!(u16::from_le_bytes([pk.timestamp[0], pk.timestamp[1]]) >= 2024);
+        let lists = parse_signature_lists(&pk.data).unwrap();
+        assert_eq!(lists.len(), 1);
+        assert_eq!(lists[0].0, EFI_CERT_X509_GUID);
+        assert_eq!(lists[0].1, [[&owner.0[..], CERT].concat()]);
+        let dbx = store.get(EFI_IMAGE_SECURITY_DATABASE_GUID, "dbx").unwrap();
+        assert_eq!(dbx.describe().unwrap(), "SHA256 x1");
+
+        let mut store = VarStore::parse(blank_image(0x1000)).unwrap();
+        let keys = SecureBootKeys { dbx: vec![bad_esl], ..Default::default() };
+        assert!(matches!(store.enroll(&keys, owner), Err(VarStoreError::SignatureList(..))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
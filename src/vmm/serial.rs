@@ -3,6 +3,8 @@ use std::fmt::Debug;
 
 use log::{ debug, error, info };
 
+use super::snapshot::{ self, Snapshot, StateBuf, StateReader };
+
 #[allow(dead_code)]
 pub struct SerialPort {
     pub port: u32,
@@ -50,3 +52,18 @@ impl Debug for SerialPort {
             .finish()
     }
 }
+
+impl Snapshot for SerialPort {
+    fn snapshot_id(&self) -> String {
+        format!("serial@{:x}", self.port)
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        state.put_bytes(&self.line_buffer);
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.line_buffer = state.get_bytes()?.to_vec();
+        Ok(())
+    }
+}
@@ -0,0 +1,511 @@
+//! SMBIOS 3.x tables describing the system, handed to the firmware through fw_cfg.
+//!
+//! The structures go in "etc/smbios/smbios-tables" and a 64 bit entry point in
+//! "etc/smbios/smbios-anchor", as QEMU does. OVMF takes the version from the
+//! anchor, installs every structure through its SMBIOS protocol and builds
+//! the entry point the guest sees, so the table address in ours stays 0.
+//! Strings come from the `[smbios]` table of the config, the processor from
+//! the vCPU CPUID and the memory from the RAM layout.
+
+use std::{ fs::File, io::Read };
+
+use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use serde::{ Deserialize, Deserializer };
+
+use super::{ ram::PCI_HOLE_END, Vm };
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+pub const SMBIOS_TABLES_FILE: &str = "etc/smbios/smbios-tables";
+pub const SMBIOS_ANCHOR_FILE: &str = "etc/smbios/smbios-anchor";
+
+const SMBIOS_MAJOR: u8 = 3;
+const SMBIOS_MINOR: u8 = 2;
+const ANCHOR_SIZE: usize = 24;
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_CHASSIS: u8 = 3;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_MEMORY_ARRAY: u8 = 16;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_MEMORY_MAPPED: u8 = 19;
+const TYPE_BOOT_INFO: u8 = 32;
+const TYPE_END: u8 = 127;
+
+/// Formatted area sizes of the versions we generate
+const BIOS_SIZE: usize = 0x1a;
+const SYSTEM_SIZE: usize = 0x1b;
+const CHASSIS_SIZE: usize = 0x16;
+const PROCESSOR_SIZE: usize = 0x30;
+const MEMORY_ARRAY_SIZE: usize = 0x17;
+const MEMORY_DEVICE_SIZE: usize = 0x28;
+const MEMORY_MAPPED_SIZE: usize = 0x1f;
+const BOOT_INFO_SIZE: usize = 0x0b;
+const END_SIZE: usize = 4;
+
+/// BIOS characteristics not supported, the OS looks at the extension bytes
+const BIOS_CHARACTERISTICS_UNSUPPORTED: u64 = 1 << 3;
+/// UEFI and virtual machine
+const BIOS_CHARACTERISTICS_EXT2: u8 = 1 << 3 | 1 << 4;
+const BIOS_SEGMENT: u16 = 0xe800;
+const WAKEUP_POWER_SWITCH: u8 = 0x06;
+const STATE_SAFE: u8 = 0x03;
+const SECURITY_UNKNOWN: u8 = 0x02;
+const PROCESSOR_CENTRAL: u8 = 0x03;
+const PROCESSOR_FAMILY_OTHER: u8 = 0x01;
+/// CPU socket populated, CPU enabled
+const PROCESSOR_ENABLED: u8 = 0x41;
+const PROCESSOR_UPGRADE_OTHER: u8 = 0x01;
+const PROCESSOR_64BIT: u16 = 1 << 2;
+const NO_HANDLE: u16 = 0xffff;
+/// No error information structure
+const NO_ERROR_INFO: u16 = 0xfffe;
+const LOCATION_SYSTEM_BOARD: u8 = 0x03;
+const USE_SYSTEM_MEMORY: u8 = 0x03;
+const ECC_MULTI_BIT: u8 = 0x06;
+const FORM_FACTOR_DIMM: u8 = 0x09;
+const MEMORY_TYPE_RAM: u8 = 0x07;
+const MEMORY_DETAIL_OTHER: u16 = 1 << 1;
+/// Size fields saying the extended one holds the value
+const MEMORY_ARRAY_EXTENDED: u32 = 0x8000_0000;
+const MEMORY_DEVICE_EXTENDED: u16 = 0x7fff;
+const MAPPED_EXTENDED: u32 = 0xffff_ffff;
+
+/// Strings of the structures, the `[smbios]` table of the config file.
+/// Empty strings are left out, the guest sees them as not specified.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmbiosConfig {
+    pub bios_vendor: String,
+    pub bios_version: String,
+    /// mm/dd/yyyy
+    pub bios_date: String,
+    pub manufacturer: String,
+    pub product: String,
+    pub version: String,
+    pub serial: String,
+    /// Random on each run when not set, keep one to look like the same machine
+    #[serde(deserialize_with = "parse_uuid")]
+    pub uuid: Option<[u8; 16]>,
+    pub sku: String,
+    pub family: String,
+    /// SMBIOS chassis type, 0x03 is a desktop
+    pub chassis_type: u8,
+    pub asset_tag: String,
+    /// Max and current speed of the processor
+    pub cpu_mhz: u16,
+}
+
+impl Default for SmbiosConfig {
+    fn default() -> Self {
+        SmbiosConfig {
+            bios_vendor: "EFI Development Kit II / OVMF".to_string(),
+            bios_version: "0.0.0".to_string(),
+            bios_date: "02/06/2015".to_string(),
+            manufacturer: String::new(),
+            product: String::new(),
+            version: String::new(),
+            serial: String::new(),
+            uuid: None,
+            sku: String::new(),
+            family: String::new(),
+            chassis_type: 0x03,
+            asset_tag: String::new(),
+            cpu_mhz: 2000,
+        }
+    }
+}
+
+/// 8-4-4-4-12 hex digits, in the order they are written
+fn parse_uuid<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<[u8; 16]>, D::Error> {
+    let uuid = String::deserialize(deserializer)?;
+    let groups: Vec<&str> = uuid.split('-').collect();
+    let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+    let digits = groups.concat();
+    if lengths != [8, 4, 4, 4, 12] || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(serde::de::Error::custom(format!("bad UUID {uuid}")));
+    }
+    let mut bytes = [0; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    Ok(Some(bytes))
+}
+
+/// Version 4 UUID from the host random source
+fn random_uuid() -> [u8; 16] {
+    let mut uuid = [0; 16];
+    if let Err(e) = File::open("/dev/urandom").and_then(|mut random| random.read_exact(&mut uuid)) {
+        error!("No random SMBIOS UUID: {e}");
+        return [0; 16];
+    }
+    uuid[6] = uuid[6] & 0x0f | 0x40;
+    uuid[8] = uuid[8] & 0x3f | 0x80;
+    uuid
+}
+
+/// Since SMBIOS 2.6 the first three fields are little endian
+fn uuid_wire(uuid: &[u8; 16]) -> [u8; 16] {
+    let mut wire = *uuid;
+    wire[0..4].reverse();
+    wire[4..6].reverse();
+    wire[6..8].reverse();
+    wire
+}
+
+/// What the structures describe besides the config
+#[derive(Debug, Clone)]
+pub struct SmbiosPlatform {
+    pub cpus: u8,
+    /// CPUID leaf 0 vendor and the brand string of leaves 0x80000002-4
+    pub cpu_vendor: String,
+    pub cpu_brand: String,
+    /// CPUID leaf 1 EAX then EDX, the processor ID field
+    pub cpu_id: u64,
+    /// RAM bytes below and above 4G
+    pub low_ram: u64,
+    pub high_ram: u64,
+}
+
+/// Blobs of the fw_cfg files
+#[derive(Debug)]
+pub struct SmbiosTables {
+    pub anchor: Vec<u8>,
+    pub tables: Vec<u8>,
+}
+
+/// Formatted area of a structure and its string set
+struct Structure {
+    data: Vec<u8>,
+    strings: Vec<String>,
+}
+
+impl Structure {
+    fn new(ty: u8, handle: u16, size: usize) -> Self {
+        let mut data = vec![0; size];
+        data[0] = ty;
+        data[1] = size as u8;
+        data[2..4].copy_from_slice(&handle.to_le_bytes());
+        Structure { data, strings: vec![] }
+    }
+
+    fn put_u8(&mut self, offset: usize, value: u8) {
+        self.data[offset] = value;
+    }
+
+    fn put_u16(&mut self, offset: usize, value: u16) {
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u64(&mut self, offset: usize, value: u64) {
+        self.data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Number the string at `offset`, 0 for an empty one
+    fn put_string(&mut self, offset: usize, value: &str) {
+        let value = value.replace('\0', "");
+        if value.is_empty() {
+            self.data[offset] = 0;
+            return;
+        }
+        self.strings.push(value);
+        self.data[offset] = self.strings.len() as u8;
+    }
+
+    /// Each string NUL terminated and one more NUL, two for an empty set
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.data;
+        for string in &self.strings {
+            bytes.extend_from_slice(string.as_bytes());
+            bytes.push(0);
+        }
+        if self.strings.is_empty() {
+            bytes.push(0);
+        }
+        bytes.push(0);
+        bytes
+    }
+}
+
+fn bios(config: &SmbiosConfig) -> Structure {
+    let mut bios = Structure::new(TYPE_BIOS, 0x0000, BIOS_SIZE);
+    bios.put_string(0x04, &config.bios_vendor);
+    bios.put_string(0x05, &config.bios_version);
+    bios.put_u16(0x06, BIOS_SEGMENT);
+    bios.put_string(0x08, &config.bios_date);
+    bios.put_u64(0x0a, BIOS_CHARACTERISTICS_UNSUPPORTED);
+    bios.put_u8(0x13, BIOS_CHARACTERISTICS_EXT2);
+    // No BIOS or embedded controller release numbers
+    bios.data[0x14..0x18].fill(0xff);
+    bios
+}
+
+fn system(config: &SmbiosConfig, uuid: &[u8; 16]) -> Structure {
+    let mut system = Structure::new(TYPE_SYSTEM, 0x0100, SYSTEM_SIZE);
+    system.put_string(0x04, &config.manufacturer);
+    system.put_string(0x05, &config.product);
+    system.put_string(0x06, &config.version);
+    system.put_string(0x07, &config.serial);
+    system.data[0x08..0x18].copy_from_slice(&uuid_wire(uuid));
+    system.put_u8(0x18, WAKEUP_POWER_SWITCH);
+    system.put_string(0x19, &config.sku);
+    system.put_string(0x1a, &config.family);
+    system
+}
+
+fn chassis(config: &SmbiosConfig) -> Structure {
+    let mut chassis = Structure::new(TYPE_CHASSIS, 0x0300, CHASSIS_SIZE);
+    chassis.put_string(0x04, &config.manufacturer);
+    chassis.put_u8(0x05, config.chassis_type);
+    chassis.put_string(0x06, &config.version);
+    chassis.put_string(0x07, &config.serial);
+    chassis.put_string(0x08, &config.asset_tag);
+    chassis.put_u8(0x09, STATE_SAFE);
+    chassis.put_u8(0x0a, STATE_SAFE);
+    chassis.put_u8(0x0b, STATE_SAFE);
+    chassis.put_u8(0x0c, SECURITY_UNKNOWN);
+    chassis.put_string(0x15, &config.sku);
+    chassis
+}
+
+/// One package with a core and thread per vCPU, as the CPUID topology says
+fn processor(config: &SmbiosConfig, platform: &SmbiosPlatform) -> Structure {
+    let mut processor = Structure::new(TYPE_PROCESSOR, 0x0400, PROCESSOR_SIZE);
+    processor.put_string(0x04, "CPU 0");
+    processor.put_u8(0x05, PROCESSOR_CENTRAL);
+    processor.put_u8(0x06, PROCESSOR_FAMILY_OTHER);
+    processor.put_string(0x07, &platform.cpu_vendor);
+    processor.put_u64(0x08, platform.cpu_id);
+    processor.put_string(0x10, &platform.cpu_brand);
+    processor.put_u16(0x14, config.cpu_mhz);
+    processor.put_u16(0x16, config.cpu_mhz);
+    processor.put_u8(0x18, PROCESSOR_ENABLED);
+    processor.put_u8(0x19, PROCESSOR_UPGRADE_OTHER);
+    processor.put_u16(0x1a, NO_HANDLE);
+    processor.put_u16(0x1c, NO_HANDLE);
+    processor.put_u16(0x1e, NO_HANDLE);
+    for offset in [0x23, 0x24, 0x25] {
+        processor.put_u8(offset, platform.cpus);
+    }
+    processor.put_u16(0x26, PROCESSOR_64BIT);
+    processor.put_u16(0x28, PROCESSOR_FAMILY_OTHER as u16);
+    for offset in [0x2a, 0x2c, 0x2e] {
+        processor.put_u16(offset, platform.cpus as u16);
+    }
+    processor
+}
+
+fn memory_array(ram: u64) -> Structure {
+    let mut array = Structure::new(TYPE_MEMORY_ARRAY, 0x1000, MEMORY_ARRAY_SIZE);
+    array.put_u8(0x04, LOCATION_SYSTEM_BOARD);
+    array.put_u8(0x05, USE_SYSTEM_MEMORY);
+    array.put_u8(0x06, ECC_MULTI_BIT);
+    let kib = ram >> 10;
+    if kib < MEMORY_ARRAY_EXTENDED as u64 {
+        array.put_u32(0x07, kib as u32);
+    } else {
+        array.put_u32(0x07, MEMORY_ARRAY_EXTENDED);
+        array.put_u64(0x0f, ram);
+    }
+    array.put_u16(0x0b, NO_ERROR_INFO);
+    array.put_u16(0x0d, 1);
+    array
+}
+
+/// All the RAM as one DIMM
+fn memory_device(ram: u64) -> Structure {
+    let mut device = Structure::new(TYPE_MEMORY_DEVICE, 0x1100, MEMORY_DEVICE_SIZE);
+    device.put_u16(0x04, 0x1000);
+    device.put_u16(0x06, NO_ERROR_INFO);
+    device.put_u16(0x08, 64);
+    device.put_u16(0x0a, 64);
+    let mib = ram >> 20;
+    if mib < MEMORY_DEVICE_EXTENDED as u64 {
+        device.put_u16(0x0c, mib as u16);
+    } else {
+        device.put_u16(0x0c, MEMORY_DEVICE_EXTENDED);
+        device.put_u32(0x1c, mib as u32);
+    }
+    device.put_u8(0x0e, FORM_FACTOR_DIMM);
+    device.put_string(0x10, "DIMM 0");
+    device.put_u8(0x12, MEMORY_TYPE_RAM);
+    device.put_u16(0x13, MEMORY_DETAIL_OTHER);
+    device
+}
+
+/// Guest physical range `start..start + size` of the array
+fn memory_mapped(index: u16, start: u64, size: u64) -> Structure {
+    let mut mapped = Structure::new(TYPE_MEMORY_MAPPED, 0x1300 + index, MEMORY_MAPPED_SIZE);
+    let end = start + size - 1;
+    if end >> 10 < MAPPED_EXTENDED as u64 {
+        mapped.put_u32(0x04, (start >> 10) as u32);
+        mapped.put_u32(0x08, (end >> 10) as u32);
+    } else {
+        mapped.put_u32(0x04, MAPPED_EXTENDED);
+        mapped.put_u32(0x08, MAPPED_EXTENDED);
+        mapped.put_u64(0x0f, start);
+        mapped.put_u64(0x17, end);
+    }
+    mapped.put_u16(0x0c, 0x1000);
+    mapped.put_u8(0x0e, 1);
+    mapped
+}
+
+/// SMBIOS 3.0 64 bit entry point, the firmware fills the table address in
+fn anchor(tables_len: usize) -> Vec<u8> {
+    let mut anchor = vec![0; ANCHOR_SIZE];
+    anchor[0..5].copy_from_slice(b"_SM3_");
+    anchor[6] = ANCHOR_SIZE as u8;
+    anchor[7] = SMBIOS_MAJOR;
+    anchor[8] = SMBIOS_MINOR;
+    // Entry point revision 1
+    anchor[10] = 1;
+    anchor[12..16].copy_from_slice(&(tables_len as u32).to_le_bytes());
+    anchor[5] = anchor.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg();
+    anchor
+}
+
+pub fn build_tables(config: &SmbiosConfig, platform: &SmbiosPlatform) -> SmbiosTables {
+    let uuid = config.uuid.unwrap_or_else(random_uuid);
+    let ram = platform.low_ram + platform.high_ram;
+    let mut structures = vec![
+        bios(config),
+        system(config, &uuid),
+        chassis(config),
+        processor(config, platform),
+        memory_array(ram),
+        memory_device(ram),
+        memory_mapped(0, 0, platform.low_ram),
+    ];
+    if platform.high_ram != 0 {
+        structures.push(memory_mapped(1, PCI_HOLE_END, platform.high_ram));
+    }
+    structures.push(Structure::new(TYPE_BOOT_INFO, 0x2000, BOOT_INFO_SIZE));
+    structures.push(Structure::new(TYPE_END, 0x7f00, END_SIZE));
+    let tables: Vec<u8> = structures.into_iter().flat_map(Structure::into_bytes).collect();
+    SmbiosTables { anchor: anchor(tables.len()), tables }
+}
+
+/// Registers of a CPUID leaf as bytes, in `regs` order
+fn cpuid_bytes(entries: &[kvm_bindings::kvm_cpuid_entry2], leaf: u32, regs: &[usize]) -> Vec<u8> {
+    let Some(entry) = entries.iter().find(|entry| entry.function == leaf) else {
+        return vec![];
+    };
+    let values = [entry.eax, entry.ebx, entry.ecx, entry.edx];
+    regs.iter().flat_map(|&reg| values[reg].to_le_bytes()).collect()
+}
+
+fn cpuid_string(bytes: Vec<u8>) -> String {
+    String::from_utf8_lossy(&bytes).trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string()
+}
+
+impl Vm {
+    /// Give the firmware the SMBIOS tables, after the vCPU CPUID is set
+    pub(super) fn add_smbios_tables(&mut self, config: &SmbiosConfig) -> Result<()> {
+        let cpuid = self.vcpu_fd.get_cpuid2(KVM_MAX_CPUID_ENTRIES)?;
+        let entries = cpuid.as_slice();
+        let signature = cpuid_bytes(entries, 0x1, &[0, 3]);
+        let (low_ram, high_ram) = self.ram.split_4g();
+        let platform = SmbiosPlatform {
+            cpus: self.vcpu_count() as u8,
+            cpu_vendor: cpuid_string(cpuid_bytes(entries, 0x0, &[1, 3, 2])),
+            cpu_brand: cpuid_string(
+                (0x8000_0002..=0x8000_0004).flat_map(|leaf| cpuid_bytes(entries, leaf, &[0, 1, 2, 3])).collect(),
+            ),
+            cpu_id: signature.try_into().map_or(0, u64::from_le_bytes),
+            low_ram,
+            high_ram,
+        };
+        let tables = build_tables(config, &platform);
+        info!("SMBIOS tables: {} bytes", tables.tables.len());
+        self.add_fw_cfg_file(SMBIOS_ANCHOR_FILE, tables.anchor)?;
+        self.add_fw_cfg_file(SMBIOS_TABLES_FILE, tables.tables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn platform() -> SmbiosPlatform {
+        SmbiosPlatform {
+            cpus: 4,
+            cpu_vendor: "GenuineIntel".to_string(),
+            cpu_brand: "Intel(R) Core(TM) i7-10700 CPU @ 2.90GHz".to_string(),
+            cpu_id: 0xbfebfbff_000a0655,
+            low_ram: 0xb000_0000,
+            high_ram: 0x5000_0000,
+        }
+    }
+
+    /// (type, formatted area, strings) of each structure
+    fn structures(tables: &[u8]) -> Vec<(u8, Vec<u8>, Vec<String>)> {
+        let mut structures = vec![];
+        let mut at = 0;
+        while at < tables.len() {
+            let len = tables[at + 1] as usize;
+            let data = tables[at..at + len].to_vec();
+            let end = at + len + tables[at + len..].windows(2).position(|w| w == [0, 0]).unwrap();
+            let strings = tables[at + len..end]
+                .split(|&b| b == 0)
+                .filter(|s| !s.is_empty())
+                .map(|s| String::from_utf8_lossy(s).into_owned())
+                .collect();
+            structures.push((data[0], data, strings));
+            at = end + 2;
+        }
+        structures
+    }
+
+    #[test]
+    fn structures_and_strings() {
+        let config: SmbiosConfig = toml::from_str(
+            "manufacturer = \"Dell Inc.\"\nproduct = \"OptiPlex 7080\"\nserial = \"7XK2Q93\"\n\
+             uuid = \"4c4c4544-0058-4b10-8032-b7c04f513933\"",
+        )
+        .unwrap();
+        let tables = build_tables(&config, &platform());
+        let structures = structures(&tables.tables);
+        let types: Vec<u8> = structures.iter().map(|(ty, ..)| *ty).collect();
+        assert_eq!(types, [0, 1, 3, 4, 16, 17, 19, 19, 32, 127]);
+
+        let (_, system, strings) = &structures[1];
+        assert_eq!(strings, &["Dell Inc.", "OptiPlex 7080", "7XK2Q93"]);
+        // Version not set, serial is the third string
+        assert_eq!(system[0x04..0x08], [1, 2, 0, 3]);
+        assert_eq!(system[0x08..0x18], [0x44, 0x45, 0x4c, 0x4c, 0x58, 0, 0x10, 0x4b, 0x80, 0x32, 0xb7, 0xc0, 0x4f, 0x51, 0x39, 0x33]);
+
+        let (_, processor, strings) = &structures[3];
+        assert_eq!(strings[1], "GenuineIntel");
+        assert_eq!(processor[0x08..0x10], 0xbfebfbff_000a0655u64.to_le_bytes());
+        assert_eq!(processor[0x23], 4);
+
+        // 4G of RAM on both sides of the PCI hole
+        assert_eq!(structures[5].1[0x0c..0x0e], 4096u16.to_le_bytes());
+        assert_eq!(structures[7].1[0x04..0x0c], [0, 0, 0x40, 0, 0xff, 0xff, 0x53, 0]);
+        // No strings, two NULs
+        assert_eq!(tables.tables[tables.tables.len() - 6..], [127, 4, 0, 0x7f, 0, 0]);
+    }
+
+    #[test]
+    fn anchor_sums_to_zero() {
+        let tables = build_tables(&SmbiosConfig::default(), &platform());
+        assert_eq!(&tables.anchor[0..5], b"_SM3_");
+        assert_eq!(tables.anchor.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0);
+        assert_eq!(u32::from_le_bytes(tables.anchor[12..16].try_into().unwrap()) as usize, tables.tables.len());
+    }
+
+    #[test]
+    fn uuid_parsing() {
+        assert!(toml::from_str::<SmbiosConfig>("uuid = \"4c4c4544-0058-4b10-8032\"").is_err());
+        assert!(toml::from_str::<SmbiosConfig>("uuid = \"4c4c4544-0058-4b10-8032-b7c04f51393g\"").is_err());
+        assert_eq!(random_uuid()[6] >> 4, 4);
+    }
+}
@@ -0,0 +1,907 @@
+//! VM snapshots: guest RAM, vCPU state, in-kernel irqchip/PIT/clock and emulated devices.
+//!
+//! File layout is `MAGIC | version:u32 | section*`, each section being
+//! `tag:[u8; 4] | len:u64 | payload`, all integers little endian.
+//! RAM is stored sparse: only non zero pages are written, as `page_idx:u64 | page`.
+//!
+//! The last full snapshot taken or restored becomes the VM base. Incremental
+//! snapshots only hold the pages written since the base, found with the dirty
+//! log, and name their base in a `BASE` section. The same pages are what
+//! [`Vm::reset_to_base`] reloads to rewind the guest.
+//!
+//! A `FIRM` section keeps the firmware image and its load address, for a
+//! restored VM to run the firmware again on reboot.
+
+use core::slice;
+use std::{
+    collections::HashMap,
+    fs::{ File, OpenOptions },
+    io::{ BufReader, BufWriter, Read, Seek, SeekFrom, Write },
+    mem::size_of,
+    path::{ Path, PathBuf },
+    sync::{ Arc, Mutex },
+    time::{ SystemTime, UNIX_EPOCH },
+};
+
+use kvm_bindings::*;
+use kvm_ioctls::{ VcpuFd, VmFd };
+use vm_memory::GuestAddress;
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+
+use super::{ irq::IrqChipMode, ram::{ Ram, PAGE_SIZE }, Vm };
+
+const MAGIC: &[u8; 8] = b"RVMMSNAP";
+const VERSION: u32 = 1;
+
+const TAG_ID: [u8; 4] = *b"ID  ";
+const TAG_BASE: [u8; 4] = *b"BASE";
+const TAG_RAM: [u8; 4] = *b"RAM ";
+const TAG_VCPU: [u8; 4] = *b"VCPU";
+const TAG_IRQCHIP: [u8; 4] = *b"IRQC";
+const TAG_PIT: [u8; 4] = *b"PIT ";
+const TAG_CLOCK: [u8; 4] = *b"CLCK";
+const TAG_DEVICE: [u8; 4] = *b"DEV ";
+const TAG_FIRMWARE: [u8; 4] = *b"FIRM";
+
+/// PIC master, PIC slave and IOAPIC
+const IRQCHIP_IDS: [u32; 3] = [KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_IRQCHIP_IOAPIC];
+
+pub type Result<T> = std::result::Result<T, SnapshotError>;
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum SnapshotError {
+    /// Snapshot I/O error: {0}
+    Io(#[from] std::io::Error),
+    /// KVM error while saving or restoring state: {0}
+    Kvm(#[from] kvm_ioctls::Error),
+    /// Not a snapshot file
+    BadMagic,
+    /// Unsupported snapshot version {0}
+    BadVersion(u32),
+    /// Truncated or malformed snapshot section
+    Malformed,
+    /// Snapshot RAM size 0x{0:x} does not match VM RAM size 0x{1:x}
+    RamSize(usize, usize),
+    /// Snapshot holds no state for device {0}
+    MissingDevice(String),
+    /// KVM refused to restore MSR 0x{0:x}
+    MsrRestore(u32),
+    /// No base snapshot, take or restore a full snapshot first
+    NoBase,
+    /// Base snapshot {0:?} is not the one the incremental snapshot was taken from
+    BaseMismatch(PathBuf),
+    /// Base snapshot {0:?} of the incremental snapshot is missing
+    MissingBase(PathBuf),
+}
+
+/// Implemented by every emulated device so its state follows the VM across snapshots
+pub trait Snapshot {
+    /// Name of the device section, must be unique within a VM
+    fn snapshot_id(&self) -> String;
+    fn save_state(&self, state: &mut StateBuf);
+    fn restore_state(&mut self, state: &mut StateReader) -> Result<()>;
+}
+
+/// Devices shared with the event loop thread
+impl<T: Snapshot + ?Sized> Snapshot for Arc<Mutex<T>> {
+    fn snapshot_id(&self) -> String {
+        self.lock().unwrap().snapshot_id()
+    }
+    fn save_state(&self, state: &mut StateBuf) {
+        self.lock().unwrap().save_state(state)
+    }
+    fn restore_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.lock().unwrap().restore_state(state)
+    }
+}
+
+// Raw views of the kvm_bindings structs, they are all plain C data
+fn pod_bytes<T>(v: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(v as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Little endian encoder for section payloads
+#[derive(Debug, Default)]
+pub struct StateBuf(pub Vec<u8>);
+
+#[allow(unused)]
+impl StateBuf {
+    pub fn put_u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+    pub fn put_u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    pub fn put_u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    pub fn put_u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    /// Length prefixed byte string
+    pub fn put_bytes(&mut self, v: &[u8]) {
+        self.put_u64(v.len() as u64);
+        self.0.extend_from_slice(v);
+    }
+    fn put_pod<T>(&mut self, v: &T) {
+        self.0.extend_from_slice(pod_bytes(v));
+    }
+}
+
+/// Decoder matching [`StateBuf`]
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+#[allow(unused)]
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).ok_or(SnapshotError::Malformed)?;
+        let data = self.buf.get(self.pos..end).ok_or(SnapshotError::Malformed)?;
+        self.pos = end;
+        Ok(data)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn get_u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn get_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn get_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn get_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.get_u64()? as usize;
+        self.take(len)
+    }
+    fn get_pod<T>(&mut self) -> Result<T> {
+        let data = self.take(size_of::<T>())?;
+        Ok(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const T) })
+    }
+}
+
+/// Full architectural state of a single vCPU
+pub struct VcpuState {
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+    fpu: kvm_fpu,
+    xsave: kvm_xsave,
+    xcrs: kvm_xcrs,
+    msrs: Vec<kvm_msr_entry>,
+    lapic: kvm_lapic_state,
+    events: kvm_vcpu_events,
+    debugregs: kvm_debugregs,
+    mp_state: kvm_mp_state,
+}
+
+/// KVM finishes the last PIO/MMIO exit on the next KVM_RUN, enter with
+/// immediate_exit so it lands in the state we are about to save or overwrite
+pub(super) fn complete_pending_io(vcpu: &VcpuFd) -> Result<()> {
+    vcpu.set_kvm_immediate_exit(1);
+    let ret = vcpu.run();
+    vcpu.set_kvm_immediate_exit(0);
+    match ret {
+        Err(e) if e.errno() == libc::EINTR => Ok(()),
+        Err(e) => Err(e.into()),
+        Ok(exit) => {
+            warn!("Unexpected exit while completing pending I/O: {exit:x?}");
+            Ok(())
+        }
+    }
+}
+
+impl VcpuState {
+    pub fn save(vcpu: &VcpuFd, msr_indices: &[u32]) -> Result<Self> {
+        complete_pending_io(vcpu)?;
+        Ok(Self {
+            regs: vcpu.get_regs()?,
+            sregs: vcpu.get_sregs()?,
+            fpu: vcpu.get_fpu()?,
+            xsave: vcpu.get_xsave()?,
+            xcrs: vcpu.get_xcrs()?,
+            msrs: save_msrs(vcpu, msr_indices)?,
+            lapic: vcpu.get_lapic()?,
+            events: vcpu.get_vcpu_events()?,
+            debugregs: vcpu.get_debug_regs()?,
+            mp_state: vcpu.get_mp_state()?,
+        })
+    }
+
+    pub fn restore(&self, vcpu: &VcpuFd) -> Result<()> {
+        // sregs first, KVM checks the rest against CR0/CR4/EFER
+        vcpu.set_sregs(&self.sregs)?;
+        vcpu.set_regs(&self.regs)?;
+        vcpu.set_fpu(&self.fpu)?;
+        vcpu.set_xsave(&self.xsave)?;
+        vcpu.set_xcrs(&self.xcrs)?;
+        let msrs = Msrs::from_entries(&self.msrs).map_err(|_| SnapshotError::Malformed)?;
+        // KVM stops at the first MSR it refuses, the following ones aren't written either
+        let written = vcpu.set_msrs(&msrs)?;
+        if let Some(refused) = self.msrs.get(written) {
+            return Err(SnapshotError::MsrRestore(refused.index));
+        }
+        vcpu.set_lapic(&self.lapic)?;
+        vcpu.set_vcpu_events(&self.events)?;
+        vcpu.set_debug_regs(&self.debugregs)?;
+        vcpu.set_mp_state(self.mp_state)?;
+        Ok(())
+    }
+
+    fn encode(&self, buf: &mut StateBuf) {
+        buf.put_pod(&self.regs);
+        buf.put_pod(&self.sregs);
+        buf.put_pod(&self.fpu);
+        buf.put_pod(&self.xsave);
+        buf.put_pod(&self.xcrs);
+        buf.put_u32(self.msrs.len() as u32);
+        for msr in &self.msrs {
+            buf.put_u32(msr.index);
+            buf.put_u64(msr.data);
+        }
+        buf.put_pod(&self.lapic);
+        buf.put_pod(&self.events);
+        buf.put_pod(&self.debugregs);
+        buf.put_pod(&self.mp_state);
+    }
+
+    fn decode(r: &mut StateReader) -> Result<Self> {
+        let regs = r.get_pod()?;
+        let sregs = r.get_pod()?;
+        let fpu = r.get_pod()?;
+        let xsave = r.get_pod()?;
+        let xcrs = r.get_pod()?;
+        let msr_count = r.get_u32()?;
+        let mut msrs = Vec::with_capacity(msr_count as usize);
+        for _ in 0..msr_count {
+            let index = r.get_u32()?;
+            let data = r.get_u64()?;
+            msrs.push(kvm_msr_entry { index, data, ..Default::default() });
+        }
+        Ok(Self {
+            regs,
+            sregs,
+            fpu,
+            xsave,
+            xcrs,
+            msrs,
+            lapic: r.get_pod()?,
+            events: r.get_pod()?,
+            debugregs: r.get_pod()?,
+            mp_state: r.get_pod()?,
+        })
+    }
+}
+
+/// KVM_GET_MSRS stops at the first MSR it can't read, skip it and go on with the rest
+fn save_msrs(vcpu: &VcpuFd, msr_indices: &[u32]) -> Result<Vec<kvm_msr_entry>> {
+    let mut saved = vec![];
+    let mut remaining = msr_indices;
+    while !remaining.is_empty() {
+        let entries: Vec<kvm_msr_entry> = remaining
+            .iter()
+            .map(|&index| kvm_msr_entry { index, ..Default::default() })
+            .collect();
+        let mut msrs = Msrs::from_entries(&entries).map_err(|_| SnapshotError::Malformed)?;
+        let read = vcpu.get_msrs(&mut msrs)?;
+        saved.extend_from_slice(&msrs.as_slice()[..read]);
+        if read < remaining.len() {
+            debug!("Skipping unreadable MSR 0x{:x}", remaining[read]);
+        }
+        remaining = &remaining[(read + 1).min(remaining.len())..];
+    }
+    Ok(saved)
+}
+
+/// VM wide in-kernel state: irqchips, PIT and kvmclock.
+/// A split irqchip has neither in-kernel PIC/IOAPIC nor PIT.
+pub(super) struct VmState {
+    irqchips: Vec<kvm_irqchip>,
+    pit: Option<kvm_pit_state2>,
+    clock: kvm_clock_data,
+}
+
+impl VmState {
+    pub(super) fn save(vm_fd: &VmFd, mode: IrqChipMode) -> Result<Self> {
+        let mut irqchips = vec![];
+        let mut pit = None;
+        if mode == IrqChipMode::Kernel {
+            for chip_id in IRQCHIP_IDS {
+                let mut chip = kvm_irqchip { chip_id, ..Default::default() };
+                vm_fd.get_irqchip(&mut chip)?;
+                irqchips.push(chip);
+            }
+            pit = Some(vm_fd.get_pit2()?);
+        }
+        Ok(Self { irqchips, pit, clock: vm_fd.get_clock()? })
+    }
+
+    fn restore(&self, vm_fd: &VmFd) -> Result<()> {
+        self.restore_irqchips(vm_fd)?;
+        // Only KVM_CLOCK_REALTIME is accepted back, and we want the saved guest time as is
+        let clock = kvm_clock_data { flags: 0, ..self.clock };
+        vm_fd.set_clock(&clock)?;
+        Ok(())
+    }
+
+    /// Everything but the clock
+    pub(super) fn restore_irqchips(&self, vm_fd: &VmFd) -> Result<()> {
+        for chip in &self.irqchips {
+            vm_fd.set_irqchip(chip)?;
+        }
+        if let Some(pit) = &self.pit {
+            vm_fd.set_pit2(pit)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_section_header<W: Write>(w: &mut W, tag: [u8; 4], len: u64) -> Result<()> {
+    w.write_all(&tag)?;
+    w.write_all(&len.to_le_bytes())?;
+    Ok(())
+}
+
+fn write_section<W: Write>(w: &mut W, tag: [u8; 4], payload: &[u8]) -> Result<()> {
+    write_section_header(w, tag, payload.len() as u64)?;
+    w.write_all(payload)?;
+    Ok(())
+}
+
+/// Sparse RAM section: `mem_size:u64 | page_count:u64 | (page_idx:u64 | page)*`
+fn write_ram<W: Write>(w: &mut W, ram: &Ram, pages: &[u64]) -> Result<()> {
+    let len = 16 + (pages.len() * (8 + PAGE_SIZE)) as u64;
+    write_section_header(w, TAG_RAM, len)?;
+    w.write_all(&(ram.mem_size as u64).to_le_bytes())?;
+    w.write_all(&(pages.len() as u64).to_le_bytes())?;
+    for &page_idx in pages {
+        w.write_all(&page_idx.to_le_bytes())?;
+        w.write_all(ram.page(page_idx).ok_or(SnapshotError::Malformed)?)?;
+    }
+    Ok(())
+}
+
+fn snapshot_id() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+fn non_zero_pages(ram: &Ram) -> Vec<u64> {
+    (0..ram.page_count())
+        .filter(|&idx| ram.page(idx).is_some_and(|page| page.iter().any(|&b| b != 0)))
+        .collect()
+}
+
+/// A snapshot file opened for restore, sections are indexed but only read on demand
+#[derive(Debug)]
+pub struct SnapshotFile {
+    file: BufReader<File>,
+    /// File size, every section lies below it
+    len: u64,
+    sections: Vec<([u8; 4], u64, u64)>,
+}
+
+impl SnapshotFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let mut version = [0u8; 4];
+        file.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != VERSION {
+            return Err(SnapshotError::BadVersion(version));
+        }
+
+        let mut sections = vec![];
+        let file_len = file.get_ref().metadata()?.len();
+        let mut offset = file.stream_position()?;
+        while offset < file_len {
+            let mut header = [0u8; 12];
+            file.read_exact(&mut header)?;
+            let tag: [u8; 4] = header[..4].try_into().unwrap();
+            let len = u64::from_le_bytes(header[4..].try_into().unwrap());
+            offset += 12;
+            sections.push((tag, offset, len));
+            offset = offset
+                .checked_add(len)
+                .filter(|&end| end <= file_len)
+                .ok_or(SnapshotError::Malformed)?;
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(Self { file, len: file_len, sections })
+    }
+
+    fn sections(&self, tag: [u8; 4]) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.sections
+            .iter()
+            .filter(move |(t, _, _)| *t == tag)
+            .map(|&(_, offset, len)| (offset, len))
+    }
+
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        // Sizes come from the file, don't allocate for data it doesn't hold
+        if offset.checked_add(len).is_none_or(|end| end > self.len) {
+            return Err(SnapshotError::Malformed);
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0u8; len as usize];
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_sections(&mut self, tag: [u8; 4]) -> Result<Vec<Vec<u8>>> {
+        let found: Vec<_> = self.sections(tag).collect();
+        found
+            .into_iter()
+            .map(|(offset, len)| self.read_at(offset, len))
+            .collect()
+    }
+
+    fn read_section(&mut self, tag: [u8; 4]) -> Result<Vec<u8>> {
+        self.read_sections(tag)?.into_iter().next().ok_or(SnapshotError::Malformed)
+    }
+
+    /// Unique id of the snapshot, referenced by incremental snapshots
+    pub fn id(&mut self) -> Result<u64> {
+        StateReader::new(&self.read_section(TAG_ID)?).get_u64()
+    }
+
+    /// Id and path of the base of an incremental snapshot, `None` for a full one
+    pub fn base(&mut self) -> Result<Option<(u64, PathBuf)>> {
+        if self.sections(TAG_BASE).next().is_none() {
+            return Ok(None);
+        }
+        let payload = self.read_section(TAG_BASE)?;
+        let mut r = StateReader::new(&payload);
+        let id = r.get_u64()?;
+        let path = PathBuf::from(String::from_utf8_lossy(r.get_bytes()?).into_owned());
+        Ok(Some((id, path)))
+    }
+
+    /// Load address and image of the firmware, `None` if the snapshot has none
+    pub fn firmware(&mut self) -> Result<Option<(GuestAddress, Vec<u8>)>> {
+        if self.sections(TAG_FIRMWARE).next().is_none() {
+            return Ok(None);
+        }
+        let payload = self.read_section(TAG_FIRMWARE)?;
+        let mut r = StateReader::new(&payload);
+        let addr = GuestAddress(r.get_u64()?);
+        Ok(Some((addr, r.get_bytes()?.to_vec())))
+    }
+
+    /// RAM section header as (offset of the first page entry, guest RAM size, page count)
+    fn ram_header(&mut self) -> Result<(u64, usize, u64)> {
+        let (offset, len) = self.sections(TAG_RAM).next().filter(|&(_, len)| len >= 16).ok_or(SnapshotError::Malformed)?;
+        let header = self.read_at(offset, 16)?;
+        let mem_size = u64::from_le_bytes(header[..8].try_into().unwrap()) as usize;
+        let page_count = u64::from_le_bytes(header[8..].try_into().unwrap());
+        if page_count.checked_mul(8 + PAGE_SIZE as u64).is_none_or(|size| size > len - 16) {
+            return Err(SnapshotError::Malformed);
+        }
+        Ok((offset + 16, mem_size, page_count))
+    }
+
+    /// Guest RAM size recorded in the snapshot
+    pub fn mem_size(&mut self) -> Result<usize> {
+        Ok(self.ram_header()?.1)
+    }
+
+    /// Index of every stored page
+    pub fn pages(&mut self) -> Result<Vec<u64>> {
+        Ok(self.page_offsets()?.into_keys().collect())
+    }
+
+    /// File offset of every stored page
+    fn page_offsets(&mut self) -> Result<HashMap<u64, u64>> {
+        let (mut entry, _, page_count) = self.ram_header()?;
+        let mut offsets = HashMap::with_capacity(page_count as usize);
+        for _ in 0..page_count {
+            let idx = u64::from_le_bytes(self.read_at(entry, 8)?.try_into().unwrap());
+            offsets.insert(idx, entry + 8);
+            entry += 8 + PAGE_SIZE as u64;
+        }
+        Ok(offsets)
+    }
+
+    /// Copy every stored page back into guest RAM
+    pub fn restore_ram(&mut self, ram: &Ram) -> Result<()> {
+        // Leaves the file at the first page entry
+        let (_, mem_size, page_count) = self.ram_header()?;
+        if mem_size != ram.mem_size {
+            return Err(SnapshotError::RamSize(mem_size, ram.mem_size));
+        }
+        let mut page = vec![0u8; PAGE_SIZE];
+        for _ in 0..page_count {
+            let mut idx = [0u8; 8];
+            self.file.read_exact(&mut idx)?;
+            self.file.read_exact(&mut page)?;
+            let idx = u64::from_le_bytes(idx);
+            if idx >= ram.page_count() {
+                return Err(SnapshotError::Malformed);
+            }
+            ram.write_page(idx, &page);
+        }
+        info!("Restored {page_count} RAM pages");
+        Ok(())
+    }
+
+    pub fn vcpu_states(&mut self) -> Result<Vec<VcpuState>> {
+        self.read_sections(TAG_VCPU)?
+            .iter()
+            .map(|payload| VcpuState::decode(&mut StateReader::new(payload)))
+            .collect()
+    }
+
+    fn vm_state(&mut self) -> Result<VmState> {
+        let mut irqchips = vec![];
+        for payload in self.read_sections(TAG_IRQCHIP)? {
+            irqchips.push(StateReader::new(&payload).get_pod()?);
+        }
+        let pit = match self.read_sections(TAG_PIT)?.first() {
+            Some(payload) => Some(StateReader::new(payload).get_pod()?),
+            None => None,
+        };
+        let clock = StateReader::new(&self.read_section(TAG_CLOCK)?).get_pod()?;
+        Ok(VmState { irqchips, pit, clock })
+    }
+
+    /// Device sections as (snapshot_id, state)
+    fn device_states(&mut self) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut states = vec![];
+        for payload in self.read_sections(TAG_DEVICE)? {
+            let mut r = StateReader::new(&payload);
+            let id = String::from_utf8_lossy(r.get_bytes()?).into_owned();
+            states.push((id, r.get_bytes()?.to_vec()));
+        }
+        Ok(states)
+    }
+
+    /// Restore everything but RAM into an already built VM, its event loop quiesced
+    pub fn restore_state(&mut self, vm: &mut Vm) -> Result<()> {
+        self.vm_state()?.restore(&vm.vm_fd)?;
+        let vcpus = self.vcpu_states()?;
+        if vcpus.len() != 1 {
+            warn!("Snapshot holds {} vCPUs, restoring the first one only", vcpus.len());
+        }
+        vcpus.first().ok_or(SnapshotError::Malformed)?.restore(&vm.vcpu_fd)?;
+
+        let states = self.device_states()?;
+        for device in vm.snapshot_devices() {
+            let id = device.snapshot_id();
+            let (_, state) = states
+                .iter()
+                .find(|(saved_id, _)| *saved_id == id)
+                .ok_or_else(|| SnapshotError::MissingDevice(id.clone()))?;
+            device.restore_state(&mut StateReader::new(state))?;
+            debug!("Restored device {id}");
+        }
+        vm.sync_irq_routes()?;
+        vm.sync_pci_bars()?;
+        Ok(())
+    }
+}
+
+/// The full snapshot incremental snapshots and resets are relative to
+#[derive(Debug)]
+pub struct BaseSnapshot {
+    path: PathBuf,
+    id: u64,
+    file: SnapshotFile,
+    /// Dirty log marker taken when the guest was last in the base state
+    marker: u32,
+    /// Lazily built index of the base RAM pages
+    page_offsets: Option<HashMap<u64, u64>>,
+}
+
+impl BaseSnapshot {
+    pub fn open<P: AsRef<Path>>(path: P, marker: u32) -> Result<Self> {
+        let path = path.as_ref().canonicalize()?;
+        let mut file = SnapshotFile::open(&path)?;
+        if file.base()?.is_some() {
+            warn!("{} is incremental, it can't be used as a base", path.to_string_lossy());
+            return Err(SnapshotError::NoBase);
+        }
+        let id = file.id()?;
+        Ok(Self { path, id, file, marker, page_offsets: None })
+    }
+
+    /// Put `pages` back to their base content, pages the base didn't store are zero
+    fn restore_pages(&mut self, ram: &Ram, pages: &[u64]) -> Result<()> {
+        if self.page_offsets.is_none() {
+            self.page_offsets = Some(self.file.page_offsets()?);
+        }
+        let offsets = self.page_offsets.as_ref().unwrap();
+        let zero = vec![0u8; PAGE_SIZE];
+        for &idx in pages {
+            match offsets.get(&idx) {
+                Some(&offset) => {
+                    let page = self.file.read_at(offset, PAGE_SIZE as u64)?;
+                    ram.write_page(idx, &page);
+                }
+                None => ram.write_page(idx, &zero),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Vm {
+    /// Every emulated device carried in snapshots
+    pub fn snapshot_devices(&mut self) -> Vec<&mut dyn Snapshot> {
+        let mut devices: Vec<&mut dyn Snapshot> = vec![&mut self.serial, &mut self.pci];
+        if let Some(ioapic) = self.irq.ioapic.as_mut() {
+            devices.push(ioapic);
+        }
+        if let Some(i8042) = self.i8042.as_mut() {
+            devices.push(i8042);
+        }
+        if let Some(rtc) = self.rtc.as_mut() {
+            devices.push(rtc);
+        }
+        if let Some(hpet) = self.hpet.as_mut() {
+            devices.push(hpet);
+        }
+        if let Some(fw_cfg) = self.fw_cfg.as_mut() {
+            devices.push(fw_cfg);
+        }
+        if let Some(acpi_pm) = self.acpi_pm.as_mut() {
+            devices.push(acpi_pm);
+        }
+        if let Some(tpm) = self.tpm.as_mut() {
+            devices.push(tpm);
+        }
+        devices.extend(self.virtio_mmio.iter_mut().map(|device| device as &mut dyn Snapshot));
+        devices
+    }
+
+    /// Save the whole VM to `path`, the VM must not be running.
+    /// The snapshot becomes the base of following incremental snapshots.
+    pub fn snapshot<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        info!("Snapshotting VM to {}", path.to_string_lossy());
+        let _quiesced = self.event_loop.quiesce()?;
+        let marker = self.dirty.mark(&self.vm_fd, &self.ram)?;
+        let pages = non_zero_pages(&self.ram);
+        self.write_snapshot(path, None, &pages)?;
+        self.base = Some(BaseSnapshot::open(path, marker)?);
+        Ok(())
+    }
+
+    /// Save only the RAM pages written since the base snapshot, with the full vCPU and device state
+    pub fn snapshot_incremental<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let base = self.base.as_ref().ok_or(SnapshotError::NoBase)?;
+        let base_ref = (base.id, base.path.clone());
+        let marker = base.marker;
+        info!("Incremental snapshot to {} over {}", path.to_string_lossy(), base_ref.1.to_string_lossy());
+        let _quiesced = self.event_loop.quiesce()?;
+        self.dirty.sync(&self.vm_fd, &self.ram)?;
+        let pages = self.dirty.changed_since(marker);
+        self.write_snapshot(path, Some(base_ref), &pages)
+    }
+
+    /// Rewind the guest to the base snapshot, only reloading the pages written since
+    pub fn reset_to_base(&mut self) -> Result<()> {
+        let _quiesced = self.event_loop.quiesce()?;
+        let mut base = self.base.take().ok_or(SnapshotError::NoBase)?;
+        self.dirty.sync(&self.vm_fd, &self.ram)?;
+        let pages = self.dirty.changed_since(base.marker);
+        let reset = complete_pending_io(&self.vcpu_fd)
+            .and_then(|_| base.restore_pages(&self.ram, &pages))
+            .and_then(|_| base.file.restore_state(self));
+        // Restored pages match the base again, whatever the outcome the log must move past them
+        base.marker = self.dirty.mark(&self.vm_fd, &self.ram)?;
+        self.base = Some(base);
+        reset?;
+        // The base was running, a VM paused by a reset source goes on from it
+        self.paused = false;
+        info!("Reset to base, {} pages reloaded", pages.len());
+        Ok(())
+    }
+
+    /// Make `path` the base snapshot, the VM holding it but for the `changed`
+    /// pages, those an incremental snapshot restored over it
+    pub(super) fn set_base<P: AsRef<Path>>(&mut self, path: P, changed: &[u64]) -> Result<()> {
+        let marker = self.dirty.mark(&self.vm_fd, &self.ram)?;
+        self.dirty.touch(changed);
+        self.base = Some(BaseSnapshot::open(path, marker)?);
+        Ok(())
+    }
+
+    fn write_snapshot(&mut self, path: &Path, base: Option<(u64, PathBuf)>, pages: &[u64]) -> Result<()> {
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+        let mut w = BufWriter::new(file);
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        write_section(&mut w, TAG_ID, &snapshot_id().to_le_bytes())?;
+        if let Some((base_id, base_path)) = base {
+            let mut buf = StateBuf::default();
+            buf.put_u64(base_id);
+            buf.put_bytes(base_path.to_string_lossy().as_bytes());
+            write_section(&mut w, TAG_BASE, &buf.0)?;
+        }
+        if let Some((addr, image)) = self.power_on_firmware() {
+            let mut buf = StateBuf::default();
+            buf.put_u64(addr.0);
+            buf.put_bytes(image);
+            write_section(&mut w, TAG_FIRMWARE, &buf.0)?;
+        }
+
+        let vm_state = VmState::save(&self.vm_fd, self.irq.mode())?;
+        for chip in &vm_state.irqchips {
+            write_section(&mut w, TAG_IRQCHIP, pod_bytes(chip))?;
+        }
+        if let Some(pit) = &vm_state.pit {
+            write_section(&mut w, TAG_PIT, pod_bytes(pit))?;
+        }
+        write_section(&mut w, TAG_CLOCK, pod_bytes(&vm_state.clock))?;
+
+        let mut buf = StateBuf::default();
+        VcpuState::save(&self.vcpu_fd, &self.msr_indices)?.encode(&mut buf);
+        write_section(&mut w, TAG_VCPU, &buf.0)?;
+
+        for device in self.snapshot_devices() {
+            let mut state = StateBuf::default();
+            device.save_state(&mut state);
+            let mut buf = StateBuf::default();
+            buf.put_bytes(device.snapshot_id().as_bytes());
+            buf.put_bytes(&state.0);
+            write_section(&mut w, TAG_DEVICE, &buf.0)?;
+        }
+
+        write_ram(&mut w, &self.ram, pages)?;
+        w.flush()?;
+        info!("Snapshot done, {} RAM pages saved", pages.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ ffi::CString, io, os::{ fd::FromRawFd, unix::fs::FileExt } };
+
+    use super::*;
+
+    /// Anonymous in-memory file, and a path opening it again
+    fn memfd(content: &[u8]) -> (File, String) {
+        let name = CString::new("snapshot-test").unwrap();
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+        assert!(fd >= 0, "memfd_create: {}", io::Error::last_os_error());
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.write_all_at(content, 0).unwrap();
+        (file, format!("/proc/self/fd/{fd}"))
+    }
+
+    fn snapshot_image(sections: &[([u8; 4], u64, &[u8])]) -> Vec<u8> {
+        let mut image = MAGIC.to_vec();
+        image.extend(VERSION.to_le_bytes());
+        for &(tag, len, payload) in sections {
+            write_section_header(&mut image, tag, len).unwrap();
+            image.extend(payload);
+        }
+        image
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut buf = StateBuf::default();
+        buf.put_u8(0x12);
+        buf.put_u16(0x3456);
+        buf.put_u32(0x789a_bcde);
+        buf.put_u64(u64::MAX - 1);
+        buf.put_bytes(b"serial");
+        let mut r = StateReader::new(&buf.0);
+        assert_eq!(r.get_u8().unwrap(), 0x12);
+        assert_eq!(r.get_u16().unwrap(), 0x3456);
+        assert_eq!(r.get_u32().unwrap(), 0x789a_bcde);
+        assert_eq!(r.get_u64().unwrap(), u64::MAX - 1);
+        assert_eq!(r.get_bytes().unwrap(), b"serial");
+        assert!(matches!(r.get_u8(), Err(SnapshotError::Malformed)));
+
+        // A length prefix larger than what's left
+        let mut buf = StateBuf::default();
+        buf.put_u64(u64::MAX);
+        assert!(matches!(StateReader::new(&buf.0).get_bytes(), Err(SnapshotError::Malformed)));
+    }
+
+    #[test]
+    fn sections_indexed() {
+        let mut ram = (0x200000u64).to_le_bytes().to_vec();
+        ram.extend(0u64.to_le_bytes());
+        let (_file, path) = memfd(&snapshot_image(&[(TAG_CLOCK, 4, &[1, 2, 3, 4]), (TAG_RAM, 16, &ram)]));
+        let mut snapshot = SnapshotFile::open(&path).unwrap();
+        assert_eq!(snapshot.read_section(TAG_CLOCK).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(snapshot.mem_size().unwrap(), 0x200000);
+        assert!(matches!(snapshot.read_section(TAG_PIT), Err(SnapshotError::Malformed)));
+    }
+
+    #[test]
+    fn firmware_section() {
+        let (_file, path) = memfd(&snapshot_image(&[(TAG_CLOCK, 4, &[1, 2, 3, 4])]));
+        assert!(SnapshotFile::open(&path).unwrap().firmware().unwrap().is_none());
+
+        let mut firmware = StateBuf::default();
+        firmware.put_u64(0xffc0_0000);
+        firmware.put_bytes(&[0x90; 16]);
+        let (_file, path) = memfd(&snapshot_image(&[(TAG_FIRMWARE, firmware.0.len() as u64, &firmware.0)]));
+        let (addr, image) = SnapshotFile::open(&path).unwrap().firmware().unwrap().unwrap();
+        assert_eq!(addr, GuestAddress(0xffc0_0000));
+        assert_eq!(image, [0x90; 16]);
+    }
+
+    #[test]
+    fn truncated_snapshot_rejected() {
+        let (_file, path) = memfd(b"RVMMSNAX\x01\0\0\0");
+        assert!(matches!(SnapshotFile::open(&path), Err(SnapshotError::BadMagic)));
+
+        // A section running past the end of the file, don't allocate its claimed size
+        let (_file, path) = memfd(&snapshot_image(&[(TAG_VCPU, 1 << 40, &[0; 16])]));
+        assert!(matches!(SnapshotFile::open(&path), Err(SnapshotError::Malformed)));
+        let (_file, path) = memfd(&snapshot_image(&[(TAG_VCPU, u64::MAX - 8, &[])]));
+        assert!(matches!(SnapshotFile::open(&path), Err(SnapshotError::Malformed)));
+    }
+
+    #[test]
+    fn incremental_snapshot_pages() {
+        let mut ram = (0x200000u64).to_le_bytes().to_vec();
+        ram.extend(2u64.to_le_bytes());
+        for (idx, fill) in [(7u64, 0xaa), (3, 0x55)] {
+            ram.extend(idx.to_le_bytes());
+            ram.extend([fill; PAGE_SIZE]);
+        }
+        let mut base = StateBuf::default();
+        base.put_u64(0x1234);
+        base.put_bytes(b"/snapshots/base");
+        let image = snapshot_image(&[
+            (TAG_ID, 8, &0x5678u64.to_le_bytes()),
+            (TAG_BASE, base.0.len() as u64, &base.0),
+            (TAG_RAM, ram.len() as u64, &ram),
+        ]);
+        let (_file, path) = memfd(&image);
+        let mut snapshot = SnapshotFile::open(&path).unwrap();
+        assert_eq!(snapshot.id().unwrap(), 0x5678);
+        assert_eq!(snapshot.base().unwrap(), Some((0x1234, PathBuf::from("/snapshots/base"))));
+        let offsets = snapshot.page_offsets().unwrap();
+        assert_eq!(snapshot.read_at(offsets[&3], PAGE_SIZE as u64).unwrap(), [0x55; PAGE_SIZE]);
+        assert_eq!(snapshot.read_at(offsets[&7], 1).unwrap(), [0xaa]);
+        let mut pages = snapshot.pages().unwrap();
+        pages.sort_unstable();
+        assert_eq!(pages, [3, 7]);
+
+        // More pages than the section holds
+        ram[8..16].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        let (_file, path) = memfd(&snapshot_image(&[(TAG_RAM, ram.len() as u64, &ram)]));
+        let mut snapshot = SnapshotFile::open(&path).unwrap();
+        assert!(matches!(snapshot.pages(), Err(SnapshotError::Malformed)));
+    }
+
+    #[test]
+    fn reset_to_base_resumes_a_paused_vm() {
+        use crate::vmm::vm_builder::BuildVm;
+        // Needs /dev/kvm
+        let Ok(kvm) = kvm_ioctls::Kvm::new() else {
+            return;
+        };
+        let mut vm = kvm.setup_vm(IrqChipMode::Kernel).unwrap().ram(1 << 24).load_asm(&[0xf4]).build().unwrap();
+        let path = std::env::temp_dir().join(format!("reset-base-test-{}", std::process::id()));
+        vm.snapshot(&path).unwrap();
+        vm.paused = true;
+        let reset = vm.reset_to_base();
+        std::fs::remove_file(&path).unwrap();
+        reset.unwrap();
+        assert!(!vm.paused);
+    }
+}
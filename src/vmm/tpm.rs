@@ -0,0 +1,589 @@
+//! TPM 2.0 with the CRB interface at 0xFED40000, locality 0 only.
+//!
+//! The guest writes a command in the CRB data buffer and sets CTRL_START, the
+//! command goes to a [`TpmBackend`] right away and the response replaces it
+//! in the buffer, the vCPU waiting on the exit meanwhile. The backend is
+//! normally swtpm: we connect to its control socket
+//! (`swtpm socket --tpm2 --ctrl type=unixio,path=...`) and pass it one end of
+//! a socket pair for the commands, as QEMU does. [`StandInTpm`] answers a few
+//! commands, for the tests and VMs without swtpm at hand; no OS would accept
+//! it as a TPM.
+//!
+//! PCR extends on their way to the backend are logged and kept, which makes
+//! the measured boot visible from here. Snapshots carry the CRB registers and
+//! buffer, not the TPM state, which stays with swtpm.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{ self, Read, Write },
+    os::{ fd::AsRawFd, unix::net::UnixStream },
+    path::Path,
+    sync::{ Arc, Mutex },
+};
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+use super::{
+    bus::BusDevice,
+    snapshot::{ self, Snapshot, StateBuf, StateReader },
+    Vm,
+};
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+pub const TPM_CRB_BASE: u64 = 0xfed4_0000;
+/// Registers and buffer of locality 0
+const TPM_CRB_SIZE: u64 = 0x1000;
+/// The five localities the ACPI device claims
+pub const TPM_CRB_REGION: u64 = 0x5000;
+pub const TPM_CONFIG_FILE: &str = "etc/tpm/config";
+
+const CRB_LOC_STATE: u64 = 0x00;
+const CRB_LOC_CTRL: u64 = 0x08;
+const CRB_LOC_STS: u64 = 0x0c;
+const CRB_INTF_ID: u64 = 0x30;
+const CRB_INTF_ID_HIGH: u64 = 0x34;
+/// Start of the control area the TPM2 table points to
+pub const CRB_CTRL_REQ: u64 = 0x40;
+const CRB_CTRL_STS: u64 = 0x44;
+const CRB_CTRL_CANCEL: u64 = 0x48;
+const CRB_CTRL_START: u64 = 0x4c;
+const CRB_INT_ENABLE: u64 = 0x50;
+const CRB_CTRL_CMD_SIZE: u64 = 0x58;
+const CRB_CTRL_CMD_LADDR: u64 = 0x5c;
+const CRB_CTRL_RSP_SIZE: u64 = 0x64;
+const CRB_CTRL_RSP_ADDR: u64 = 0x68;
+const CRB_DATA_BUFFER: u64 = 0x80;
+/// Command and response share the rest of the locality
+const CRB_BUFFER_SIZE: usize = (TPM_CRB_SIZE - CRB_DATA_BUFFER) as usize;
+
+const LOC_STATE_ASSIGNED: u32 = 1 << 1;
+const LOC_STATE_REG_VALID: u32 = 1 << 7;
+const LOC_CTRL_REQUEST: u32 = 1 << 0;
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+const LOC_STS_GRANTED: u32 = 1 << 0;
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+const CTRL_STS_FATAL: u32 = 1 << 0;
+const CTRL_STS_IDLE: u32 = 1 << 1;
+
+/// CRB interface and version, 64 byte transfers, CRB only and selected
+const INTF_ID: u32 = 1 | 1 << 4 | 3 << 11 | 1 << 14 | 1 << 17;
+/// IBM vendor id of the swtpm, device 1
+const INTF_ID_HIGH: u32 = 0x0001_1014;
+
+/// fw_cfg "etc/tpm/config": no PPI, TPM 2.0
+const TPM_VERSION_2: u8 = 2;
+
+const TPM_HEADER_SIZE: usize = 10;
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_RC_SUCCESS: u32 = 0x000;
+const TPM_RC_INITIALIZE: u32 = 0x100;
+const TPM_RC_FAILURE: u32 = 0x101;
+const TPM_RC_COMMAND_CODE: u32 = 0x143;
+const TPM_CC_SELF_TEST: u32 = 0x143;
+const TPM_CC_STARTUP: u32 = 0x144;
+const TPM_CC_SHUTDOWN: u32 = 0x145;
+const TPM_CC_GET_RANDOM: u32 = 0x17b;
+const TPM_CC_PCR_EXTEND: u32 = 0x182;
+
+const SWTPM_CMD_INIT: u32 = 2;
+const SWTPM_CMD_SET_LOCALITY: u32 = 5;
+const SWTPM_CMD_CANCEL_TPM_CMD: u32 = 9;
+const SWTPM_CMD_SET_DATAFD: u32 = 16;
+
+/// Where the TPM commands go
+pub trait TpmBackend: Send + fmt::Debug {
+    /// Power the TPM on, again after a reset, the firmware sends TPM2_Startup next
+    fn init(&mut self) -> io::Result<()>;
+    /// Run one command from `locality`, returns the response
+    fn execute(&mut self, locality: u8, command: &[u8]) -> io::Result<Vec<u8>>;
+    fn cancel(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// swtpm reached through its control socket
+#[derive(Debug)]
+pub struct Swtpm {
+    ctrl: UnixStream,
+    data: UnixStream,
+    /// Last locality given to swtpm, None before the first command
+    locality: Option<u8>,
+}
+
+impl Swtpm {
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let ctrl = UnixStream::connect(path)?;
+        let (data, remote) = UnixStream::pair()?;
+        ctrl.send_with_fd(&SWTPM_CMD_SET_DATAFD.to_be_bytes()[..], remote.as_raw_fd())
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+        let mut swtpm = Swtpm { ctrl, data, locality: None };
+        swtpm.result(SWTPM_CMD_SET_DATAFD)?;
+        Ok(swtpm)
+    }
+
+    /// Send a control command, its answer is a result code
+    fn control(&mut self, command: u32, payload: &[u8]) -> io::Result<()> {
+        let mut message = command.to_be_bytes().to_vec();
+        message.extend_from_slice(payload);
+        self.ctrl.write_all(&message)?;
+        self.result(command)
+    }
+
+    fn result(&mut self, command: u32) -> io::Result<()> {
+        let mut result = [0; 4];
+        self.ctrl.read_exact(&mut result)?;
+        match u32::from_be_bytes(result) {
+            TPM_RC_SUCCESS => Ok(()),
+            rc => Err(io::Error::other(format!("swtpm control command {command} failed: 0x{rc:x}"))),
+        }
+    }
+}
+
+impl TpmBackend for Swtpm {
+    fn init(&mut self) -> io::Result<()> {
+        self.locality = None;
+        self.control(SWTPM_CMD_INIT, &0u32.to_be_bytes())
+    }
+
+    fn execute(&mut self, locality: u8, command: &[u8]) -> io::Result<Vec<u8>> {
+        if self.locality != Some(locality) {
+            self.control(SWTPM_CMD_SET_LOCALITY, &[locality])?;
+            self.locality = Some(locality);
+        }
+        self.data.write_all(command)?;
+        let mut response = vec![0; TPM_HEADER_SIZE];
+        self.data.read_exact(&mut response)?;
+        let size = u32::from_be_bytes(response[2..6].try_into().unwrap()) as usize;
+        if size < TPM_HEADER_SIZE {
+            return Err(io::Error::other(format!("swtpm response of {size} bytes")));
+        }
+        response.resize(size, 0);
+        self.data.read_exact(&mut response[TPM_HEADER_SIZE..])?;
+        Ok(response)
+    }
+
+    fn cancel(&mut self) -> io::Result<()> {
+        self.control(SWTPM_CMD_CANCEL_TPM_CMD, &[])
+    }
+}
+
+/// Response without sessions, `params` after the header
+fn tpm_response(rc: u32, params: &[u8]) -> Vec<u8> {
+    let mut response = TPM_ST_NO_SESSIONS.to_be_bytes().to_vec();
+    response.extend_from_slice(&((TPM_HEADER_SIZE + params.len()) as u32).to_be_bytes());
+    response.extend_from_slice(&rc.to_be_bytes());
+    response.extend_from_slice(params);
+    response
+}
+
+/// Built-in stand-in: TPM2_Startup first, then success for self tests, shutdowns
+/// and PCR extends, host random bytes for TPM2_GetRandom, and nothing else
+#[derive(Debug, Default)]
+pub struct StandInTpm {
+    started: bool,
+}
+
+impl TpmBackend for StandInTpm {
+    fn init(&mut self) -> io::Result<()> {
+        self.started = false;
+        Ok(())
+    }
+
+    fn execute(&mut self, _locality: u8, command: &[u8]) -> io::Result<Vec<u8>> {
+        let Some(code) = command_code(command) else {
+            return Ok(tpm_response(TPM_RC_FAILURE, &[]));
+        };
+        let response = match code {
+            TPM_CC_STARTUP => {
+                self.started = true;
+                tpm_response(TPM_RC_SUCCESS, &[])
+            }
+            _ if !self.started => tpm_response(TPM_RC_INITIALIZE, &[]),
+            TPM_CC_SELF_TEST | TPM_CC_SHUTDOWN | TPM_CC_PCR_EXTEND => tpm_response(TPM_RC_SUCCESS, &[]),
+            TPM_CC_GET_RANDOM if command.len() >= TPM_HEADER_SIZE + 2 => {
+                let requested = u16::from_be_bytes([command[10], command[11]]).min(64);
+                let mut random = vec![0; requested as usize];
+                File::open("/dev/urandom")?.read_exact(&mut random)?;
+                let mut params = requested.to_be_bytes().to_vec();
+                params.extend(random);
+                tpm_response(TPM_RC_SUCCESS, &params)
+            }
+            _ => tpm_response(TPM_RC_COMMAND_CODE, &[]),
+        };
+        Ok(response)
+    }
+}
+
+fn command_code(command: &[u8]) -> Option<u32> {
+    command.get(6..TPM_HEADER_SIZE).map(|code| u32::from_be_bytes(code.try_into().unwrap()))
+}
+
+/// Digest sizes of the TPM_ALG_IDs a PCR bank may use
+fn digest_size(alg: u16) -> Option<usize> {
+    match alg {
+        0x0004 => Some(20),
+        0x000b | 0x0012 => Some(32),
+        0x000c => Some(48),
+        0x000d => Some(64),
+        _ => None,
+    }
+}
+
+/// A TPM2_PCR_Extend seen going to the backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcrExtend {
+    pub pcr: u32,
+    /// TPM_ALG_ID and digest of each bank
+    pub digests: Vec<(u16, Vec<u8>)>,
+}
+
+impl fmt::Display for PcrExtend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PCR {}", self.pcr)?;
+        for (alg, digest) in &self.digests {
+            let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+            write!(f, " alg 0x{alg:x} {hex}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Handle, authorization area then TPML_DIGEST_VALUES
+fn parse_pcr_extend(command: &[u8]) -> Option<PcrExtend> {
+    let u32_at = |at: usize| command.get(at..at + 4).map(|v| u32::from_be_bytes(v.try_into().unwrap()));
+    let pcr = u32_at(TPM_HEADER_SIZE)?;
+    let auth_size = u32_at(TPM_HEADER_SIZE + 4)? as usize;
+    let mut at = TPM_HEADER_SIZE + 8 + auth_size;
+    let count = u32_at(at)?;
+    at += 4;
+    let mut digests = vec![];
+    for _ in 0..count {
+        let alg = u16::from_be_bytes(command.get(at..at + 2)?.try_into().unwrap());
+        let size = digest_size(alg)?;
+        digests.push((alg, command.get(at + 2..at + 2 + size)?.to_vec()));
+        at += 2 + size;
+    }
+    Some(PcrExtend { pcr, digests })
+}
+
+pub struct TpmCrb {
+    loc_granted: bool,
+    ctrl_sts: u32,
+    cancel: u32,
+    int_enable: u32,
+    buffer: Vec<u8>,
+    backend: Box<dyn TpmBackend>,
+    /// Since power on
+    measurements: Vec<PcrExtend>,
+}
+
+impl fmt::Debug for TpmCrb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TpmCrb")
+            .field("loc_granted", &self.loc_granted)
+            .field("ctrl_sts", &self.ctrl_sts)
+            .field("backend", &self.backend)
+            .finish()
+    }
+}
+
+impl TpmCrb {
+    pub fn new(backend: Box<dyn TpmBackend>) -> Self {
+        TpmCrb {
+            loc_granted: false,
+            ctrl_sts: CTRL_STS_IDLE,
+            cancel: 0,
+            int_enable: 0,
+            buffer: vec![0; CRB_BUFFER_SIZE],
+            backend,
+            measurements: vec![],
+        }
+    }
+
+    /// Power the backend on, the measurements start over
+    pub fn power_on(&mut self) -> io::Result<()> {
+        self.measurements.clear();
+        self.ctrl_sts = CTRL_STS_IDLE;
+        self.backend.init()
+    }
+
+    pub fn measurements(&self) -> &[PcrExtend] {
+        &self.measurements
+    }
+
+    fn reg(&self, offset: u64) -> u32 {
+        match offset {
+            CRB_LOC_STATE => LOC_STATE_REG_VALID | if self.loc_granted { LOC_STATE_ASSIGNED } else { 0 },
+            CRB_LOC_STS if self.loc_granted => LOC_STS_GRANTED,
+            CRB_INTF_ID => INTF_ID,
+            CRB_INTF_ID_HIGH => INTF_ID_HIGH,
+            CRB_CTRL_STS => self.ctrl_sts,
+            CRB_CTRL_CANCEL => self.cancel,
+            // Commands complete before the exit returns
+            CRB_CTRL_START => 0,
+            CRB_INT_ENABLE => self.int_enable,
+            CRB_CTRL_CMD_SIZE | CRB_CTRL_RSP_SIZE => CRB_BUFFER_SIZE as u32,
+            CRB_CTRL_CMD_LADDR | CRB_CTRL_RSP_ADDR => (TPM_CRB_BASE + CRB_DATA_BUFFER) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u32) {
+        match offset {
+            CRB_LOC_CTRL if value & LOC_CTRL_REQUEST != 0 => self.loc_granted = true,
+            CRB_LOC_CTRL if value & LOC_CTRL_RELINQUISH != 0 => self.loc_granted = false,
+            CRB_CTRL_REQ if value & CTRL_REQ_CMD_READY != 0 => self.ctrl_sts &= !CTRL_STS_IDLE,
+            CRB_CTRL_REQ if value & CTRL_REQ_GO_IDLE != 0 => self.ctrl_sts |= CTRL_STS_IDLE,
+            CRB_CTRL_CANCEL => {
+                self.cancel = value & 1;
+                if self.cancel != 0 {
+                    if let Err(e) = self.backend.cancel() {
+                        warn!("TPM cancel failed: {e}");
+                    }
+                }
+            }
+            CRB_CTRL_START if value & 1 != 0 => self.start(),
+            CRB_INT_ENABLE => self.int_enable = value,
+            _ => debug!("TPM CRB write ignored at +0x{offset:x}: 0x{value:x}"),
+        }
+    }
+
+    /// Run the command in the buffer, the response replaces it
+    fn start(&mut self) {
+        if self.ctrl_sts & CTRL_STS_IDLE != 0 || !self.loc_granted {
+            warn!("TPM command started while idle or without the locality, ignored");
+            return;
+        }
+        let size = u32::from_be_bytes(self.buffer[2..6].try_into().unwrap()) as usize;
+        let command = &self.buffer[..size.clamp(TPM_HEADER_SIZE, CRB_BUFFER_SIZE)];
+        let code = command_code(command).unwrap_or(0);
+        debug!("TPM command 0x{code:x}, {} bytes", command.len());
+        let extend = match code {
+            TPM_CC_PCR_EXTEND => parse_pcr_extend(command).or_else(|| {
+                warn!("Malformed TPM2_PCR_Extend");
+                None
+            }),
+            _ => None,
+        };
+        let response = match self.backend.execute(0, command) {
+            Ok(response) => response,
+            Err(e) => {
+                error!("TPM backend failed command 0x{code:x}: {e}");
+                self.ctrl_sts |= CTRL_STS_FATAL;
+                tpm_response(TPM_RC_FAILURE, &[])
+            }
+        };
+        // Only extends the TPM did are measurements
+        let rc = response.get(6..TPM_HEADER_SIZE).map(|rc| u32::from_be_bytes(rc.try_into().unwrap()));
+        if let Some(extend) = extend {
+            if rc == Some(TPM_RC_SUCCESS) {
+                info!("TPM {extend}");
+                self.measurements.push(extend);
+            } else {
+                warn!("TPM refused {extend}: {rc:x?}");
+            }
+        }
+        if response.len() > CRB_BUFFER_SIZE {
+            error!("TPM response of {} bytes truncated", response.len());
+        }
+        let len = response.len().min(CRB_BUFFER_SIZE);
+        self.buffer[..len].copy_from_slice(&response[..len]);
+        self.cancel = 0;
+    }
+}
+
+impl BusDevice for TpmCrb {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            let at = offset + i as u64;
+            *byte = if at >= CRB_DATA_BUFFER {
+                self.buffer[(at - CRB_DATA_BUFFER) as usize]
+            } else {
+                self.reg(at & !3).to_le_bytes()[(at & 3) as usize]
+            };
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if offset >= CRB_DATA_BUFFER {
+            let start = (offset - CRB_DATA_BUFFER) as usize;
+            let end = (start + data.len()).min(CRB_BUFFER_SIZE);
+            self.buffer[start..end].copy_from_slice(&data[..end - start]);
+            return;
+        }
+        // Registers are 32 bit, narrower writes land in the low bytes
+        let mut value = [0; 4];
+        let len = data.len().min(4);
+        value[..len].copy_from_slice(&data[..len]);
+        self.write_reg(offset & !3, u32::from_le_bytes(value) << (8 * (offset & 3)));
+    }
+}
+
+impl Snapshot for TpmCrb {
+    fn snapshot_id(&self) -> String {
+        "tpm-crb".to_string()
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        state.put_u8(self.loc_granted as u8);
+        state.put_u32(self.ctrl_sts);
+        state.put_u32(self.int_enable);
+        state.put_bytes(&self.buffer);
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.loc_granted = state.get_u8()? != 0;
+        self.ctrl_sts = state.get_u32()?;
+        self.int_enable = state.get_u32()?;
+        let buffer = state.get_bytes()?;
+        if buffer.len() != CRB_BUFFER_SIZE {
+            return Err(snapshot::SnapshotError::Malformed);
+        }
+        self.buffer.copy_from_slice(buffer);
+        self.cancel = 0;
+        Ok(())
+    }
+}
+
+impl Vm {
+    /// Plug the CRB and power the TPM on, after fw_cfg which gets its config
+    pub(super) fn add_tpm(&mut self, backend: Box<dyn TpmBackend>) -> Result<()> {
+        let io_error = |e: io::Error| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO));
+        let mut crb = TpmCrb::new(backend);
+        crb.power_on().map_err(|e| {
+            error!("TPM power on failed: {e}");
+            io_error(e)
+        })?;
+        let crb = Arc::new(Mutex::new(crb));
+        self.mmio_bus.insert(TPM_CRB_BASE, TPM_CRB_SIZE, crb.clone()).map_err(|e| {
+            error!("TPM CRB: {e}");
+            kvm_ioctls::Error::new(libc::EEXIST)
+        })?;
+        let mut config = 0u32.to_le_bytes().to_vec();
+        config.extend([TPM_VERSION_2, 0]);
+        self.add_fw_cfg_file(TPM_CONFIG_FILE, config)?;
+        info!("TPM 2.0 CRB at 0x{TPM_CRB_BASE:x}");
+        self.tpm = Some(crb);
+        Ok(())
+    }
+
+    /// PCR extends of the guest since power on
+    pub fn tpm_measurements(&self) -> Option<Vec<PcrExtend>> {
+        self.tpm.as_ref().map(|tpm| tpm.lock().unwrap().measurements().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ os::unix::net::UnixListener, thread };
+
+    use super::*;
+
+    fn command(code: u32, params: &[u8]) -> Vec<u8> {
+        let mut command = TPM_ST_NO_SESSIONS.to_be_bytes().to_vec();
+        command.extend_from_slice(&((TPM_HEADER_SIZE + params.len()) as u32).to_be_bytes());
+        command.extend_from_slice(&code.to_be_bytes());
+        command.extend_from_slice(params);
+        command
+    }
+
+    fn rc(response: &[u8]) -> u32 {
+        u32::from_be_bytes(response[6..10].try_into().unwrap())
+    }
+
+    /// Locality, command ready, then the command through the buffer
+    fn run(crb: &mut TpmCrb, command: &[u8]) -> Vec<u8> {
+        crb.write(CRB_LOC_CTRL, &LOC_CTRL_REQUEST.to_le_bytes());
+        crb.write(CRB_CTRL_REQ, &CTRL_REQ_CMD_READY.to_le_bytes());
+        crb.write(CRB_DATA_BUFFER, command);
+        crb.write(CRB_CTRL_START, &1u32.to_le_bytes());
+        let mut header = [0; TPM_HEADER_SIZE];
+        crb.read(CRB_DATA_BUFFER, &mut header);
+        let mut response = vec![0; u32::from_be_bytes(header[2..6].try_into().unwrap()) as usize];
+        crb.read(CRB_DATA_BUFFER, &mut response);
+        response
+    }
+
+    #[test]
+    fn crb_registers() {
+        let mut crb = TpmCrb::new(Box::new(StandInTpm::default()));
+        let mut id = [0; 8];
+        crb.read(CRB_INTF_ID, &mut id);
+        assert_eq!(u64::from_le_bytes(id), (INTF_ID_HIGH as u64) << 32 | INTF_ID as u64);
+        let mut sts = [0; 4];
+        crb.read(CRB_LOC_STS, &mut sts);
+        assert_eq!(sts, [0; 4]);
+        crb.write(CRB_LOC_CTRL, &[LOC_CTRL_REQUEST as u8]);
+        crb.read(CRB_LOC_STS, &mut sts);
+        assert_eq!(u32::from_le_bytes(sts), LOC_STS_GRANTED);
+        crb.read(CRB_CTRL_CMD_LADDR, &mut sts);
+        assert_eq!(u32::from_le_bytes(sts) as u64, TPM_CRB_BASE + CRB_DATA_BUFFER);
+    }
+
+    #[test]
+    fn commands_through_the_buffer() {
+        let mut crb = TpmCrb::new(Box::new(StandInTpm::default()));
+        crb.power_on().unwrap();
+        assert_eq!(rc(&run(&mut crb, &command(TPM_CC_SELF_TEST, &[1]))), TPM_RC_INITIALIZE);
+        assert_eq!(rc(&run(&mut crb, &command(TPM_CC_STARTUP, &[0, 0]))), TPM_RC_SUCCESS);
+        let random = run(&mut crb, &command(TPM_CC_GET_RANDOM, &16u16.to_be_bytes()));
+        assert_eq!((rc(&random), random.len()), (TPM_RC_SUCCESS, TPM_HEADER_SIZE + 2 + 16));
+
+        // PCR 7, an empty password session, one SHA-256 digest
+        let mut params = 7u32.to_be_bytes().to_vec();
+        params.extend(9u32.to_be_bytes());
+        params.extend([0x40, 0, 0, 9, 0, 0, 1, 0, 0]);
+        params.extend(1u32.to_be_bytes());
+        params.extend(0x000bu16.to_be_bytes());
+        params.extend([0xab; 32]);
+        assert_eq!(rc(&run(&mut crb, &command(TPM_CC_PCR_EXTEND, &params))), TPM_RC_SUCCESS);
+        assert_eq!(crb.measurements(), [PcrExtend { pcr: 7, digests: vec![(0x000b, vec![0xab; 32])] }]);
+
+        crb.power_on().unwrap();
+        assert!(crb.measurements().is_empty());
+        assert_eq!(rc(&run(&mut crb, &command(TPM_CC_SELF_TEST, &[1]))), TPM_RC_INITIALIZE);
+        // Refused before TPM2_Startup, not measured
+        assert_eq!(rc(&run(&mut crb, &command(TPM_CC_PCR_EXTEND, &params))), TPM_RC_INITIALIZE);
+        assert!(crb.measurements().is_empty());
+    }
+
+    /// swtpm control channel: SET_DATAFD then INIT, commands echoed back on the data socket
+    #[test]
+    fn swtpm_protocol() {
+        let dir = std::env::temp_dir().join(format!("tpm-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ctrl.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut ctrl, _) = listener.accept().unwrap();
+            let mut cmd = [0; 4];
+            let (len, data) = ctrl.recv_with_fd(&mut cmd).unwrap();
+            assert_eq!((len, u32::from_be_bytes(cmd)), (4, SWTPM_CMD_SET_DATAFD));
+            let mut data = UnixStream::from(std::os::fd::OwnedFd::from(data.unwrap()));
+            ctrl.write_all(&0u32.to_be_bytes()).unwrap();
+            let mut init = [0; 8];
+            ctrl.read_exact(&mut init).unwrap();
+            assert_eq!(u32::from_be_bytes(init[..4].try_into().unwrap()), SWTPM_CMD_INIT);
+            ctrl.write_all(&0u32.to_be_bytes()).unwrap();
+            let mut locality = [0; 5];
+            ctrl.read_exact(&mut locality).unwrap();
+            assert_eq!(locality, [0, 0, 0, 5, 0]);
+            ctrl.write_all(&0u32.to_be_bytes()).unwrap();
+            let mut command = [0; TPM_HEADER_SIZE + 2];
+            data.read_exact(&mut command).unwrap();
+            data.write_all(&tpm_response(TPM_RC_SUCCESS, &command[TPM_HEADER_SIZE..])).unwrap();
+        });
+        let mut swtpm = Swtpm::connect(&path).unwrap();
+        swtpm.init().unwrap();
+        let response = swtpm.execute(0, &command(TPM_CC_STARTUP, &[0, 0])).unwrap();
+        assert_eq!(response, tpm_response(TPM_RC_SUCCESS, &[0, 0]));
+        server.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
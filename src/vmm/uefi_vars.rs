@@ -0,0 +1,485 @@
+//! UEFI authenticated variable store of an OVMF_VARS.fd image.
+//!
+//! The image starts with a firmware volume header, the variable store comes
+//! right after it: a store header then variables one after the other, each a
+//! header, its UTF-16 name and its data, 4 byte aligned, up to free space of
+//! 0xff. OVMF appends a variable on each write and marks the old one deleted,
+//! we only keep the live ones and write them all back packed, as the
+//! firmware's reclaim does. The fault tolerant write blocks after the store
+//! are left alone.
+//!
+//! Only vars files are read: there is no pflash device, OVMF keeps the
+//! variables of a running VM in its RAM emulated store.
+
+use std::{ fmt, fs, ops::Range, path::Path, str::FromStr };
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+
+use super::device_path::{ format_device_path, utf16_string };
+use super::secure_boot::describe_signature_lists;
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum VarStoreError {
+    /// Can't access variable store image: {0}
+    Io(#[from] std::io::Error),
+    /// No firmware volume header, not an OVMF variable store
+    NotFirmwareVolume,
+    /// Not an authenticated variable store
+    NotAuthenticated,
+    /// Variable store header out of the image
+    Truncated,
+    /// Variable store full, {0} bytes missing
+    Full(usize),
+    /// Bad GUID {0}
+    Guid(String),
+    /// Bad signature list {0}: {1}
+    SignatureList(String, String),
+    /// Unknown variable attribute {0}
+    Attribute(String),
+    /// No variable {0}
+    NotFound(String),
+    /// Bad variable data: {0}
+    Data(String),
+}
+
+pub type Result<T> = std::result::Result<T, VarStoreError>;
+
+pub const EFI_VARIABLE_NON_VOLATILE: u32 = 0x01;
+pub const EFI_VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x02;
+pub const EFI_VARIABLE_RUNTIME_ACCESS: u32 = 0x04;
+pub const EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS: u32 = 0x20;
+
+/// Default of new variables
+pub const DEFAULT_ATTRIBUTES: u32 = EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS;
+
+/// Short names of the attribute bits, in bit order
+const ATTRIBUTE_NAMES: [&str; 7] = ["NV", "BS", "RT", "HR", "AW", "AT", "AP"];
+
+const FV_SIGNATURE: &[u8; 4] = b"_FVH";
+const FV_SIGNATURE_OFFSET: usize = 0x28;
+const FV_HEADER_LENGTH_OFFSET: usize = 0x30;
+const STORE_HEADER_SIZE: usize = 28;
+const VARIABLE_HEADER_SIZE: usize = 60;
+const START_ID: u16 = 0x55aa;
+const VAR_ADDED: u8 = 0x3f;
+const VAR_IN_DELETED_TRANSITION: u8 = 0xfe;
+const ERASED: u8 = 0xff;
+
+const LOAD_OPTION_ACTIVE: u32 = 0x01;
+const LOAD_OPTION_FORCE_RECONNECT: u32 = 0x02;
+const LOAD_OPTION_HIDDEN: u32 = 0x08;
+const LOAD_OPTION_CATEGORY_APP: u32 = 0x100;
+/// Before the description of an EFI_LOAD_OPTION: attributes and path length
+const LOAD_OPTION_HEADER_SIZE: usize = 6;
+
+/// A GUID as laid out in memory: the first three fields little endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let (a, b, c, d) = (data1.to_le_bytes(), data2.to_le_bytes(), data3.to_le_bytes(), data4);
+        Guid([a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]])
+    }
+
+    /// The first 16 bytes of `bytes`
+    pub fn read(bytes: &[u8]) -> Self {
+        Guid(bytes[..16].try_into().unwrap())
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            u32::from_le_bytes(g[0..4].try_into().unwrap()),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            g[8],
+            g[9]
+        )?;
+        g[10..].iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+impl FromStr for Guid {
+    type Err = VarStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bad = || VarStoreError::Guid(s.to_string());
+        let groups: Vec<&str> = s.split('-').collect();
+        let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+        if lengths != [8, 4, 4, 4, 12] {
+            return Err(bad());
+        }
+        let data1 = u32::from_str_radix(groups[0], 16).map_err(|_| bad())?;
+        let data2 = u16::from_str_radix(groups[1], 16).map_err(|_| bad())?;
+        let data3 = u16::from_str_radix(groups[2], 16).map_err(|_| bad())?;
+        let tail = [groups[3], groups[4]].concat();
+        let mut data4 = [0; 8];
+        for (i, byte) in data4.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(tail.get(i * 2..i * 2 + 2).ok_or_else(bad)?, 16).map_err(|_| bad())?;
+        }
+        Ok(Guid::new(data1, data2, data3, data4))
+    }
+}
+
+/// Store header signature of authenticated variable stores
+pub const EFI_AUTHENTICATED_VARIABLE_GUID: Guid =
+    Guid::new(0xaaf32c78, 0x947b, 0x439a, [0xa1, 0x80, 0x2e, 0x14, 0x4e, 0xc3, 0x77, 0x92]);
+/// Vendor of the variables the UEFI spec defines, PK and KEK among them
+pub const EFI_GLOBAL_VARIABLE: Guid =
+    Guid::new(0x8be4df61, 0x93ca, 0x11d2, [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c]);
+/// Vendor of db and dbx
+pub const EFI_IMAGE_SECURITY_DATABASE_GUID: Guid =
+    Guid::new(0xd719b2cb, 0x3d3a, 0x4596, [0xa3, 0xbc, 0xda, 0xd0, 0x0e, 0x67, 0x65, 0x6f]);
+
+/// Attribute bits by their short names, like `NV,BS,RT`
+pub fn format_attributes(attributes: u32) -> String {
+    let names: Vec<&str> = ATTRIBUTE_NAMES
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| attributes & 1 << bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    names.join(",")
+}
+
+/// Attributes from their short names, comma separated, or a number
+pub fn parse_attributes(text: &str) -> Result<u32> {
+    if let Some(hex) = text.strip_prefix("0x") {
+        return u32::from_str_radix(hex, 16).map_err(|_| VarStoreError::Attribute(text.to_string()));
+    }
+    text.split(',').filter(|name| !name.is_empty()).try_fold(0, |attributes, name| {
+        match ATTRIBUTE_NAMES.iter().position(|known| known.eq_ignore_ascii_case(name.trim())) {
+            Some(bit) => Ok(attributes | 1 << bit),
+            None => Err(VarStoreError::Attribute(name.to_string())),
+        }
+    })
+}
+
+/// `Boot####` and the other numbered options of the boot manager
+fn is_load_option(name: &str) -> bool {
+    ["Boot", "Driver", "SysPrep", "PlatformRecovery"].iter().any(|prefix| {
+        name.strip_prefix(prefix).is_some_and(|number| number.len() == 4 && number.chars().all(|c| c.is_ascii_hexdigit()))
+    })
+}
+
+/// Description, device path and flags of an EFI_LOAD_OPTION
+fn format_load_option(data: &[u8]) -> Option<String> {
+    let attributes = u32::from_le_bytes(data.get(0..4)?.try_into().unwrap());
+    let path_length = u16::from_le_bytes(data.get(4..6)?.try_into().unwrap()) as usize;
+    let (description, description_size) = utf16_string(data.get(LOAD_OPTION_HEADER_SIZE..)?);
+    let path_start = LOAD_OPTION_HEADER_SIZE + description_size;
+    let path = data.get(path_start..path_start + path_length)?;
+    let flags: Vec<&str> = [
+        (LOAD_OPTION_ACTIVE, "active"),
+        (LOAD_OPTION_FORCE_RECONNECT, "reconnect"),
+        (LOAD_OPTION_HIDDEN, "hidden"),
+        (LOAD_OPTION_CATEGORY_APP, "app"),
+    ]
+    .into_iter()
+    .filter(|(bit, _)| attributes & bit != 0)
+    .map(|(_, flag)| flag)
+    .collect();
+    let mut out = format!("\"{description}\" {} [{}]", format_device_path(path), flags.join(","));
+    let optional = data.len() - path_start - path_length;
+    if optional != 0 {
+        out.push_str(&format!(" +{optional} bytes"));
+    }
+    Some(out)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub guid: Guid,
+    pub name: String,
+    pub attributes: u32,
+    pub monotonic_count: u64,
+    /// EFI_TIME of the last time based authenticated write
+    pub timestamp: [u8; 16],
+    pub pubkey_index: u32,
+    pub data: Vec<u8>,
+}
+
+impl Variable {
+    pub fn new(guid: Guid, name: &str, attributes: u32, data: Vec<u8>) -> Self {
+        Variable {
+            guid,
+            name: name.to_string(),
+            attributes,
+            monotonic_count: 0,
+            timestamp: [0; 16],
+            pubkey_index: 0,
+            data,
+        }
+    }
+
+    /// The value of the variables the spec defines, decoded
+    pub fn describe(&self) -> Option<String> {
+        let data = &self.data;
+        let option_numbers = || {
+            let numbers: Vec<String> =
+                data.chunks_exact(2).map(|c| format!("{:04X}", u16::from_le_bytes([c[0], c[1]]))).collect();
+            numbers.join(",")
+        };
+        if self.guid == EFI_IMAGE_SECURITY_DATABASE_GUID {
+            return matches!(self.name.as_str(), "db" | "dbx" | "dbt" | "dbr").then(|| describe_signature_lists(data));
+        }
+        if self.guid != EFI_GLOBAL_VARIABLE {
+            return None;
+        }
+        match self.name.as_str() {
+            "BootOrder" | "DriverOrder" | "SysPrepOrder" | "BootCurrent" | "BootNext" => Some(option_numbers()),
+            "Timeout" => Some(format!("{} s", u16::from_le_bytes(data.get(..2)?.try_into().unwrap()))),
+            "Lang" | "PlatformLang" | "LangCodes" | "PlatformLangCodes" => {
+                Some(format!("\"{}\"", String::from_utf8_lossy(data).trim_end_matches('\0')))
+            }
+            "SecureBoot" | "SetupMode" | "AuditMode" | "DeployedMode" | "VendorKeys" => Some(data.first()?.to_string()),
+            "ConIn" | "ConOut" | "ErrOut" | "ConInDev" | "ConOutDev" | "ErrOutDev" => Some(format_device_path(data)),
+            "PK" | "KEK" | "PKDefault" | "KEKDefault" | "dbDefault" | "dbxDefault" => {
+                Some(describe_signature_lists(data))
+            }
+            name if is_load_option(name) => format_load_option(data),
+            _ => None,
+        }
+    }
+
+    /// Header, NUL terminated UTF-16 name then data
+    fn encode(&self) -> Vec<u8> {
+        let mut name: Vec<u8> = self.name.encode_utf16().chain([0]).flat_map(u16::to_le_bytes).collect();
+        let mut out = START_ID.to_le_bytes().to_vec();
+        out.extend([VAR_ADDED, 0]);
+        out.extend(self.attributes.to_le_bytes());
+        out.extend(self.monotonic_count.to_le_bytes());
+        out.extend(self.timestamp);
+        out.extend(self.pubkey_index.to_le_bytes());
+        out.extend((name.len() as u32).to_le_bytes());
+        out.extend((self.data.len() as u32).to_le_bytes());
+        out.extend(self.guid.0);
+        out.append(&mut name);
+        out.extend(&self.data);
+        out
+    }
+}
+
+/// Variable at `at` and where the next one may start, None at the end of the store
+fn decode_variable(store: &[u8], at: usize) -> Option<(Option<Variable>, usize)> {
+    let header = store.get(at..at + VARIABLE_HEADER_SIZE)?;
+    if u16::from_le_bytes([header[0], header[1]]) != START_ID {
+        return None;
+    }
+    let state = header[2];
+    let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let name_size = u32_at(36) as usize;
+    let data_size = u32_at(40) as usize;
+    let name_start = at + VARIABLE_HEADER_SIZE;
+    let data_start = name_start + name_size;
+    let end = data_start.checked_add(data_size).filter(|&end| end <= store.len())?;
+    // Deleted ones and those whose write didn't complete are skipped
+    let live = state == VAR_ADDED || state == VAR_ADDED & VAR_IN_DELETED_TRANSITION;
+    let variable = live.then(|| {
+        let name: Vec<u16> =
+            store[name_start..data_start].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        Variable {
+            guid: Guid::read(&header[44..60]),
+            name: String::from_utf16_lossy(&name).trim_end_matches('\0').to_string(),
+            attributes: u32_at(4),
+            monotonic_count: u64::from_le_bytes(header[8..16].try_into().unwrap()),
+            timestamp: header[16..32].try_into().unwrap(),
+            pubkey_index: u32_at(32),
+            data: store[data_start..end].to_vec(),
+        }
+    });
+    Some((variable, end.next_multiple_of(4)))
+}
+
+/// A variable store image, edited in memory
+#[derive(Debug)]
+pub struct VarStore {
+    image: Vec<u8>,
+    /// The store in the image, its header included
+    store: Range<usize>,
+    variables: Vec<Variable>,
+}
+
+impl VarStore {
+    pub fn parse(image: Vec<u8>) -> Result<Self> {
+        if image.get(FV_SIGNATURE_OFFSET..FV_SIGNATURE_OFFSET + 4) != Some(FV_SIGNATURE) {
+            return Err(VarStoreError::NotFirmwareVolume);
+        }
+        let header_length = image.get(FV_HEADER_LENGTH_OFFSET..FV_HEADER_LENGTH_OFFSET + 2).ok_or(VarStoreError::Truncated)?;
+        let start = u16::from_le_bytes(header_length.try_into().unwrap()) as usize;
+        let header = image.get(start..start + STORE_HEADER_SIZE).ok_or(VarStoreError::Truncated)?;
+        if Guid::read(header) != EFI_AUTHENTICATED_VARIABLE_GUID {
+            return Err(VarStoreError::NotAuthenticated);
+        }
+        let size = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+        if size < STORE_HEADER_SIZE || start + size > image.len() {
+            return Err(VarStoreError::Truncated);
+        }
+        let mut vars = VarStore { image, store: start..start + size, variables: vec![] };
+        let mut at = STORE_HEADER_SIZE;
+        while let Some((variable, next)) = decode_variable(&vars.image[vars.store.clone()], at) {
+            // A copy in deleted transition comes before the one replacing it
+            if let Some(variable) = variable {
+                vars.set(variable);
+            }
+            at = next;
+        }
+        debug!("Variable store of {size} bytes, {} variables", vars.variables.len());
+        Ok(vars)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(fs::read(path)?)
+    }
+
+    pub fn variables(&self) -> &[Variable] {
+        &self.variables
+    }
+
+    pub fn get(&self, guid: Guid, name: &str) -> Option<&Variable> {
+        self.variables.iter().find(|variable| variable.guid == guid && variable.name == name)
+    }
+
+    /// Add `variable` or replace the one with its GUID and name
+    pub fn set(&mut self, variable: Variable) {
+        match self.variables.iter_mut().find(|v| v.guid == variable.guid && v.name == variable.name) {
+            Some(old) => *old = variable,
+            None => self.variables.push(variable),
+        }
+    }
+
+    /// Returns whether there was such a variable
+    pub fn delete(&mut self, guid: Guid, name: &str) -> bool {
+        let count = self.variables.len();
+        self.variables.retain(|variable| variable.guid != guid || variable.name != name);
+        self.variables.len() != count
+    }
+
+    /// The image with the variables packed after the store header
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut packed = vec![];
+        for variable in &self.variables {
+            packed.resize(packed.len().next_multiple_of(4), ERASED);
+            packed.extend(variable.encode());
+        }
+        let room = self.store.len() - STORE_HEADER_SIZE;
+        if packed.len() > room {
+            return Err(VarStoreError::Full(packed.len() - room));
+        }
+        packed.resize(room, ERASED);
+        let mut image = self.image.clone();
+        image[self.store.start + STORE_HEADER_SIZE..self.store.end].copy_from_slice(&packed);
+        Ok(image)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_bytes()?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Empty store of `size` bytes behind an OVMF like firmware volume header
+    pub(crate) fn blank_image(size: usize) -> Vec<u8> {
+        let mut image = vec![0; 0x48];
+        image[FV_SIGNATURE_OFFSET..FV_SIGNATURE_OFFSET + 4].copy_from_slice(FV_SIGNATURE);
+        image[FV_HEADER_LENGTH_OFFSET..FV_HEADER_LENGTH_OFFSET + 2].copy_from_slice(&0x48u16.to_le_bytes());
+        image.extend(EFI_AUTHENTICATED_VARIABLE_GUID.0);
+        image.extend((size as u32).to_le_bytes());
+        image.extend([0x5a, 0xfe, 0, 0, 0, 0, 0, 0]);
+        image.resize(0x48 + size, ERASED);
+        // Fault tolerant write blocks
+        image.extend([0xaa; 0x20]);
+        image
+    }
+
+    #[test]
+    fn guid_text() {
+        let text = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+        assert_eq!(text.parse::<Guid>().unwrap(), EFI_GLOBAL_VARIABLE);
+        assert_eq!(EFI_GLOBAL_VARIABLE.to_string(), text);
+        assert_eq!(EFI_GLOBAL_VARIABLE.0[..4], [0x61, 0xdf, 0xe4, 0x8b]);
+        assert!("8be4df61-93ca-11d2-aa0d".parse::<Guid>().is_err());
+    }
+
+    #[test]
+    fn variables_round_trip() {
+        let mut store = VarStore::parse(blank_image(0x400)).unwrap();
+        assert!(store.variables().is_empty());
+        let attributes = EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS;
+        store.set(Variable::new(EFI_GLOBAL_VARIABLE, "Timeout", attributes, vec![5, 0]));
+        store.set(Variable::new(EFI_GLOBAL_VARIABLE, "Lang", attributes, b"eng".to_vec()));
+        store.set(Variable::new(EFI_GLOBAL_VARIABLE, "Timeout", attributes, vec![0, 0]));
+        let image = store.to_bytes().unwrap();
+        assert_eq!(image[image.len() - 0x20..], [0xaa; 0x20]);
+
+        let mut store = VarStore::parse(image).unwrap();
+        let names: Vec<&str> = store.variables().iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["Timeout", "Lang"]);
+        assert_eq!(store.get(EFI_GLOBAL_VARIABLE, "Timeout").unwrap().data, [0, 0]);
+        assert!(store.delete(EFI_GLOBAL_VARIABLE, "Lang"));
+        assert!(!store.delete(EFI_GLOBAL_VARIABLE, "Lang"));
+
+        store.set(Variable::new(EFI_GLOBAL_VARIABLE, "Big", attributes, vec![0; 0x400]));
+        assert!(matches!(store.to_bytes(), Err(VarStoreError::Full(_))));
+    }
+
+    #[test]
+    fn truncated_images_refused() {
+        let image = blank_image(0x200);
+        for len in [FV_SIGNATURE_OFFSET + 4, FV_HEADER_LENGTH_OFFSET + 1, 0x48 + STORE_HEADER_SIZE - 1, 0x1ff] {
+            let truncated = image[..len].to_vec();
+            assert!(matches!(VarStore::parse(truncated), Err(VarStoreError::Truncated)), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn deleted_variables_skipped() {
+        let mut image = blank_image(0x200);
+        let at = 0x48 + STORE_HEADER_SIZE;
+        let old = Variable::new(EFI_GLOBAL_VARIABLE, "Timeout", EFI_VARIABLE_NON_VOLATILE, vec![5, 0]).encode();
+        image[at..at + old.len()].copy_from_slice(&old);
+        // Deleted flag cleared, as the firmware does
+        image[at + 2] &= 0xfd;
+        let new = Variable::new(EFI_GLOBAL_VARIABLE, "Timeout", EFI_VARIABLE_NON_VOLATILE, vec![1, 0]).encode();
+        let next = (at + old.len()).next_multiple_of(4);
+        image[next..next + new.len()].copy_from_slice(&new);
+        let store = VarStore::parse(image).unwrap();
+        assert_eq!(store.variables().len(), 1);
+        assert_eq!(store.variables()[0].data, [1, 0]);
+        assert_eq!(format_attributes(EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS), "NV,AT");
+    }
+
+    #[test]
+    fn well_known_variables_described() {
+        let global = |name: &str, data: &[u8]| Variable::new(EFI_GLOBAL_VARIABLE, name, DEFAULT_ATTRIBUTES, data.to_vec());
+        assert_eq!(global("BootOrder", &[1, 0, 0, 0, 0x0a, 0]).describe().unwrap(), "0001,0000,000A");
+        assert_eq!(global("Timeout", &[5, 0]).describe().unwrap(), "5 s");
+        assert_eq!(global("PlatformLang", b"en\0").describe().unwrap(), "\"en\"");
+        assert_eq!(global("Unknown", &[1]).describe(), None);
+
+        let mut option = (LOAD_OPTION_ACTIVE | LOAD_OPTION_CATEGORY_APP).to_le_bytes().to_vec();
+        let path = crate::vmm::device_path::tests::disk_boot_path();
+        option.extend((path.len() as u16).to_le_bytes());
+        option.extend("UEFI Disk".encode_utf16().chain([0]).flat_map(u16::to_le_bytes));
+        option.extend(&path);
+        option.extend([0xaa; 4]);
+        let described = global("Boot000A", &option).describe().unwrap();
+        assert!(described.starts_with("\"UEFI Disk\" PciRoot(0x0)/Pci(0x1F,0x2)/"), "{described}");
+        assert!(described.ends_with("\\EFI\\BOOT\\BOOTX64.EFI [active,app] +4 bytes"), "{described}");
+        assert_eq!(global("BootXYZW", &option).describe(), None);
+
+        assert_eq!(parse_attributes("NV,bs,RT").unwrap(), DEFAULT_ATTRIBUTES);
+        assert_eq!(parse_attributes("0x27").unwrap(), 0x27);
+        assert!(matches!(parse_attributes("NV,XX"), Err(VarStoreError::Attribute(_))));
+    }
+}
@@ -0,0 +1,100 @@
+//! vCPU state at reset, what real hardware or QEMU give firmware and kernels.
+//!
+//! Runs once per vCPU after its CPUID is set, whatever the boot path.
+
+use kvm_bindings::{ kvm_fpu, kvm_lapic_state, kvm_msr_entry, Msrs };
+use kvm_ioctls::VcpuFd;
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+
+use super::msr::{ MSR_IA32_SYSENTER_CS, MSR_IA32_SYSENTER_EIP, MSR_IA32_SYSENTER_ESP };
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+const MSR_IA32_TSC: u32 = 0x10;
+const MSR_IA32_MISC_ENABLE: u32 = 0x1a0;
+const MSR_IA32_CR_PAT: u32 = 0x277;
+
+/// Fast strings, no BTS, no PEBS
+const MISC_ENABLE_DEFAULT: u64 = 1 << 0 | 1 << 11 | 1 << 12;
+/// WB, WT, UC-, UC, repeated for PAT4-7
+const PAT_DEFAULT: u64 = 0x0007_0406_0007_0406;
+
+const FPU_FCW_DEFAULT: u16 = 0x37f;
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+const APIC_LVT_LINT0: usize = 0x350;
+const APIC_LVT_LINT1: usize = 0x360;
+const APIC_LVT_MASKED: u32 = 1 << 16;
+const APIC_MODE_NMI: u32 = 0x4;
+const APIC_MODE_EXTINT: u32 = 0x7;
+
+fn set_lapic_reg(lapic: &mut kvm_lapic_state, reg: usize, value: u32) {
+    for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+        lapic.regs[reg + i] = byte as _;
+    }
+}
+
+/// Like the MP spec virtual wire mode: PIC interrupts through the BSP LINT0, NMIs on LINT1
+fn set_lint(lapic: &mut kvm_lapic_state, bsp: bool) {
+    let lint0 = if bsp { APIC_MODE_EXTINT << 8 } else { APIC_LVT_MASKED };
+    set_lapic_reg(lapic, APIC_LVT_LINT0, lint0);
+    set_lapic_reg(lapic, APIC_LVT_LINT1, APIC_MODE_NMI << 8);
+}
+
+/// Reset MSRs, FPU and LAPIC of a vCPU, `bsp` gets the legacy PIC wired to its LINT0
+pub fn init_vcpu(vcpu: &VcpuFd, bsp: bool) -> Result<()> {
+    let entries = [
+        (MSR_IA32_TSC, 0),
+        (MSR_IA32_SYSENTER_CS, 0),
+        (MSR_IA32_SYSENTER_ESP, 0),
+        (MSR_IA32_SYSENTER_EIP, 0),
+        (MSR_IA32_MISC_ENABLE, MISC_ENABLE_DEFAULT),
+        (MSR_IA32_CR_PAT, PAT_DEFAULT),
+    ].map(|(index, data)| kvm_msr_entry { index, data, ..Default::default() });
+    let msrs = Msrs::from_entries(&entries).map_err(|_| kvm_ioctls::Error::new(libc::E2BIG))?;
+    let written = vcpu.set_msrs(&msrs)?;
+    if written != entries.len() {
+        warn!("Only {written}/{} reset MSRs set, stopped at 0x{:x}", entries.len(), entries[written].index);
+    }
+
+    let fpu = kvm_fpu { fcw: FPU_FCW_DEFAULT, mxcsr: MXCSR_DEFAULT, ..Default::default() };
+    vcpu.set_fpu(&fpu)?;
+
+    let mut lapic = vcpu.get_lapic()?;
+    set_lint(&mut lapic, bsp);
+    vcpu.set_lapic(&lapic)?;
+    debug!("vCPU reset state set, bsp={bsp}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lapic_reg(lapic: &kvm_lapic_state, reg: usize) -> u32 {
+        u32::from_le_bytes([0, 1, 2, 3].map(|i| lapic.regs[reg + i] as u8))
+    }
+
+    #[test]
+    fn virtual_wire_lints() {
+        let mut lapic = kvm_lapic_state::default();
+        // Other registers are left alone
+        set_lapic_reg(&mut lapic, 0x370, 0xdead_beef);
+        set_lint(&mut lapic, true);
+        assert_eq!(lapic_reg(&lapic, APIC_LVT_LINT0), 0x700);
+        assert_eq!(lapic_reg(&lapic, APIC_LVT_LINT1), 0x400);
+        assert_eq!(lapic_reg(&lapic, 0x370), 0xdead_beef);
+
+        set_lint(&mut lapic, false);
+        assert_eq!(lapic_reg(&lapic, APIC_LVT_LINT0), APIC_LVT_MASKED);
+        assert_eq!(lapic_reg(&lapic, APIC_LVT_LINT1), 0x400);
+    }
+
+    #[test]
+    fn pat_power_on_value() {
+        // PAT0-7 as the SDM lists them after reset
+        let types: Vec<u8> = PAT_DEFAULT.to_le_bytes().to_vec();
+        assert_eq!(types, [6, 4, 7, 0, 6, 4, 7, 0]);
+    }
+}
@@ -0,0 +1,675 @@
+//! Virtio 1.x transports, modern virtio-pci and virtio-mmio, over the `virtio-queue` crate.
+//!
+//! Device models implement [`VirtioDevice`] and only see their queues. Queue
+//! notifications land on ioeventfds and the event loop thread runs the device,
+//! then raises its interrupt: an MSI-X vector on PCI, an irqfd line on MMIO.
+//! Linux finds MMIO devices through `virtio_mmio.device=512@<base>:<gsi>`.
+
+use std::{ os::fd::{ AsRawFd, RawFd }, sync::{ Arc, Mutex } };
+
+use kvm_ioctls::IoEventAddress;
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use serde::Deserialize;
+use virtio_queue::{ Queue, QueueState, QueueT };
+use vm_memory::{ bitmap::AtomicBitmap, GuestMemoryMmap };
+use vmm_sys_util::eventfd::{ EventFd, EFD_NONBLOCK };
+
+use super::{
+    bus::BusDevice,
+    event_loop::EventHandler,
+    irq::{ IrqFd, MsiSender },
+    msix::Msix,
+    pci::{ BarKind, PciBar, PciConfig, PciDevice },
+    snapshot::{ self, Snapshot, StateBuf, StateReader },
+    Vm,
+};
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+pub type GuestMem = GuestMemoryMmap<AtomicBitmap>;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+
+const INTERRUPT_USED_BUFFER: u8 = 1 << 0;
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+/// Modern device ids are 0x1040 + the virtio device type
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+/// Layout of BAR 0
+const COMMON_CFG: u64 = 0x0000;
+const COMMON_CFG_SIZE: u64 = 0x38;
+const ISR_CFG: u64 = 0x1000;
+const DEVICE_CFG: u64 = 0x2000;
+const DEVICE_CFG_SIZE: u64 = 0x1000;
+const NOTIFY_CFG: u64 = 0x3000;
+const NOTIFY_OFF_MULTIPLIER: u64 = 4;
+const MSIX_TABLE: u64 = 0x4000;
+const MSIX_PBA: u64 = 0x5000;
+const BAR_SIZE: u64 = 0x8000;
+
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+pub const VIRTIO_MMIO_BASE: u64 = 0xfeb0_0000;
+pub const VIRTIO_MMIO_SIZE: u64 = 0x200;
+/// First GSI of the MMIO devices, past the legacy ISA ones
+pub const VIRTIO_MMIO_GSI_BASE: u32 = 16;
+/// Limit of the mmio transport, the builder refuses more virtio devices: each
+/// has a GSI of its own from 16 to 19, the HPET comparators having the IOAPIC
+/// pins above
+pub const VIRTIO_MMIO_MAX_DEVICES: usize = 4;
+
+const MMIO_MAGIC: u32 = 0x7472_6976;
+const MMIO_VERSION: u32 = 2;
+const MMIO_VENDOR_ID: u32 = 0x554d_4551;
+const MMIO_CONFIG: u64 = 0x100;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VirtioTransport {
+    #[default]
+    Pci,
+    /// At most [`VIRTIO_MMIO_MAX_DEVICES`] devices
+    Mmio,
+}
+
+/// Device side of a virtio device, transport agnostic
+pub trait VirtioDevice: Send {
+    /// Virtio device type, 2 for block
+    fn device_type(&self) -> u32;
+    /// Device feature bits, the transport adds VIRTIO_F_VERSION_1
+    fn features(&self) -> u64;
+    fn queue_max_sizes(&self) -> Vec<u16>;
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+    /// The driver set DRIVER_OK with the `features` it acked
+    fn activate(&mut self, _features: u64) {}
+    /// Handle the available buffers of queue `idx`, returns whether any was used
+    fn process_queue(&mut self, idx: usize, queue: &mut Queue, mem: &GuestMem) -> bool;
+    fn reset(&mut self) {}
+}
+
+/// Device status, feature negotiation and queues, common to both transports
+struct VirtioCommon {
+    device: Box<dyn VirtioDevice>,
+    mem: GuestMem,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    status: u8,
+    queue_sel: u16,
+    queues: Vec<Queue>,
+    config_generation: u8,
+    /// Interrupt status, read to clear on PCI and acked on MMIO
+    isr: u8,
+}
+
+fn feature_word(features: u64, sel: u32) -> u32 {
+    match sel {
+        0 => features as u32,
+        1 => (features >> 32) as u32,
+        _ => 0,
+    }
+}
+
+/// Set the low or high half of `value`
+fn set_half(value: u64, high: bool, half: u32) -> u64 {
+    if high {
+        (value & 0xffff_ffff) | (half as u64) << 32
+    } else {
+        (value & !0xffff_ffff) | half as u64
+    }
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    let len = data.len().min(4);
+    bytes[..len].copy_from_slice(&data[..len]);
+    u32::from_le_bytes(bytes)
+}
+
+fn write_value(data: &mut [u8], value: u64) {
+    let len = data.len().min(8);
+    data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+}
+
+impl VirtioCommon {
+    fn new(device: Box<dyn VirtioDevice>, mem: GuestMem) -> Self {
+        let queues = device
+            .queue_max_sizes()
+            .into_iter()
+            .map(|max| Queue::new(max).expect("Bad virtio queue size"))
+            .collect();
+        Self {
+            device,
+            mem,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            status: 0,
+            queue_sel: 0,
+            queues,
+            config_generation: 0,
+            isr: 0,
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn set_driver_features(&mut self, value: u32) {
+        if self.status & STATUS_FEATURES_OK != 0 || self.driver_features_sel > 1 {
+            return;
+        }
+        let features = set_half(self.driver_features, self.driver_features_sel == 1, value);
+        self.driver_features = features & self.device_features();
+    }
+
+    fn queue(&self) -> Option<&Queue> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    fn queue_mut(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn set_status(&mut self, mut status: u8) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+        // Legacy drivers are not supported
+        if status & STATUS_FEATURES_OK != 0 && self.driver_features & VIRTIO_F_VERSION_1 == 0 {
+            warn!("virtio driver did not ack VIRTIO_F_VERSION_1");
+            status &= !STATUS_FEATURES_OK;
+        }
+        let activate = status & STATUS_DRIVER_OK != 0 && self.status & STATUS_DRIVER_OK == 0;
+        self.status = status;
+        if activate {
+            info!("virtio device type {} ready, features 0x{:x}", self.device.device_type(), self.driver_features);
+            self.device.activate(self.driver_features);
+        }
+    }
+
+    fn reset(&mut self) {
+        debug!("virtio device type {} reset", self.device.device_type());
+        self.device.reset();
+        self.queues.iter_mut().for_each(|queue| queue.reset());
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.queue_sel = 0;
+        self.isr = 0;
+    }
+
+    /// Run queue `idx`, returns whether the driver wants an interrupt
+    fn process(&mut self, idx: usize) -> bool {
+        if self.status & STATUS_DRIVER_OK == 0 {
+            return false;
+        }
+        let Some(queue) = self.queues.get_mut(idx) else {
+            return false;
+        };
+        if !queue.ready() || !self.device.process_queue(idx, queue, &self.mem) {
+            return false;
+        }
+        self.isr |= INTERRUPT_USED_BUFFER;
+        queue.needs_notification(&self.mem).unwrap_or(true)
+    }
+
+    fn save(&self, state: &mut StateBuf) {
+        state.put_u32(self.device_features_sel);
+        state.put_u32(self.driver_features_sel);
+        state.put_u64(self.driver_features);
+        state.put_u8(self.status);
+        state.put_u16(self.queue_sel);
+        state.put_u8(self.config_generation);
+        state.put_u8(self.isr);
+        state.put_u32(self.queues.len() as u32);
+        for queue in &self.queues {
+            let q = queue.state();
+            state.put_u16(q.max_size);
+            state.put_u16(q.next_avail);
+            state.put_u16(q.next_used);
+            state.put_u8(q.event_idx_enabled as u8);
+            state.put_u16(q.size);
+            state.put_u8(q.ready as u8);
+            state.put_u64(q.desc_table);
+            state.put_u64(q.avail_ring);
+            state.put_u64(q.used_ring);
+        }
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.device_features_sel = state.get_u32()?;
+        self.driver_features_sel = state.get_u32()?;
+        self.driver_features = state.get_u64()?;
+        self.status = state.get_u8()?;
+        self.queue_sel = state.get_u16()?;
+        self.config_generation = state.get_u8()?;
+        self.isr = state.get_u8()?;
+        if state.get_u32()? as usize != self.queues.len() {
+            return Err(snapshot::SnapshotError::Malformed);
+        }
+        for queue in self.queues.iter_mut() {
+            let q = QueueState {
+                max_size: state.get_u16()?,
+                next_avail: state.get_u16()?,
+                next_used: state.get_u16()?,
+                event_idx_enabled: state.get_u8()? != 0,
+                size: state.get_u16()?,
+                ready: state.get_u8()? != 0,
+                desc_table: state.get_u64()?,
+                avail_ring: state.get_u64()?,
+                used_ring: state.get_u64()?,
+            };
+            *queue = Queue::try_from(q).map_err(|_| snapshot::SnapshotError::Malformed)?;
+        }
+        if self.status & STATUS_DRIVER_OK != 0 {
+            self.device.activate(self.driver_features);
+        }
+        Ok(())
+    }
+}
+
+/// Modern virtio-pci function, every structure in BAR 0, interrupts through MSI-X
+pub struct VirtioPci {
+    common: VirtioCommon,
+    config: PciConfig,
+    /// One vector per queue plus the config change one
+    msix: Msix,
+    config_vector: u16,
+    queue_vectors: Vec<u16>,
+    /// Queue notifications, by queue
+    notify: Vec<EventFd>,
+}
+
+/// Class code a driver expects for a device type
+fn pci_class(device_type: u32) -> u32 {
+    match device_type {
+        1 => 0x02_00_00,
+        2 => 0x01_80_00,
+        _ => 0xff_00_00,
+    }
+}
+
+fn virtio_pci_cap(cfg_type: u8, offset: u64, length: u64, extra: &[u8]) -> Vec<u8> {
+    // cap_len counts the id and next bytes the config space adds
+    let mut body = vec![(16 + extra.len()) as u8, cfg_type, 0, 0, 0, 0];
+    body.extend_from_slice(&(offset as u32).to_le_bytes());
+    body.extend_from_slice(&(length as u32).to_le_bytes());
+    body.extend_from_slice(extra);
+    body
+}
+
+impl VirtioPci {
+    pub fn new(device: Box<dyn VirtioDevice>, mem: GuestMem, msi: MsiSender) -> std::io::Result<Self> {
+        let device_type = device.device_type();
+        let common = VirtioCommon::new(device, mem);
+        let queue_count = common.queues.len();
+        let vector_count = queue_count + 1;
+
+        let mut config = PciConfig::new(
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + device_type as u16,
+            pci_class(device_type),
+            1
+        );
+        config.set_subsystem(VIRTIO_PCI_VENDOR_ID, 0x1100);
+        config.add_bar(0, PciBar { size: BAR_SIZE, kind: BarKind::Mem64, prefetchable: false });
+
+        let msix = Msix::new(&mut config, vector_count, 0, MSIX_TABLE, MSIX_PBA, msi);
+        let notify_len = queue_count as u64 * NOTIFY_OFF_MULTIPLIER;
+        for (cfg_type, offset, length, extra) in [
+            (VIRTIO_PCI_CAP_COMMON_CFG, COMMON_CFG, COMMON_CFG_SIZE, vec![]),
+            (VIRTIO_PCI_CAP_NOTIFY_CFG, NOTIFY_CFG, notify_len, (NOTIFY_OFF_MULTIPLIER as u32).to_le_bytes().to_vec()),
+            (VIRTIO_PCI_CAP_ISR_CFG, ISR_CFG, 1, vec![]),
+            (VIRTIO_PCI_CAP_DEVICE_CFG, DEVICE_CFG, DEVICE_CFG_SIZE, vec![]),
+        ] {
+            config.add_capability(PCI_CAP_ID_VENDOR, &virtio_pci_cap(cfg_type, offset, length, &extra), &[]);
+        }
+
+        let notify = (0..queue_count).map(|_| EventFd::new(EFD_NONBLOCK)).collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            common,
+            config,
+            msix,
+            config_vector: VIRTIO_MSI_NO_VECTOR,
+            queue_vectors: vec![VIRTIO_MSI_NO_VECTOR; queue_count],
+            notify,
+        })
+    }
+
+    fn signal(&mut self, vector: u16) {
+        self.msix.signal(&self.config, vector);
+    }
+
+    fn process_queue(&mut self, idx: usize) {
+        if self.common.process(idx) {
+            self.signal(self.queue_vectors[idx]);
+        }
+    }
+
+    fn common_read(&self, offset: u64) -> u32 {
+        let common = &self.common;
+        let queue = common.queue();
+        match offset {
+            0x00 => common.device_features_sel,
+            0x04 => feature_word(common.device_features(), common.device_features_sel),
+            0x08 => common.driver_features_sel,
+            0x0c => feature_word(common.driver_features, common.driver_features_sel),
+            0x10 => self.config_vector as u32,
+            0x12 => common.queues.len() as u32,
+            0x14 => common.status as u32,
+            0x15 => common.config_generation as u32,
+            0x16 => common.queue_sel as u32,
+            0x18 => queue.map_or(0, |q| q.size() as u32),
+            0x1a => self.queue_vectors.get(common.queue_sel as usize).map_or(VIRTIO_MSI_NO_VECTOR, |&v| v) as u32,
+            0x1c => queue.map_or(0, |q| q.ready() as u32),
+            0x1e => common.queue_sel as u32,
+            0x20 => queue.map_or(0, |q| q.desc_table() as u32),
+            0x24 => queue.map_or(0, |q| (q.desc_table() >> 32) as u32),
+            0x28 => queue.map_or(0, |q| q.avail_ring() as u32),
+            0x2c => queue.map_or(0, |q| (q.avail_ring() >> 32) as u32),
+            0x30 => queue.map_or(0, |q| q.used_ring() as u32),
+            0x34 => queue.map_or(0, |q| (q.used_ring() >> 32) as u32),
+            _ => 0,
+        }
+    }
+
+    fn common_write(&mut self, offset: u64, value: u32) {
+        let vector_count = self.msix.vectors() as u32;
+        // Vectors past the table read back as NO_VECTOR, which the driver takes as a refusal
+        let vector = if value < vector_count { value as u16 } else { VIRTIO_MSI_NO_VECTOR };
+        let queue_sel = self.common.queue_sel as usize;
+        match offset {
+            0x00 => self.common.device_features_sel = value,
+            0x08 => self.common.driver_features_sel = value,
+            0x0c => self.common.set_driver_features(value),
+            0x10 => self.config_vector = vector,
+            0x14 => {
+                self.common.set_status(value as u8);
+                if value == 0 {
+                    self.config_vector = VIRTIO_MSI_NO_VECTOR;
+                    self.queue_vectors.fill(VIRTIO_MSI_NO_VECTOR);
+                }
+            }
+            0x16 => self.common.queue_sel = value as u16,
+            0x1a =>
+                if let Some(v) = self.queue_vectors.get_mut(queue_sel) {
+                    *v = vector;
+                }
+            _ => {
+                let Some(queue) = self.common.queue_mut() else {
+                    return;
+                };
+                match offset {
+                    0x18 => queue.set_size(value as u16),
+                    0x1c => queue.set_ready(value == 1),
+                    0x20 => queue.set_desc_table_address(Some(value), None),
+                    0x24 => queue.set_desc_table_address(None, Some(value)),
+                    0x28 => queue.set_avail_ring_address(Some(value), None),
+                    0x2c => queue.set_avail_ring_address(None, Some(value)),
+                    0x30 => queue.set_used_ring_address(Some(value), None),
+                    0x34 => queue.set_used_ring_address(None, Some(value)),
+                    _ => debug!("virtio-pci common config write 0x{offset:x} ignored"),
+                }
+            }
+        }
+    }
+}
+
+impl PciDevice for VirtioPci {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn bar_read(&mut self, _bar: usize, offset: u64, data: &mut [u8]) {
+        let value = match offset {
+            COMMON_CFG..ISR_CFG if data.len() == 8 =>
+                self.common_read(offset) as u64 | (self.common_read(offset + 4) as u64) << 32,
+            COMMON_CFG..ISR_CFG => self.common_read(offset) as u64,
+            ISR_CFG..DEVICE_CFG => std::mem::take(&mut self.common.isr) as u64,
+            DEVICE_CFG..NOTIFY_CFG => {
+                return self.common.device.read_config(offset - DEVICE_CFG, data);
+            }
+            MSIX_TABLE..MSIX_PBA => self.msix.table_read(offset - MSIX_TABLE) as u64,
+            MSIX_PBA..BAR_SIZE if offset == MSIX_PBA => self.msix.pba(),
+            _ => 0,
+        };
+        write_value(data, value);
+    }
+
+    fn bar_write(&mut self, _bar: usize, offset: u64, data: &[u8]) {
+        match offset {
+            COMMON_CFG..ISR_CFG => {
+                self.common_write(offset, read_u32(data));
+                if data.len() == 8 {
+                    self.common_write(offset + 4, read_u32(&data[4..]));
+                }
+            }
+            DEVICE_CFG..NOTIFY_CFG => self.common.device.write_config(offset - DEVICE_CFG, data),
+            // Only reached while the ioeventfds are not registered
+            NOTIFY_CFG..MSIX_TABLE => self.process_queue(((offset - NOTIFY_CFG) / NOTIFY_OFF_MULTIPLIER) as usize),
+            MSIX_TABLE..MSIX_PBA => self.msix.table_write(&self.config, offset - MSIX_TABLE, read_u32(data)),
+            _ => debug!("virtio-pci BAR write 0x{offset:x} ignored"),
+        }
+    }
+
+    fn config_written(&mut self, offset: usize, len: usize) {
+        self.msix.config_written(&self.config, offset, len);
+    }
+
+    fn ioeventfds(&self, bar: usize) -> Vec<(u64, EventFd)> {
+        if bar != 0 {
+            return vec![];
+        }
+        self.notify
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, fd)| Some((NOTIFY_CFG + idx as u64 * NOTIFY_OFF_MULTIPLIER, fd.try_clone().ok()?)))
+            .collect()
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        self.common.save(state);
+        state.put_u16(self.config_vector);
+        for &vector in &self.queue_vectors {
+            state.put_u16(vector);
+        }
+        self.msix.save(state);
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.common.restore(state)?;
+        self.config_vector = state.get_u16()?;
+        for vector in self.queue_vectors.iter_mut() {
+            *vector = state.get_u16()?;
+        }
+        self.msix.restore(state)
+    }
+}
+
+impl EventHandler for VirtioPci {
+    fn handle_event(&mut self, fd: RawFd) {
+        let Some(idx) = self.notify.iter().position(|notify| notify.as_raw_fd() == fd) else {
+            return;
+        };
+        let _ = self.notify[idx].read();
+        self.process_queue(idx);
+    }
+}
+
+/// virtio-mmio version 2 device, one interrupt line for everything
+pub struct VirtioMmio {
+    base: u64,
+    common: VirtioCommon,
+    irq: IrqFd,
+    /// Any queue notification
+    notify: EventFd,
+}
+
+impl std::fmt::Debug for VirtioMmio {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VirtioMmio")
+            .field("base", &self.base)
+            .field("device_type", &self.common.device.device_type())
+            .field("status", &self.common.status)
+            .finish()
+    }
+}
+
+impl VirtioMmio {
+    fn process_queues(&mut self) {
+        let mut interrupt = false;
+        for idx in 0..self.common.queues.len() {
+            interrupt |= self.common.process(idx);
+        }
+        if interrupt {
+            if let Err(e) = self.irq.trigger() {
+                error!("virtio-mmio@{:x} interrupt failed: {e}", self.base);
+            }
+        }
+    }
+
+    fn register_read(&self, offset: u64) -> u32 {
+        let common = &self.common;
+        let queue = common.queue();
+        match offset {
+            0x000 => MMIO_MAGIC,
+            0x004 => MMIO_VERSION,
+            0x008 => common.device.device_type(),
+            0x00c => MMIO_VENDOR_ID,
+            0x010 => feature_word(common.device_features(), common.device_features_sel),
+            0x034 => queue.map_or(0, |q| q.max_size() as u32),
+            0x044 => queue.map_or(0, |q| q.ready() as u32),
+            0x060 => common.isr as u32,
+            0x070 => common.status as u32,
+            0x0fc => common.config_generation as u32,
+            _ => 0,
+        }
+    }
+
+    fn register_write(&mut self, offset: u64, value: u32) {
+        match offset {
+            0x014 => self.common.device_features_sel = value,
+            0x020 => self.common.set_driver_features(value),
+            0x024 => self.common.driver_features_sel = value,
+            0x030 => self.common.queue_sel = value as u16,
+            0x050 => self.process_queues(),
+            0x064 => self.common.isr &= !(value as u8),
+            0x070 => self.common.set_status(value as u8),
+            _ => {
+                let Some(queue) = self.common.queue_mut() else {
+                    return;
+                };
+                match offset {
+                    0x038 => queue.set_size(value as u16),
+                    0x044 => queue.set_ready(value == 1),
+                    0x080 => queue.set_desc_table_address(Some(value), None),
+                    0x084 => queue.set_desc_table_address(None, Some(value)),
+                    0x090 => queue.set_avail_ring_address(Some(value), None),
+                    0x094 => queue.set_avail_ring_address(None, Some(value)),
+                    0x0a0 => queue.set_used_ring_address(Some(value), None),
+                    0x0a4 => queue.set_used_ring_address(None, Some(value)),
+                    _ => debug!("virtio-mmio register write 0x{offset:x} ignored"),
+                }
+            }
+        }
+    }
+}
+
+impl BusDevice for VirtioMmio {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if offset >= MMIO_CONFIG {
+            return self.common.device.read_config(offset - MMIO_CONFIG, data);
+        }
+        write_value(data, self.register_read(offset) as u64);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if offset >= MMIO_CONFIG {
+            return self.common.device.write_config(offset - MMIO_CONFIG, data);
+        }
+        self.register_write(offset, read_u32(data));
+    }
+}
+
+impl EventHandler for VirtioMmio {
+    fn handle_event(&mut self, _fd: RawFd) {
+        let _ = self.notify.read();
+        self.process_queues();
+    }
+}
+
+impl Snapshot for VirtioMmio {
+    fn snapshot_id(&self) -> String {
+        format!("virtio-mmio@{:x}", self.base)
+    }
+
+    fn save_state(&self, state: &mut StateBuf) {
+        self.common.save(state);
+    }
+
+    fn restore_state(&mut self, state: &mut StateReader) -> snapshot::Result<()> {
+        self.common.restore(state)
+    }
+}
+
+fn io_error(e: std::io::Error) -> kvm_ioctls::Error {
+    kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO))
+}
+
+impl Vm {
+    /// Plug `device` as a virtio-pci function in `slot` of bus 0
+    pub fn add_virtio_pci(&mut self, slot: u8, device: Box<dyn VirtioDevice>) -> Result<()> {
+        let transport = VirtioPci::new(device, self.ram.guest_mem_map.clone(), self.msi_sender()?)
+            .map_err(io_error)?;
+        let fds: Vec<RawFd> = transport.notify.iter().map(|fd| fd.as_raw_fd()).collect();
+        let transport = Arc::new(Mutex::new(transport));
+        for fd in fds {
+            self.event_loop.add(fd, transport.clone()).map_err(io_error)?;
+        }
+        self.add_pci_device(slot, transport)
+    }
+
+    /// Plug `device` as a virtio-mmio one raising `gsi`, returns its base address
+    pub fn add_virtio_mmio(&mut self, device: Box<dyn VirtioDevice>, gsi: u32) -> Result<u64> {
+        let idx = self.virtio_mmio.len();
+        if idx >= VIRTIO_MMIO_MAX_DEVICES {
+            return Err(kvm_ioctls::Error::new(libc::ENOSPC));
+        }
+        let base = VIRTIO_MMIO_BASE + idx as u64 * VIRTIO_MMIO_SIZE;
+        let transport = Arc::new(Mutex::new(VirtioMmio {
+            base,
+            common: VirtioCommon::new(device, self.ram.guest_mem_map.clone()),
+            irq: self.irqfd(gsi)?,
+            notify: EventFd::new(EFD_NONBLOCK).map_err(io_error)?,
+        }));
+        let notify = IoEventAddress::Mmio(base + 0x050);
+        self.add_ioevent_handler(&transport.lock().unwrap().notify, notify, transport.clone())?;
+        self.mmio_bus
+            .insert(base, VIRTIO_MMIO_SIZE, transport.clone())
+            .map_err(|_| kvm_ioctls::Error::new(libc::EBUSY))?;
+        info!("virtio-mmio device at 0x{base:x}, GSI {gsi}");
+        self.virtio_mmio.push(transport);
+        Ok(base)
+    }
+}
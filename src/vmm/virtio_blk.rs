@@ -0,0 +1,394 @@
+//! virtio-blk device model on top of a [`DiskBackend`].
+
+use std::io;
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use virtio_queue::{ Descriptor, Queue, QueueOwnedT, QueueT };
+use vm_memory::{ Address, Bytes, GuestMemoryError };
+
+use super::{ disk::{ DiskBackend, SECTOR_SIZE }, virtio::{ GuestMem, VirtioDevice } };
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const QUEUE_SIZE: u16 = 256;
+/// Type, reserved, sector
+const REQUEST_HEADER_SIZE: usize = 16;
+/// Sector, sector count, flags
+const DISCARD_SEGMENT_SIZE: usize = 16;
+const DISCARD_MAX_SEGMENTS: u32 = 1;
+const ID_SIZE: usize = 20;
+/// Data moves between guest buffers and the disk in chunks of this size at most
+const BOUNCE_SIZE: usize = 64 << 10;
+
+/// struct virtio_blk_config up to discard_sector_alignment
+const CONFIG_SIZE: usize = 48;
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+enum RequestError {
+    /// Unsupported request type {0}
+    Unsupported(u32),
+    /// Malformed descriptor chain
+    Malformed,
+    /// Request at sector {0} goes past the disk end
+    OutOfRange(u64),
+    /// Guest memory access failed: {0}
+    Memory(#[from] GuestMemoryError),
+    /// Disk I/O failed: {0}
+    Io(#[from] io::Error),
+}
+
+pub struct VirtioBlock {
+    disk: Box<dyn DiskBackend>,
+    /// Serial returned by GET_ID, NUL padded
+    id: [u8; ID_SIZE],
+    config: [u8; CONFIG_SIZE],
+    bounce: Vec<u8>,
+}
+
+impl std::fmt::Debug for VirtioBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VirtioBlock")
+            .field("id", &String::from_utf8_lossy(&self.id).trim_end_matches('\0'))
+            .field("size", &self.disk.size())
+            .field("read_only", &self.disk.read_only())
+            .finish()
+    }
+}
+
+impl VirtioBlock {
+    pub fn new(disk: Box<dyn DiskBackend>, id: &str) -> Self {
+        let mut serial = [0u8; ID_SIZE];
+        let len = id.len().min(ID_SIZE);
+        serial[..len].copy_from_slice(&id.as_bytes()[..len]);
+
+        let mut config = [0u8; CONFIG_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| config[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(0, &(disk.size() / SECTOR_SIZE).to_le_bytes());
+        // The header and status take two descriptors
+        put(12, &(QUEUE_SIZE as u32 - 2).to_le_bytes());
+        put(20, &(SECTOR_SIZE as u32).to_le_bytes());
+        put(36, &u32::MAX.to_le_bytes());
+        put(40, &DISCARD_MAX_SEGMENTS.to_le_bytes());
+        put(44, &1u32.to_le_bytes());
+        Self { disk, id: serial, config, bounce: vec![0; BOUNCE_SIZE] }
+    }
+
+    /// Byte offset of `len` bytes at `sector`, if they are on the disk
+    fn disk_range(&self, sector: u64, len: u64) -> Result<u64, RequestError> {
+        let offset = sector.checked_mul(SECTOR_SIZE).ok_or(RequestError::OutOfRange(sector))?;
+        match offset.checked_add(len) {
+            Some(end) if end <= self.disk.size() => Ok(offset),
+            _ => Err(RequestError::OutOfRange(sector)),
+        }
+    }
+
+    /// Run one request, returns the status and the bytes written to guest buffers
+    fn execute(&mut self, request: u32, sector: u64, data: &[Descriptor], mem: &GuestMem) -> Result<u32, RequestError> {
+        let mut written = 0;
+        match request {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+                let to_guest = request == VIRTIO_BLK_T_IN;
+                let total = data.iter().map(|desc| desc.len() as u64).sum();
+                let mut offset = self.disk_range(sector, total)?;
+                for desc in data {
+                    if desc.is_write_only() != to_guest {
+                        return Err(RequestError::Malformed);
+                    }
+                    // Through the bounce buffer, descriptors are as large as the guest likes
+                    let mut done = 0;
+                    while done < desc.len() as usize {
+                        let len = (desc.len() as usize - done).min(BOUNCE_SIZE);
+                        let buf = &mut self.bounce[..len];
+                        let addr = desc.addr().unchecked_add(done as u64);
+                        if to_guest {
+                            self.disk.read_at(buf, offset)?;
+                            mem.write_slice(buf, addr)?;
+                        } else {
+                            mem.read_slice(buf, addr)?;
+                            self.disk.write_at(buf, offset)?;
+                        }
+                        offset += len as u64;
+                        done += len;
+                    }
+                    if to_guest {
+                        written += desc.len();
+                    }
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => self.disk.flush()?,
+            VIRTIO_BLK_T_GET_ID => {
+                let desc = data.first().filter(|desc| desc.is_write_only()).ok_or(RequestError::Malformed)?;
+                let len = (desc.len() as usize).min(ID_SIZE);
+                mem.write_slice(&self.id[..len], desc.addr())?;
+                written = len as u32;
+            }
+            VIRTIO_BLK_T_DISCARD => {
+                let table_len: usize = data.iter().map(|desc| desc.len() as usize).sum();
+                if !table_len.is_multiple_of(DISCARD_SEGMENT_SIZE) || table_len > DISCARD_MAX_SEGMENTS as usize * DISCARD_SEGMENT_SIZE {
+                    return Err(RequestError::Malformed);
+                }
+                let mut table = [0u8; DISCARD_MAX_SEGMENTS as usize * DISCARD_SEGMENT_SIZE];
+                let mut filled = 0;
+                for desc in data {
+                    mem.read_slice(&mut table[filled..filled + desc.len() as usize], desc.addr())?;
+                    filled += desc.len() as usize;
+                }
+                for segment in table[..filled].chunks_exact(DISCARD_SEGMENT_SIZE) {
+                    let sector = u64::from_le_bytes(segment[..8].try_into().unwrap());
+                    let count = u32::from_le_bytes(segment[8..12].try_into().unwrap()) as u64;
+                    let offset = self.disk_range(sector, count * SECTOR_SIZE)?;
+                    self.disk.discard(offset, count * SECTOR_SIZE)?;
+                }
+            }
+            request => return Err(RequestError::Unsupported(request)),
+        }
+        Ok(written)
+    }
+
+    /// Handle a whole descriptor chain, returns the used length
+    fn handle_request(&mut self, descs: &[Descriptor], mem: &GuestMem) -> u32 {
+        let (Some(header), Some(status)) = (descs.first(), descs.last()) else {
+            return 0;
+        };
+        if descs.len() < 2 || header.is_write_only() || (header.len() as usize) < REQUEST_HEADER_SIZE || !status.is_write_only() {
+            warn!("virtio-blk: malformed request");
+            return 0;
+        }
+        let mut raw = [0u8; REQUEST_HEADER_SIZE];
+        if let Err(e) = mem.read_slice(&mut raw, header.addr()) {
+            warn!("virtio-blk: can't read request header: {e}");
+            return 0;
+        }
+        let request = u32::from_le_bytes(raw[..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(raw[8..].try_into().unwrap());
+        let (status_code, written) = match self.execute(request, sector, &descs[1..descs.len() - 1], mem) {
+            Ok(written) => (VIRTIO_BLK_S_OK, written),
+            Err(e @ RequestError::Unsupported(_)) => {
+                debug!("virtio-blk: {e}");
+                (VIRTIO_BLK_S_UNSUPP, 0)
+            }
+            Err(e) => {
+                warn!("virtio-blk: request {request} at sector {sector} failed: {e}");
+                (VIRTIO_BLK_S_IOERR, 0)
+            }
+        };
+        if let Err(e) = mem.write_slice(&[status_code], status.addr()) {
+            warn!("virtio-blk: can't write request status: {e}");
+        }
+        written + 1
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        let features = VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+        if self.disk.read_only() {
+            features | VIRTIO_BLK_F_RO
+        } else {
+            features | VIRTIO_BLK_F_DISCARD
+        }
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn process_queue(&mut self, _idx: usize, queue: &mut Queue, mem: &GuestMem) -> bool {
+        let mut used = false;
+        loop {
+            let chains: Vec<(u16, Vec<Descriptor>)> = match queue.iter(mem) {
+                Ok(iter) => iter.map(|chain| (chain.head_index(), chain.collect())).collect(),
+                Err(e) => {
+                    error!("virtio-blk: bad queue: {e}");
+                    return used;
+                }
+            };
+            if chains.is_empty() {
+                return used;
+            }
+            for (head, descs) in chains {
+                let len = self.handle_request(&descs, mem);
+                if let Err(e) = queue.add_used(mem, head, len) {
+                    error!("virtio-blk: can't add used buffer: {e}");
+                }
+                used = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{ Arc, Mutex };
+
+    use vm_memory::GuestAddress;
+
+    use super::*;
+
+    const VIRTQ_DESC_F_WRITE: u16 = 2;
+    const HEADER: u64 = 0x1000;
+    const STATUS: u64 = 0x1100;
+    const DATA: u64 = 0x10_0000;
+
+    /// (offset, length) of every discard
+    type Discards = Arc<Mutex<Vec<(u64, u64)>>>;
+
+    /// Disk in memory, recording discards
+    struct MemDisk {
+        data: Vec<u8>,
+        discarded: Discards,
+    }
+
+    impl DiskBackend for MemDisk {
+        fn size(&self) -> u64 {
+            self.data.len() as u64
+        }
+
+        fn read_only(&self) -> bool {
+            false
+        }
+
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            buf.copy_from_slice(&self.data[offset as usize..offset as usize + buf.len()]);
+            Ok(())
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+            self.data[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+            self.discarded.lock().unwrap().push((offset, len));
+            Ok(())
+        }
+    }
+
+    fn setup(disk_size: usize) -> (VirtioBlock, GuestMem, Discards) {
+        let discarded = Arc::new(Mutex::new(vec![]));
+        let data = (0..disk_size).map(|i| (i / SECTOR_SIZE as usize) as u8).collect();
+        let disk = MemDisk { data, discarded: discarded.clone() };
+        let mem = GuestMem::from_ranges(&[(GuestAddress(0), 0x40_0000)]).unwrap();
+        (VirtioBlock::new(Box::new(disk), "test-disk"), mem, discarded)
+    }
+
+    /// Run a request with `data` descriptors as (addr, len, device writable), returns the status
+    fn request(blk: &mut VirtioBlock, mem: &GuestMem, request: u32, sector: u64, data: &[(u64, u32, bool)]) -> (u8, u32) {
+        let mut header = [0u8; REQUEST_HEADER_SIZE];
+        header[..4].copy_from_slice(&request.to_le_bytes());
+        header[8..].copy_from_slice(&sector.to_le_bytes());
+        mem.write_slice(&header, GuestAddress(HEADER)).unwrap();
+        let mut descs = vec![Descriptor::new(HEADER, REQUEST_HEADER_SIZE as u32, 0, 0)];
+        for &(addr, len, write) in data {
+            descs.push(Descriptor::new(addr, len, if write { VIRTQ_DESC_F_WRITE } else { 0 }, 0));
+        }
+        descs.push(Descriptor::new(STATUS, 1, VIRTQ_DESC_F_WRITE, 0));
+        let used = blk.handle_request(&descs, mem);
+        (mem.read_obj(GuestAddress(STATUS)).unwrap(), used)
+    }
+
+    #[test]
+    fn read_write_through_bounce_buffer() {
+        let (mut blk, mem, _) = setup(0x10_0000);
+        // Larger than the bounce buffer, across two descriptors
+        let len = BOUNCE_SIZE as u32 * 2 + 0x200;
+        let (status, used) = request(&mut blk, &mem, VIRTIO_BLK_T_IN, 2, &[(DATA, len, true), (DATA + len as u64, 0x200, true)]);
+        assert_eq!((status, used), (VIRTIO_BLK_S_OK, len + 0x200 + 1));
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+        mem.read_slice(&mut sector, GuestAddress(DATA + len as u64)).unwrap();
+        // Sector 259, its bytes are its number truncated
+        assert_eq!(sector, [259u32 as u8; SECTOR_SIZE as usize]);
+
+        mem.write_slice(&[0xaa; 0x400], GuestAddress(DATA)).unwrap();
+        let (status, used) = request(&mut blk, &mem, VIRTIO_BLK_T_OUT, 1, &[(DATA, 0x400, false)]);
+        assert_eq!((status, used), (VIRTIO_BLK_S_OK, 1));
+        request(&mut blk, &mem, VIRTIO_BLK_T_IN, 0, &[(DATA, 0x800, true)]);
+        mem.read_slice(&mut sector, GuestAddress(DATA + 0x400)).unwrap();
+        assert_eq!(sector, [0xaa; SECTOR_SIZE as usize]);
+
+        // Data direction must match the request
+        let (status, _) = request(&mut blk, &mem, VIRTIO_BLK_T_IN, 0, &[(DATA, 0x200, false)]);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+    }
+
+    #[test]
+    fn out_of_range_requests() {
+        let (mut blk, mem, discarded) = setup(0x1000);
+        let (status, _) = request(&mut blk, &mem, VIRTIO_BLK_T_IN, 7, &[(DATA, 0x400, true)]);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+        let (status, _) = request(&mut blk, &mem, VIRTIO_BLK_T_OUT, u64::MAX / 2, &[(DATA, 0x200, false)]);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+        let (status, _) = request(&mut blk, &mem, VIRTIO_BLK_T_IN, 7, &[(DATA, 0x200, true)]);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+
+        let mut segment = [0u8; DISCARD_SEGMENT_SIZE];
+        segment[..8].copy_from_slice(&(u64::MAX / 4).to_le_bytes());
+        segment[8..12].copy_from_slice(&1u32.to_le_bytes());
+        mem.write_slice(&segment, GuestAddress(DATA)).unwrap();
+        let (status, _) = request(&mut blk, &mem, VIRTIO_BLK_T_DISCARD, 0, &[(DATA, 16, false)]);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+        assert!(discarded.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn discard_segments() {
+        let (mut blk, mem, discarded) = setup(0x10000);
+        let mut segment = [0u8; DISCARD_SEGMENT_SIZE];
+        segment[..8].copy_from_slice(&4u64.to_le_bytes());
+        segment[8..12].copy_from_slice(&8u32.to_le_bytes());
+        mem.write_slice(&segment, GuestAddress(DATA)).unwrap();
+        let (status, _) = request(&mut blk, &mem, VIRTIO_BLK_T_DISCARD, 0, &[(DATA, 16, false)]);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(*discarded.lock().unwrap(), [(0x800, 0x1000)]);
+
+        // Tables that aren't whole segments, or more than advertised, are rejected
+        for len in [12, 32, 0x10_0000] {
+            let (status, _) = request(&mut blk, &mem, VIRTIO_BLK_T_DISCARD, 0, &[(DATA, len, false)]);
+            assert_eq!(status, VIRTIO_BLK_S_IOERR, "table of {len} bytes");
+        }
+        assert_eq!(discarded.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn get_id_and_unsupported() {
+        let (mut blk, mem, _) = setup(0x1000);
+        let (status, used) = request(&mut blk, &mem, VIRTIO_BLK_T_GET_ID, 0, &[(DATA, 64, true)]);
+        assert_eq!((status, used), (VIRTIO_BLK_S_OK, ID_SIZE as u32 + 1));
+        let mut id = [0u8; ID_SIZE];
+        mem.read_slice(&mut id, GuestAddress(DATA)).unwrap();
+        assert_eq!(&id[..10], b"test-disk\0");
+        let (status, _) = request(&mut blk, &mem, 0x42, 0, &[]);
+        assert_eq!(status, VIRTIO_BLK_S_UNSUPP);
+    }
+}
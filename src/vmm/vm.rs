@@ -1,5 +1,8 @@
 extern crate vmm_sys_util;
 
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
 use std::usize;
 
 use kvm_bindings::kvm_interrupt;
@@ -9,7 +12,11 @@ use log::{ debug, error, info, warn };
 
 use crate::mem_inspection::*;
 
-use super::Vm;
+use super::{
+    reset::{ ResetSource, RESET_CONTROL_PORT },
+    snapshot::SnapshotError,
+    Vm,
+};
 type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
 
 #[allow(dead_code)]
@@ -22,6 +29,11 @@ impl Vm {
         &self.vcpu_fd
     }
 
+    /// One vCPU for now
+    pub fn vcpu_count(&self) -> usize {
+        1
+    }
+
     fn print_code_at_rip(&self, count: usize) -> Result<()> {
         let addr = self.vcpu_fd.get_regs()?.rip;
         let cs = self.vcpu_fd.get_sregs()?.cs.base;
@@ -43,7 +55,17 @@ impl Vm {
 
     #[allow(unused)]
     pub fn run(&mut self) -> Result<bool> {
-        let vcpu_exit = self.vcpu_fd.run().unwrap();
+        if self.paused {
+            // Let the monitor in until it resets the VM
+            thread::sleep(Duration::from_millis(100));
+            return Ok(true);
+        }
+        let vcpu_exit = match self.vcpu_fd.run() {
+            Ok(vcpu_exit) => vcpu_exit,
+            // Kicked out of KVM_RUN, e.g. by the monitor
+            Err(e) if e.errno() == libc::EINTR => return Ok(true),
+            Err(e) => return Err(e),
+        };
         info!("--------------------------");
         let rip = self.vcpu_fd.get_regs()?.rip;
         let cs_selector = self.vcpu_fd.get_sregs()?.cs.selector as u64;
@@ -52,6 +74,24 @@ impl Vm {
 
         debug!("addr=0x{addr:x},cs_selector=0x{:x},rip=0x{rip:x}", cs_selector);
         match vcpu_exit {
+            // Byte wide, inside the PCI config I/O range
+            VcpuExit::IoIn(RESET_CONTROL_PORT, [data]) => *data = self.reset_control_read(),
+            VcpuExit::IoOut(RESET_CONTROL_PORT, &[data]) => self.reset_control_write(data),
+            VcpuExit::IoIn(port, data) if self.is_pci_config_io(port) => self.pci_io_read(port, data),
+            VcpuExit::IoOut(port, data) if self.is_pci_config_io(port) => {
+                let data = data.to_vec();
+                self.pci_io_write(port, &data)?;
+            }
+            VcpuExit::IoIn(port, data) if self.pio_bus.contains(port as u64) => {
+                self.pio_bus.read(port as u64, data);
+            }
+            VcpuExit::IoOut(port, data) if self.pio_bus.contains(port as u64) => {
+                self.pio_bus.write(port as u64, data);
+                if self.shutdown_request.swap(false, Ordering::SeqCst) {
+                    info!("Guest powered off");
+                    return Ok(false);
+                }
+            }
             VcpuExit::IoIn(addr, mut data_asked) => {
                 // TOFIX
                 debug!("IoIn[0x{addr:x}, {data_asked:x?}]");
@@ -67,39 +107,65 @@ impl Vm {
                     self.serial.data_out(data_given);
                 }
             }
+            VcpuExit::MmioRead(addr, data) if self.is_pci_ecam(addr) => self.pci_ecam_read(addr, data),
+            VcpuExit::MmioWrite(addr, data) if self.is_pci_ecam(addr) => {
+                let data = data.to_vec();
+                self.pci_ecam_write(addr, &data)?;
+            }
+            VcpuExit::MmioRead(addr, data) if self.mmio_bus.contains(addr) => {
+                self.mmio_bus.read(addr, data);
+            }
+            VcpuExit::MmioWrite(addr, data) if self.mmio_bus.contains(addr) => {
+                self.mmio_bus.write(addr, data);
+            }
+            VcpuExit::MmioWrite(addr, data) if self.is_ioapic_mmio(addr) => {
+                let data = data.to_vec();
+                self.ioapic_mmio_write(addr, &data)?;
+            }
+            VcpuExit::MmioRead(addr, data) if self.is_ioapic_mmio(addr) => self.ioapic_mmio_read(addr, data),
+            VcpuExit::IoapicEoi(vector) => self.ioapic_eoi(vector)?,
+            VcpuExit::MmioWrite(addr, data) if self.ram.is_watched(addr) => {
+                let data = data.to_vec();
+                self.handle_watched_write(addr, &data)?;
+            }
             VcpuExit::MmioWrite(addr, data) => {
                 debug!("MmioWrite addr=0x{addr:x?} {data:x?}");
                 println!("MmioWrite addr=0x{addr:x?} {data:x?}");
             }
             VcpuExit::MmioRead(addr, data) => debug!("MmioWrite 0x{addr:x?} {data:x?}"),
-            VcpuExit::Hlt => {
-                self.vcpu_fd.get_vcpu_events().unwrap().interrupt.injected = 0;
-                #[deprecated(
-                    note = "Hardcoded interrupt for HLT, meaningless, remember to study this"
-                )]
-                return Ok(false);
-            }
+            VcpuExit::X86Rdmsr(exit) => self.msr_exits.rdmsr(&self.vcpu_fd, exit),
+            VcpuExit::X86Wrmsr(exit) => self.msr_exits.wrmsr(&self.vcpu_fd, exit),
+            // The in-kernel LAPIC waits for the interrupt, the exit only tells us the guest idled
+            VcpuExit::Hlt => debug!("HLT"),
             VcpuExit::InternalError => {
                 self.crash_report("Internal Error");
                 return Ok(false);
             }
-            VcpuExit::Intr => {
-                error!("{self:?} CATCHED YAHOUU");
-                //self.vm_fd.set_irq_line(0, active=true).unwrap();
-                todo!();
-            }
+            // A signal is pending for this thread, same as an EINTR from KVM_RUN
+            VcpuExit::Intr => debug!("KVM_EXIT_INTR"),
             VcpuExit::Shutdown => {
-                return Ok(false);
+                self.crash_report("Triple fault");
+                self.reset_line.pull(ResetSource::TripleFault);
             }
             VcpuExit::Exception => {
                 error!("EXCEPTION {:x?}", self.vcpu_fd.get_vcpu_events().unwrap().exception);
                 panic!("EXCEPTION {:x?}", self.vcpu_fd.get_vcpu_events().unwrap().exception);
             }
-            _ => {
-                error!("{self:x?} not yet implemented");
-                todo!();
+            exit => {
+                error!("Unhandled vCPU exit {exit:x?}, stopping");
+                self.crash_report("Unhandled exit");
+                return Ok(false);
             }
         }
+        if let Some(source) = self.reset_line.take() {
+            return self.handle_reset(source).map_err(|e| {
+                error!("Reset failed: {e}");
+                match e {
+                    SnapshotError::Kvm(e) => e,
+                    _ => kvm_ioctls::Error::new(libc::EIO),
+                }
+            });
+        }
         Ok(true)
     }
 
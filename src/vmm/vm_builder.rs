@@ -2,15 +2,41 @@ use core::slice;
 use std::{
     fs::{ File, OpenOptions },
     io::{ stdin,Read, Write },
-    path::Path,
+    path::{ Path, PathBuf },
 };
 
 use goblin::Object;
-use kvm_ioctls::{ Kvm, VcpuFd, VmFd };
+use kvm_bindings::{ kvm_create_device, CpuId, KVM_MAX_CPUID_ENTRIES };
+use kvm_ioctls::{ DeviceFd, Kvm, VcpuFd, VmFd };
 #[allow(unused)]
 use log::{ debug, error, info, warn };
+use vm_memory::GuestAddress;
 
-use super::{ ram::{ BuildRam, Ram }, serial::SerialPort, Vm };
+use super::{
+    ahci::AhciDrive,
+    bus::Bus,
+    cpuid::CpuConfig,
+    dirty::DirtyLog,
+    disk::{ open_disk, DiskOptions },
+    event_loop::EventLoop,
+    irq::{ IrqChipMode, IrqRouting },
+    iso9660::{ self, BootPlatform, ISO_SECTOR_SIZE },
+    msr::{ MsrExits, MsrFilter },
+    nvme::NvmeNamespace,
+    pci::PciRoot,
+    ram::{ BuildRam, Ram },
+    registry::{ self, Allocations, DeviceError, Placement },
+    reset::ResetConfig,
+    rtc::RtcConfig,
+    serial::SerialPort,
+    smbios::SmbiosConfig,
+    snapshot::{ self, SnapshotError, SnapshotFile },
+    tpm::{ StandInTpm, Swtpm, TpmBackend },
+    vcpu_init::init_vcpu,
+    virtio::{ VirtioDevice, VirtioTransport },
+    virtio_blk::VirtioBlock,
+    Vm,
+};
 use std::thread;
 use std::time::Duration;
 
@@ -26,6 +52,31 @@ pub struct VmBuilder {
     ram: Option<Ram>,
     //serial: Box<dyn SerialPort>,
     serial: SerialPort,
+    irq: IrqRouting,
+    event_loop: EventLoop,
+    msr_indices: Vec<u32>,
+    /// CPUID KVM can expose on this host, the guest one is derived from it
+    supported_cpuid: CpuId,
+    cpu: CpuConfig,
+    /// MSRs whose guest accesses exit to userspace
+    msr_filter: MsrFilter,
+    /// Snapshot being restored and the path of its base, itself when it is a full one
+    restore: Option<(SnapshotFile, PathBuf)>,
+    disks: Vec<(VirtioBlock, Placement)>,
+    virtio_transport: VirtioTransport,
+    /// PCI slots and GSIs of the devices added so far
+    allocations: Allocations,
+    /// Drives of the AHCI controller, by port
+    ahci: Vec<AhciDrive>,
+    /// Namespaces of the NVMe controller, and its serial number
+    nvme: Vec<NvmeNamespace>,
+    nvme_serial: String,
+    rtc: RtcConfig,
+    reset: ResetConfig,
+    smbios: SmbiosConfig,
+    tpm: Option<Box<dyn TpmBackend>>,
+    /// In-kernel devices, by kind name
+    kvm_devices: Vec<(&'static str, DeviceFd)>,
 }
 
 pub fn find_entrypoint(firmware_code: &[u8]) -> u64 {
@@ -58,6 +109,13 @@ pub fn find_entrypoint(firmware_code: &[u8]) -> u64 {
 #[allow(unused)]
 impl VmBuilder {
     pub fn build(mut self) -> Result<Vm> {
+        self.init_vcpus()?;
+        if !self.msr_filter.is_empty() {
+            self.msr_filter.apply(&self.vm_fd)?;
+        }
+        if let Some((snapshot, base_path)) = self.restore.take() {
+            return self.build_restored(snapshot, base_path);
+        }
         if self.code.is_empty() {
             error!("No code loaded, can't run the VM without code. I decided to not build it");
             panic!("Attempt to build VM without code");
@@ -101,13 +159,119 @@ impl VmBuilder {
         }
 
         thread::sleep(Duration::from_secs(3));
-        Ok(Vm {
+        self.into_vm()
+    }
+
+    /// CPUID and reset state of every vCPU, whatever the boot path.
+    /// A restored snapshot overwrites the latter afterwards.
+    fn init_vcpus(&self) -> Result<()> {
+        let vcpus = [&self.vcpu_fd];
+        for (id, vcpu) in vcpus.iter().enumerate() {
+            // Before anything touches MSRs or XSAVE state, KVM checks them against the guest CPUID
+            let cpuid = self.cpu.cpuid(&self.supported_cpuid, id as u8, vcpus.len() as u8)?;
+            vcpu.set_cpuid2(&cpuid)?;
+            init_vcpu(vcpu, id == 0)?;
+        }
+        Ok(())
+    }
+
+    fn into_vm(self) -> Result<Vm> {
+        let ram = self.ram.expect("Can't make VM Without RAM");
+        let firmware_addr = GuestAddress(ram.guest_phys_addr);
+        let firmware = self.code;
+        let mut vm = Vm {
             slot: self.slot,
             vm_fd: self.vm_fd,
             vcpu_fd: self.vcpu_fd,
-            ram: self.ram.expect("Can't make VM Without RAM"),
+            dirty: DirtyLog::new(&ram),
+            ram,
             serial: self.serial,
-        })
+            irq: self.irq,
+            pio_bus: Bus::default(),
+            mmio_bus: Bus::default(),
+            pci: PciRoot::default(),
+            virtio_mmio: vec![],
+            i8042: None,
+            input: None,
+            rtc: None,
+            hpet: None,
+            fw_cfg: None,
+            acpi_pm: None,
+            power_button: None,
+            tpm: None,
+            reset_line: Default::default(),
+            reset_config: self.reset,
+            reset_control: 0,
+            paused: false,
+            power_on: None,
+            shutdown_request: Default::default(),
+            event_loop: self.event_loop,
+            msr_indices: self.msr_indices,
+            msr_exits: MsrExits::default(),
+            base: None,
+            kvm_devices: self.kvm_devices,
+        };
+        // Before a snapshot restore, which carries their state
+        vm.add_i8042()?;
+        vm.add_rtc(self.rtc)?;
+        vm.add_hpet()?;
+        vm.add_acpi_pm()?;
+        for (disk, placement) in self.disks {
+            let disk: Box<dyn VirtioDevice> = Box::new(disk);
+            match placement {
+                Placement::Pci(slot) => vm.add_virtio_pci(slot, disk)?,
+                Placement::Mmio(gsi) => {
+                    vm.add_virtio_mmio(disk, gsi)?;
+                }
+                Placement::Fixed => unreachable!("virtio devices are placed on their transport"),
+            }
+        }
+        if let Some(slot) = self.allocations.pci_function("ahci") {
+            vm.add_ahci(slot, self.ahci)?;
+        }
+        if let Some(slot) = self.allocations.pci_function("nvme") {
+            vm.add_nvme(slot, self.nvme, &self.nvme_serial)?;
+        }
+        // Once every device is there to be described
+        vm.add_fw_cfg()?;
+        if let Some(backend) = self.tpm {
+            vm.add_tpm(backend)?;
+        }
+        vm.add_acpi_tables()?;
+        vm.add_smbios_tables(&self.smbios)?;
+        // Devices as created and the vCPU at its entry point, a snapshot restore comes after
+        vm.capture_power_on(firmware_addr, firmware).map_err(|e| match e {
+            SnapshotError::Kvm(e) => e,
+            _ => kvm_ioctls::Error::new(libc::EIO),
+        })?;
+        Ok(vm)
+    }
+
+    fn build_restored(self, mut snapshot: SnapshotFile, base_path: PathBuf) -> Result<Vm> {
+        let mut vm = self.into_vm()?;
+        let quiesced = vm.event_loop.quiesce().map_err(|e| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO)))?;
+        let restored = snapshot.restore_state(&mut vm).and_then(|_| {
+            // What an incremental snapshot restored over its base differs from it
+            let changed = match snapshot.base()? {
+                Some(_) => snapshot.pages()?,
+                None => vec![],
+            };
+            if let Some((addr, firmware)) = snapshot.firmware()? {
+                vm.set_power_on_firmware(addr, firmware);
+            } else {
+                warn!("Snapshot holds no firmware image, reboots restart on what RAM holds");
+            }
+            vm.set_base(&base_path, &changed)
+        });
+        drop(quiesced);
+        if let Err(e) = restored {
+            error!("Snapshot restore failed: {e}");
+            return Err(match e {
+                SnapshotError::Kvm(e) => e,
+                _ => kvm_ioctls::Error::new(libc::EINVAL),
+            });
+        }
+        Ok(vm)
     }
 
     pub fn ram(mut self, mem_size: usize) -> Self {
@@ -135,15 +299,181 @@ impl VmBuilder {
         self.code = b;
         Ok(self)
     }
+
+    /// Attach a disk image as a virtio-blk device. Options rather than a read only
+    /// flag, qcow2 images also need their format and backing file policy.
+    pub fn disk<P: AsRef<Path>>(mut self, path: P, options: DiskOptions) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let disk = VirtioBlock::new(open_disk(path, options)?, &drive_id(path));
+        let placement = self.allocate("virtio-blk")?;
+        self.disks.push((disk, placement));
+        Ok(self)
+    }
+
+    /// Attach a disk image on the next AHCI port
+    pub fn ahci_disk<P: AsRef<Path>>(mut self, path: P, options: DiskOptions) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let drive = AhciDrive::disk(open_disk(path, options)?, &drive_id(path));
+        self.allocate("ahci-disk")?;
+        self.ahci.push(drive);
+        Ok(self)
+    }
+
+    /// Attach an ISO image as a read only ATAPI CD-ROM on the next AHCI port.
+    /// OVMF boots it through its El Torito catalog, an image it can't boot is only warned about.
+    pub fn cdrom<P: AsRef<Path>>(mut self, path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let mut disk = open_disk(path, DiskOptions { readonly: true, ..Default::default() })?;
+        if disk.size() % ISO_SECTOR_SIZE != 0 {
+            warn!("CD-ROM {}: size isn't a multiple of {ISO_SECTOR_SIZE} bytes", path.to_string_lossy());
+        }
+        match iso9660::probe(disk.as_mut()) {
+            Ok(iso) if iso.boot.contains(&BootPlatform::Efi) => {
+                info!("CD-ROM {}: volume {:?}, bootable", path.to_string_lossy(), iso.volume_id);
+            }
+            Ok(iso) => {
+                let platforms: Vec<String> = iso.boot.iter().map(|platform| platform.to_string()).collect();
+                warn!(
+                    "CD-ROM {}: volume {:?} has no UEFI boot entry{}",
+                    path.to_string_lossy(),
+                    iso.volume_id,
+                    if platforms.is_empty() { String::new() } else { format!(", only {}", platforms.join(", ")) }
+                );
+            }
+            Err(e) => warn!("CD-ROM {}: {e}", path.to_string_lossy()),
+        }
+        self.allocate("cdrom")?;
+        self.ahci.push(AhciDrive::cdrom(disk, &drive_id(path)));
+        Ok(self)
+    }
+
+    /// Attach a disk image as the next namespace of the NVMe controller
+    pub fn nvme_disk<P: AsRef<Path>>(mut self, path: P, options: DiskOptions) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let namespace = NvmeNamespace::new(open_disk(path, options)?);
+        self.allocate("nvme")?;
+        if self.nvme.is_empty() {
+            self.nvme_serial = drive_id(path);
+        }
+        self.nvme.push(namespace);
+        Ok(self)
+    }
+
+    /// TPM 2.0 backed by the swtpm listening on the `socket` control socket,
+    /// or by the stand-in TPM without one
+    pub fn tpm(mut self, socket: Option<&Path>) -> std::io::Result<Self> {
+        let backend: Box<dyn TpmBackend> = match socket {
+            Some(socket) => Box::new(Swtpm::connect(socket)?),
+            None => {
+                warn!("TPM without swtpm, the stand-in only answers a few commands");
+                Box::new(StandInTpm::default())
+            }
+        };
+        self.allocate("tpm-crb")?;
+        self.tpm = Some(backend);
+        Ok(self)
+    }
+
+    /// Slot or lines of a new device of `kind`, from the bus and IRQ the registry gives it
+    fn allocate(&mut self, kind: &str) -> std::io::Result<Placement> {
+        let kind = registry::find(kind).expect("Device kind not in the registry");
+        self.allocations.place(kind, self.virtio_transport)
+    }
+
+    /// Create an in-kernel KVM device, see [`registry::KVM_DEVICE_KINDS`]
+    pub fn kvm_device(mut self, kind: &str) -> std::result::Result<Self, DeviceError> {
+        let kind = registry::find_kvm(kind).ok_or_else(|| DeviceError::UnknownKind(kind.to_string()))?;
+        let mut device = kvm_create_device { type_: kind.device_type, fd: 0, flags: 0 };
+        let fd = self
+            .vm_fd
+            .create_device(&mut device)
+            .map_err(|e| DeviceError::Create(kind.name, std::io::Error::from_raw_os_error(e.errno())))?;
+        info!("In-kernel {} device created", kind.name);
+        self.kvm_devices.push((kind.name, fd));
+        Ok(self)
+    }
+
+    /// How virtio devices are plugged, PCI by default. Applies to the virtio devices added after.
+    pub fn virtio_transport(mut self, transport: VirtioTransport) -> Self {
+        self.virtio_transport = transport;
+        self
+    }
+
+    /// Clock source and start time of the RTC
+    pub fn rtc(mut self, rtc: RtcConfig) -> Self {
+        self.rtc = rtc;
+        self
+    }
+
+    /// What each reset source does, a reboot by default
+    pub fn reset(mut self, reset: ResetConfig) -> Self {
+        self.reset = reset;
+        self
+    }
+
+    /// Strings of the SMBIOS tables the guest sees
+    pub fn smbios(mut self, smbios: SmbiosConfig) -> Self {
+        self.smbios = smbios;
+        self
+    }
+
+    /// CPU model and CPUID overrides of the guest
+    pub fn cpu(mut self, cpu: CpuConfig) -> Self {
+        self.cpu = cpu;
+        self
+    }
+
+    /// Make guest RDMSR/WRMSR of the filtered MSRs exit to userspace, see [`Vm::set_msr_handler`]
+    pub fn msr_filter(mut self, filter: MsrFilter) -> Self {
+        self.msr_filter = filter;
+        self
+    }
+
+    /// Restore a snapshot taken with [`Vm::snapshot`] or [`Vm::snapshot_incremental`]
+    /// instead of booting from code, incremental ones pull their base first.
+    /// RAM is created from the snapshot size if [`VmBuilder::ram`] wasn't called.
+    pub fn restore<P: AsRef<Path>>(mut self, snapshot_path: P) -> snapshot::Result<Self> {
+        let snapshot_path: &Path = snapshot_path.as_ref();
+        info!("restoring {}", snapshot_path.to_string_lossy());
+        let mut snapshot = SnapshotFile::open(snapshot_path)?;
+        if self.ram.is_none() {
+            self = self.ram(snapshot.mem_size()?);
+        }
+        let ram = self.ram.as_ref().unwrap();
+        let base_path = match snapshot.base()? {
+            Some((base_id, base_path)) => {
+                info!("restoring base {}", base_path.to_string_lossy());
+                if !base_path.is_file() {
+                    return Err(SnapshotError::MissingBase(base_path));
+                }
+                let mut base = SnapshotFile::open(&base_path)?;
+                if base.id()? != base_id {
+                    return Err(SnapshotError::BaseMismatch(base_path));
+                }
+                base.restore_ram(ram)?;
+                base_path
+            }
+            None => snapshot_path.to_path_buf(),
+        };
+        snapshot.restore_ram(ram)?;
+        self.restore = Some((snapshot, base_path));
+        Ok(self)
+    }
+}
+
+/// Serial number a guest sees for an image, its file name
+fn drive_id(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
 }
 
 pub trait BuildVm {
-    fn setup_vm(&self) -> Result<VmBuilder>;
+    /// The irqchip has to exist before the vCPUs, so it is picked here
+    fn setup_vm(&self, irqchip: IrqChipMode) -> Result<VmBuilder>;
 }
 
 /// Wrapper around VM Creation for KVM, intended to refactor code, maybe useless idk
 impl BuildVm for Kvm {
-    fn setup_vm(&self) -> Result<VmBuilder> {
+    fn setup_vm(&self, irqchip: IrqChipMode) -> Result<VmBuilder> {
         // TMP TODO REMOVE
         let path = "/tmp/vmm.serial";
         let file: File = OpenOptions::new().read(true).write(true).create(true).open(path).unwrap();
@@ -153,8 +483,12 @@ impl BuildVm for Kvm {
         let fd_out = Box::new(file);
         // TMP TODO REMOVE
         let vm_fd = self.create_vm()?;
-        vm_fd.create_irq_chip()?;
+        let irq = IrqRouting::new(&vm_fd, irqchip)?;
+        let event_loop = EventLoop::start()
+            .map_err(|e| kvm_ioctls::Error::new(e.raw_os_error().unwrap_or(libc::EIO)))?;
         let vcpu_fd = vm_fd.create_vcpu(0)?;
+        let msr_indices = self.get_msr_index_list()?.as_slice().to_vec();
+        let supported_cpuid = self.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
 
         Ok(VmBuilder {
             slot: 0,
@@ -164,7 +498,25 @@ impl BuildVm for Kvm {
             ram: None,
             // TODO VM Builder args
             //serial: SerialPort::new(0x38f, fd_in, fd_out),
-            serial: SerialPort::new(0x38f, Box::new(stdin()), fd_out),
+            serial: SerialPort::new(0x3f8, Box::new(stdin()), fd_out),
+            irq,
+            event_loop,
+            msr_indices,
+            supported_cpuid,
+            cpu: CpuConfig::default(),
+            msr_filter: MsrFilter::default(),
+            restore: None,
+            disks: vec![],
+            virtio_transport: VirtioTransport::default(),
+            allocations: Allocations::default(),
+            ahci: vec![],
+            nvme: vec![],
+            nvme_serial: String::new(),
+            rtc: RtcConfig::default(),
+            reset: ResetConfig::default(),
+            smbios: SmbiosConfig::default(),
+            tpm: None,
+            kvm_devices: vec![],
         })
     }
 }
@@ -0,0 +1,49 @@
+//! Data watchpoints on guest physical memory, as many as we want.
+//!
+//! Watched pages are mapped read only in KVM so guest writes exit as MMIO
+//! writes: we log who wrote what, then do the write ourselves and resume.
+//!
+//! KVM has already emulated a write when it exits, RIP points at the next
+//! instruction and the exit doesn't say how long the writer was. The log gives
+//! that RIP as `next_rip`, the writer is the instruction ending there.
+
+#[allow(unused)]
+use log::{ debug, error, info, warn };
+use vm_memory::{ Bytes, GuestAddress };
+
+use super::{ VCpu, Vm };
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+impl Vm {
+    /// Trap guest writes to [guest_addr, guest_addr + size), page granular
+    pub fn watch_writes(&mut self, guest_addr: u64, size: usize) -> Result<()> {
+        // Rebuilding the slots drops their KVM dirty bitmaps
+        self.dirty.sync(&self.vm_fd, &self.ram)?;
+        self.ram.watch(&self.vm_fd, guest_addr, size)
+    }
+
+    pub fn unwatch_writes(&mut self, guest_addr: u64, size: usize) -> Result<()> {
+        self.dirty.sync(&self.vm_fd, &self.ram)?;
+        self.ram.unwatch(&self.vm_fd, guest_addr, size)
+    }
+
+    /// Log and perform a guest write that hit a watched page.
+    /// KVM already emulated the writing instruction, RIP points right after it.
+    pub(super) fn handle_watched_write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        let next_rip = self.vcpu_fd.get_rip()?;
+        let mut value = [0u8; 8];
+        let len = data.len().min(8);
+        value[..len].copy_from_slice(&data[..len]);
+        info!(
+            "Watched write @ 0x{addr:x} by the instruction ending at next_rip=0x{next_rip:x}: size={} value=0x{:x}",
+            data.len(),
+            u64::from_le_bytes(value)
+        );
+        // Through vm-memory so the write lands in the dirty log like the guest one would
+        if let Err(e) = self.ram.guest_mem_map.write_slice(data, GuestAddress(addr)) {
+            error!("Could not emulate watched write @ 0x{addr:x}: {e}");
+        }
+        Ok(())
+    }
+}
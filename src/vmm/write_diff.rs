@@ -0,0 +1,332 @@
+//! What did the guest write between two points in time.
+//!
+//! [`Vm::diff_mark`] records a dirty log marker and copies the ranges we want
+//! byte level diffs for, [`Vm::diff_report`] then lists every page written since,
+//! optionally mapped back to guest virtual addresses and the PE image holding them.
+
+use std::{ collections::{ BTreeMap, HashMap, HashSet }, fmt };
+
+#[allow(unused)]
+use log::{ debug, info, warn };
+use vm_memory::{ Bytes, GuestAddress, GuestMemory };
+
+use super::{ paging::PageWalker, ram::PAGE_SIZE, Vm };
+
+type Result<T> = std::result::Result<T, kvm_ioctls::Error>;
+
+/// How far below a virtual address we look for the MZ header of its image
+const MAX_IMAGE_SCAN: u64 = 64 << 20;
+/// PE signature, file header and optional header up to the export directory entry
+const PE_HEADERS_LEN: u64 = 24 + 112 + 8;
+
+/// A point in time to diff against
+#[derive(Debug)]
+pub struct DiffMark {
+    marker: u32,
+    /// (guest physical address, content at mark time)
+    ranges: Vec<(u64, Vec<u8>)>,
+}
+
+/// A run of bytes that changed inside a watched range
+#[derive(Debug)]
+pub struct ByteDiff {
+    pub guest_addr: u64,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+/// PE image found in guest virtual memory
+#[derive(Debug, Clone)]
+pub struct GuestModule {
+    pub base: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+#[derive(Debug)]
+pub struct VirtualPage {
+    pub virt: u64,
+    pub module: Option<GuestModule>,
+}
+
+#[derive(Debug, Default)]
+pub struct WriteDiff {
+    /// Guest physical address of every page written since the mark
+    pub pages: Vec<u64>,
+    pub bytes: Vec<ByteDiff>,
+    /// Virtual aliases of the written pages in the current address space, if asked for
+    pub virtual_pages: BTreeMap<u64, Vec<VirtualPage>>,
+}
+
+impl Vm {
+    /// Mark the current point in time, `ranges` (guest physical address, size) get byte level diffs
+    pub fn diff_mark(&mut self, ranges: &[(u64, usize)]) -> Result<DiffMark> {
+        let marker = self.dirty.mark(&self.vm_fd, &self.ram)?;
+        let mut copies = vec![];
+        for &(addr, size) in ranges {
+            // Copies are made before reading, only for ranges the guest RAM holds
+            if !self.ram.guest_mem_map.check_range(GuestAddress(addr), size) {
+                warn!("Can't watch 0x{addr:x}+0x{size:x}: not in guest RAM");
+                continue;
+            }
+            let mut copy = vec![0u8; size];
+            match self.ram.guest_mem_map.read_slice(&mut copy, GuestAddress(addr)) {
+                Ok(()) => copies.push((addr, copy)),
+                Err(e) => warn!("Can't watch 0x{addr:x}+0x{size:x}: {e}"),
+            }
+        }
+        Ok(DiffMark { marker, ranges: copies })
+    }
+
+    /// Everything written since `mark`, with virtual addresses and modules when `resolve_virtual`
+    pub fn diff_report(&mut self, mark: &DiffMark, resolve_virtual: bool) -> Result<WriteDiff> {
+        self.dirty.sync(&self.vm_fd, &self.ram)?;
+        let mut pages: Vec<u64> = self.dirty
+            .changed_since(mark.marker)
+            .into_iter()
+            .filter_map(|idx| self.ram.guest_addr(idx as usize * PAGE_SIZE))
+            .collect();
+        pages.sort_unstable();
+
+        let mut diff = WriteDiff { pages, ..Default::default() };
+        for (addr, old) in &mark.ranges {
+            let mut new = vec![0u8; old.len()];
+            if self.ram.guest_mem_map.read_slice(&mut new, GuestAddress(*addr)).is_err() {
+                continue;
+            }
+            diff.bytes.extend(byte_runs(*addr, old, &new));
+        }
+        if resolve_virtual {
+            let sregs = self.vcpu_fd.get_sregs()?;
+            let walker = PageWalker::new(&self.ram, &sregs);
+            diff.virtual_pages = virtual_aliases(&walker, &diff.pages);
+        }
+        Ok(diff)
+    }
+}
+
+/// Group the differing bytes of `old` and `new` in contiguous runs
+fn byte_runs(addr: u64, old: &[u8], new: &[u8]) -> Vec<ByteDiff> {
+    let mut runs = vec![];
+    let mut i = 0;
+    while i < old.len() {
+        if old[i] == new[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < old.len() && old[i] != new[i] {
+            i += 1;
+        }
+        runs.push(ByteDiff {
+            guest_addr: addr + start as u64,
+            old: old[start..i].to_vec(),
+            new: new[start..i].to_vec(),
+        });
+    }
+    runs
+}
+
+fn virtual_aliases(walker: &PageWalker, pages: &[u64]) -> BTreeMap<u64, Vec<VirtualPage>> {
+    let mut aliases: BTreeMap<u64, Vec<VirtualPage>> = BTreeMap::new();
+    let mut modules = ModuleCache::default();
+    let mut virts = vec![];
+    walker.for_each_mapping(|mapping| {
+        let start = pages.partition_point(|&p| p < mapping.phys);
+        for &page in pages[start..].iter().take_while(|&&p| p < mapping.phys + mapping.size) {
+            virts.push((page, mapping.virt + (page - mapping.phys)));
+        }
+    });
+    for (page, virt) in virts {
+        let module = modules.find(walker, virt);
+        aliases.entry(page).or_default().push(VirtualPage { virt, module });
+    }
+    aliases
+}
+
+fn read_u16(walker: &PageWalker, virt: u64) -> Option<u16> {
+    let mut buf = [0u8; 2];
+    walker.read_virt(virt, &mut buf)?;
+    Some(u16::from_le_bytes(buf))
+}
+
+fn read_u32(walker: &PageWalker, virt: u64) -> Option<u32> {
+    let mut buf = [0u8; 4];
+    walker.read_virt(virt, &mut buf)?;
+    Some(u32::from_le_bytes(buf))
+}
+
+/// Images found during a diff report, and what was learnt looking for them
+#[derive(Debug, Default)]
+struct ModuleCache {
+    modules: Vec<GuestModule>,
+    /// Image starting at a page, None when the page doesn't start one
+    headers: HashMap<u64, Option<GuestModule>>,
+    /// Virtual pages no image covers
+    misses: HashSet<u64>,
+}
+
+impl ModuleCache {
+    fn image_at(&mut self, walker: &PageWalker, base: u64) -> Option<GuestModule> {
+        self.headers
+            .entry(base)
+            .or_insert_with(|| (read_u16(walker, base) == Some(0x5a4d)).then(|| parse_image(walker, base)).flatten())
+            .clone()
+    }
+
+    /// Walk down page by page from `virt` to the first image covering it, an
+    /// MZ header whose image ends below `virt` doesn't stop the walk
+    fn find(&mut self, walker: &PageWalker, virt: u64) -> Option<GuestModule> {
+        if let Some(module) = self.modules.iter().find(|m| (m.base..m.base + m.size).contains(&virt)) {
+            return Some(module.clone());
+        }
+        let page = virt & !(PAGE_SIZE as u64 - 1);
+        if self.misses.contains(&page) {
+            return None;
+        }
+        let lowest = page.saturating_sub(MAX_IMAGE_SCAN);
+        let mut base = Some(page);
+        while let Some(start) = base.filter(|&start| start >= lowest) {
+            if let Some(module) = self.image_at(walker, start).filter(|module| virt < start + module.size) {
+                self.modules.push(module.clone());
+                return Some(module);
+            }
+            base = start.checked_sub(PAGE_SIZE as u64);
+        }
+        self.misses.insert(page);
+        None
+    }
+}
+
+/// Size and export name of a mapped PE image, `None` for headers pointing outside of it
+fn parse_image(walker: &PageWalker, base: u64) -> Option<GuestModule> {
+    let e_lfanew = read_u32(walker, base + 0x3c)? as u64;
+    let pe = base.checked_add(e_lfanew)?;
+    if read_u32(walker, pe)? != 0x0000_4550 {
+        return None;
+    }
+    let optional = pe.checked_add(24)?;
+    let size = read_u32(walker, optional.checked_add(56)?)? as u64;
+    // Past that the image can't be found anyway, the offsets below now stay inside it
+    if size > MAX_IMAGE_SCAN || e_lfanew + PE_HEADERS_LEN > size || base.checked_add(size).is_none() {
+        return None;
+    }
+    let export_dir = match read_u16(walker, optional)? {
+        0x20b => optional + 112,
+        0x10b => optional + 96,
+        _ => return None,
+    };
+    let export_rva = read_u32(walker, export_dir)? as u64;
+    let name = if export_rva == 0 {
+        format!("image@{base:x}")
+    } else {
+        if export_rva + 16 > size {
+            return None;
+        }
+        let name_rva = read_u32(walker, base + export_rva + 12)? as u64;
+        if name_rva >= size {
+            return None;
+        }
+        let mut name = [0u8; 64];
+        let name = &mut name[..(size - name_rva).min(64) as usize];
+        walker.read_virt(base + name_rva, name)?;
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        String::from_utf8_lossy(&name[..len]).into_owned()
+    };
+    Some(GuestModule { base, size, name })
+}
+
+impl fmt::Display for WriteDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} pages written", self.pages.len())?;
+        for page in &self.pages {
+            write!(f, "  0x{page:x}")?;
+            for alias in self.virtual_pages.get(page).into_iter().flatten() {
+                write!(f, " va=0x{:x}", alias.virt)?;
+                if let Some(module) = &alias.module {
+                    write!(f, " ({}+0x{:x})", module.name, alias.virt - module.base)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        for diff in &self.bytes {
+            writeln!(f, "  0x{:x}: {:02x?} -> {:02x?}", diff.guest_addr, diff.old, diff.new)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kvm_bindings::kvm_sregs;
+
+    use super::*;
+    use crate::vmm::ram::Ram;
+
+    /// PE32+ headers of an image without exports
+    fn write_image(ram: &Ram, base: u64, size: u32) {
+        let mem = &ram.guest_mem_map;
+        mem.write_obj(0x5a4du16, GuestAddress(base)).unwrap();
+        mem.write_obj(0x80u32, GuestAddress(base + 0x3c)).unwrap();
+        mem.write_obj(0x0000_4550u32, GuestAddress(base + 0x80)).unwrap();
+        mem.write_obj(0x20bu16, GuestAddress(base + 0x98)).unwrap();
+        mem.write_obj(size, GuestAddress(base + 0x98 + 56)).unwrap();
+    }
+
+    #[test]
+    fn changed_byte_runs() {
+        let old = [0, 1, 2, 3, 4, 5, 6, 7];
+        let new = [0, 9, 9, 3, 4, 5, 6, 8];
+        let runs = byte_runs(0x1000, &old, &new);
+        assert_eq!(runs.len(), 2);
+        assert_eq!((runs[0].guest_addr, &runs[0].old[..], &runs[0].new[..]), (0x1001, &[1, 2][..], &[9, 9][..]));
+        assert_eq!((runs[1].guest_addr, &runs[1].old[..], &runs[1].new[..]), (0x1007, &[7][..], &[8][..]));
+        assert!(byte_runs(0, &old, &old).is_empty());
+    }
+
+    #[test]
+    fn module_lookup_skips_images_ending_below() {
+        let ram = Ram::anonymous(0x40000);
+        write_image(&ram, 0x10000, 0x8000);
+        write_image(&ram, 0x13000, 0x1000);
+        // Paging off, virtual addresses are physical ones
+        let sregs = kvm_sregs::default();
+        let walker = PageWalker::new(&ram, &sregs);
+        let mut modules = ModuleCache::default();
+
+        assert_eq!(modules.find(&walker, 0x13800).unwrap().base, 0x13000);
+        let module = modules.find(&walker, 0x14010).unwrap();
+        assert_eq!((module.base, module.size, module.name.as_str()), (0x10000, 0x8000, "image@10000"));
+        assert_eq!(modules.find(&walker, 0x11000).unwrap().base, 0x10000);
+        assert!(modules.find(&walker, 0x20000).is_none());
+        assert!(modules.misses.contains(&0x20000));
+        assert!(modules.find(&walker, 0x8000).is_none());
+    }
+
+    #[test]
+    fn malformed_images_skipped() {
+        let ram = Ram::anonymous(0x40000);
+        let mem = &ram.guest_mem_map;
+        let sregs = kvm_sregs::default();
+        let walker = PageWalker::new(&ram, &sregs);
+        write_image(&ram, 0x10000, 0x2000);
+        assert!(parse_image(&walker, 0x10000).is_some());
+
+        // Sizes no image has
+        write_image(&ram, 0x10000, u32::MAX);
+        assert!(parse_image(&walker, 0x10000).is_none());
+        write_image(&ram, 0x10000, 0x80);
+        assert!(parse_image(&walker, 0x10000).is_none());
+        // Export directory, then its name, past the end of the image
+        write_image(&ram, 0x10000, 0x2000);
+        mem.write_obj(0x1ff8u32, GuestAddress(0x10000 + 0x98 + 112)).unwrap();
+        assert!(parse_image(&walker, 0x10000).is_none());
+        mem.write_obj(0x1000u32, GuestAddress(0x10000 + 0x98 + 112)).unwrap();
+        mem.write_obj(0xffff_0000u32, GuestAddress(0x11000 + 12)).unwrap();
+        assert!(parse_image(&walker, 0x10000).is_none());
+        // The name is cut at the end of the image
+        mem.write_obj(0x1ffeu32, GuestAddress(0x11000 + 12)).unwrap();
+        mem.write_slice(b"ab", GuestAddress(0x11ffe)).unwrap();
+        assert_eq!(parse_image(&walker, 0x10000).unwrap().name, "ab");
+    }
+}